@@ -1,12 +1,15 @@
-use crate::{Result, MAX_COMPRESSED_CHUNK_SIZE};
+use crate::pack::{self, Packed};
+use crate::{max_compressed_size, Result, RECORD_SIZE};
 use async_std::fs::{create_dir, read_dir, rename, File};
-use async_std::path::Path;
+use async_std::path::{Path, PathBuf};
 use async_std::prelude::*;
 use async_std::sync::Arc;
 use async_std::task;
 use minicbor_derive::{Decode, Encode};
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 
 #[derive(Debug, Clone, Encode, Decode)]
@@ -26,10 +29,67 @@ impl ArchiveLocation {
     }
 }
 
+/// Hash algorithm of an archive whose header names none
+const DEFAULT_HASH: &str = "seahash";
+
+/// Chunking of an archive whose header names none
+const DEFAULT_CHUNKING: &str = "fixed";
+
+/// Hash algorithm of a format 1 or 2 archive whose header names none
+const LEGACY_HASH: &str = "seahash-xor";
+
+/// Version of the archive format this build writes
+///
+///   1: no header, hashes possibly stored as a bare ChunkHash
+///   2: header with mode and version, hashes always a FileHash
+///   3: seahash combines the chunk hashes in order rather than XOR
+///      folding them, older archives keep folding as seahash-xor; a
+///      file not hashed in full yet has the hash FileHash::PENDING,
+///      and entries may keep the hash of their first chunk
+///
+///   The header's version is that of the sets it doesn't list with a
+///   `set N version V` line, so an archive may mix sets of several
+///   versions and each is decoded as its own, see set_version.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// How the items in an archive are encoded, kept in its header
+///
+///   An archive without a header predates headers and is Plain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveMode {
+    Plain,
+    Private,
+}
+
+impl std::fmt::Display for ArchiveMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ArchiveMode::Plain => "plain",
+            ArchiveMode::Private => "private",
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct Archive {
     limit: usize,
-    archive: String,
+    /// most bytes one record writes, see set_record_limit
+    max_record: usize,
+    mode: ArchiveMode,
+    version: u32,
+    /// sets written at another version than the header's
+    set_versions: BTreeMap<usize, u32>,
+    injested: BTreeMap<String, u64>,
+    hash: String,
+    chunking: String,
+    archive: PathBuf,
+    /// a single .fda file rather than a directory, see pack.rs
+    packed: bool,
+    /// the packed archive's index, parsed once, see open_packed
+    packed_index: Arc<Mutex<Option<Arc<Packed>>>>,
+    /// name, size and digest of the sets flushed to a packed
+    /// archive's staging directory, packed by finish
+    staged: Vec<(String, u64, String)>,
     record_type: String,
     active_tasks: Arc<AtomicUsize>,
     waiting_tasks: Arc<AtomicUsize>,
@@ -48,8 +108,15 @@ impl std::fmt::Debug for Archive {
             .field("wbuf.len()", &wbuf_len)
             .field("rbuf.len()", &rbuf_len)
             .field("limit", &self.limit)
+            .field("max_record", &self.max_record)
             .field("archive", &self.archive)
             .field("record_type", &self.record_type)
+            .field("mode", &self.mode)
+            .field("version", &self.version)
+            .field("set_versions", &self.set_versions)
+            .field("injested", &self.injested)
+            .field("hash", &self.hash)
+            .field("chunking", &self.chunking)
             .field("read_serial_number", &self.read_serial_number)
             .field("read_offset", &self.read_offset)
             .field("write_serial_number", &self.write_serial_number)
@@ -68,7 +135,17 @@ impl Archive {
             read_serial_number: 0,
             read_offset: 0,
             limit,
-            archive: archive.to_string(),
+            max_record: max_compressed_size(RECORD_SIZE),
+            mode: ArchiveMode::Plain,
+            version: FORMAT_VERSION,
+            set_versions: BTreeMap::new(),
+            injested: BTreeMap::new(),
+            hash: DEFAULT_HASH.to_string(),
+            chunking: DEFAULT_CHUNKING.to_string(),
+            archive: PathBuf::from(archive),
+            packed: pack::is_packed(archive),
+            packed_index: Arc::new(Mutex::new(None)),
+            staged: Vec::new(),
             record_type,
             active_tasks: Arc::new(AtomicUsize::new(0)),
             waiting_tasks: Arc::new(AtomicUsize::new(0)),
@@ -86,6 +163,11 @@ impl Archive {
         &self.record_type
     }
 
+    /// Size the room kept for a record to records of `record_limit`
+    pub fn set_record_limit(&mut self, record_limit: usize) {
+        self.max_record = max_compressed_size(record_limit);
+    }
+
     pub fn set_write_serial_number(&mut self, num: usize) {
         self.write_serial_number = num;
     }
@@ -94,9 +176,100 @@ impl Archive {
         self.read_serial_number
     }
 
+    /// Name of the set file with the given serial number
+    fn set_name(&self, serial_number: usize) -> String {
+        format!("{:04}_{}.cbor", serial_number, self.record_type)
+    }
+
+    /// The eight digit name a set may also have been given
+    ///
+    ///   Sets are written four digits wide, wider past 9999, but an
+    ///   archive may also hold sets named eight digits wide, and these
+    ///   are read just the same.
+    fn wide_set_name(&self, serial_number: usize) -> String {
+        format!("{:08}_{}.cbor", serial_number, self.record_type)
+    }
+
+    /// Path of the set file with the given serial number
+    fn set_path(&self, serial_number: usize) -> PathBuf {
+        self.archive.join(self.set_name(serial_number))
+    }
+
+    /// Where a packed archive's sets are written before finish packs them
+    fn staging(&self) -> PathBuf {
+        let mut staging = self.archive.as_os_str().to_owned();
+        staging.push(".staging");
+        PathBuf::from(staging)
+    }
+
+    /// The index of a packed archive, None if not written yet
+    ///
+    ///   Parsed once rather than for each set read, and forgotten
+    ///   whenever this archive writes the file again.
+    fn open_packed(&self) -> Result<Option<Arc<Packed>>> {
+        let mut index = self.packed_index.lock().unwrap();
+        if index.is_none() {
+            *index = Packed::open(self.archive.as_ref())?.map(Arc::new);
+        }
+        Ok(index.clone())
+    }
+
+    /// Forget the packed archive's index, after writing the file
+    fn forget_packed(&self) {
+        *self.packed_index.lock().unwrap() = None;
+    }
+
+    /// One of the archive's files other than a set, None if missing
+    async fn read_text(&self, name: &str) -> Result<Option<String>> {
+        let bytes = match self.packed {
+            true => match self.open_packed()? {
+                Some(packed) => packed.read(name)?,
+                None => None,
+            },
+            false => match async_std::fs::read(self.archive.join(name)).await {
+                Ok(bytes) => Some(bytes),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            },
+        };
+        match bytes {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes).map_err(|_| {
+                format!("{}: {} is not text", self.archive.display(), name)
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace one of the archive's files other than a set
+    ///
+    ///   A packed archive is rewritten, unless it already holds the
+    ///   same bytes, and created holding only this file if missing.
+    async fn write_text(&self, name: &str, text: String) -> Result<()> {
+        if !self.packed {
+            // renamed over, so a reader sees the old or the new, whole
+            let tmp = self.archive.join(format!("{}.tmp", name));
+            async_std::fs::write(&tmp, text).await?;
+            rename(&tmp, self.archive.join(name)).await?;
+            return Ok(());
+        }
+        match self.open_packed()? {
+            Some(packed) => {
+                if packed.read(name)?.as_deref() != Some(text.as_bytes()) {
+                    packed.rewrite(vec![(name.to_string(), Some(text.into_bytes()))])?;
+                }
+            }
+            None => pack::write(
+                self.archive.as_ref(),
+                vec![(name.to_string(), text.into_bytes())],
+            )?,
+        }
+        self.forget_packed();
+        Ok(())
+    }
+
     pub fn write_location(&self) -> ArchiveLocation {
         // if we will overrun, bump to next set
-        if self.write_buffer.len() + MAX_COMPRESSED_CHUNK_SIZE > self.limit {
+        if self.write_buffer.len() + self.max_record > self.limit {
             ArchiveLocation {
                 archive_set: self.write_serial_number + 1,
                 set_offset: 0,
@@ -114,8 +287,8 @@ impl Archive {
         // someone through self.write_lcoation() that we were going
         // to.  Also, make sure we are not exceeding it!  This is
         // easy with LZ4 compression as there is a fixed worst case size
-        assert!(v.len() < MAX_COMPRESSED_CHUNK_SIZE);
-        if self.write_buffer.len() + MAX_COMPRESSED_CHUNK_SIZE > self.limit {
+        assert!(v.len() < self.max_record);
+        if self.write_buffer.len() + self.max_record > self.limit {
             self.flush()?;
         }
         self.write_buffer.extend_from_slice(&v);
@@ -125,10 +298,18 @@ impl Archive {
     pub fn flush(&mut self) -> Result<()> {
         if self.write_buffer.len() > 0 {
             // create name for this physical file
-            let name = format!(
-                "{}/{:04}_{}.cbor",
-                self.archive, self.write_serial_number, self.record_type
-            );
+            let name = match self.packed {
+                true => {
+                    std::fs::create_dir_all(self.staging())?;
+                    self.staged.push((
+                        self.set_name(self.write_serial_number),
+                        self.write_buffer.len() as u64,
+                        set_digest(&self.write_buffer, false),
+                    ));
+                    self.staging().join(self.set_name(self.write_serial_number))
+                }
+                false => self.set_path(self.write_serial_number),
+            };
 
             // bump waiting count before spawn so we see it
             self.waiting_tasks.fetch_add(1, Ordering::SeqCst);
@@ -140,6 +321,8 @@ impl Archive {
                 self.waiting_tasks.clone(),
             ));
 
+            // this build's format from now on, whatever was there before
+            self.set_versions.remove(&self.write_serial_number);
             // reset buffer
             self.write_serial_number += 1;
             self.write_buffer = Vec::new();
@@ -151,30 +334,74 @@ impl Archive {
     ///
     ///   1. flush out the remaining data
     ///   2. wait for all subtasks invoved in write to finish
+    ///
+    ///   3. for a packed archive, pack the staged sets in place of
+    ///      those it held, with the header and manifest they go with
     pub async fn finish(&mut self) -> Result<()> {
         self.flush()?;
+        let written = self.write_serial_number;
+        self.set_versions.retain(|set, _| *set < written);
 
         while self.task_counts().1 > 0 {
             task::sleep(Duration::from_millis(200)).await;
         }
+        if self.packed {
+            let staging = self.staging();
+            async_std::fs::create_dir_all(&staging).await?;
+            let staged = std::mem::take(&mut self.staged);
+            let sets: Vec<_> = staged
+                .iter()
+                .map(|(name, _, _)| staging.join(name).into())
+                .collect();
+            async_std::fs::write(staging.join(self.header_name()), self.header_text()).await?;
+            async_std::fs::write(
+                staging.join(self.manifest_name()),
+                self.manifest_text(staged),
+            )
+            .await?;
+            pack::pack(
+                staging.as_ref(),
+                &sets,
+                &self.record_type,
+                self.archive.as_ref(),
+            )?;
+            self.forget_packed();
+            async_std::fs::remove_dir_all(&staging).await?;
+        }
         Ok(())
     }
 
     /// Read a set of data from the archive
+    ///
+    ///   With no set loaded yet, reading starts at the offset given by
+    ///   seek (zero otherwise), else at the start of the next set.
     pub fn read(&mut self, len: usize) -> Result<Option<&[u8]>> {
         if self.read_buffer.is_none()
             || self.read_buffer.as_ref().unwrap().len() < self.read_offset + len
         {
             if self.read_buffer.is_some() {
                 self.read_serial_number += 1;
+                self.read_offset = 0;
             }
-            self.read_offset = 0;
             self.read_buffer = None;
-            let name = format!(
-                "{}/{:04}_{}.cbor",
-                self.archive, self.read_serial_number, self.record_type
-            );
-            self.read_buffer = read_file(name)?;
+            self.read_buffer = match self.packed {
+                // only the set wanted is read, not the whole file
+                true => match self.open_packed()? {
+                    Some(packed) => match packed.read(&self.set_name(self.read_serial_number))? {
+                        Some(data) => Some(data),
+                        None => packed.read(&self.wide_set_name(self.read_serial_number))?,
+                    }
+                    .map(Arc::new),
+                    None => None,
+                },
+                false => match read_file(self.set_path(self.read_serial_number))? {
+                    Some(data) => Some(data),
+                    None => read_file(
+                        self.archive
+                            .join(self.wide_set_name(self.read_serial_number)),
+                    )?,
+                },
+            };
         }
         if let Some(buf) = &self.read_buffer {
             let offset = self.read_offset;
@@ -189,6 +416,21 @@ impl Archive {
         }
     }
 
+    /// Forget the current read position and buffer
+    pub fn reset_read(&mut self) {
+        self.forget_packed();
+        self.read_buffer = None;
+        self.read_serial_number = 0;
+        self.read_offset = 0;
+    }
+
+    /// Drop unflushed data and start writing again at the first set
+    pub fn reset_write(&mut self) {
+        self.write_buffer = Vec::new();
+        self.staged.clear();
+        self.write_serial_number = 0;
+    }
+
     /// seek to a specific location to read next
     pub fn seek(&mut self, location: ArchiveLocation) -> Result<()> {
         self.read_buffer = None;
@@ -197,24 +439,556 @@ impl Archive {
         Ok(())
     }
 
+    /// Check that the set files form one unambiguous sequence
+    ///
+    ///   Leftover files with an extra suffix (e.g. `.tmp`), empty sets,
+    ///   two files for one serial number, and sets after a gap in the
+    ///   numbering all make what gets read depend on the filesystem.
+    ///   Serials are read four or eight digits wide, see
+    ///   wide_set_name, so a set padded any other way, `000010_file.cbor`,
+    ///   would never be read and is refused too.
+    ///   Such files are refused, or with `repair` moved into a
+    ///   `<type>.repair` directory.  Returns the names of the sets that
+    ///   will be read, in order.
+    ///   A packed archive's sets are named as if it were a directory,
+    ///   and are only written in sequence, so anything else is refused.
+    pub async fn check_sets(&self, repair: bool, verbose: u64) -> Result<Vec<String>> {
+        if self.packed {
+            let packed = match self.open_packed()? {
+                Some(packed) => packed,
+                None => return Ok(Vec::new()),
+            };
+            let sets: Vec<_> = packed
+                .files()
+                .filter(|(name, _)| name.ends_with(&format!("_{}.cbor", self.record_type)))
+                .collect();
+            for (n, (name, len)) in sets.iter().enumerate() {
+                if *name != self.set_name(n) && *name != self.wide_set_name(n) {
+                    return Err(format!(
+                        "{}: holds set {} where {} belongs",
+                        self.archive.display(),
+                        name,
+                        self.set_name(n)
+                    )
+                    .into());
+                }
+                if verbose > 0 {
+                    eprintln!(
+                        "archive set {}: {} ({} bytes)",
+                        self.archive.display(),
+                        name,
+                        len
+                    );
+                }
+            }
+            return Ok(sets.iter().map(|(name, _)| name.to_string()).collect());
+        }
+        let mut dir = match read_dir(&self.archive).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let re = Regex::new(&format!(r"^(\d{{4,}})_{}\.cbor(.*)$", self.record_type)).unwrap();
+        let mut sets: Vec<(usize, PathBuf, u64)> = Vec::new();
+        let mut bad: Vec<(PathBuf, &str)> = Vec::new();
+        while let Some(res) = dir.next().await {
+            let entry = res?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(caps) = re.captures(&file_name) {
+                let len = entry.metadata().await?.len();
+                if !caps[2].is_empty() {
+                    bad.push((entry.path(), "leftover file"));
+                } else if len == 0 {
+                    bad.push((entry.path(), "empty set"));
+                } else {
+                    let serial = caps[1].parse()?;
+                    match file_name == self.set_name(serial)
+                        || file_name == self.wide_set_name(serial)
+                    {
+                        true => sets.push((serial, entry.path(), len)),
+                        false => bad.push((entry.path(), "serial neither four nor eight digits")),
+                    }
+                }
+            }
+        }
+        sets.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        // read stops at the first missing serial, so anything after a
+        // gap or a repeated serial is not part of this generation
+        let mut good = Vec::new();
+        for (serial, path, len) in sets {
+            if serial == good.len() {
+                if verbose > 0 {
+                    eprintln!("archive set {} ({} bytes)", path.display(), len);
+                }
+                good.push(path.file_name().unwrap().to_string_lossy().into_owned());
+            } else if serial < good.len() {
+                bad.push((path, "second file for one serial number"));
+            } else {
+                bad.push((path, "set after a gap in the numbering"));
+            }
+        }
+
+        if !bad.is_empty() {
+            for (path, why) in &bad {
+                eprintln!("archive: {}: {}", path.display(), why);
+            }
+            if !repair {
+                return Err(format!(
+                    "{} ambiguous files in archive {}, use --repair to move them aside",
+                    bad.len(),
+                    self.archive.display()
+                )
+                .into());
+            }
+            let aside = self.archive.join(format!("{}.repair", self.record_type));
+            if !aside.exists().await {
+                create_dir(&aside).await?;
+            }
+            for (path, _) in bad {
+                let to = aside.join(path.file_name().unwrap());
+                status!("mv {} {}", path.display(), to.display());
+                rename(&path, &to).await?;
+            }
+        }
+        Ok(good)
+    }
+
+    /// Serial number for the next set appended to this archive
+    ///
+    ///   Existing names may be four or eight digits wide, so
+    ///   `0009_file.cbor` can be followed by `00000010_file.cbor`.  The
+    ///   sets must form one sequence from zero with no leftovers, see
+    ///   check_sets, or an appended set would either never be read or
+    ///   overwrite a live one; anything else is an error.
+    pub async fn next_free_serial(&self) -> Result<usize> {
+        Ok(self.check_sets(false, 0).await?.len())
+    }
+
+    pub fn mode(&self) -> ArchiveMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ArchiveMode) {
+        self.mode = mode;
+    }
+
+    /// Name of the --hash algorithm the items were hashed with
+    pub fn hash_algorithm(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn set_hash_algorithm(&mut self, hash: String) {
+        self.hash = hash;
+    }
+
+    /// Where the items' files were cut into chunks to hash, see --cdc
+    pub fn chunking(&self) -> &str {
+        &self.chunking
+    }
+
+    pub fn set_chunking(&mut self, chunking: String) {
+        self.chunking = chunking;
+    }
+
+    /// When each injest root was last scanned to completion, unix seconds
+    pub fn injested(&self) -> &BTreeMap<String, u64> {
+        &self.injested
+    }
+
+    pub fn set_injested(&mut self, injested: BTreeMap<String, u64>) {
+        self.injested = injested;
+    }
+
+    /// Name of the header describing how the sets are encoded
+    fn header_name(&self) -> String {
+        format!("{}.header", self.record_type)
+    }
+
+    /// Format version found by read_header
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Format version of one set, the header's unless it lists the set
+    pub fn set_version(&self, serial_number: usize) -> u32 {
+        *self
+            .set_versions
+            .get(&serial_number)
+            .unwrap_or(&self.version)
+    }
+
+    /// Format version of the set being read, to decode its items by
+    pub fn read_set_version(&self) -> u32 {
+        self.set_version(self.read_serial_number)
+    }
+
+    /// Oldest format version of the header and of the first `sets` sets
+    pub fn oldest_version(&self, sets: usize) -> u32 {
+        (0..sets)
+            .map(|set| self.set_version(set))
+            .fold(self.version, u32::min)
+    }
+
+    /// How many of the first `sets` sets are of each format version
+    pub fn version_mix(&self, sets: usize) -> BTreeMap<u32, usize> {
+        let mut mix = BTreeMap::new();
+        for set in 0..sets {
+            *mix.entry(self.set_version(set)).or_insert(0) += 1;
+        }
+        mix
+    }
+
+    /// Load the mode and version from the header
+    ///
+    ///   No header means a format 1 archive, always Plain.  A header
+    ///   without a version line was written by format 2.  Before format
+    ///   3 a header without a hash line meant seahash-xor, and sets
+    ///   added later kept hashing as the archive did, so with any set
+    ///   older than 3 it still does.
+    pub async fn read_header(&mut self) -> Result<ArchiveMode> {
+        let path = self.archive.join(self.header_name());
+        self.injested.clear();
+        self.set_versions.clear();
+        self.hash = DEFAULT_HASH.to_string();
+        self.chunking = DEFAULT_CHUNKING.to_string();
+        let text = self.read_text(&self.header_name()).await?;
+        let text = match text {
+            Some(text) => text,
+            None => {
+                self.mode = ArchiveMode::Plain;
+                // a new archive gets the current format when first written
+                self.version = match self.next_free_serial().await? {
+                    0 => FORMAT_VERSION,
+                    _ => {
+                        self.hash = LEGACY_HASH.to_string();
+                        1
+                    }
+                };
+                return Ok(self.mode);
+            }
+        };
+        let mut mode = None;
+        let mut hash = None;
+        self.version = 2;
+        let parse_version = |version: &str| -> Result<u32> {
+            match version.parse() {
+                Ok(version) if version > FORMAT_VERSION => Err(format!(
+                    "{}: format version {} is newer than this find_dups ({})",
+                    path.display(),
+                    version,
+                    FORMAT_VERSION
+                )
+                .into()),
+                Ok(version) if version > 0 => Ok(version),
+                _ => Err(format!("{}: bad version {:?}", path.display(), version).into()),
+            }
+        };
+        for line in text.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            // the root may hold spaces, so it is the rest of the line
+            if let Some(rest) = line.strip_prefix("injested ") {
+                let (secs, root) = rest
+                    .split_once(' ')
+                    .and_then(|(secs, root)| Some((secs.parse().ok()?, root)))
+                    .ok_or_else(|| format!("{}: bad line {:?}", path.display(), line))?;
+                self.injested.insert(root.to_string(), secs);
+                continue;
+            }
+            if let Some(chunking) = line.strip_prefix("chunking ") {
+                self.chunking = chunking.to_string();
+                continue;
+            }
+            mode = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["mode", "plain"] => Some(ArchiveMode::Plain),
+                ["mode", "private"] => Some(ArchiveMode::Private),
+                ["hash", named] => {
+                    hash = Some(named.to_string());
+                    mode
+                }
+                ["version", version] => {
+                    self.version = parse_version(version)?;
+                    mode
+                }
+                ["set", set, "version", version] => {
+                    let set = set
+                        .parse()
+                        .map_err(|_| format!("{}: bad line {:?}", path.display(), line))?;
+                    self.set_versions.insert(set, parse_version(version)?);
+                    mode
+                }
+                // refuse rather than misread sets in an unknown encoding
+                _ => {
+                    return Err(
+                        format!("{}: unknown header line {:?}", path.display(), line).into(),
+                    )
+                }
+            };
+        }
+        self.mode = mode.ok_or_else(|| format!("{}: no mode in header", path.display()))?;
+        self.hash = match hash {
+            Some(hash) => hash,
+            None if self.version < 3 || self.set_versions.values().any(|v| *v < 3) => {
+                LEGACY_HASH.to_string()
+            }
+            None => DEFAULT_HASH.to_string(),
+        };
+        Ok(self.mode)
+    }
+
+    /// Record the current mode in the header, with this build's version
+    pub async fn write_header(&self) -> Result<()> {
+        self.write_text(&self.header_name(), self.header_text())
+            .await
+    }
+
+    /// The header write_header writes
+    fn header_text(&self) -> String {
+        let mut header = format!(
+            "# find_dups {} archive\nversion {}\nmode {}\n",
+            self.record_type, FORMAT_VERSION, self.mode
+        );
+        // older builds refuse the line, so only write it when needed
+        if self.hash != DEFAULT_HASH {
+            header += &format!("hash {}\n", self.hash);
+        }
+        if self.chunking != DEFAULT_CHUNKING {
+            header += &format!("chunking {}\n", self.chunking);
+        }
+        for (set, version) in &self.set_versions {
+            header += &format!("set {} version {}\n", set, version);
+        }
+        for (root, secs) in &self.injested {
+            header += &format!("injested {} {}\n", secs, root);
+        }
+        header
+    }
+
+    /// Total bytes of the set files that will be read
+    pub async fn sets_size(&self) -> Result<u64> {
+        let sets = self.check_sets(false, 0).await?;
+        if self.packed {
+            return Ok(match self.open_packed()? {
+                Some(packed) => packed
+                    .files()
+                    .filter(|(name, _)| sets.iter().any(|set| set == name))
+                    .map(|(_, len)| len)
+                    .sum(),
+                None => 0,
+            });
+        }
+        let mut total = 0;
+        for name in sets {
+            total += async_std::fs::metadata(self.archive.join(name))
+                .await?
+                .len();
+        }
+        Ok(total)
+    }
+
+    /// Name of the manifest listing this archive's set files
+    fn manifest_name(&self) -> String {
+        format!("{}.manifest", self.record_type)
+    }
+
+    /// Name, size and digest of each set file, in serial order, see
+    /// set_digest
+    async fn set_digests(&self, seahash: bool) -> Result<Vec<(String, u64, String)>> {
+        let mut ret = Vec::new();
+        let packed = match self.packed {
+            true => self.open_packed()?,
+            false => None,
+        };
+        for name in self.check_sets(false, 0).await? {
+            let data = match &packed {
+                Some(packed) => packed.read(&name)?.unwrap_or_default(),
+                None => async_std::fs::read(self.archive.join(&name)).await?,
+            };
+            ret.push((name, data.len() as u64, set_digest(&data, seahash)));
+        }
+        Ok(ret)
+    }
+
+    /// Write a manifest of the set files for checking replicas
+    pub async fn write_manifest(&self) -> Result<()> {
+        let manifest = self.manifest_text(self.set_digests(false).await?);
+        self.write_text(&self.manifest_name(), manifest).await
+    }
+
+    /// A manifest of sets named, sized and hashed as given
+    fn manifest_text(&self, digests: Vec<(String, u64, String)>) -> String {
+        let mut manifest = format!("# find_dups {} sets: name size blake3\n", self.record_type);
+        for (name, len, digest) in digests {
+            manifest += &format!("{} {} {}\n", name, len, digest);
+        }
+        manifest
+    }
+
+    /// Compare the set files against the stored manifest
+    ///
+    ///   Prints each added, missing or modified set and returns true if
+    ///   there were none.  A manifest written before blake3 was used
+    ///   names seahash in its header, and is checked by seahash.
+    pub async fn check_manifest(&self) -> Result<bool> {
+        let path = self.archive.join(self.manifest_name());
+        let text = self
+            .read_text(&self.manifest_name())
+            .await?
+            .ok_or_else(|| format!("{}: no manifest", path.display()))?;
+        let seahash = text
+            .lines()
+            .next()
+            .is_some_and(|header| header.ends_with(" seahash"));
+        let mut expected = std::collections::BTreeMap::new();
+        for line in text.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("{}: bad manifest line {:?}", path.display(), line).into());
+            }
+            expected.insert(
+                fields[0].to_string(),
+                (fields[1].to_string(), fields[2].to_string()),
+            );
+        }
+        let mut ok = true;
+        for (name, len, digest) in self.set_digests(seahash).await? {
+            match expected.remove(&name) {
+                None => {
+                    println!("added {}", name);
+                    ok = false;
+                }
+                Some((elen, ehash)) => {
+                    if elen != len.to_string() || ehash != digest {
+                        println!("modified {}", name);
+                        ok = false;
+                    }
+                }
+            }
+        }
+        for name in expected.keys() {
+            println!("missing {}", name);
+            ok = false;
+        }
+        Ok(ok)
+    }
+
+    /// Copy this archive directory into a single file, see `find_dups pack`
+    pub async fn pack(&self, to: &str) -> Result<()> {
+        if self.packed {
+            return Err(format!("{} is already a single file", self.archive.display()).into());
+        }
+        if !pack::is_packed(to) {
+            return Err(format!("{} must end in {}", to, pack::SUFFIX).into());
+        }
+        let sets: Vec<std::path::PathBuf> = self
+            .check_sets(false, 0)
+            .await?
+            .into_iter()
+            .map(|name| self.archive.join(name).into())
+            .collect();
+        let n = pack::pack(self.archive.as_ref(), &sets, &self.record_type, to.as_ref())?;
+        println!(
+            "packed {} files of {} into {}",
+            n,
+            self.archive.display(),
+            to
+        );
+        Ok(())
+    }
+
+    /// Replace the sets, header and manifest with those a Record wrote
+    /// in `staging`, the old sets kept in the backup
+    ///
+    ///   The old sets are copied to the backup, not moved, and each
+    ///   staged file is renamed over the one it replaces, so no set is
+    ///   ever missing or half written.  The header goes last, so until
+    ///   then the sets are read as they were, and a swap cut short is
+    ///   put right by restoring the backup.  `staging` is removed once
+    ///   the swap is done.
+    pub async fn swap_in(&self, staging: &Path) -> Result<()> {
+        let backup = self.archive.join(format!("{}.backup", self.record_type));
+        if backup.exists().await {
+            // sets of an older generation would be taken for these
+            for set in self.sets_in(&backup).await? {
+                async_std::fs::remove_file(set).await?;
+            }
+        } else {
+            create_dir(&backup).await?;
+        }
+        let old = self.sets_in(&self.archive).await?;
+        for set in &old {
+            let to = backup.join(set.file_name().unwrap());
+            status!("cp {} {}", set.display(), to.display());
+            async_std::fs::copy(set, to).await?;
+        }
+        let new = self.sets_in(staging).await?;
+        for set in &new {
+            rename(set, self.archive.join(set.file_name().unwrap())).await?;
+        }
+        for set in old.iter().skip(new.len()) {
+            async_std::fs::remove_file(set).await?;
+        }
+        for name in [self.manifest_name(), self.header_name()] {
+            if staging.join(&name).exists().await {
+                rename(staging.join(&name), self.archive.join(&name)).await?;
+            }
+        }
+        async_std::fs::remove_dir_all(staging).await?;
+        Ok(())
+    }
+
+    /// The set files of this record type in `dir`, in serial order
+    async fn sets_in(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let re = Regex::new(&format!(r"^(\d{{4,}})_{}\.cbor$", self.record_type)).unwrap();
+        let mut sets = Vec::new();
+        let mut entries = read_dir(dir).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(serial) = re.captures(&name).and_then(|caps| caps[1].parse().ok()) {
+                sets.push((serial, entry.path()));
+            }
+        }
+        sets.sort_unstable_by_key(|(serial, _): &(usize, PathBuf)| *serial);
+        Ok(sets.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Move the sets aside before they are written again
+    ///
+    ///   A packed archive is copied whole to `<path>.backup`.
     pub async fn backup(&self) -> Result<()> {
-        let backup = format!("{}/{}.backup", self.archive, self.record_type);
+        if self.packed {
+            if self.archive.exists().await {
+                let mut backup = self.archive.as_os_str().to_owned();
+                backup.push(".backup");
+                let backup = PathBuf::from(backup);
+                status!("cp {} {}", self.archive.display(), backup.display());
+                async_std::fs::copy(&self.archive, backup).await?;
+            }
+            return Ok(());
+        }
+        let backup = self.archive.join(format!("{}.backup", self.record_type));
 
-        if !Path::new(&backup).exists().await {
+        if !backup.exists().await {
             create_dir(&backup).await?;
         }
 
         let mut dir = read_dir(&self.archive).await?;
 
-        let regex_str = format!(".*(\\d{{4}}_{}.cbor)", self.record_type);
-        let re = Regex::new(&regex_str).unwrap();
+        // whole names only, as serials run past four digits and a set
+        // still being written ends in .tmp
+        let re = Regex::new(&format!(r"^\d{{4,}}_{}\.cbor$", self.record_type)).unwrap();
         while let Some(res) = dir.next().await {
             let entry = res?;
-            let path = entry.path().into_os_string().into_string().unwrap();
-            if let Some(caps) = re.captures(&path) {
-                let file_name = caps.get(1).unwrap().as_str();
-                let to = format!("{}/{}", backup, file_name);
-                println!("mv {} {}", &path, to);
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if re.is_match(&file_name) {
+                let to = backup.join(&file_name);
+                status!("mv {} {}", entry.path().display(), to.display());
                 rename(entry.path(), to).await?;
             }
         }
@@ -223,7 +997,7 @@ impl Archive {
 }
 
 pub async fn write_file(
-    name: String,
+    name: PathBuf,
     v: Vec<u8>,
     active_tasks: Arc<AtomicUsize>,
     waiting_tasks: Arc<AtomicUsize>,
@@ -244,7 +1018,7 @@ pub async fn write_file(
 }
 
 use std::io::Read;
-pub fn read_file(name: String) -> Result<Option<Arc<Vec<u8>>>> {
+pub fn read_file(name: PathBuf) -> Result<Option<Arc<Vec<u8>>>> {
     if let Ok(mut f) = std::fs::File::open(name) {
         let mut buf: Vec<u8> = Vec::new();
         f.read_to_end(&mut buf)?;
@@ -253,3 +1027,184 @@ pub fn read_file(name: String) -> Result<Option<Arc<Vec<u8>>>> {
         Ok(None)
     }
 }
+
+/// What a manifest keeps of a set's bytes, in hex: a blake3 digest, or
+/// a seahash for manifests written before blake3 was used
+fn set_digest(data: &[u8], seahash: bool) -> String {
+    match seahash {
+        true => format!("{:016x}", seahash::hash(data)),
+        false => blake3::hash(data).to_hex().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn archive(dir: &std::path::Path) -> Archive {
+        Archive::new(dir.to_str().unwrap(), "test".to_string(), 1 << 20)
+    }
+
+    #[test]
+    fn every_spelling_names_the_same_sets() {
+        let set_path = |spelling: &str| {
+            let archive = crate::normalize_archive_path(spelling, false).unwrap();
+            Archive::new(&archive, "file".to_string(), 1 << 20).set_path(3)
+        };
+        let expected = PathBuf::from("/tmp/finddups/0003_file.cbor");
+        for spelling in [
+            "/tmp/finddups",
+            "/tmp/finddups/",
+            "/tmp//finddups",
+            "/tmp/./finddups/.",
+            "//tmp/finddups//",
+        ] {
+            assert_eq!(set_path(spelling), expected, "{}", spelling);
+        }
+        if let Ok(home) = std::env::var("HOME") {
+            let expected = PathBuf::from(&home).join("finddups/0003_file.cbor");
+            assert_eq!(set_path("~/finddups/"), expected);
+            assert_eq!(set_path("~//finddups"), expected);
+        }
+        assert_eq!(
+            set_path("~user/finddups"),
+            PathBuf::from("~user/finddups/0003_file.cbor")
+        );
+    }
+
+    #[test]
+    fn bad_archive_paths_are_refused() {
+        assert!(crate::normalize_archive_path("", false).is_err());
+        assert!(crate::normalize_archive_path("  ", false).is_err());
+        assert!(crate::normalize_archive_path("rel/archive", true).is_err());
+        assert_eq!(
+            crate::normalize_archive_path("./rel//archive/", false).unwrap(),
+            "rel/archive"
+        );
+        assert_eq!(crate::normalize_archive_path("./", false).unwrap(), ".");
+    }
+
+    #[test]
+    fn manifests_check_sets_by_blake3_or_an_older_seahash() {
+        let dir = scratch("manifest");
+        std::fs::write(dir.join("0000_test.cbor"), b"set").unwrap();
+        let manifest = dir.join("test.manifest");
+        task::block_on(async {
+            let archive = archive(&dir);
+            archive.write_manifest().await.unwrap();
+            let text = std::fs::read_to_string(&manifest).unwrap();
+            assert!(text.contains(&blake3::hash(b"set").to_hex().to_string()));
+            assert!(archive.check_manifest().await.unwrap());
+
+            std::fs::write(
+                &manifest,
+                format!(
+                    "# find_dups test sets: name size seahash\n0000_test.cbor 3 {:016x}\n",
+                    seahash::hash(b"set")
+                ),
+            )
+            .unwrap();
+            assert!(archive.check_manifest().await.unwrap());
+            std::fs::write(dir.join("0000_test.cbor"), b"SET").unwrap();
+            assert!(!archive.check_manifest().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn no_sets_in_an_empty_or_missing_archive() {
+        let dir = scratch("sets-empty");
+        task::block_on(async {
+            assert!(archive(&dir).check_sets(false, 0).await.unwrap().is_empty());
+            let missing = archive(&dir.join("missing"));
+            assert!(missing.check_sets(false, 0).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn gaps_and_leftovers_are_refused() {
+        let dir = scratch("sets-gap");
+        for name in ["0000_test.cbor", "0002_test.cbor"] {
+            std::fs::write(dir.join(name), b"set").unwrap();
+        }
+        task::block_on(async {
+            assert!(archive(&dir).check_sets(false, 0).await.is_err());
+            assert_eq!(archive(&dir).check_sets(true, 0).await.unwrap().len(), 1);
+            assert!(dir.join("test.repair/0002_test.cbor").exists());
+            std::fs::write(dir.join("0001_test.cbor.tmp"), b"set").unwrap();
+            assert!(archive(&dir).check_sets(false, 0).await.is_err());
+        });
+    }
+
+    #[test]
+    fn next_free_serial_follows_the_sets() {
+        let dir = scratch("sets-next");
+        task::block_on(async {
+            assert_eq!(archive(&dir).next_free_serial().await.unwrap(), 0);
+            let missing = archive(&dir.join("missing"));
+            assert_eq!(missing.next_free_serial().await.unwrap(), 0);
+            std::fs::write(dir.join("0000_test.cbor"), b"set").unwrap();
+            std::fs::write(dir.join("0002_test.cbor"), b"set").unwrap();
+            assert!(archive(&dir).next_free_serial().await.is_err());
+            std::fs::write(dir.join("0001_test.cbor.tmp"), b"set").unwrap();
+            std::fs::remove_file(dir.join("0002_test.cbor")).unwrap();
+            assert!(archive(&dir).next_free_serial().await.is_err());
+        });
+    }
+
+    #[test]
+    fn sets_four_and_eight_digits_wide_mix() {
+        let dir = scratch("sets-wide");
+        for (name, data) in [("0000_test.cbor", b"zero"), ("00000001_test.cbor", b"one!")] {
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+        let mut sets = archive(&dir);
+        task::block_on(async {
+            assert_eq!(sets.next_free_serial().await.unwrap(), 2);
+            let found = sets.check_sets(false, 0).await.unwrap();
+            assert_eq!(found, ["0000_test.cbor", "00000001_test.cbor"]);
+        });
+        assert_eq!(sets.read(4).unwrap().unwrap(), b"zero");
+        assert_eq!(sets.read(4).unwrap().unwrap(), b"one!");
+        assert!(sets.read(4).unwrap().is_none());
+
+        // one serial in both widths, or padded to another width
+        std::fs::write(dir.join("00000000_test.cbor"), b"zero").unwrap();
+        assert!(task::block_on(sets.next_free_serial()).is_err());
+        std::fs::remove_file(dir.join("00000000_test.cbor")).unwrap();
+        std::fs::write(dir.join("000002_test.cbor"), b"two!").unwrap();
+        assert!(task::block_on(sets.next_free_serial()).is_err());
+    }
+
+    #[test]
+    fn serials_roll_over_past_four_digits() {
+        let dir = scratch("sets-rollover");
+        for serial in 0..10000u32 {
+            let name = format!("{:04}_test.cbor", serial);
+            std::fs::write(dir.join(name), serial.to_le_bytes()).unwrap();
+        }
+        let mut sets = archive(&dir);
+        task::block_on(async {
+            sets.set_write_serial_number(10000);
+            sets.write(&10000u32.to_le_bytes()).unwrap();
+            sets.finish().await.unwrap();
+            let found = sets.check_sets(false, 0).await.unwrap();
+            assert_eq!(found.len(), 10001);
+            assert!(found[10000].ends_with("10000_test.cbor"));
+        });
+        assert!(dir.join("10000_test.cbor").exists());
+        for serial in 0..=10000u32 {
+            let read = sets.read(4).unwrap().unwrap();
+            assert_eq!(read, serial.to_le_bytes());
+        }
+        assert!(sets.read(4).unwrap().is_none());
+        task::block_on(sets.backup()).unwrap();
+        let backup = dir.join("test.backup");
+        assert_eq!(
+            std::fs::read(backup.join("0000_test.cbor")).unwrap(),
+            [0; 4]
+        );
+        assert!(backup.join("10000_test.cbor").exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
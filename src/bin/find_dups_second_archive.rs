@@ -1,7 +1,7 @@
 use async_std::task;
 use clap::{app_from_crate, arg};
 
-use find_dups::{file::FileStore, Config};
+use find_dups::{file::FileStore, normalize_archive_path, Config};
 
 fn main() {
     let matches = app_from_crate!()
@@ -21,24 +21,64 @@ fn main() {
                 .default_value("/tmp/finddups"),
         )
         .arg(arg!(-s --second_archive <path> "Path to second archive").required(false))
+        .arg(
+            arg!(--"map-prefix" <mapping> ... "Treat archived paths under OLD as under NEW, given as OLD=NEW")
+                .required(false),
+        )
+        .arg(
+            arg!(--show <spelling> "Print archived paths as archived (original) or after --map-prefix (mapped)")
+                .required(false)
+                .possible_values(["original", "mapped"])
+                .default_value("mapped"),
+        )
+        .arg(
+            arg!(--"uid-map" <file> "Map archived owner uids through a file of from:to lines")
+                .required(false),
+        )
+        .arg(
+            arg!(--repair "Move leftover or ambiguous set files out of the archive instead of stopping")
+                .required(false),
+        )
         .arg(arg!(-r --report "Produce a report summarizing duplicate files").required(false))
+        .arg(
+            arg!(-'0' --print0 "End each result with a NUL rather than a newline")
+                .required(false),
+        )
         .arg(arg!(-v --verbose ... "increase verbosity level").required(false))
+        .arg(
+            arg!(-q --quiet "Print only results and errors, no status")
+                .required(false)
+                .conflicts_with("verbose"),
+        )
         .get_matches();
 
     // Get the configuration
-    let (config, _dir_receiver) = Config::new(&matches);
-
-    let file_store1 = FileStore::new(matches.value_of("archive").unwrap(), config.clone());
-    let file_store2 = FileStore::new(matches.value_of("second_archive").unwrap(), config.clone());
-
-    task::block_on(async {
-        file_store1.read().await.expect("fs1 read");
-        file_store2.read().await.expect("fs2 read")
+    let (config, _dir_receiver) = Config::new(&matches).unwrap_or_else(|e| {
+        eprintln!("find_dups_second_archive: {}", e);
+        std::process::exit(1);
     });
 
-    file_store1
-        .find_dups_second_archive(&file_store2)
-        .expect("find_dups_second_archive");
+    let archive = |name| {
+        let path = match matches.value_of(name) {
+            Some(path) => normalize_archive_path(path, false),
+            None => Err(format!("--{} is required", name).into()),
+        };
+        path.unwrap_or_else(|e| {
+            eprintln!("find_dups_second_archive: {}", e);
+            std::process::exit(1);
+        })
+    };
+    let file_store1 = FileStore::new(&archive("archive"), config.clone());
+    let file_store2 = FileStore::new(&archive("second_archive"), config.clone());
+
+    if let Err(e) = task::block_on(async {
+        file_store1.read().await?;
+        file_store2.read().await?;
+        file_store1.find_dups_second_archive(&file_store2).await
+    }) {
+        eprintln!("find_dups_second_archive: {}", e);
+        std::process::exit(1);
+    }
 
     // All done!
 }
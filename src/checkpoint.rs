@@ -0,0 +1,206 @@
+//! resumable hashing of big files, see --checkpoint-hashing
+//!
+//! Hashing a disk image of hundreds of gigabytes takes hours, and a run
+//! stopped part way through would start it over.  With
+//! --checkpoint-hashing, hash_file adds the chunk hashes read so far to
+//! a sidecar every EVERY chunks, one per file in the archive's
+//! `checkpoint` directory, and a later run hashing the same file, of
+//! the same size and modification time, reads on from the last of
+//! them.  A sidecar is removed once its file is hashed, and those left
+//! for files since changed or gone are removed at the end of an injest.
+//!
+//! Only seahash keeps a list of chunk hashes to resume, and the whole
+//! file digest can't be resumed, so a resumed file gets none.
+//!
+//! A sidecar is its Key, then the chunk hashes new at each checkpoint,
+//! each CBOR.  A checkpoint cut short by a crash doesn't decode and is
+//! cut off, so the one before it stands.
+
+use crate::file::ChunkHash;
+use crate::Result;
+use minicbor_derive::{Decode, Encode};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Chunks hashed between checkpoints, a GiB of 64KiB chunks
+pub const EVERY: usize = 16 * 1024;
+
+/// The file a sidecar is for, which must match to resume from it
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct Key {
+    #[n(0)]
+    pub path: String,
+    #[n(1)]
+    pub len: u64,
+    #[n(2)]
+    pub mod_secs: u64,
+    #[n(3)]
+    pub mod_nanos: u32,
+    /// the hash algorithm and chunking, as the archive header has them
+    #[n(4)]
+    pub hashing: String,
+}
+
+/// Chunks hashed since the checkpoint before
+#[derive(Debug, Encode, Decode)]
+struct Step {
+    /// bytes hashed, to the end of the last chunk
+    #[n(0)]
+    offset: u64,
+    #[n(1)]
+    chunks: Vec<ChunkHash>,
+}
+
+/// Where hashing a file got to
+#[derive(Debug, Default)]
+pub struct Resume {
+    pub offset: u64,
+    pub chunks: Vec<ChunkHash>,
+}
+
+/// The sidecar of a file being hashed
+#[derive(Debug)]
+pub struct Checkpoint {
+    sidecar: PathBuf,
+    /// to write before the first checkpoint, if the sidecar is new
+    key: Option<Key>,
+    /// chunks already in the sidecar
+    saved: usize,
+}
+
+impl Checkpoint {
+    /// The sidecar for `key`, and where hashing got to if it was of
+    /// this very file
+    ///
+    ///   A sidecar of the file as it was before it changed is removed,
+    ///   and a new one is only written at the first checkpoint.
+    pub fn open(archive: &str, key: &Key) -> Result<(Self, Resume)> {
+        let sidecar = sidecar(archive, &key.path);
+        match read(&sidecar)? {
+            Some((found, resume, good)) if found == *key => {
+                // drop a checkpoint cut short, or later ones would follow it
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&sidecar)?
+                    .set_len(good)?;
+                let saved = resume.chunks.len();
+                let checkpoint = Checkpoint {
+                    sidecar,
+                    key: None,
+                    saved,
+                };
+                Ok((checkpoint, resume))
+            }
+            found => {
+                if found.is_some() {
+                    std::fs::remove_file(&sidecar)?;
+                }
+                let checkpoint = Checkpoint {
+                    sidecar,
+                    key: Some(key.clone()),
+                    saved: 0,
+                };
+                Ok((checkpoint, Resume::default()))
+            }
+        }
+    }
+
+    /// Add the chunk hashes new since the last checkpoint, hashed up to
+    /// `offset`
+    pub fn save(&mut self, offset: u64, chunks: &[ChunkHash]) -> Result<()> {
+        let mut bytes = Vec::new();
+        if let Some(key) = &self.key {
+            std::fs::create_dir_all(self.sidecar.parent().unwrap())?;
+            bytes = minicbor::to_vec(key)?;
+        }
+        let step = Step {
+            offset,
+            chunks: chunks[self.saved..].to_vec(),
+        };
+        bytes.extend(minicbor::to_vec(&step)?);
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.sidecar)?;
+        f.write_all(&bytes)?;
+        f.sync_data()?;
+        self.key = None;
+        self.saved = chunks.len();
+        Ok(())
+    }
+
+    /// The file is hashed, drop its sidecar
+    pub fn done(self) -> Result<()> {
+        match std::fs::remove_file(&self.sidecar) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn sidecar(archive: &str, path: &str) -> PathBuf {
+    Path::new(archive)
+        .join("checkpoint")
+        .join(format!("{:016x}", seahash::hash(path.as_bytes())))
+}
+
+/// A sidecar's key, the checkpoints in it and how many of its bytes
+/// decode, None if there is none or its key doesn't decode
+fn read(sidecar: &Path) -> Result<Option<(Key, Resume, u64)>> {
+    let bytes = match std::fs::read(sidecar) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut d = minicbor::Decoder::new(&bytes);
+    let key: Key = match d.decode() {
+        Ok(key) => key,
+        Err(_) => return Ok(None),
+    };
+    let mut resume = Resume::default();
+    let mut good = d.position();
+    while good < bytes.len() {
+        match d.decode::<Step>() {
+            Ok(step) => {
+                resume.offset = step.offset;
+                resume.chunks.extend(step.chunks);
+                good = d.position();
+            }
+            Err(_) => break,
+        }
+    }
+    Ok(Some((key, resume, good as u64)))
+}
+
+/// Remove the sidecars of files changed or gone since they were left,
+/// returning how many
+pub fn prune(archive: &str) -> Result<usize> {
+    let entries = match std::fs::read_dir(Path::new(archive).join("checkpoint")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let sidecar = entry?.path();
+        let current = match read(&sidecar)? {
+            Some((key, _, _)) => std::fs::metadata(&key.path).ok().is_some_and(|m| {
+                let mtime = m
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+                m.len() == key.len
+                    && mtime.is_some_and(|t| {
+                        (t.as_secs(), t.subsec_nanos()) == (key.mod_secs, key.mod_nanos)
+                    })
+            }),
+            None => false,
+        };
+        if !current {
+            std::fs::remove_file(&sidecar)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
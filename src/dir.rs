@@ -1,78 +1,410 @@
 //! directory broker and support functions for wayback
 
-use crate::{file::FileStore, Config, Result};
+use crate::exclude::Excludes;
+use crate::file::{event_line, EventSink, FileStore, Format, Json, Special, Symlinks, Unstable};
+use crate::ignore::Ignores;
+use crate::resume;
+use crate::skip::{DirSkips, Reason};
+use crate::stats::{describe_version_mix, Phases};
+use crate::{write_status, Config, Outcome, Result};
 use async_std::fs;
 use async_std::io;
-use async_std::path::PathBuf;
+use async_std::path::{Path, PathBuf};
 use async_std::prelude::*;
 use futures::channel::mpsc::{Receiver, Sender};
+use futures::stream::FuturesUnordered;
 use futures::SinkExt;
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Devices shown by the hash latency summary
+const SLOWEST_SOURCES: usize = 5;
+
+/// Entries of a --files-from list between counts sent to the broker
+const LIST_BATCH: usize = 1000;
+
+/// Time directory tasks get to finish once interrupted, before what
+/// they have added so far is written without them
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// A directory waiting for a task, its depth and the ignore rules above it
+type Queued = (PathBuf, usize, Option<Arc<Ignores>>);
+
+/// What the walk leaves out of a path by its name alone: dot files with
+/// --skip-hidden, and the exclude and include globs and regexes
+///
+///   Nothing here looks at the filesystem, so what a walk would take can
+///   be tried on made up paths.  A path is hidden or excluded first, and
+///   only a file left is then asked whether it is included, after the
+///   gitignore rules and the times.
+#[derive(Clone, Debug)]
+pub struct PathFilter {
+    excludes: Arc<Excludes>,
+    skip_hidden: bool,
+}
+
+impl PathFilter {
+    pub fn new(excludes: Arc<Excludes>, skip_hidden: bool) -> Self {
+        PathFilter {
+            excludes,
+            skip_hidden,
+        }
+    }
+
+    /// True if `path` is a dot file or directory left out by --skip-hidden
+    pub fn hidden(&self, path: &Path) -> bool {
+        self.skip_hidden
+            && path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+    }
+
+    /// True if an exclude glob or regex leaves `path` out, see exclude.rs
+    pub fn excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.excludes.matches(&path.to_string_lossy(), is_dir)
+    }
+
+    /// False for a file matching none of the include globs and regexes
+    /// given
+    pub fn included(&self, path: &Path) -> bool {
+        self.excludes.included(&path.to_string_lossy())
+    }
+}
 
 #[derive(Debug)]
 pub enum DirBrokerMessage {
     NewDir {
         path: PathBuf,
         depth: usize,
+        /// the gitignore rules in force above it, see ignore.rs
+        ignores: Option<Arc<Ignores>>,
+        /// device and inode with --symlinks follow, to walk it once
+        id: Option<(u64, u64)>,
     },
     Error {
+        path: PathBuf,
         e: io::Error,
     },
+    /// a --files-from list to add the files of, see process_list
+    NewList {
+        list: String,
+    },
+    /// counts of a list so far, its task still running
+    Listed {
+        counts: DirCounts,
+    },
+    /// no more roots are coming from stdin, see read_roots
+    RootsDone,
+    /// cancelled, by SIGINT or SIGTERM or a ScanHandle
+    Shutdown,
+    /// stop, and go on, walking queued directories, see ScanHandle
+    Pause,
+    Resume,
     Report,
     Done {
-        files: usize,
-        dirs: usize,
-        errors: usize,
+        path: PathBuf,
+        counts: DirCounts,
     },
 }
 
+/// What directory tasks found, by kind of entry
+///
+///   Symlinks and special files are not followed, but are still
+///   counted in files as they go through add_file.  Errors include
+///   unreadable and vanished entries and files that failed to hash,
+///   unstable ones among them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirCounts {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    pub special: usize,
+    pub unreadable: usize,
+    pub vanished: usize,
+    /// files that kept changing while hashed, see Unstable
+    pub unstable: usize,
+    /// files left out by exclude patterns
+    pub excluded: usize,
+    /// directories left out by exclude patterns, their trees unwalked
+    pub excluded_dirs: usize,
+    /// files matching no include pattern
+    pub not_included: usize,
+    /// files and directories gitignore rules leave out
+    pub ignored: usize,
+    pub ignored_dirs: usize,
+    /// files and directories left out by --skip-hidden
+    pub hidden: usize,
+    pub hidden_dirs: usize,
+    /// entries read from a --files-from list
+    pub listed: usize,
+    /// files modified outside --newer-than and --older-than
+    pub outside_times: usize,
+    /// symlinks walked or hashed with --symlinks follow
+    pub followed_links: usize,
+    /// symlinks kept with --symlinks record
+    pub recorded_links: usize,
+    /// symlinks to nothing, with --symlinks follow or record
+    pub broken_links: usize,
+    /// directories reached again through a symlink, not walked twice
+    pub link_loops: usize,
+    /// directories reached again by another root or mount, not walked
+    /// twice, with --walk-once
+    pub walked_again: usize,
+    pub errors: usize,
+    pub bytes: u64,
+}
+
+impl DirCounts {
+    pub fn add(&mut self, other: &DirCounts) {
+        self.files += other.files;
+        self.dirs += other.dirs;
+        self.symlinks += other.symlinks;
+        self.special += other.special;
+        self.unreadable += other.unreadable;
+        self.vanished += other.vanished;
+        self.unstable += other.unstable;
+        self.excluded += other.excluded;
+        self.excluded_dirs += other.excluded_dirs;
+        self.not_included += other.not_included;
+        self.ignored += other.ignored;
+        self.ignored_dirs += other.ignored_dirs;
+        self.hidden += other.hidden;
+        self.hidden_dirs += other.hidden_dirs;
+        self.outside_times += other.outside_times;
+        self.listed += other.listed;
+        self.followed_links += other.followed_links;
+        self.recorded_links += other.recorded_links;
+        self.broken_links += other.broken_links;
+        self.link_loops += other.link_loops;
+        self.walked_again += other.walked_again;
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+    }
+}
+
 pub async fn dir_broker_loop(
     config: Config,
-    mut incoming_messages: Receiver<DirBrokerMessage>,
-) -> Result<()> {
-    let mut todo: Vec<(PathBuf, usize)> = Vec::new();
+    incoming_messages: &mut Receiver<DirBrokerMessage>,
+) -> Result<Outcome> {
+    let mut todo: Vec<Queued> = Vec::new();
+    // with -i - the run isn't over until stdin is
+    let mut roots_open = config.roots_from_stdin;
+    // when the run was cancelled, see ScanHandle
+    let mut stopping: Option<Instant> = None;
+    let mut paused = false;
+    // directories queued, by device and inode, with --walk-once or
+    // --symlinks follow
+    let mut walked: HashSet<(u64, u64)> = HashSet::new();
+    let mut active: HashMap<PathBuf, (Instant, usize)> = HashMap::new();
+    // with --checkpoint-every, the subdirectories each directory under
+    // way queued, see resume.rs
+    let mut reached: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    // subdirectories queued before the checkpoint resumed
+    let mut passed: HashSet<PathBuf> = HashSet::new();
     let mut active_count: usize = 0;
     let mut error_count: usize = 0;
     let mut dir_count: usize = 0;
     let mut file_count: usize = 0;
+    let mut census = DirCounts::default();
     let start = Instant::now();
-    let file_store = FileStore::new(&config.archive, config.clone());
+    let mut phases = Phases::new();
+    let others = config
+        .other_archives
+        .iter()
+        .map(|archive| FileStore::new(archive, config.clone()))
+        .collect();
+    let file_store = FileStore::new(&config.archive, config.clone()).with_others(others);
 
-    if config.verbose > 0 {
-        eprintln!("reading file archive");
+    if config.read_archive {
+        if config.verbose > 0 {
+            eprintln!("reading file archive");
+        }
+        file_store.read_all().await?;
+    }
+    // before counting, so pruned orphans don't look like lost entries
+    let mut pruned_orphans = 0;
+    if !config.orphan_roots.is_empty() {
+        let orphans = file_store.orphans();
+        if config.prune_orphans {
+            pruned_orphans = orphans;
+        }
     }
-
-    file_store.read().await?;
     let initial_files = file_store.index().len();
+    if let Some(saved) = &config.resumed {
+        saved.check_hashing(&config.archive, &file_store.hashing())?;
+    }
 
     if config.verbose > 0 {
         eprintln!("initial_files: {}", initial_files);
     }
 
+    if config.match_full {
+        let undigested = file_store.undigested();
+        if undigested > 0 {
+            eprintln!(
+                "warning: {} archived files have no whole file digest, so --match full \
+                 never matches them",
+                undigested
+            );
+        }
+    }
+    if config.preflight && initial_files > 0 {
+        file_store.preflight().await?;
+    }
+    if !config.injest {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        for (_, root) in &config.path_roots {
+            print_overlap(&config, &file_store, root, now);
+        }
+    }
+    if !config.injest && config.missing && !config.missing_by_path {
+        for (root, _) in &config.path_roots {
+            // the notice above already explains a recorded injest
+            if file_store.injested_overlapping(root).is_empty() && file_store.archived_under(root) {
+                eprintln!(
+                    "warning: {} is already in the archive, --missing only finds \
+                     content not archived anywhere; use --missing-by-path to find \
+                     files absent from a restored copy",
+                    root
+                );
+            }
+        }
+    }
+    phases.record(
+        "load",
+        start.elapsed(),
+        format!("{} entries", initial_files),
+    );
+    let scan_start = Instant::now();
+    // --changed-only looks again at anything changed once the walk
+    // began, before it was resumed if it was
+    let scan_since = match &config.resumed {
+        Some(saved) => saved.run.since,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    let mut roots: Vec<String> = Vec::new();
+    if let Some(saved) = &config.resumed {
+        roots = saved.run.roots.clone();
+        passed = saved.reached.iter().map(PathBuf::from).collect();
+        for dir in &saved.pending {
+            todo.push(resumed_dir(&config, &file_store, &roots, dir, &mut walked).await?);
+        }
+    }
+    // when what is left of the walk was last kept, and the entries then
+    let mut last_checkpoint = (Instant::now(), initial_files);
+
     let mut last_change_event = Instant::now();
     let mut last_file_count = 0;
+    // bytes read for hashing as of the last report, for the rate since
+    let mut last_read = (0, Instant::now());
     let mut last_nfiles = 0;
     let mut last_dir_count = 0;
 
     loop {
         // wait for a message from someone ... can we hang here???
         if let Some(msg) = incoming_messages.next().await {
+            if let (DirBrokerMessage::NewDir { path, depth, .. }, Some(_)) =
+                (&msg, config.checkpoint_every)
+            {
+                if let Some(parent) = path.parent().filter(|_| *depth > 0) {
+                    if active.contains_key(parent) {
+                        reached.entry(parent.into()).or_default().push(path.clone());
+                    }
+                }
+            }
             match msg {
-                DirBrokerMessage::NewDir { path, depth } => {
-                    todo.push((path, depth));
+                DirBrokerMessage::NewDir { path, .. } if passed.contains(&path) => {
+                    // queued before the checkpoint, walked then or
+                    // queued again from it
                 }
-                DirBrokerMessage::Error { e: _e } => {
+                DirBrokerMessage::NewDir { id: Some(id), .. } if !walked.insert(id) => {
+                    // a link back up, a second link to a directory, or a
+                    // root inside one walked already
+                    match config.symlinks {
+                        Symlinks::Follow => census.link_loops += 1,
+                        _ => census.walked_again += 1,
+                    }
+                }
+                DirBrokerMessage::NewDir {
+                    path,
+                    depth,
+                    ignores,
+                    ..
+                } => {
+                    if depth == 0 {
+                        let root = crate::normalize_path(&path.to_string_lossy());
+                        if config.changed_only.is_some() {
+                            print_cutoff(&file_store, &root, scan_since);
+                        }
+                        roots.push(root);
+                    }
+                    todo.push((path, depth, ignores));
+                }
+                DirBrokerMessage::Error { path, e } => {
+                    reached.remove(&path);
+                    let task = active.remove(&path);
+                    if let (Some(events), Some((started, depth))) = (&config.events, task) {
+                        events.emit(
+                            "dir_error",
+                            vec![
+                                ("path", Json::Str(path.to_string_lossy().into_owned())),
+                                ("depth", Json::Num(depth as u64)),
+                                ("seconds", Json::Float(started.elapsed().as_secs_f64())),
+                                ("error", Json::Str(e.to_string())),
+                            ],
+                        );
+                    }
                     active_count -= 1;
                     error_count += 1;
+                    census.unreadable += 1;
                 }
-                DirBrokerMessage::Done {
-                    files,
-                    dirs: _dirs,
-                    errors,
-                } => {
+                DirBrokerMessage::NewList { list } => {
+                    active.insert(PathBuf::from(&list), (Instant::now(), 0));
+                    crate::spawn_until_shutdown(
+                        config.shutdown.clone(),
+                        process_list(
+                            list,
+                            config.print0,
+                            config.file_concurrency,
+                            file_store.clone(),
+                            config.dir_broker_sender.clone(),
+                        ),
+                    );
+                    active_count += 1;
+                }
+                DirBrokerMessage::RootsDone => roots_open = false,
+                DirBrokerMessage::Shutdown => stopping = Some(Instant::now()),
+                DirBrokerMessage::Pause => paused = true,
+                DirBrokerMessage::Resume => {
+                    paused = false;
+                    last_change_event = Instant::now();
+                }
+                DirBrokerMessage::Listed { counts } => {
+                    error_count += counts.errors;
+                    file_count += counts.files;
+                    census.add(&counts);
+                }
+                DirBrokerMessage::Done { path, counts } => {
+                    if let Some(findings) = &config.findings {
+                        findings.dir_done(&path.to_string_lossy())?;
+                    }
+                    reached.remove(&path);
+                    let task = active.remove(&path);
+                    if let (Some(events), Some((started, depth))) = (&config.events, task) {
+                        emit_dir_finished(events, &path, depth, started, &counts);
+                    }
                     active_count -= 1;
-                    error_count += errors;
-                    file_count += files;
+                    error_count += counts.errors;
+                    file_count += counts.files;
+                    census.add(&counts);
                 }
                 DirBrokerMessage::Report => {
                     let nfiles = file_store.index().len();
@@ -85,51 +417,249 @@ pub async fn dir_broker_loop(
                         last_file_count = file_count;
                         last_change_event = Instant::now();
                     }
-                    if (active_count > 0 || nfiles > initial_files) && config.verbose > 0 {
+                    let read = file_store.stats().bytes_read();
+                    let mb_per_sec = (read - last_read.0) as f64
+                        / 1e6
+                        / last_read.1.elapsed().as_secs_f64().max(f64::EPSILON);
+                    last_read = (read, Instant::now());
+                    if (active_count > 0 || nfiles > initial_files)
+                        && config.verbose > 0
+                        && !config.progress_json
+                    {
+                        let limit = match &config.bwlimit {
+                            Some(throttle) => format!("/{:.1}", throttle.rate() / 1e6),
+                            None => String::new(),
+                        };
+                        // a --files-from run walks no directories
+                        let walked = match &config.files_from {
+                            Some(_) => format!("listed:{}", census.listed),
+                            None => format!("dirs:{}", dir_count),
+                        };
                         eprintln!(
-                            "files:{} dirs:{} nfiles:{} err:{} fps:{:.1} active:{}",
+                            "files:{} {} nfiles:{} err:{} fps:{:.1} active:{} hashing:{}/{} MB/s:{:.1}{}",
                             file_count,
-                            dir_count,
+                            walked,
                             file_store.index().len() - initial_files,
                             error_count,
                             (file_store.index().len() - initial_files) as f64 * 1000.0
                                 / start.elapsed().as_millis() as f64,
                             active_count,
+                            file_store.stats().hashing(),
+                            config.hash_pool.threads(),
+                            mb_per_sec,
+                            limit,
                         );
                     }
-                    if last_change_event.elapsed().as_secs() > config.timeout {
-                        eprintln!("stall detected, exiting");
-                        if config.injest && nfiles > initial_files {
-                            let last_report = Instant::now();
-                            file_store.write().await?;
+                    let progress = || {
+                        vec![
+                            ("files", Json::Num(file_count as u64)),
+                            ("dirs", Json::Num(dir_count as u64)),
+                            ("listed", Json::Num(census.listed as u64)),
+                            (
+                                "new_entries",
+                                Json::Num(nfiles.saturating_sub(initial_files) as u64),
+                            ),
+                            ("errors", Json::Num(error_count as u64)),
+                            (
+                                "files_per_sec",
+                                Json::Float(file_count as f64 / start.elapsed().as_secs_f64()),
+                            ),
+                            ("active", Json::Num(active_count as u64)),
+                            ("hashing", Json::Num(file_store.stats().hashing() as u64)),
+                            ("elapsed_secs", Json::Float(start.elapsed().as_secs_f64())),
+                            ("bytes_hashed", Json::Num(read)),
+                            ("mb_per_sec", Json::Float(mb_per_sec)),
+                        ]
+                    };
+                    if config.progress_json && (active_count > 0 || nfiles > initial_files) {
+                        eprintln!("{}", event_line("progress", progress()));
+                    }
+                    if let Some(events) = config.events.as_ref().filter(|_| active_count > 0) {
+                        events.emit("progress", progress());
+                    }
+                    if config.verbose > 1 && active_count > 0 {
+                        print_slowest(&file_store);
+                    }
+                    if config.verbose > 2 {
+                        print_active(&active, &todo);
+                    }
+                    let checkpoint_due = config.checkpoint_every.is_some_and(|every| {
+                        last_checkpoint.0.elapsed() >= every
+                            || nfiles >= last_checkpoint.1 + resume::ENTRIES
+                    });
+                    if checkpoint_due && stopping.is_none() && active_count > 0 {
+                        let began = Instant::now();
+                        file_store.write().await?;
+                        let left = checkpoint_walk(
+                            &config,
+                            &file_store,
+                            &roots,
+                            scan_since,
+                            &todo,
+                            &active,
+                            &reached,
+                        )
+                        .await?;
+                        if config.verbose > 0 {
                             eprintln!(
+                                "checkpoint: {} entries and {} directories left to walk, in {:.3} seconds",
+                                nfiles,
+                                left,
+                                began.elapsed().as_secs_f64()
+                            );
+                        }
+                        last_checkpoint = (Instant::now(), nfiles);
+                    }
+                    if paused || (roots_open && active_count == 0 && todo.is_empty()) {
+                        // waiting on whoever paused it, or on stdin for
+                        // the next root, is no stall
+                        last_change_event = Instant::now();
+                    }
+                    let stalled = last_change_event.elapsed().as_secs() > config.timeout;
+                    let stopped = stopping
+                        .is_some_and(|since| active_count == 0 || since.elapsed() > SHUTDOWN_GRACE);
+                    if stalled || stopped {
+                        match stopping {
+                            Some(_) => {
+                                status!("interrupted, {} directories unfinished", active_count)
+                            }
+                            None => eprintln!("stall detected, exiting"),
+                        }
+                        if let Some(sorted) = &config.sorted {
+                            sorted.release()?;
+                        }
+                        print_active(&active, &todo);
+                        print_census(&census, &file_store, &config);
+                        print_slowest(&file_store);
+                        print_timed_out(&file_store);
+                        record_scan_phases(
+                            &mut phases,
+                            scan_start,
+                            &file_store,
+                            dir_count,
+                            file_count,
+                        );
+                        let (_, dirs_changed, dirs_created) = file_store.dir_counts();
+                        let updated = config.injest
+                            && (nfiles > initial_files
+                                || file_store.refreshed() > 0
+                                || dirs_changed + dirs_created > 0
+                                || file_store.symlinks_changed() > 0
+                                || pruned_orphans > 0);
+                        if updated {
+                            let last_report = Instant::now();
+                            let written = file_store.write().await?;
+                            status!(
                                 "wrote file store in {} seconds",
                                 last_report.elapsed().as_millis() as f64 / 1000.0
                             );
+                            phases.record(
+                                "write",
+                                last_report.elapsed(),
+                                write_detail(file_store.index().len(), written),
+                            );
+                        }
+                        if config.injest && config.checkpoint_every.is_some() {
+                            let left = checkpoint_walk(
+                                &config,
+                                &file_store,
+                                &roots,
+                                scan_since,
+                                &todo,
+                                &active,
+                                &reached,
+                            )
+                            .await?;
+                            status!("{} directories left to walk, continue with --resume", left);
+                        }
+                        write_skips(&config, scan_since).await?;
+                        prune_checkpoints(&config)?;
+                        status!("phases:");
+                        phases.print(start.elapsed());
+                        if let Some(events) = &config.events {
+                            emit_summary(events, &phases, start);
+                            emit_done(
+                                events,
+                                nfiles.saturating_sub(initial_files),
+                                file_count,
+                                dir_count,
+                                error_count,
+                                start,
+                                stalled,
+                            );
                         }
-                        return Ok(());
+                        // whatever was written, the walk did not finish
+                        return finish_status(
+                            &config,
+                            match stopping {
+                                Some(_) => Outcome::Interrupted,
+                                None => Outcome::Partial,
+                            },
+                            nfiles.saturating_sub(initial_files),
+                            file_count,
+                            dir_count,
+                            error_count,
+                            start,
+                        );
                     }
                 }
             }
         }
 
-        // if we are not to busy, launch some work
-        while !todo.is_empty() && active_count < config.concurrency {
-            let (path, depth) = todo.pop().unwrap();
-            crate::spawn_and_log_error(process_dir(
-                path,
-                depth,
-                file_store.clone(),
-                config.dir_broker_sender.clone(),
-            ));
+        // if we are not to busy, launch some work, unless stopping or
+        // paused
+        while stopping.is_none() && !paused && !todo.is_empty() && active_count < config.concurrency
+        {
+            let (path, depth, ignores) = todo.pop().unwrap();
+            if let Some(events) = &config.events {
+                events.emit(
+                    "dir_started",
+                    vec![
+                        ("path", Json::Str(path.to_string_lossy().into_owned())),
+                        ("depth", Json::Num(depth as u64)),
+                    ],
+                );
+            }
+            active.insert(path.clone(), (Instant::now(), depth));
+            // a resumed run still walks the directories an earlier run
+            // finished, for the subdirectories it may not have reached
+            let skip_files = config
+                .findings
+                .as_ref()
+                .is_some_and(|findings| findings.is_done(&path.to_string_lossy()));
+            crate::spawn_until_shutdown(
+                config.shutdown.clone(),
+                process_dir(
+                    path,
+                    depth,
+                    ignores,
+                    skip_files,
+                    config.metadata_window,
+                    config.file_concurrency,
+                    file_store.clone(),
+                    config.dir_broker_sender.clone(),
+                ),
+            );
             active_count += 1;
             dir_count += 1;
         }
 
         // if we are done, finish up
-        if active_count == 0 && todo.is_empty() {
+        if active_count == 0 && todo.is_empty() && !roots_open {
+            if config.injest {
+                let (unread, headed) = file_store.finish_waiting().await;
+                if unread + headed > 0 {
+                    status!(
+                        "{} files left unread as no other file has their size, \
+                         {} more only read in part as none has their first chunk",
+                        unread,
+                        headed
+                    );
+                }
+            }
             let nfiles = file_store.index().len();
-            eprintln!(
+            record_scan_phases(&mut phases, scan_start, &file_store, dir_count, file_count);
+            status!(
                 "completed {}: {} files in {} dirs with {} new entries, {} errors in {} seconds",
                 if config.injest { "injest" } else { "check" },
                 file_count,
@@ -138,86 +668,1241 @@ pub async fn dir_broker_loop(
                 error_count,
                 start.elapsed().as_millis() as f64 / 1000.0
             );
+            // released first, so the census follows the results held
+            if let Some(sorted) = &config.sorted {
+                sorted.release()?;
+            }
+            print_census(&census, &file_store, &config);
+            print_slowest(&file_store);
+            print_timed_out(&file_store);
+            if config.changed_only.is_some() {
+                status!(
+                    "changed-only: {} files assumed unchanged, not read",
+                    file_store.unchanged()
+                );
+            }
 
-            if config.prune {
-                file_store.prune().await?;
+            let report_start = Instant::now();
+            if config.missing_by_path {
+                let missing = file_store.report_missing_by_path();
+                status!("{} archived paths missing", missing);
+            }
+            if file_store.records_dirs() {
+                print_dirs(&config, &file_store);
+            }
+            let mut pruned = 0;
+            match config.prune && config.resumed.is_some() {
+                true => eprintln!(
+                    "not pruning: a resumed walk passed by the directories finished before, \
+                     injest again without --resume to prune"
+                ),
+                false if config.prune => pruned = file_store.prune().await?,
+                false => (),
             }
 
+            // a signal after the walk skips what is left to report, on
+            // to writing the archive
+            if config.removable_dirs && !crate::interrupted() {
+                file_store.removable_dirs()?;
+            }
             if config.report || config.list || (config.injest && config.duplicate) {
                 file_store.report().await?;
             }
+            if config.similar.is_some() && !crate::interrupted() {
+                file_store.report_similar().await?;
+            }
+            phases.record("report", report_start.elapsed(), String::new());
+            let mut collisions = 0;
+            if config.hash_audit > 0 && !crate::interrupted() {
+                let audit = file_store.hash_audit().await?;
+                collisions = audit.collisions;
+                phases.record(
+                    "audit",
+                    Duration::from_secs_f64(audit.seconds),
+                    format!(
+                        "{} groups, {} members compared",
+                        audit.groups, audit.compared
+                    ),
+                );
+            }
 
-            if config.injest && nfiles > initial_files {
+            let (_, dirs_changed, dirs_created) = file_store.dir_counts();
+            let updated = config.injest
+                && (nfiles > initial_files
+                    || file_store.refreshed() > 0
+                    || dirs_changed + dirs_created > 0
+                    || file_store.symlinks_changed() > 0
+                    || pruned_orphans + pruned > 0);
+            if updated {
                 let last_report = Instant::now();
-                file_store.write().await?;
-                eprintln!(
+                let written = file_store.write().await?;
+                status!(
                     "wrote file store in {} seconds",
                     last_report.elapsed().as_millis() as f64 / 1000.0
                 );
+                phases.record(
+                    "write",
+                    last_report.elapsed(),
+                    write_detail(file_store.index().len(), written),
+                );
+            }
+            write_skips(&config, scan_since).await?;
+            prune_checkpoints(&config)?;
+            if let Some(path) = &config.manifest {
+                let n = file_store.write_checksum_manifest(path)?;
+                status!("wrote {} files to manifest {}", n, path);
+            }
+            if let Some(path) = &config.check_manifest {
+                let (checked, bad) = file_store.check_checksum_manifest(path)?;
+                status!(
+                    "checked {} manifest entries, {} differ or are not archived",
+                    checked,
+                    bad
+                );
+            }
+            if config.injest && error_count == 0 {
+                match config.filters_walk() {
+                    true if config.verbose > 0 => eprintln!(
+                        "not recording an injest time for --changed-only, filters left files out"
+                    ),
+                    true => (),
+                    false => file_store.mark_injested(&roots, scan_since).await?,
+                }
+            }
+            if config.injest {
+                // the walk is done, there is nothing left to resume
+                resume::clear(&config.archive).await?;
+            }
+            if let Some(output) = &config.output {
+                status!("results written to {}", output);
             }
 
-            return Ok(());
+            status!("phases:");
+            phases.print(start.elapsed());
+            if let Some(events) = &config.events {
+                emit_summary(events, &phases, start);
+                emit_done(
+                    events,
+                    file_store.index().len().saturating_sub(initial_files),
+                    file_count,
+                    dir_count,
+                    error_count,
+                    start,
+                    false,
+                );
+            }
+            if let Some(findings) = &config.findings {
+                findings.event(
+                    "run_completed",
+                    vec![
+                        ("files", Json::Num(file_count as u64)),
+                        ("dirs", Json::Num(dir_count as u64)),
+                        ("errors", Json::Num(error_count as u64)),
+                        ("seconds", Json::Float(start.elapsed().as_secs_f64())),
+                    ],
+                )?;
+            }
+            let outcome = match (error_count > 0, updated) {
+                // a hash collision puts every deletion made on the hash in doubt
+                _ if collisions > 0 => Outcome::Failed,
+                _ if crate::interrupted() => Outcome::Interrupted,
+                (true, _) => Outcome::Partial,
+                (false, true) => Outcome::Updated,
+                (false, false) => Outcome::NoChange,
+            };
+            return finish_status(
+                &config,
+                outcome,
+                file_store.index().len().saturating_sub(initial_files),
+                file_count,
+                dir_count,
+                error_count,
+                start,
+            );
         }
     }
 }
 
+/// Queue a directory left by a checkpointed walk, as the walk from its
+/// root queued it, see resume.rs
+async fn resumed_dir(
+    config: &Config,
+    file_store: &FileStore,
+    roots: &[String],
+    dir: &resume::Pending,
+    walked: &mut HashSet<(u64, u64)>,
+) -> Result<Queued> {
+    let path = std::path::Path::new(&dir.path);
+    let root = roots
+        .iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.len());
+    let ignores = match root {
+        Some(root) if config.respect_gitignore || !config.ignore_files.is_empty() => {
+            Some(Ignores::above(root, path, &config.ignore_files, config.respect_gitignore).await?)
+        }
+        _ => None,
+    };
+    // with --walk-once a directory queued counts as walked, as it did
+    // in the run checkpointed
+    if let Some(id) = fs::metadata(path)
+        .await
+        .ok()
+        .and_then(|metadata| file_store.dir_id(&metadata))
+    {
+        walked.insert(id);
+    }
+    Ok((PathBuf::from(path), dir.depth as usize, ignores))
+}
+
+/// Keep what is left of the walk, the directories queued and under
+/// way, returning how many
+///
+///   The file store should have been written just before.  Those under
+///   way are walked again on --resume, passing by the subdirectories
+///   in `reached` they queued already.
+async fn checkpoint_walk(
+    config: &Config,
+    file_store: &FileStore,
+    roots: &[String],
+    since: u64,
+    todo: &[Queued],
+    active: &HashMap<PathBuf, (Instant, usize)>,
+    reached: &HashMap<PathBuf, Vec<PathBuf>>,
+) -> Result<usize> {
+    let run = resume::Run {
+        roots: roots.to_vec(),
+        hashing: file_store.hashing(),
+        since,
+    };
+    let pending: Vec<resume::Pending> = active
+        .iter()
+        .map(|(path, (_, depth))| (path, *depth))
+        .chain(todo.iter().map(|(path, depth, _)| (path, *depth)))
+        .map(|(path, depth)| resume::Pending {
+            path: path.to_string_lossy().into_owned(),
+            depth: depth as u64,
+        })
+        .collect();
+    let reached: Vec<String> = reached
+        .iter()
+        .filter(|(parent, _)| active.contains_key(*parent))
+        .flat_map(|(_, paths)| paths)
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    resume::store(&config.archive, &run, &pending, &reached).await?;
+    Ok(pending.len())
+}
+
+/// Emit what a directory task found once it is done
+fn emit_dir_finished(
+    events: &EventSink,
+    path: &PathBuf,
+    depth: usize,
+    started: Instant,
+    counts: &DirCounts,
+) {
+    events.emit(
+        "dir_finished",
+        vec![
+            ("path", Json::Str(path.to_string_lossy().into_owned())),
+            ("depth", Json::Num(depth as u64)),
+            ("seconds", Json::Float(started.elapsed().as_secs_f64())),
+            ("files", Json::Num(counts.files as u64)),
+            ("dirs", Json::Num(counts.dirs as u64)),
+            ("symlinks", Json::Num(counts.symlinks as u64)),
+            ("special", Json::Num(counts.special as u64)),
+            ("bytes", Json::Num(counts.bytes)),
+            ("errors", Json::Num(counts.errors as u64)),
+        ],
+    );
+}
+
+/// Emit the phase breakdown, just before done
+fn emit_summary(events: &EventSink, phases: &Phases, start: Instant) {
+    let total = start.elapsed().as_secs_f64();
+    let breakdown = phases
+        .phases()
+        .iter()
+        .map(|(name, elapsed, detail)| {
+            Json::Obj(vec![
+                ("phase", Json::Str(name.to_string())),
+                ("seconds", Json::Float(elapsed.as_secs_f64())),
+                (
+                    "share",
+                    Json::Float(elapsed.as_secs_f64() / total.max(f64::EPSILON)),
+                ),
+                ("detail", Json::Str(detail.clone())),
+            ])
+        })
+        .collect();
+    events.emit(
+        "summary",
+        vec![
+            ("seconds", Json::Float(total)),
+            ("phases", Json::List(breakdown)),
+        ],
+    );
+}
+
+/// Write the --status-json file, if asked for, and pass on the outcome
+fn finish_status(
+    config: &Config,
+    outcome: Outcome,
+    new_entries: usize,
+    files: usize,
+    dirs: usize,
+    errors: usize,
+    start: Instant,
+) -> Result<Outcome> {
+    if let Some(path) = &config.status_json {
+        write_status(
+            path,
+            outcome,
+            vec![
+                ("files", Json::Num(files as u64)),
+                ("dirs", Json::Num(dirs as u64)),
+                ("new_entries", Json::Num(new_entries as u64)),
+                ("errors", Json::Num(errors as u64)),
+                ("seconds", Json::Float(start.elapsed().as_secs_f64())),
+            ],
+        )?;
+    }
+    Ok(outcome)
+}
+
+/// Emit the final event of --events ndjson
+fn emit_done(
+    events: &EventSink,
+    new_entries: usize,
+    files: usize,
+    dirs: usize,
+    errors: usize,
+    start: Instant,
+    stalled: bool,
+) {
+    events.emit(
+        "done",
+        vec![
+            ("files", Json::Num(files as u64)),
+            ("dirs", Json::Num(dirs as u64)),
+            ("new_entries", Json::Num(new_entries as u64)),
+            ("errors", Json::Num(errors as u64)),
+            ("seconds", Json::Float(start.elapsed().as_secs_f64())),
+            ("stalled", Json::Bool(stalled)),
+        ],
+    );
+}
+
+/// Print the directories being worked on and the queue behind them
+fn print_active(active: &HashMap<PathBuf, (Instant, usize)>, todo: &[Queued]) {
+    status!(
+        "todo: {} queued, deepest {}",
+        todo.len(),
+        todo.iter().map(|(_, depth, _)| *depth).max().unwrap_or(0)
+    );
+    let mut active: Vec<_> = active.iter().collect();
+    active.sort_by_key(|(_, (started, _))| *started);
+    for (path, (started, depth)) in active {
+        status!(
+            "active: {:8.1}s depth {} {}",
+            started.elapsed().as_secs_f64(),
+            depth,
+            path.to_string_lossy()
+        );
+    }
+}
+
+/// Print the time --changed-only compares with for an injest root
+fn print_cutoff(file_store: &FileStore, root: &str, now: u64) {
+    match file_store.injested_at(root) {
+        Some(since) => status!(
+            "changed-only: {} last injested {:.1} hours ago (unix time {}), \
+             reading only files changed since",
+            root,
+            now.saturating_sub(since) as f64 / 3600.0,
+            since
+        ),
+        None => status!(
+            "changed-only: no complete injest of {} recorded, reading every file",
+            root
+        ),
+    }
+}
+
+/// Explain that checking a tree the archive was injested from finds
+/// little, as a run with no results looks like a broken one
+fn print_overlap(config: &Config, file_store: &FileStore, root: &str, now: u64) {
+    for (recorded, since) in file_store.injested_overlapping(root) {
+        status!(
+            "notice: checking {}, which overlaps {}, the tree archive {} was \
+             built from {:.1} hours ago",
+            root,
+            recorded,
+            config.archive,
+            now.saturating_sub(since) as f64 / 3600.0
+        );
+        status!(
+            "notice: its files are expected to be present, and only those \
+             changed since can be missing or new"
+        );
+    }
+}
+
+/// The write phase's detail: entries, and record bytes compressed
+fn write_detail(entries: usize, (logical, stored): (u64, u64)) -> String {
+    format!(
+        "{} entries, {} record bytes stored as {} ({:.1}x)",
+        entries,
+        logical,
+        stored,
+        logical as f64 / stored.max(1) as f64
+    )
+}
+
+/// Print the entry counts of the walk so far, as a line of JSON too
+/// with --format json
+fn print_census(census: &DirCounts, file_store: &FileStore, config: &Config) {
+    if config.format == Format::Json {
+        let count = |n: usize| Json::Num(n as u64);
+        let stats = file_store.stats();
+        file_store.emit_json(
+            "census",
+            vec![
+                ("files", count(census.files)),
+                ("dirs", count(census.dirs)),
+                ("symlinks", count(census.symlinks)),
+                ("special", count(census.special)),
+                ("unreadable", count(census.unreadable)),
+                ("vanished", count(census.vanished)),
+                ("unstable", count(census.unstable)),
+                ("hashed", count(stats.hash_files())),
+                ("cache_hits", count(stats.cache_hits())),
+                ("link_hits", count(stats.link_hits())),
+                ("followed_links", count(census.followed_links)),
+                ("recorded_links", count(census.recorded_links)),
+                ("broken_links", count(census.broken_links)),
+                ("link_loops", count(census.link_loops)),
+                ("walked_again", count(census.walked_again)),
+                ("excluded", count(census.excluded)),
+                ("excluded_dirs", count(census.excluded_dirs)),
+                ("not_included", count(census.not_included)),
+                ("ignored", count(census.ignored)),
+                ("ignored_dirs", count(census.ignored_dirs)),
+                ("hidden", count(census.hidden)),
+                ("hidden_dirs", count(census.hidden_dirs)),
+                ("listed", count(census.listed)),
+                ("outside_times", count(census.outside_times)),
+                ("bytes", Json::Num(census.bytes)),
+            ],
+        );
+    }
+    status!(
+        "census: {} files, {} dirs, {} symlinks, {} special, {} unreadable, {} vanished, {} unstable, {} hashed",
+        census.files,
+        census.dirs,
+        census.symlinks,
+        census.special,
+        census.unreadable,
+        census.vanished,
+        census.unstable,
+        file_store.stats().hash_files()
+    );
+    let cached = file_store.stats().cache_hits();
+    if cached > 0 {
+        status!(
+            "hash cache: {} files unchanged by device and inode, not read",
+            cached
+        );
+    }
+    let linked = file_store.stats().link_hits();
+    if linked > 0 {
+        status!(
+            "hardlinks: {} files hashed by another link to the same inode, not read",
+            linked
+        );
+    }
+    if census.followed_links + census.recorded_links + census.broken_links + census.link_loops > 0 {
+        status!(
+            "symlinks: {} followed, {} recorded, {} broken, {} dirs reached again not walked",
+            census.followed_links,
+            census.recorded_links,
+            census.broken_links,
+            census.link_loops
+        );
+    }
+    if census.walked_again > 0 {
+        status!(
+            "walked once: {} dirs reached again by another root or mount",
+            census.walked_again
+        );
+    }
+    if census.symlinks + census.special > 0 {
+        eprintln!(
+            "warning: skipped {} symlinks and {} special files, only regular files are archived",
+            census.symlinks, census.special
+        );
+    }
+    if census.excluded + census.excluded_dirs > 0 {
+        status!(
+            "excluded: {} files and {} dirs matching exclude patterns",
+            census.excluded,
+            census.excluded_dirs
+        );
+    }
+    if census.ignored + census.ignored_dirs > 0 {
+        status!(
+            "ignored: {} files and {} dirs by gitignore rules",
+            census.ignored,
+            census.ignored_dirs
+        );
+    }
+    if census.hidden + census.hidden_dirs > 0 && config.verbose > 0 {
+        status!(
+            "hidden: {} files and {} dirs skipped, see --skip-hidden",
+            census.hidden,
+            census.hidden_dirs
+        );
+    }
+    if census.listed > 0 {
+        status!("files-from: {} entries listed", census.listed);
+    }
+    if census.outside_times > 0 {
+        status!(
+            "time filter: {} files modified outside --newer-than/--older-than, not looked at",
+            census.outside_times
+        );
+    }
+    if census.not_included > 0 {
+        status!(
+            "not included: {} files matching no include pattern",
+            census.not_included
+        );
+    }
+    let mix = file_store.stats().version_mix();
+    if mix.len() > 1 {
+        status!("archive sets: {}", describe_version_mix(&mix));
+    }
+}
+
+/// Print what --record-dirs recorded or verified
+fn print_dirs(config: &Config, file_store: &FileStore) {
+    let (seen, changed, created) = file_store.dir_counts();
+    if config.injest {
+        status!(
+            "dirs: {} scanned, {} newly recorded, {} updated",
+            seen,
+            created,
+            changed
+        );
+    } else {
+        status!(
+            "dirs: {} verified, {} changed, {} created, {} missing",
+            seen - changed - created,
+            changed,
+            created,
+            file_store.missing_dirs()
+        );
+    }
+}
+
+/// Print hash latency and throughput of the slowest devices
+fn print_slowest(file_store: &FileStore) {
+    let sources = file_store.stats().slowest_sources();
+    if sources.is_empty() {
+        return;
+    }
+    status!("hashing by device, slowest first:");
+    for s in sources.iter().take(SLOWEST_SOURCES) {
+        status!(
+            "  {} files, p50 {:.1}ms p95 {:.1}ms p99 {:.1}ms, {:.1} MB/s  {}",
+            s.files(),
+            s.quantile(0.50).as_secs_f64() * 1000.0,
+            s.quantile(0.95).as_secs_f64() * 1000.0,
+            s.quantile(0.99).as_secs_f64() * 1000.0,
+            s.mb_per_sec(),
+            s.label()
+        );
+    }
+}
+
+/// List the files given up on by --file-timeout, if any
+fn print_timed_out(file_store: &FileStore) {
+    let timed_out = file_store.stats().timed_out();
+    if !timed_out.is_empty() {
+        status!("{} files timed out while hashing:", timed_out.len());
+        for name in timed_out {
+            status!("  timed out: {}", name);
+        }
+    }
+}
+
+/// Write the run's skips to the archive, see --log-skips
+async fn write_skips(config: &Config, scan_since: u64) -> Result<()> {
+    if let Some(log) = &config.skip_log {
+        let n = log.store(&config.archive, scan_since).await?;
+        status!("recorded {} skipped files in {}", n, config.archive);
+    }
+    Ok(())
+}
+
+/// Drop the hashing checkpoints of files changed or gone, see
+/// --checkpoint-hashing
+fn prune_checkpoints(config: &Config) -> Result<()> {
+    if config.checkpoint_hashing {
+        let n = crate::checkpoint::prune(&config.archive)?;
+        if n > 0 {
+            status!("dropped {} hashing checkpoints of files changed or gone", n);
+        }
+    }
+    Ok(())
+}
+
+/// Record the walk phase and the hashing done during it
+///
+///   Hashing happens inside the walk, so its wall time overlaps the
+///   walk and its task time shows how parallel the hashing was.
+fn record_scan_phases(
+    phases: &mut Phases,
+    scan_start: Instant,
+    file_store: &FileStore,
+    dirs: usize,
+    files: usize,
+) {
+    phases.record(
+        "walk",
+        scan_start.elapsed(),
+        format!(
+            "{} dirs, {} files, {:.3}s blocked queueing dirs",
+            dirs,
+            files,
+            file_store.stats().send_blocked_time().as_secs_f64()
+        ),
+    );
+    let stats = file_store.stats();
+    let wall = stats.hash_wall_time();
+    let task = stats.hash_task_time();
+    phases.record(
+        "hash",
+        wall,
+        format!(
+            "{} files, {} bytes, {:.3}s task time, {:.1}x parallel",
+            stats.hash_files(),
+            stats.hash_bytes(),
+            task.as_secs_f64(),
+            task.as_secs_f64() / wall.as_secs_f64().max(f64::EPSILON)
+        ),
+    );
+}
+
+/// Count what came of adding a file
+fn count_added(
+    path: &PathBuf,
+    metadata: &fs::Metadata,
+    added: Result<()>,
+    counts: &mut DirCounts,
+    skips: &mut DirSkips,
+) {
+    match added {
+        Ok(()) => {
+            counts.files += 1;
+            counts.bytes += metadata.len();
+        }
+        Err(e) if e.downcast_ref::<Special>().is_some() => {
+            // became one since it was listed
+            counts.special += 1;
+            skips.push(path, Reason::Special, String::new());
+        }
+        Err(e) => {
+            counts.errors += 1;
+            if e.downcast_ref::<Unstable>().is_some() {
+                counts.unstable += 1;
+                skips.push(path, Reason::Unstable, String::new());
+                eprintln!("add_file: {} ({})", e, path.to_str().unwrap());
+            } else {
+                skips.push(path, Reason::Error, e.to_string());
+                eprintln!("add_file: {:?} ({})", e, path.to_str().unwrap());
+            }
+        }
+    }
+}
+
+/// Add the files named in a --files-from list, without walking
+///
+///   `list` is a file of paths one per line, or NUL separated with
+///   `nul`, `-` being stdin.  Each is stat'd, following symlinks, and
+///   handed to add_file, up to `files` at once.  The counts go to the
+///   broker every LIST_BATCH entries, so progress follows the list,
+///   and the list counts as one directory task throughout.
+pub async fn process_list(
+    list: String,
+    nul: bool,
+    files: usize,
+    file_store: FileStore,
+    mut dir_broker_sender: Sender<DirBrokerMessage>,
+) -> Result<()> {
+    let path = PathBuf::from(&list);
+    let mut reader: Box<dyn io::BufRead + Unpin + Send> = match list.as_str() {
+        "-" => Box::new(io::BufReader::new(io::stdin())),
+        _ => match fs::File::open(&path).await {
+            Ok(f) => Box::new(io::BufReader::new(f)),
+            Err(e) => {
+                eprintln!("files-from: {} ({})", e, list);
+                dir_broker_sender
+                    .send(DirBrokerMessage::Error { path, e })
+                    .await?;
+                return Ok(());
+            }
+        },
+    };
+    let separator = if nul { 0 } else { b'\n' };
+    let mut counts = DirCounts::default();
+    let mut skips = file_store.skips();
+    let mut adding = Tasks::new();
+    let mut reading = true;
+    let mut line = Vec::new();
+    while reading || !adding.is_empty() {
+        if reading && adding.len() < files.max(1) {
+            line.clear();
+            match reader.read_until(separator, &mut line).await {
+                Ok(0) => reading = false,
+                Ok(_) => {
+                    if line.last() == Some(&separator) {
+                        line.pop();
+                    }
+                    if line.is_empty() {
+                        continue;
+                    }
+                    counts.listed += 1;
+                    let listed = PathBuf::from(String::from_utf8_lossy(&line).into_owned());
+                    match fs::metadata(&listed).await {
+                        Ok(metadata) if metadata.is_file() => {
+                            let file_store = file_store.clone();
+                            adding.spawn(async move {
+                                let added = file_store.add_file(&listed, &metadata).await;
+                                (listed, metadata, added)
+                            });
+                        }
+                        Ok(_) => {
+                            counts.special += 1;
+                            skips.push(&listed, Reason::Special, "listed".to_string());
+                        }
+                        Err(e) => {
+                            counts.errors += 1;
+                            if e.kind() == io::ErrorKind::NotFound {
+                                counts.vanished += 1;
+                                skips.push(&listed, Reason::Vanished, String::new());
+                            } else {
+                                counts.unreadable += 1;
+                                skips.push(&listed, Reason::Unreadable, e.to_string());
+                            }
+                            eprintln!("files-from: {} ({})", e, listed.to_string_lossy());
+                        }
+                    }
+                    if counts.listed % LIST_BATCH == 0 {
+                        let batch = std::mem::take(&mut counts);
+                        let sent = dir_broker_sender
+                            .send(DirBrokerMessage::Listed { counts: batch })
+                            .await;
+                        if let Err(e) = sent {
+                            adding.cancel().await;
+                            return Err(e.into());
+                        }
+                    }
+                }
+                Err(e) => {
+                    counts.errors += 1;
+                    eprintln!("files-from: {} ({})", e, list);
+                    reading = false;
+                }
+            }
+            continue;
+        }
+        if let Some((listed, metadata, added)) = adding.next().await {
+            count_added(&listed, &metadata, added, &mut counts, &mut skips);
+        }
+    }
+    skips.finish(&path);
+    dir_broker_sender
+        .send(DirBrokerMessage::Done { path, counts })
+        .await?;
+    Ok(())
+}
+
+/// Count a symlink whose target can't be read, broken if there is none
+fn broken_link(path: &PathBuf, e: io::Error, counts: &mut DirCounts, skips: &mut DirSkips) {
+    if e.kind() == io::ErrorKind::NotFound {
+        counts.broken_links += 1;
+        skips.push(path, Reason::Symlink, "broken".to_string());
+    } else {
+        counts.errors += 1;
+        counts.unreadable += 1;
+        skips.push(path, Reason::Unreadable, e.to_string());
+    }
+}
+
+/// Tasks spawned for one directory, cancelled if it is left before
+/// they are all done
+///
+///   A walk stopped at shutdown drops the directory's future, and one
+///   that fails sending to the broker returns early.  Tasks merely
+///   detached would go on adding files to a store being written out.
+struct Tasks<T: Send + 'static>(FuturesUnordered<async_std::task::JoinHandle<T>>);
+
+impl<T: Send + 'static> Tasks<T> {
+    fn new() -> Self {
+        Tasks(FuturesUnordered::new())
+    }
+
+    fn spawn<F: Future<Output = T> + Send + 'static>(&mut self, future: F) {
+        self.0.push(async_std::task::spawn(future));
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The output of the next task to finish, None once none are left
+    async fn next(&mut self) -> Option<T> {
+        self.0.next().await
+    }
+
+    /// Cancel the tasks still running, and wait until they are
+    async fn cancel(mut self) {
+        for task in std::mem::take(&mut self.0) {
+            task.cancel().await;
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for Tasks<T> {
+    fn drop(&mut self) {
+        let tasks = std::mem::take(&mut self.0);
+        if !tasks.is_empty() {
+            async_std::task::spawn(async move {
+                for task in tasks {
+                    task.cancel().await;
+                }
+            });
+        }
+    }
+}
+
+/// `call` on each item of `items`, with up to `window` calls in flight
+/// while the items are still read, the results in the order of the items
+///
+///   Each metadata call is a round trip on a network filesystem, so a
+///   directory listed one at a time would take as many round trips as
+///   it has entries.  Reading stops at the first error, which is
+///   returned with the results of the items before it.
+async fn in_window<S, T, E, F, Fut>(
+    mut items: S,
+    window: usize,
+    call: F,
+) -> (Vec<Fut::Output>, Option<E>)
+where
+    S: Stream<Item = std::result::Result<T, E>> + Unpin,
+    F: Fn(T) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let (mut done, mut error) = (Vec::new(), None);
+    let mut in_flight = Tasks::new();
+    let mut reading = true;
+    while reading || !in_flight.is_empty() {
+        if reading && in_flight.len() < window.max(1) {
+            match items.next().await {
+                Some(Ok(item)) => {
+                    let (index, call) = (done.len() + in_flight.len(), call(item));
+                    in_flight.spawn(async move { (index, call.await) });
+                }
+                Some(Err(e)) => {
+                    error = Some(e);
+                    reading = false;
+                }
+                None => reading = false,
+            }
+            continue;
+        }
+        if let Some(finished) = in_flight.next().await {
+            done.push(finished);
+        }
+    }
+    done.sort_unstable_by_key(|(index, _)| *index);
+    (done.into_iter().map(|(_, output)| output).collect(), error)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn process_dir(
     path: PathBuf,
     depth: usize,
+    ignores: Option<Arc<Ignores>>,
+    skip_files: bool,
+    window: usize,
+    files: usize,
     file_store: FileStore,
     mut dir_broker_sender: Sender<DirBrokerMessage>,
 ) -> Result<()> {
-    let mut dir = match fs::read_dir(path).await {
+    let mut dir = match fs::read_dir(&path).await {
         Ok(r) => r,
         Err(e) => {
             if let Some(inner) = e.get_ref() {
                 eprintln!("read_dir: {}", inner);
             }
+            let mut skips = file_store.skips();
+            skips.push(&path, Reason::Unreadable, e.to_string());
+            skips.finish(&path);
             dir_broker_sender
-                .send(DirBrokerMessage::Error { e })
+                .send(DirBrokerMessage::Error { path, e })
                 .await?;
             return Ok(());
         }
     };
 
-    let mut files: usize = 0;
-    let mut dirs: usize = 0;
-    let mut errors: usize = 0;
+    let mut counts = DirCounts::default();
+    let ignores = match ignores {
+        Some(ignores) => Some(ignores.descend(path.as_ref()).await),
+        None => None,
+    };
+    let mut skips = file_store.skips();
+    if file_store.records_dirs() {
+        let added = match fs::metadata(&path).await {
+            Ok(metadata) => file_store.add_dir(&path, &metadata),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = added {
+            counts.errors += 1;
+            eprintln!("add_dir: {:?} ({})", e, path.to_str().unwrap());
+        }
+    }
+
+    // Only gather entries while the directory is open, a blocked send
+    // or a long hash here can make network filesystems drop the handle.
+    let (listed, error) = in_window(&mut dir, window, |entry: fs::DirEntry| async move {
+        let metadata = entry.metadata().await;
+        (entry, metadata)
+    })
+    .await;
+    drop(dir);
+    if let Some(e) = error {
+        counts.errors += 1;
+        counts.unreadable += 1;
+        skips.push(&path, Reason::Unreadable, format!("listing: {}", e));
+        eprintln!("read_dir: {:?}", e);
+    }
 
-    while let Some(res) = dir.next().await {
-        let entry = res?;
-        match entry.metadata().await {
+    let filter = file_store.path_filter();
+    let mut subdirs = Vec::new();
+    let mut plain = Vec::new();
+    for (entry, metadata) in listed {
+        match metadata {
+            Ok(metadata) if file_store.is_archive_dir(&entry.path(), &metadata) => (),
+            Ok(metadata) if filter.hidden(&entry.path()) => {
+                match metadata.is_dir() {
+                    true => counts.hidden_dirs += 1,
+                    false => counts.hidden += 1,
+                }
+                skips.push(&entry.path(), Reason::Excluded, "hidden".to_string());
+            }
+            Ok(metadata) if filter.excluded(&entry.path(), metadata.is_dir()) => {
+                match metadata.is_dir() {
+                    true => counts.excluded_dirs += 1,
+                    false => counts.excluded += 1,
+                }
+                skips.push(&entry.path(), Reason::Excluded, String::new());
+            }
+            Ok(metadata)
+                if ignores
+                    .as_ref()
+                    .is_some_and(|i| i.ignored(entry.path().as_ref(), metadata.is_dir())) =>
+            {
+                match metadata.is_dir() {
+                    true => counts.ignored_dirs += 1,
+                    false => counts.ignored += 1,
+                }
+                skips.push(
+                    &entry.path(),
+                    Reason::Excluded,
+                    "ignored by gitignore rules".to_string(),
+                );
+            }
+            Ok(metadata)
+                if metadata.is_file() && file_store.outside_times(&entry.path(), &metadata) =>
+            {
+                counts.outside_times += 1;
+            }
+            Ok(metadata) if metadata.is_file() && !filter.included(&entry.path()) => {
+                counts.not_included += 1;
+                skips.push(
+                    &entry.path(),
+                    Reason::Excluded,
+                    "matches no --include pattern".to_string(),
+                );
+            }
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let policy = file_store.symlink_policy();
+                if skip_files && policy != Symlinks::Follow {
+                    continue;
+                }
+                let path = entry.path();
+                match policy {
+                    Symlinks::Skip => {
+                        // nothing to hash, counted and left out, see print_census
+                        counts.symlinks += 1;
+                        skips.push(&path, Reason::Symlink, String::new());
+                    }
+                    Symlinks::Follow => match fs::metadata(&path).await {
+                        Ok(target) if file_store.is_archive_dir(&path, &target) => (),
+                        Ok(target) if target.is_dir() => {
+                            counts.followed_links += 1;
+                            subdirs.push((path, Some((target.dev(), target.ino()))));
+                        }
+                        Ok(_) if skip_files => (),
+                        Ok(target) if !target.is_file() => {
+                            counts.special += 1;
+                            skips.push(&path, Reason::Special, "symlink target".to_string());
+                        }
+                        Ok(target) if file_store.outside_times(&path, &target) => {
+                            counts.outside_times += 1;
+                        }
+                        Ok(_) if !filter.included(&path) => {
+                            counts.not_included += 1;
+                            skips.push(
+                                &path,
+                                Reason::Excluded,
+                                "matches no --include pattern".to_string(),
+                            );
+                        }
+                        Ok(target) => {
+                            counts.followed_links += 1;
+                            plain.push((path, target));
+                        }
+                        Err(e) => broken_link(&path, e, &mut counts, &mut skips),
+                    },
+                    Symlinks::Record => {
+                        let added = match fs::read_link(&path).await {
+                            Ok(target) => file_store.add_symlink(
+                                &path,
+                                &metadata,
+                                target.to_string_lossy().into_owned(),
+                            ),
+                            Err(e) => Err(e.into()),
+                        };
+                        match added {
+                            Ok(()) => counts.recorded_links += 1,
+                            Err(e) => {
+                                counts.errors += 1;
+                                skips.push(&path, Reason::Error, e.to_string());
+                                eprintln!("add_symlink: {} ({})", e, path.to_string_lossy());
+                            }
+                        }
+                        if let Err(e) = fs::metadata(&path).await {
+                            broken_link(&path, e, &mut counts, &mut skips);
+                        }
+                    }
+                }
+            }
             Ok(metadata) => {
                 if metadata.is_dir() {
-                    dir_broker_sender
-                        .send(DirBrokerMessage::NewDir {
-                            path: entry.path(),
-                            depth: depth + 1,
-                        })
-                        .await?;
-                    dirs += 1;
-                } else {
-                    match file_store.add_file(&entry.path(), &metadata).await {
-                        Ok(()) => files += 1,
-                        Err(e) => {
-                            errors += 1;
-                            eprintln!("add_file: {:?} ({})", e, entry.path().to_str().unwrap());
-                        }
+                    subdirs.push((entry.path(), file_store.dir_id(&metadata)));
+                } else if !skip_files {
+                    // nothing to hash, counted and left out, see print_census
+                    if !metadata.is_file() {
+                        counts.special += 1;
+                        skips.push(&entry.path(), Reason::Special, String::new());
+                    } else {
+                        plain.push((entry.path(), metadata));
                     }
                 }
             }
             Err(e) => {
-                errors += 1;
+                counts.errors += 1;
+                if e.kind() == io::ErrorKind::NotFound {
+                    // deleted since the directory was listed
+                    counts.vanished += 1;
+                    skips.push(&entry.path(), Reason::Vanished, String::new());
+                } else {
+                    counts.unreadable += 1;
+                    skips.push(&entry.path(), Reason::Unreadable, e.to_string());
+                }
                 eprintln!("metadata: {:?} ({})", e, entry.path().to_str().unwrap());
             }
         }
     }
+
+    // queue subdirectories first so other tasks can start on them
+    for (path, id) in subdirs {
+        let blocked = Instant::now();
+        dir_broker_sender
+            .send(DirBrokerMessage::NewDir {
+                path,
+                depth: depth + 1,
+                ignores: ignores.clone(),
+                id,
+            })
+            .await?;
+        file_store.stats().add_send_blocked(blocked.elapsed());
+        counts.dirs += 1;
+    }
+
+    // Up to `files` are added at once, each a task of its own that
+    // hands its result back here, so the counts in Done stay whole and
+    // the broker still sees one task per directory.
+    let mut plain = plain.into_iter();
+    let mut adding = Tasks::new();
+    loop {
+        while adding.len() < files.max(1) {
+            let (path, metadata) = match plain.next() {
+                Some(file) => file,
+                None => break,
+            };
+            let file_store = file_store.clone();
+            adding.spawn(async move {
+                let added = file_store.add_file(&path, &metadata).await;
+                (path, metadata, added)
+            });
+        }
+        let (path, metadata, added) = match adding.next().await {
+            Some(done) => done,
+            None => break,
+        };
+        count_added(&path, &metadata, added, &mut counts, &mut skips);
+    }
+    skips.finish(&path);
     dir_broker_sender
-        .send(DirBrokerMessage::Done {
-            files,
-            dirs,
-            errors,
-        })
+        .send(DirBrokerMessage::Done { path, counts })
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn filter(globs: &[&str], includes: &[&str], regexes: (&[&str], &[&str])) -> PathFilter {
+        let excludes = Excludes::new(Vec::new(), strings(globs))
+            .unwrap()
+            .with_includes(strings(includes))
+            .unwrap()
+            .with_regexes(strings(regexes.0), strings(regexes.1))
+            .unwrap()
+            .with_roots(&["/src"]);
+        PathFilter::new(Arc::new(excludes), false)
+    }
+
+    #[test]
+    fn excluded_directories_and_regexes() {
+        let f = filter(&["**/node_modules/**"], &[], (&["/cache-[0-9]+/"], &[]));
+        assert!(f.excluded(Path::new("/src/web/node_modules"), true));
+        assert!(!f.excluded(Path::new("/src/web/node_modules"), false));
+        assert!(f.excluded(Path::new("/src/web/node_modules/a.js"), false));
+        assert!(f.excluded(Path::new("/src/cache-12/a"), false));
+        assert!(f.excluded(Path::new("/src/cache-12"), true));
+        assert!(!f.excluded(Path::new("/src/cache-x/a"), false));
+        assert!(f.included(Path::new("/src/cache-x/a")));
+    }
+
+    #[test]
+    fn includes_compose_globs_and_regexes() {
+        let f = filter(&[], &["*.jpg"], (&[], &["^/src/raw/"]));
+        assert!(f.included(Path::new("/src/a/b.jpg")));
+        assert!(f.included(Path::new("/src/raw/b.nef")));
+        assert!(!f.included(Path::new("/src/a/b.nef")));
+        let f = filter(&["**/tmp/**"], &["*.jpg"], (&[], &[]));
+        assert!(f.excluded(Path::new("/src/tmp/b.jpg"), false));
+        assert!(f.included(Path::new("/src/tmp/b.jpg")));
+    }
+
+    #[test]
+    fn hidden_only_with_skip_hidden() {
+        let f = filter(&[], &[], (&[], &[]));
+        assert!(!f.hidden(Path::new("/src/.git")));
+        let f = PathFilter::new(f.excludes.clone(), true);
+        assert!(f.hidden(Path::new("/src/.git")));
+        assert!(f.hidden(Path::new("/src/a/.profile")));
+        assert!(!f.hidden(Path::new("/src/.a/b")));
+        assert!(!f.hidden(Path::new("/src/a.b")));
+        assert!(!f.excluded(Path::new("/src/.git"), true));
+    }
+
+    #[test]
+    fn the_window_overlaps_slow_calls_in_order() {
+        let slow = |window| {
+            let items = futures::stream::iter((0..64).map(Ok::<_, ()>));
+            let started = Instant::now();
+            let (done, error) =
+                async_std::task::block_on(in_window(items, window, |i| async move {
+                    // a round trip to a far away server
+                    async_std::task::sleep(Duration::from_millis(20)).await;
+                    i * 2
+                }));
+            assert_eq!(done, (0..64).map(|i| i * 2).collect::<Vec<_>>());
+            assert!(error.is_none());
+            started.elapsed()
+        };
+        let (one, sixteen) = (slow(1), slow(16));
+        assert!(one >= Duration::from_millis(64 * 20));
+        assert!(sixteen * 8 < one, "{:?} against {:?}", sixteen, one);
+    }
+
+    #[test]
+    fn tasks_left_behind_are_cancelled() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let finished = Arc::new(AtomicUsize::new(0));
+        let tasks = || {
+            let mut tasks = Tasks::new();
+            for _ in 0..4 {
+                let finished = finished.clone();
+                tasks.spawn(async move {
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            tasks
+        };
+        async_std::task::block_on(async {
+            tasks().cancel().await;
+            // as when a directory's future is dropped at shutdown
+            drop(tasks());
+            let mut waited = tasks();
+            while waited.next().await.is_some() {}
+            async_std::task::sleep(Duration::from_millis(200)).await;
+        });
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn the_window_stops_at_an_error() {
+        let items = futures::stream::iter(vec![Ok(1), Ok(2), Err("unreadable"), Ok(4)]);
+        let (done, error) = async_std::task::block_on(in_window(items, 4, |i| async move { i }));
+        assert_eq!((done, error), (vec![1, 2], Some("unreadable")));
+    }
+
+    #[test]
+    fn thousands_of_sibling_directories_are_walked() {
+        let dir = crate::testing::scratch("siblings");
+        let (archive, root) = (dir.join("archive"), dir.join("root"));
+        std::fs::create_dir(&archive).unwrap();
+        for i in 0..10_000 {
+            let sub = root.join(format!("{:05}", i));
+            std::fs::create_dir_all(&sub).unwrap();
+            if i % 1000 == 0 {
+                std::fs::write(sub.join("file"), i.to_string()).unwrap();
+            }
+        }
+        let archive = archive.to_str().unwrap();
+        let outcome = crate::testing::scan(&["-a", archive, "-q"], &[root.to_str().unwrap()]);
+        assert_eq!(outcome, crate::Outcome::Updated);
+        let store = FileStore::new(archive, crate::testing::config(&["-a", archive]));
+        async_std::task::block_on(store.read_all()).unwrap();
+        assert_eq!(store.index().len(), 10);
+    }
+}
@@ -0,0 +1,346 @@
+//! exclusion patterns, see --exclude and `find_dups exclude`
+//!
+//! Patterns are globs matched against the whole path, and against the
+//! path under the injest root it was found in: `**` matches anything,
+//! `/` included, a leading `**/` no directory at all too, `*` and `?`
+//! anything but `/`.  So `/srv/cache/**` leaves out the one directory,
+//! `build/**` a `build` directory directly under any root and
+//! `**/build/**` one at any depth.  A pattern with no `/` is matched against
+//! the file name alone.  A directory is matched with a `/` after its
+//! path too, so `**/node_modules/**` leaves the directory unwalked
+//! rather than walking it for nothing.
+//!
+//! Patterns kept in an archive, as its `exclude` sets, apply to every
+//! injest and check against it as well as those given with --exclude.
+//!
+//! Given any --include patterns, a file is only taken if it matches one
+//! of them as well, after the excludes, as rsync has it.  Directories
+//! are still walked, so `**/thumb/*.jpg` finds thumbnails at any depth.
+//!
+//! --exclude-regex and --include-regex are regexes, unanchored, for the
+//! rules globs can't put, `/cache-[0-9]+/` say.  They are matched
+//! against the whole path alone, a directory's with a `/` after it, and
+//! count as excludes and includes like the globs do.
+
+use crate::record::{Record, RecordLocation};
+use crate::{ItemReadWrite, Result, ARCHIVE_SIZE, RECORD_SIZE};
+use regex::RegexSet;
+use std::path::Path;
+
+/// The patterns a walk leaves out
+#[derive(Clone, Debug)]
+pub struct Excludes {
+    /// kept in the archive
+    stored: Vec<String>,
+    /// given with --exclude
+    given: Vec<String>,
+    /// given with --include
+    includes: Vec<String>,
+    /// injest roots, for matching paths under them
+    roots: Vec<String>,
+    set: RegexSet,
+    include_set: RegexSet,
+    /// given with --exclude-regex and --include-regex
+    regexes: RegexSet,
+    include_regexes: RegexSet,
+}
+
+impl Default for Excludes {
+    fn default() -> Self {
+        Excludes {
+            stored: Vec::new(),
+            given: Vec::new(),
+            includes: Vec::new(),
+            roots: Vec::new(),
+            set: RegexSet::empty(),
+            include_set: RegexSet::empty(),
+            regexes: RegexSet::empty(),
+            include_regexes: RegexSet::empty(),
+        }
+    }
+}
+
+impl Excludes {
+    pub fn new(stored: Vec<String>, given: Vec<String>) -> Result<Self> {
+        let set = RegexSet::new(stored.iter().chain(&given).map(|glob| glob_regex(glob)))?;
+        Ok(Excludes {
+            stored,
+            given,
+            set,
+            ..Default::default()
+        })
+    }
+
+    /// The same excludes, taking only files matching one of `includes`
+    /// if there are any
+    pub fn with_includes(mut self, includes: Vec<String>) -> Result<Self> {
+        self.include_set = RegexSet::new(includes.iter().map(|glob| glob_regex(glob)))?;
+        self.includes = includes;
+        Ok(self)
+    }
+
+    /// The same globs, with regexes excluding and including paths
+    ///
+    ///   The regexes should have been checked with check_regex, so a bad
+    ///   one is refused before a walk begins.
+    pub fn with_regexes(mut self, exclude: Vec<String>, include: Vec<String>) -> Result<Self> {
+        self.regexes = RegexSet::new(exclude)?;
+        self.include_regexes = RegexSet::new(include)?;
+        Ok(self)
+    }
+
+    /// The same --exclude patterns with the archive's stored ones
+    pub fn with_stored(&self, stored: Vec<String>) -> Result<Self> {
+        let set = RegexSet::new(
+            stored
+                .iter()
+                .chain(&self.given)
+                .map(|glob| glob_regex(glob)),
+        )?;
+        Ok(Excludes {
+            stored,
+            set,
+            ..self.clone()
+        })
+    }
+
+    /// The same patterns, also matched against paths under `roots`
+    pub fn with_roots(&self, roots: &[&str]) -> Self {
+        Excludes {
+            roots: roots.iter().map(|root| root.to_string()).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// True if there is no pattern of any kind
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+            && self.include_set.is_empty()
+            && self.regexes.is_empty()
+            && self.include_regexes.is_empty()
+    }
+
+    /// True if patterns given for this run leave files out, as those
+    /// kept in the archive apply to every run alike
+    pub fn narrows(&self) -> bool {
+        !self.given.is_empty()
+            || !self.include_set.is_empty()
+            || !self.regexes.is_empty()
+            || !self.include_regexes.is_empty()
+    }
+
+    /// The --include patterns
+    pub fn includes(&self) -> impl Iterator<Item = &str> {
+        self.includes.iter().map(String::as_str)
+    }
+
+    /// The --exclude-regex and --include-regex patterns
+    pub fn regexes(&self) -> (&[String], &[String]) {
+        (self.regexes.patterns(), self.include_regexes.patterns())
+    }
+
+    /// Every pattern in effect, the stored ones first
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.stored.iter().chain(&self.given).map(String::as_str)
+    }
+
+    /// Patterns in effect, each followed by where it came from
+    pub fn describe(&self) -> Vec<String> {
+        let stored = self.stored.iter().map(|p| format!("{} (stored)", p));
+        let given = self.given.iter().map(|p| format!("{} (--exclude)", p));
+        stored.chain(given).collect()
+    }
+
+    /// True if a walk should leave out `path`
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.set.is_empty() && self.regexes.is_empty() {
+            return false;
+        }
+        let is_match = |set: &RegexSet, path: &str| {
+            set.is_match(path) || (is_dir && set.is_match(&format!("{}/", path)))
+        };
+        is_match(&self.regexes, path)
+            || is_match(&self.set, path)
+            || self
+                .relative(path)
+                .is_some_and(|path| is_match(&self.set, path))
+    }
+
+    /// True if a file not excluded is to be taken, as it matches an
+    /// include pattern or there are none
+    pub fn included(&self, path: &str) -> bool {
+        if self.include_set.is_empty() && self.include_regexes.is_empty() {
+            return true;
+        }
+        if self.include_regexes.is_match(path) {
+            return true;
+        }
+        let is_match = |path: &str| self.include_set.is_match(path);
+        is_match(path) || self.relative(path).is_some_and(is_match)
+    }
+
+    /// `path` under the longest root it is in, None if it is in none
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        self.roots
+            .iter()
+            .filter_map(|root| Path::new(path).strip_prefix(root).ok())
+            .filter_map(|rel| rel.to_str())
+            .filter(|rel| !rel.is_empty())
+            .min_by_key(|rel| rel.len())
+    }
+}
+
+/// Check a pattern will compile before it is kept
+pub fn check_pattern(glob: &str) -> Result<()> {
+    if glob.is_empty() {
+        return Err("empty exclude pattern".into());
+    }
+    regex::Regex::new(&glob_regex(glob)).map_err(|e| format!("{}: {}", glob, e))?;
+    Ok(())
+}
+
+/// Check a --exclude-regex or --include-regex compiles, for clap
+pub fn check_regex(regex: &str) -> std::result::Result<(), String> {
+    regex::Regex::new(regex)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// The regex a glob pattern stands for
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from(match glob.contains('/') {
+        true => "^",
+        false => "(^|/)",
+    });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` matches no directory at all too
+                match chars.peek() == Some(&'/') && regex.ends_with(['^', '/']) {
+                    true => {
+                        chars.next();
+                        regex += "(.*/)?";
+                    }
+                    false => regex += ".*",
+                }
+            }
+            '*' => regex += "[^/]*",
+            '?' => regex += "[^/]",
+            c => regex += &regex::escape(&c.to_string()),
+        }
+    }
+    regex + "$"
+}
+
+impl ItemReadWrite for Record<String> {
+    type T = String;
+    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
+        self.push(minicbor::to_vec(item)?)
+    }
+    fn read_item(&mut self) -> Result<Option<Self::T>> {
+        match &self.pull()? {
+            Some(v) => Ok(Some(minicbor::decode(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn record(archive: &str) -> Result<Record<String>> {
+    if crate::pack::is_packed(archive) {
+        return Err(format!(
+            "{} is a single file archive, which keeps no exclude patterns",
+            archive
+        )
+        .into());
+    }
+    Ok(Record::new(
+        archive,
+        "exclude".to_string(),
+        ARCHIVE_SIZE,
+        RECORD_SIZE,
+    ))
+}
+
+/// The patterns kept in an archive, none for a single file archive
+pub async fn stored(archive: &str) -> Result<Vec<String>> {
+    if crate::pack::is_packed(archive) {
+        return Ok(Vec::new());
+    }
+    let mut record = record(archive)?;
+    record.check_sets(false, 0).await?;
+    let mut patterns = Vec::new();
+    while let Some(pattern) = record.read_item()? {
+        patterns.push(pattern);
+    }
+    Ok(patterns)
+}
+
+/// Replace the patterns kept in an archive, the old ones backed up
+pub async fn store(archive: &str, patterns: &[String]) -> Result<()> {
+    let mut record = record(archive)?;
+    async_std::fs::create_dir_all(archive).await?;
+    record.backup().await?;
+    for pattern in patterns {
+        record.write_item(pattern)?;
+    }
+    record.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn includes(globs: &[&str]) -> Excludes {
+        Excludes::default()
+            .with_includes(globs.iter().map(|glob| glob.to_string()).collect())
+            .unwrap()
+    }
+
+    #[test]
+    fn include_matches_at_any_depth() {
+        let e = includes(&["**/thumb/*.jpg"]);
+        assert!(e.included("/photos/2020/06/thumb/a.jpg"));
+        assert!(e.included("/photos/thumb/a.jpg"));
+        assert!(!e.included("/photos/thumb/small/a.jpg"));
+        assert!(!e.included("/photos/thumb/a.png"));
+        assert!(!e.included("/photos/thumbs/a.jpg"));
+    }
+
+    #[test]
+    fn include_matches_directly_under_a_root() {
+        let e = includes(&["**/thumb/*.jpg"]).with_roots(&["photos"]);
+        assert!(e.included("photos/thumb/a.jpg"));
+        assert!(e.included("photos/2020/thumb/a.jpg"));
+        assert!(!e.included("photos/a.jpg"));
+    }
+
+    #[test]
+    fn no_includes_take_everything() {
+        let e = includes(&[]);
+        assert!(e.included("/anything/at/all"));
+        assert!(!e.narrows());
+        assert!(includes(&["*.jpg"]).narrows());
+    }
+
+    #[test]
+    fn name_only_include() {
+        let e = includes(&["*.jpg"]);
+        assert!(e.included("/a/b/c.jpg"));
+        assert!(!e.included("/a/b.jpg/c.png"));
+    }
+
+    #[test]
+    fn exclude_globs() {
+        let e = Excludes::new(Vec::new(), vec!["build/**".to_string()])
+            .unwrap()
+            .with_roots(&["/src"]);
+        assert!(e.matches("/src/build/out.o", false));
+        assert!(e.matches("/src/build", true));
+        assert!(!e.matches("/src/lib/build.rs", false));
+        let e = Excludes::new(vec!["**/node_modules/**".to_string()], Vec::new()).unwrap();
+        assert!(e.matches("/a/node_modules", true));
+        assert!(!e.narrows());
+    }
+}
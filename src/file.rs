@@ -1,22 +1,153 @@
 //! file functions for wayback
 
+use crate::archive::{ArchiveMode, FORMAT_VERSION};
 use crate::{
-    record::Record, record::RecordLocation, Config, ItemReadWrite, Result, ARCHIVE_SIZE,
-    CHUNK_SIZE, RECORD_SIZE,
+    checkpoint, format_gib, format_size, normalize_path,
+    policy::{Decision, Member},
+    rebase_path,
+    record::Record,
+    record::RecordLocation,
+    reflink, similar,
+    stats::{describe_version_mix, Stats},
+    Config, ItemReadWrite, Result, ARCHIVE_SIZE, CHUNK_SIZE, MAX_CHUNK_SIZE, MEDIA_SPAN,
+    RECORD_SIZE,
 };
 use async_std::fs::{File, Metadata};
+use async_std::io::SeekFrom;
 use async_std::path::PathBuf;
 use async_std::prelude::*;
 use async_std::sync::Arc;
 use dashmap::{DashMap, DashSet};
 use minicbor_derive::{Decode, Encode};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub type ChunkHash = u64;
+
+/// Number of archived files preflight will look at before giving up
+const PREFLIGHT_TRIES: usize = 16;
+
+/// How many times likelier --hash-audit is to pick a group for each
+/// of its members differing in mtime and in directory
+const AUDIT_WEIGHT: f64 = 4.0;
+
+/// Slowest read rate, in bytes per second, a hash is allowed before
+/// --file-timeout gives up on it
+const MIN_HASH_RATE: u64 = 1024 * 1024;
+
+/// Most files keeping a directory from removal for --removable-dirs to
+/// still list it as partially redundant
+const REMOVABLE_MAX_BLOCKERS: usize = 10;
+
+/// Content identity of a file as kept in the index
+///
+///   A partial hash only covers the size, head and tail of the file
+///   (see --media-mode) and never compares equal to a full hash.  The
+///   256 bit digests of --hash blake3 and sha256 keep their first 64
+///   bits in `hash`, which ids and ordering go by, and the rest in
+///   `rest`; archives of 64 bit hashes never write it.
+#[derive(Hash, Clone, Copy, Eq, PartialEq, Default, Debug, Encode, Decode)]
+pub struct FileHash {
+    #[n(0)]
+    hash: ChunkHash,
+    #[n(1)]
+    partial: bool,
+    #[n(2)]
+    rest: Option<[u64; 3]>,
+}
+
+impl FileHash {
+    pub fn full(hash: ChunkHash) -> Self {
+        FileHash {
+            hash,
+            partial: false,
+            rest: None,
+        }
+    }
+
+    pub fn partial(hash: ChunkHash) -> Self {
+        FileHash {
+            hash,
+            partial: true,
+            rest: None,
+        }
+    }
+
+    /// A 256 bit digest, read as big endian words so hex() spells it
+    /// as sha256sum and b3sum do
+    pub fn wide(digest: [u8; 32], partial: bool) -> Self {
+        let word = |i: usize| u64::from_be_bytes(digest[i * 8..i * 8 + 8].try_into().unwrap());
+        FileHash {
+            hash: word(0),
+            partial,
+            rest: Some([word(1), word(2), word(3)]),
+        }
+    }
+
+    /// Parse the digits hex() prints, 16 or 64 of them
+    pub fn from_hex(digits: &str, partial: bool) -> Option<Self> {
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let word = |i: usize| u64::from_str_radix(&digits[i * 16..i * 16 + 16], 16).ok();
+        match digits.len() {
+            16 => Some(FileHash {
+                hash: word(0)?,
+                partial,
+                rest: None,
+            }),
+            64 => Some(FileHash {
+                hash: word(0)?,
+                partial,
+                rest: Some([word(1)?, word(2)?, word(3)?]),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The whole digest in hex, without the partial mark
+    pub fn hex(&self) -> String {
+        let mut hex = format!("{:016x}", self.hash);
+        for word in self.rest.iter().flatten() {
+            hex += &format!("{:016x}", word);
+        }
+        hex
+    }
+
+    pub fn hash(&self) -> ChunkHash {
+        self.hash
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Stand-in hash of a file left waiting unhashed, see tiered_hash
+    ///
+    ///   Archived as is.  Entries with it are kept out of the hash
+    ///   groups, see FileStore::defer_hash.
+    pub const PENDING: FileHash = FileHash {
+        hash: 0,
+        partial: true,
+        rest: None,
+    };
+
+    pub fn is_pending(&self) -> bool {
+        *self == Self::PENDING
+    }
+}
+
 // inspired by github:://rsdy/zerostash/libzerostash/file.rs
 
-#[derive(Hash, Clone, Eq, PartialEq, Default, Debug, Encode, Decode)]
+/// Metadata of an archived file
+///
+///   Equality and hashing only look at the name, size and modification
+///   time, so an index lookup still finds a file after chmod or chown.
+///   Use `same_metadata` to compare the remaining fields.
+#[derive(Clone, Default, Debug, Encode, Decode)]
 pub struct Entry {
     #[n(0)]
     perm: u32,
@@ -39,6 +170,27 @@ pub struct Entry {
     len: u64,
     #[n(8)]
     name: String,
+
+    /// hash of the first chunk, kept once a file bigger than a chunk
+    /// has been through tiered_hash
+    #[n(9)]
+    head: Option<u64>,
+
+    /// device and inode, None in private archives and in entries
+    /// archived before they were kept
+    #[n(10)]
+    inode: Option<(u64, u64)>,
+
+    /// blake3 of the whole file, taken over the reads that hash it, so
+    /// it can be checked with b3sum; None unless hashed with --digest,
+    /// or if it was hashed in part or archived before digests were kept
+    #[n(11)]
+    digest: Option<FileHash>,
+
+    /// the target of a symlink kept with --symlinks record, which is
+    /// neither a file nor a directory
+    #[n(12)]
+    link: Option<String>,
 }
 
 impl Entry {
@@ -60,15 +212,199 @@ impl Entry {
 
             len: metadata.len(),
             name: path.to_str().unwrap().to_string(),
+            head: None,
+            inode: Some((metadata.dev(), metadata.ino())),
+            digest: None,
+            link: None,
         })
     }
+
+    /// 64-bit digest of the fields equality looks at
+    pub fn identity_digest(&self) -> u64 {
+        let mut hasher = seahash::SeaHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The form kept in a private archive: a keyed digest of the
+    /// name and the size, everything else left out
+    pub fn private(&self, key: &PrivateKey) -> Entry {
+        Entry {
+            is_file: self.is_file,
+            is_dir: self.is_dir,
+            len: self.len,
+            name: key.path_mac(&self.name),
+            ..Default::default()
+        }
+    }
+
+    /// True if owner, mode and type also match
+    pub fn same_metadata(&self, other: &Entry) -> bool {
+        self == other
+            && self.perm == other.perm
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.is_file == other.is_file
+            && self.is_dir == other.is_dir
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.len == other.len
+            && self.mod_secs == other.mod_secs
+            && self.mod_nanos == other.mod_nanos
+    }
+}
+
+impl Eq for Entry {}
+
+impl Hash for Entry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.len.hash(state);
+        self.mod_secs.hash(state);
+        self.mod_nanos.hash(state);
+    }
+}
+
+/// Encoding of an Entry in an archive whose header says private
+///
+///   Only the size and an HMAC of the path are stored.  Without the
+///   key the path can't be recovered, with it a rescan finds the entry
+///   again to replace or prune it.  The HMAC hides paths only: sets,
+///   hashes and sizes are not authenticated, and anyone can change
+///   them unnoticed.
+#[derive(Clone, Debug, Encode, Decode)]
+struct PrivateEntry {
+    #[n(0)]
+    len: u64,
+    #[n(1)]
+    path_mac: String,
+}
+
+/// Secret for --private-archive, never printed
+pub struct PrivateKey(Vec<u8>);
+
+impl PrivateKey {
+    /// Read a key file, ignoring a trailing newline
+    pub fn from_file(path: &str) -> Result<Self> {
+        let mut key = std::fs::read(path)?;
+        while matches!(key.last(), Some(b'\n' | b'\r')) {
+            key.pop();
+        }
+        if key.is_empty() {
+            return Err(format!("{}: private archive key is empty", path).into());
+        }
+        Ok(PrivateKey(key))
+    }
+
+    /// HMAC-SHA256 of a path, in hex
+    pub fn path_mac(&self, name: &str) -> String {
+        use hmac::{Hmac, Mac};
+
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&self.0).expect("any key length");
+        mac.update(name.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PrivateKey(..)")
+    }
+}
+
+/// An entry compared on every field, see Interner
+#[derive(Clone, Debug)]
+struct Interned(Arc<Entry>);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.same_metadata(&other.0)
+            && self.0.head == other.0.head
+            && self.0.inode == other.0.inode
+            && self.0.digest == other.0.digest
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+/// The one allocation of each distinct entry, shared by the stores of
+/// a run
+///
+///   Every entry going into an index passes through `intern`, so a
+///   file archived in several archives, or loaded again, is held once
+///   and an index key and its group member are the same allocation.
+///   Entries that differ in mode or owner are kept apart.  Entries are
+///   never dropped from here, so a pruned or replaced one lives until
+///   the end of the run.
+#[derive(Debug, Default)]
+pub struct Interner {
+    entries: DashSet<Interned>,
+    reused: AtomicUsize,
+}
+
+impl Interner {
+    /// The allocation already holding an entry equal in every field,
+    /// else this one
+    fn intern(&self, entry: Arc<Entry>) -> Arc<Entry> {
+        let candidate = Interned(entry);
+        if let Some(found) = self.entries.get(&candidate) {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            return found.0.clone();
+        }
+        if self.entries.insert(candidate.clone()) {
+            return candidate.0;
+        }
+        // lost a race with an insert of the same entry
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        self.entries
+            .get(&candidate)
+            .map_or(candidate.0, |found| found.0.clone())
+    }
+
+    /// Distinct entries held, and how many interns found one already held
+    pub fn counts(&self) -> (usize, usize) {
+        (self.entries.len(), self.reused.load(Ordering::Relaxed))
+    }
 }
 
-pub type FileIndex = DashMap<Arc<Entry>, ChunkHash>;
-pub type HashIndex = DashMap<ChunkHash, Vec<Arc<Entry>>>;
-pub type FileTuple = (Arc<Entry>, ChunkHash);
-pub type PresentSet = DashSet<Arc<Entry>>;
+pub type FileIndex = DashMap<Arc<Entry>, FileHash>;
+pub type HashIndex = DashMap<FileHash, Vec<Arc<Entry>>>;
+pub type FileTuple = (Arc<Entry>, FileHash);
+/// A file's hash and its whole file digest, see Entry::digest
+pub type Hashed = (FileHash, Option<FileHash>);
+/// Identity digests of the files seen during a scan, for --prune
+///
+///   Keeping digests instead of entries keeps a pruning run close to
+///   the memory of a plain one.  A digest collision can only keep an
+///   entry that should have been pruned, never prune a seen one.
+pub type PresentSet = DashSet<u64>;
+
+/// Size, and first chunk hash if read, a file waits under before it
+/// is hashed in full, see FileStore::defer_hash
+type WaitKey = (u64, Option<u64>);
 
+/// Archived file entries, indexed by path and by content
+///
+///   Many process_dir tasks call add_file at once.  Updates go through
+///   `insert_entry` and `remove_from_group`, which change a hash group
+///   under its DashMap shard lock, so concurrent adds of the same
+///   content never lose a group member.  A new entry is visible in
+///   `index` just before it joins its group; for a stable view of the
+///   groups that holds no locks while it is used, see `snapshot_groups`.
 #[derive(Clone, Debug)]
 pub struct FileStore {
     index: Arc<FileIndex>,
@@ -76,6 +412,40 @@ pub struct FileStore {
     record: crate::record::Record<FileTuple>,
     config: Config,
     present: Arc<PresentSet>,
+    seen_paths: Arc<DashSet<String>>,
+    injested: Arc<std::sync::RwLock<std::collections::BTreeMap<String, u64>>>,
+    unchanged: Arc<AtomicUsize>,
+    refreshed: Arc<AtomicUsize>,
+    stats: Arc<Stats>,
+    archive: String,
+    others: Arc<Vec<FileStore>>,
+    private: Arc<AtomicBool>,
+    printed_groups: Arc<DashSet<FileHash>>,
+    algorithm: Arc<std::sync::RwLock<HashAlgorithm>>,
+    cache: Arc<DashMap<(u64, u64), CachedHash>>,
+    /// hashes of inodes with more than one link, by device and inode,
+    /// each locked while its first link is hashed
+    links: Arc<DashMap<(u64, u64), LinkSlot>>,
+    chunking: Arc<std::sync::RwLock<Chunking>>,
+    dirs: Arc<DashMap<String, Arc<Entry>>>,
+    seen_dirs: Arc<DashSet<String>>,
+    /// symlinks kept with --symlinks record, and those seen this run
+    symlinks: Arc<DashMap<String, Arc<Entry>>>,
+    seen_symlinks: Arc<DashSet<String>>,
+    symlinks_changed: Arc<AtomicUsize>,
+    /// whether an archive kept an empty file, see archives_empty
+    archived_empty: Arc<std::sync::OnceLock<bool>>,
+    /// owners --uid-map changed, by name: the uid as mapped and as
+    /// archived, see archived_form
+    owners: Arc<DashMap<String, (u32, u32)>>,
+    /// set once the walk has passed the archive directory by
+    passed_archive: Arc<AtomicBool>,
+    dirs_changed: Arc<AtomicUsize>,
+    dirs_created: Arc<AtomicUsize>,
+    interner: Arc<Interner>,
+    pending: Arc<DashMap<WaitKey, Vec<Arc<Entry>>>>,
+    sized: Arc<DashSet<WaitKey>>,
+    headless: Arc<DashSet<u64>>,
 }
 
 impl FileStore {
@@ -90,6 +460,33 @@ impl FileStore {
                 RECORD_SIZE,
             ),
             present: Arc::new(PresentSet::new()),
+            seen_paths: Arc::new(DashSet::new()),
+            injested: Arc::default(),
+            unchanged: Arc::new(AtomicUsize::new(0)),
+            refreshed: Arc::new(AtomicUsize::new(0)),
+            stats: Arc::new(Stats::throttled(config.bwlimit.clone())),
+            archive: archive.to_string(),
+            others: Arc::new(Vec::new()),
+            private: Arc::new(AtomicBool::new(false)),
+            printed_groups: Arc::new(DashSet::new()),
+            algorithm: Arc::new(std::sync::RwLock::new(config.hash.unwrap_or_default())),
+            chunking: Arc::new(std::sync::RwLock::new(config.chunking.unwrap_or_default())),
+            cache: Arc::new(DashMap::new()),
+            links: Arc::new(DashMap::new()),
+            dirs: Arc::new(DashMap::new()),
+            seen_dirs: Arc::new(DashSet::new()),
+            symlinks: Arc::new(DashMap::new()),
+            seen_symlinks: Arc::new(DashSet::new()),
+            symlinks_changed: Arc::new(AtomicUsize::new(0)),
+            archived_empty: Arc::default(),
+            owners: Arc::new(DashMap::new()),
+            passed_archive: Arc::new(AtomicBool::new(false)),
+            dirs_changed: Arc::new(AtomicUsize::new(0)),
+            dirs_created: Arc::new(AtomicUsize::new(0)),
+            interner: Arc::default(),
+            pending: Arc::new(DashMap::new()),
+            sized: Arc::new(DashSet::new()),
+            headless: Arc::new(DashSet::new()),
             config: config,
         }
     }
@@ -98,277 +495,6529 @@ impl FileStore {
         &self.index
     }
 
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Look up, hash and index or report a scanned file
+    ///
+    ///   A file that changes while it is hashed, or between the scan and
+    ///   the hash, is tried once more as it is then.  If it changes again
+    ///   it is left out with an Unstable error, unless --include-unstable.
     pub async fn add_file(&self, path: &PathBuf, metadata: &Metadata) -> Result<()> {
-        let entry = Entry::new_from_path_meta(path, metadata)?;
+        match self.add_scanned(path, metadata).await {
+            Err(e) if e.downcast_ref::<Unstable>().is_some() => {
+                let metadata = async_std::fs::metadata(path).await?;
+                self.add_scanned(path, &metadata).await
+            }
+            added => added,
+        }
+    }
+
+    async fn add_scanned(&self, path: &PathBuf, metadata: &Metadata) -> Result<()> {
+        if !metadata.is_file() {
+            return Err(Special.into());
+        }
+        let scanned = Entry::new_from_path_meta(path, metadata)?;
+
+        if self.config.missing_by_path {
+            // only the path matters, see report_missing_by_path
+            self.seen_paths.insert(normalize_path(&scanned.name));
+            return Ok(());
+        }
+
+        // a private archive keeps no mtime to tell an unchanged file by,
+        // so its files are always hashed
+        let (mut entry, fast_path) = match self.private_key() {
+            Some(key) => (scanned.private(key), false),
+            None => (scanned.clone(), true),
+        };
+        if self.assumed_unchanged(&scanned, metadata) {
+            if self.config.prune {
+                // unchanged, so it is archived as it was scanned
+                self.present.insert(entry.identity_digest());
+            }
+            return Ok(());
+        }
+
+        let stored = self.stores().filter(|_| fast_path).find_map(|store| {
+            store
+                .index
+                .get(&entry)
+                .map(|item| (item.key().clone(), *item.value()))
+        });
+        let stored = match stored {
+            // with --strict-metadata a mode or owner change means re-hashing
+            Some((old, _)) if self.config.strict_metadata && !old.same_metadata(&entry) => None,
+            stored => stored,
+        };
 
-        if self.index.contains_key(&entry) {
+        if let Some((old, hash)) = stored {
             // Yay, already present!
             // if we are checking, we need to see if there are at least 2 entries
-            if self.config.present || self.config.missing {
-                let hash = self.index.get(&entry).unwrap();
-                let files = self.hindex.get(&hash).unwrap();
-                if files.len() >= 2 {
+            if (self.config.present || self.config.missing) && self.groups_len(scanned.len) {
+                let members = self.members(hash);
+                let confirmed = self
+                    .confirm_matches(path, &scanned, (hash, old.digest), members)
+                    .await;
+                let members = confirmed.as_ref().map_or(members, confirmed_members);
+                if members >= 2 {
                     if self.config.present {
-                        if self.config.verbose > 1 {
-                            println!("{} is present in archive", entry.name);
-                        } else {
-                            println!("{}", entry.name);
-                        }
-                    } else if self.config.duplicate {
-                        let names: Vec<String> = files.iter().map(|f| f.name.clone()).collect();
-                        if self.config.verbose > 1 {
-                            println!("Archive files matching: {}", names.join(", "));
-                        } else {
-                            println!("{}", names.join("\n"));
-                        }
+                        self.print_present(&scanned, hash);
+                    } else if self.config.duplicate && self.streams_group(hash) {
+                        let matching = confirmed.unwrap_or_else(|| self.matching(hash));
+                        self.print_matching(hash, matching)?;
                     }
                 }
-                if files.len() < 2 && self.config.missing {
-                    if self.config.verbose > 1 {
-                        println!("{} is not present in archive", entry.name);
-                    } else {
-                        println!("{}", entry.name);
-                    }
+                if members < 2 && self.config.missing {
+                    self.print_missing(&scanned);
                 }
             }
+            if self.uses_cache() {
+                self.remember_hash(metadata, &entry, (hash, old.digest));
+            }
+            if self.config.injest && (!old.same_metadata(&entry) || old.inode != entry.inode) {
+                // refresh mode, owner and inode without re-hashing, the
+                // inode missing from entries archived before it was kept
+                let entry = Entry {
+                    digest: old.digest,
+                    ..entry.clone()
+                };
+                self.replace_entry(entry, hash);
+            }
             if self.config.prune {
                 // if pruning we need to remember we have seen it
-                self.present.insert(Arc::new(entry));
+                self.present.insert(entry.identity_digest());
             }
         } else {
             // Not present, calculate hash
-            let hash = if entry.is_file {
-                let vec = hash_file(path, entry.len).await?;
-                vec.iter().fold(entry.len, |acc, x| acc ^ x)
-            } else if entry.is_dir {
-                0
+            let hash = {
+                use std::os::unix::fs::MetadataExt;
+                self.tiered_hash(path, &mut entry, Some(metadata.dev()))
+                    .await?
+            };
+            let hash = match hash {
+                Some(hash) => hash,
+                None => {
+                    if self.config.prune {
+                        // if pruning we need to remember we have seen it
+                        self.present.insert(entry.identity_digest());
+                    }
+                    return Ok(());
+                }
+            };
+
+            // an empty file is missing if no archive kept one before it
+            if self.config.missing && !self.groups_len(scanned.len) && !self.archives_empty() {
+                self.print_missing(&scanned);
+                self.note_finding("missing", &scanned, hash)?;
+            }
+
+            // when injesting, join the group first: the group size comes
+            // back from under its lock, so of many copies of new content
+            // racing in exactly one sees the group reach two members
+            let members = if self.config.injest {
+                if self.config.prune {
+                    // if pruning we need to remember we have seen it
+                    self.present.insert(entry.identity_digest());
+                }
+                self.replace_entry(entry.clone(), hash)
             } else {
-                0
+                self.members(hash) + 1
             };
 
-            // if we are checking, we need to see if it is already in the hash
-            if self.config.present || self.config.missing || self.config.duplicate {
-                let is_present = self.hindex.contains_key(&hash);
+            // if we are checking, we need to see if it is already in the
+            // hash; an injest leaves duplicates to the report
+            let checking = self.config.present
+                || self.config.missing
+                || (self.config.duplicate && !self.config.injest);
+            if checking && self.groups_len(scanned.len) {
+                let confirmed = self
+                    .confirm_matches(path, &scanned, (hash, entry.digest), members)
+                    .await;
+                let members = confirmed.as_ref().map_or(members, confirmed_members);
+                let is_present = members > 1;
                 if is_present {
                     if self.config.present {
-                        if self.config.verbose > 1 {
-                            println!("{} is present in archive", entry.name);
-                        } else {
-                            println!("{}", entry.name);
-                        }
-                    } else if self.config.duplicate {
-                        let files = self.hindex.get(&hash).unwrap();
-                        let names: Vec<String> = files.iter().map(|f| f.name.clone()).collect();
-                        if self.config.verbose > 1 {
-                            println!("Archive files matching: {}", names.join(", "));
-                        } else {
-                            println!("{}", names.join("\n"));
-                        }
+                        self.print_present(&scanned, hash);
+                        self.note_finding("present", &scanned, hash)?;
+                    } else if self.config.duplicate && self.streams_group(hash) {
+                        let matching = confirmed.unwrap_or_else(|| self.matching(hash));
+                        self.print_matching(hash, matching)?;
+                        self.note_finding("duplicate_found", &scanned, hash)?;
                     }
                 }
                 if !is_present && self.config.missing {
-                    if self.config.verbose > 1 {
-                        println!("{} is not present in archive", entry.name);
-                    } else {
-                        println!("{}", entry.name);
-                    }
+                    self.print_missing(&scanned);
+                    self.note_finding("missing", &scanned, hash)?;
                 }
             }
-
-            if self.config.injest {
-                if self.config.prune {
-                    // if pruning we need to remember we have seen it
-                    self.present.insert(Arc::new(entry.clone()));
-                }
-                self.index.insert(Arc::new(entry), hash);
-            }
         }
         Ok(())
     }
 
-    pub async fn write(&self) -> Result<()> {
-        let mut record = self.record.clone();
-        record.backup().await?;
-        for item in self.index.iter() {
-            record.write_item(&(item.key().clone(), *item.value()))?;
-        }
-        record.finish().await?;
-        Ok(())
+    /// True if files of `len` bytes join hash groups, and are reported
+    /// as duplicates, present or missing
+    ///
+    ///   Every empty file has the same contents, so they all hash into
+    ///   one group that says nothing, left out unless --include-empty.
+    ///   They are still kept in the index, for --list, prune and
+    ///   --missing, see archives_empty.
+    fn groups_len(&self, len: u64) -> bool {
+        len > 0 || self.config.include_empty
     }
 
-    pub async fn read(&self) -> Result<()> {
-        let mut record = self.record.clone();
-        loop {
-            match record.read_item() {
-                Ok(Some((i0, i1))) => {
-                    //println!("got {}, {} chunks", i0.name, i1.len());
-                    self.index.insert(i0.clone(), i1);
-                    let newval = if self.hindex.contains_key(&i1) {
-                        let (_key, mut vec) = self.hindex.remove(&i1).unwrap();
-                        vec.push(i0);
-                        vec
-                    } else {
-                        vec![i0]
-                    };
-                    self.hindex.insert(i1, newval);
-                }
-                Ok(None) => {
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("read file::Entry:{}", e);
-                    break;
-                }
-            }
-        }
-        Ok(())
+    /// True if an archive kept an empty file when first asked
+    ///
+    ///   Empty files are in no group unless --include-empty, so this
+    ///   is what tells --missing whether one is archived.
+    fn archives_empty(&self) -> bool {
+        *self.archived_empty.get_or_init(|| {
+            self.stores().any(|store| {
+                store
+                    .index
+                    .iter()
+                    .any(|item| item.key().is_file && item.key().len == 0)
+            })
+        })
     }
 
-    pub async fn prune(&self) -> Result<()> {
-        if self.present.len() > 0 {
-            if self.config.verbose > 0 {
-                eprintln!("pruning files not injested");
-            }
-            let mut to_remove = Vec::new();
-            for item in self.index.iter() {
-                let entry = item.key();
-                if !self.present.contains(entry) {
-                    to_remove.push(entry.clone());
-                    if self.config.verbose > 1 {
-                        eprintln!("pruning {}", entry.name);
-                    } else {
-                        println!("{}", entry.name);
-                    }
-                }
-            }
-            for item in to_remove {
-                self.index.remove(&item);
-            }
-        } else {
-            eprintln!("Nothing found, will not prune entire archive!");
+    /// True if a group should be printed now that a scanned file
+    /// matches it, which is only the first time
+    ///
+    ///   The group printed is the archived files, the same for each
+    ///   scanned copy of them, so it is printed once whatever the format.
+    fn streams_group(&self, hash: FileHash) -> bool {
+        self.printed_groups.insert(hash)
+    }
+
+    /// This store followed by the further archives given with -a
+    fn stores(&self) -> impl Iterator<Item = &FileStore> {
+        std::iter::once(self).chain(self.others.iter())
+    }
+
+    /// True if hashes are looked up and kept by device and inode
+    ///
+    ///   Private archives keep as little about files as they can, and a
+    ///   single file archive holds one record type, so neither has one.
+    fn uses_cache(&self) -> bool {
+        self.config.hash_cache && !self.is_private() && !crate::pack::is_packed(&self.archive)
+    }
+
+    /// The cached hash of a file, if it is unchanged since cached
+    fn cached_hash(&self, metadata: &Metadata, entry: &Entry) -> Option<Hashed> {
+        use std::os::unix::fs::MetadataExt;
+
+        let cached = self.cache.get(&(metadata.dev(), metadata.ino()))?;
+        // with --digest, read again what was cached without one
+        if self.config.whole_digest && cached.digest.is_none() {
+            return None;
+        }
+        match (cached.len, cached.mod_secs, cached.mod_nanos)
+            == (entry.len, entry.mod_secs, entry.mod_nanos)
+        {
+            true => Some((cached.hash, cached.digest)),
+            false => None,
         }
-        Ok(())
     }
 
-    pub async fn report(&self) -> Result<()> {
-        let mut ndup_big = 0;
-        let mut ndup = 0;
-        let mut total_size = 0;
-        if self.config.list {
-            for item in self.index.iter() {
-                let entry = item.key();
-                if self.config.verbose > 1 {
-                    let mtime =
-                        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(entry.mod_secs));
-                    println!("{:9} {:?} {}", entry.len, mtime, entry.name);
-                } else {
-                    println!("{}", entry.name);
-                }
-            }
+    /// Cache a file's full hash by device and inode, replacing what was
+    /// cached for the inode before
+    fn remember_hash(&self, metadata: &Metadata, entry: &Entry, (hash, digest): Hashed) {
+        use std::os::unix::fs::MetadataExt;
+
+        if hash.is_partial() {
+            return;
         }
+        self.cache.insert(
+            (metadata.dev(), metadata.ino()),
+            CachedHash {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+                len: entry.len,
+                mod_secs: entry.mod_secs,
+                mod_nanos: entry.mod_nanos,
+                hash,
+                digest,
+            },
+        );
+    }
 
-        if self.config.duplicate || self.config.report {
-            for item in self.hindex.iter() {
-                let files = item.value();
-                if files.len() > 1 {
-                    if self.config.duplicate && self.config.injest {
-                        // if we are not checking and are reporting duplicates
-                        // do so here
-                        let names: Vec<String> = files.iter().map(|f| f.name.clone()).collect();
-                        if self.config.verbose > 1 {
-                            println!("Archive duplicates: {}", names.join(", "));
-                        } else {
-                            println!("{}", names.join("\n"));
-                        }
-                    }
-                    ndup += 1;
-                    total_size += files[0].len * (files.len() - 1) as u64;
-                    if files[0].len > 1000000 {
-                        ndup_big += 1;
-                    }
-                }
+    /// Load the archive's `cache` sets, see CachedHash
+    ///
+    ///   The cache only saves reading, so one that can't be read is
+    ///   reported and started afresh rather than stopping the run.
+    async fn read_cache(&self) {
+        let mut record: Record<CachedHash> = Record::new(
+            &self.archive,
+            "cache".to_string(),
+            ARCHIVE_SIZE,
+            RECORD_SIZE,
+        );
+        let read = async {
+            record.check_sets(false, self.config.verbose).await?;
+            while let Some(cached) = record.read_item()? {
+                self.cache.insert((cached.dev, cached.ino), cached);
             }
-
-            println!(
-                "{} dup, {} dup big, {} total Gbytes dup",
-                ndup,
-                ndup_big,
-                total_size / (1000 * 1000 * 1000)
-            );
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        };
+        if let Err(e) = read.await {
+            eprintln!("hash cache: {}, starting it afresh", e);
+            self.cache.clear();
         }
-        return Ok(());
     }
 
-    pub fn find_dups_second_archive(&self, second: &FileStore) -> Result<()> {
-        for item in second.index.iter() {
-            let entry = item.key();
-            let present = self.hindex.contains_key(&item.value());
+    /// Write the cache of the files whose hashes the archive holds
+    ///
+    ///   Entries for hashes no longer archived are dropped, as are those
+    ///   replaced when a file changed, so the cache never outgrows the
+    ///   archive.
+    async fn write_cache(&self) -> Result<()> {
+        let mut record: Record<CachedHash> = Record::new(
+            &self.archive,
+            "cache".to_string(),
+            ARCHIVE_SIZE,
+            RECORD_SIZE,
+        );
+        record.backup().await?;
+        for cached in self.cache.iter() {
+            if self
+                .hindex
+                .get(&cached.hash)
+                .is_some_and(|files| !files.is_empty())
             {
-                if self.config.missing && !present {
-                    if self.config.verbose > 1 {
-                        println!("{} is present not in archive", entry.name);
-                    } else {
-                        println!("{}", entry.name);
-                    }
-                }
-                if self.config.present && present && entry.len > 0 {
-                    if self.config.verbose > 1 {
-                        let files = self.hindex.get(&item.value()).unwrap();
-                        let names: Vec<String> = files.iter().map(|f| f.name.clone()).collect();
-                        println!(
-                            "{} is present in archive at {}",
-                            entry.name,
-                            names.join(", ")
-                        );
-                    } else {
-                        println!("{}", entry.name);
-                    }
-                }
+                record.write_item(cached.value())?;
             }
         }
-        Ok(())
+        record.finish().await
     }
-}
 
-async fn hash_file(path: &PathBuf, len: u64) -> Result<Vec<ChunkHash>> {
-    let mut ret: Vec<ChunkHash> = Vec::new();
-    let mut f = File::open(path).await?;
-    let mut pos = 0;
-    // first we store full CHUNK_SIZE chunks until only partial one left
-    while pos + CHUNK_SIZE < len as usize {
-        let mut buf = vec![0; CHUNK_SIZE];
-        f.read_exact(&mut buf).await?;
-        ret.push(seahash::hash(&buf));
-        pos += CHUNK_SIZE;
+    /// True if the archive is private, see --private-archive
+    pub fn is_private(&self) -> bool {
+        self.private.load(Ordering::Relaxed)
     }
 
-    let mut buf = Vec::new();
-    f.read_to_end(&mut buf).await?;
-    ret.push(seahash::hash(&buf));
-    Ok(ret)
-}
+    /// The --private-archive key, if this store is the one written
+    fn private_key(&self) -> Option<&PrivateKey> {
+        match &self.config.private_key {
+            Some(key) if self.archive == self.config.archive() => Some(key),
+            _ => None,
+        }
+    }
 
-impl ItemReadWrite for Record<FileTuple> {
-    type T = FileTuple;
-    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
-        let loc = self.push(minicbor::to_vec(&item.0.as_ref())?)?;
-        self.push(minicbor::to_vec(&item.1)?)?;
-        Ok(loc)
+    /// Number of archived files with this hash in all archives
+    ///
+    ///   A hash listed by --hashes-from counts as one more.
+    fn members(&self, hash: FileHash) -> usize {
+        let known = self.config.known_hashes.contains(&hash) as usize;
+        self.stores()
+            .map(|store| store.hindex.get(&hash).map_or(0, |files| files.len()))
+            .sum::<usize>()
+            + known
     }
-    fn read_item(&mut self) -> Result<Option<Self::T>> {
-        if let Some(v0) = &self.pull()? {
-            let i0 = minicbor::decode(v0)?;
-            if let Some(v1) = &self.pull()? {
-                let i1 = minicbor::decode(v1)?;
-                Ok(Some((Arc::new(i0), i1)))
+
+    /// The archives, and --hashes-from file, that hold this hash
+    fn holders(&self, hash: FileHash) -> Vec<&str> {
+        let mut holders: Vec<&str> = self
+            .stores()
+            .filter(|store| store.hindex.contains_key(&hash))
+            .map(|store| store.archive.as_str())
+            .collect();
+        if let Some(path) = &self.config.hashes_from {
+            if self.config.known_hashes.contains(&hash) {
+                holders.push(path);
+            }
+        }
+        holders
+    }
+
+    /// Report a scanned file as present, naming the archives with
+    /// multiple -a
+    fn print_present(&self, entry: &Entry, hash: FileHash) {
+        if self.config.format == Format::Json {
+            let archives = self
+                .holders(hash)
+                .into_iter()
+                .map(|holder| Json::Str(holder.to_string()))
+                .collect();
+            self.emit_json(
+                "present",
+                vec![
+                    ("path", Json::Str(entry.name.clone())),
+                    ("hash", Json::Str(group_id(hash, 16))),
+                    ("size", Json::Num(entry.len)),
+                    ("archives", Json::List(archives)),
+                ],
+            );
+        } else if self.config.verbose > 1 {
+            let withheld = self
+                .stores()
+                .any(|store| store.is_private() && store.hindex.contains_key(&hash));
+            if withheld {
+                self.emit_line(&format!(
+                    "{} is present in archive, match withheld",
+                    entry.name
+                ));
+            } else if self.others.is_empty() && self.config.hashes_from.is_none() {
+                self.emit_line(&format!("{} is present in archive", entry.name));
             } else {
-                Err(std::boxed::Box::new(Error::new(
-                    ErrorKind::Other,
-                    "Out of data half way through read_item?",
-                )))
+                let archives = self.holders(hash);
+                self.emit_line(&format!(
+                    "{} is present in archive {}",
+                    entry.name,
+                    archives.join(", ")
+                ));
             }
         } else {
-            Ok(None)
+            self.emit_line(&entry.name);
         }
     }
+
+    /// Append a result for a scanned file to the --findings file, if any
+    fn note_finding(&self, event: &str, entry: &Entry, hash: FileHash) -> Result<()> {
+        if let Some(findings) = &self.config.findings {
+            findings.event(
+                event,
+                vec![
+                    ("path", Json::Str(entry.name.clone())),
+                    ("hash", Json::Str(group_id(hash, 16))),
+                    ("size", Json::Num(entry.len)),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Report a scanned file as missing from the archive
+    fn print_missing(&self, entry: &Entry) {
+        if self.config.format == Format::Json {
+            self.emit_json(
+                "missing",
+                vec![
+                    ("path", Json::Str(entry.name.clone())),
+                    ("size", Json::Num(entry.len)),
+                ],
+            );
+        } else if self.config.verbose > 1 {
+            self.emit_line(&format!("{} is not present in archive", entry.name));
+        } else {
+            self.emit_line(&entry.name);
+        }
+    }
+
+    /// Write one plain result, ended by a NUL rather than a newline with
+    /// --print0 so any file name survives `xargs -0`
+    fn emit_line(&self, line: &str) {
+        let end = if self.config.print0 { '\0' } else { '\n' };
+        self.write_result(line, format!("{}{}", line, end));
+    }
+
+    /// Write one result to stdout, or hold it under `key` with --sort
+    fn write_result(&self, key: &str, text: String) {
+        use std::io::Write;
+
+        if let Some(sorted) = &self.config.sorted {
+            if sorted.hold(key, text.as_bytes()) {
+                return;
+            }
+        }
+        if let Err(e) = std::io::stdout().lock().write_all(text.as_bytes()) {
+            panic!("failed printing to stdout: {}", e);
+        }
+    }
+
+    /// True if results are being held for --sort
+    fn holding(&self) -> bool {
+        self.config.sorted.as_ref().is_some_and(|s| s.holding())
+    }
+
+    /// Write one result as a line of JSON, the only place --format json
+    /// output comes from
+    pub(crate) fn emit_json(&self, kind: &str, fields: Vec<(&str, Json)>) {
+        if let Some(events) = self.config.events.as_ref().filter(|e| e.on_stdout()) {
+            // the same results, named as --events ndjson has them
+            let event = match kind {
+                "group" => "duplicate_found",
+                "summary" => "report",
+                kind => kind,
+            };
+            events.emit(event, fields);
+            return;
+        }
+        let mut object = vec![("type", Json::Str(kind.to_string()))];
+        object.extend(fields);
+        let line = json_object(object);
+        self.write_result(&line, line.clone() + "\n");
+    }
+
+    /// Write one entry as a CSV row, the header going out before the first
+    ///
+    ///   `group` is empty for --list rows.  Fields are quoted per RFC 4180.
+    fn emit_csv(&self, group: &str, entry: &Entry, hash: FileHash, evidence: &str) {
+        self.config
+            .csv_header
+            .call_once(|| println!("group,name,len,perm,uid,gid,mod_secs,hash,evidence"));
+        let row = format!(
+            "{},{},{},{:o},{},{},{},{},{}",
+            csv_field(group),
+            csv_field(&self.shown_name(entry)),
+            entry.len,
+            entry.perm,
+            entry.uid,
+            entry.gid,
+            entry.mod_secs,
+            group_id(hash, 16),
+            evidence
+        );
+        self.write_result(&row, row.clone() + "\n");
+    }
+
+    /// The archived files with a hash, one group per archive
+    fn matching(&self, hash: FileHash) -> Vec<(&FileStore, Vec<Arc<Entry>>)> {
+        self.stores()
+            .filter_map(|store| Some((store, store.hindex.get(&hash)?.clone())))
+            .collect()
+    }
+
+    /// Print archived files matching a hash, as found by matching
+    fn print_matching(
+        &self,
+        hash: FileHash,
+        matching: Vec<(&FileStore, Vec<Arc<Entry>>)>,
+    ) -> Result<()> {
+        for (store, mut files) in matching {
+            if self.holding() {
+                files.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+            store.print_group(
+                "Archive files matching",
+                hash,
+                &group_id(hash, 0),
+                &files,
+                &[],
+                None,
+                &[],
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// With --paranoid or --match full, the archived files matching a
+    /// scanned file's hash whose bytes are really the same as its
+    ///
+    ///   None when neither is given, when there is nothing to compare
+    ///   with, or for a private archive, which has no paths to open.
+    ///   --match full keeps the files whose whole file digest is the
+    ///   scanned file's, so none without one.  --paranoid leaves out
+    ///   archived files that are gone or have changed since they were
+    ///   indexed, with a warning.  A --hashes-from hash has no file to
+    ///   compare with, so it confirms nothing.
+    async fn confirm_matches(
+        &self,
+        path: &PathBuf,
+        scanned: &Entry,
+        (hash, digest): Hashed,
+        members: usize,
+    ) -> Option<Vec<(&FileStore, Vec<Arc<Entry>>)>> {
+        if !(self.config.paranoid || self.config.match_full) || members < 2 || self.is_private() {
+            return None;
+        }
+        let mut confirmed = Vec::new();
+        for (store, files) in self.matching(hash) {
+            let mut same = Vec::new();
+            for file in files.into_iter().filter(|f| f.name != scanned.name) {
+                if self.config.match_full && (digest.is_none() || file.digest != digest) {
+                    continue;
+                }
+                if !self.config.paranoid
+                    || (self.unchanged_since_indexed(&file).await
+                        && self.compare_paranoid(path, &file).await)
+                {
+                    same.push(file);
+                }
+            }
+            if !same.is_empty() {
+                confirmed.push((store, same));
+            }
+        }
+        Some(confirmed)
+    }
+
+    /// True if an archived file is still there with its indexed size,
+    /// warning about it otherwise, see --paranoid
+    async fn unchanged_since_indexed(&self, file: &Entry) -> bool {
+        match async_std::fs::metadata(&file.name).await {
+            Ok(metadata) if metadata.len() == file.len => true,
+            Ok(metadata) => {
+                eprintln!(
+                    "paranoid: {} is {} bytes, indexed as {}, left out",
+                    file.name,
+                    metadata.len(),
+                    file.len
+                );
+                false
+            }
+            Err(e) => {
+                eprintln!("paranoid: {} ({}), left out", e, file.name);
+                false
+            }
+        }
+    }
+
+    /// Byte-compare a file with an archived one, false on any error
+    async fn compare_paranoid(&self, path: &PathBuf, file: &Entry) -> bool {
+        match same_content(path, &PathBuf::from(&file.name)).await {
+            Ok(same) => same,
+            Err(e) => {
+                eprintln!(
+                    "paranoid: {} vs {}: {}",
+                    path.to_string_lossy(),
+                    file.name,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Split a group into the sets of members with the same bytes
+    ///
+    ///   Each member is compared with the first member of every set
+    ///   found so far, stopping at the first mismatching chunk.  Members
+    ///   that vanished or changed size since they were indexed are left
+    ///   out, and sets of one are dropped as no longer duplicates.
+    ///   Returns the sets and the number of members left out.
+    async fn split_identical(&self, files: &[Arc<Entry>]) -> (Vec<Vec<Arc<Entry>>>, usize) {
+        let mut sets: Vec<Vec<Arc<Entry>>> = Vec::new();
+        let mut left_out = 0;
+        'files: for file in files {
+            if !self.unchanged_since_indexed(file).await {
+                left_out += 1;
+                continue;
+            }
+            let path = PathBuf::from(&file.name);
+            for set in sets.iter_mut() {
+                match same_content(&PathBuf::from(&set[0].name), &path).await {
+                    Ok(true) => {
+                        set.push(file.clone());
+                        continue 'files;
+                    }
+                    Ok(false) => (),
+                    Err(e) => {
+                        eprintln!(
+                            "paranoid: {} vs {}: {}, left out",
+                            set[0].name, file.name, e
+                        );
+                        left_out += 1;
+                        continue 'files;
+                    }
+                }
+            }
+            sets.push(vec![file.clone()]);
+        }
+        sets.retain(|set| set.len() > 1);
+        (sets, left_out)
+    }
+
+    /// Split a group into the sets of members with the same digest by
+    /// `algorithm`, read afresh, see --verify-groups
+    ///
+    ///   Members gone, changed in size since they were indexed or
+    ///   unreadable are left out.  Returns the sets, in the order their
+    ///   first members come, sets of one included, with each member
+    ///   left out and why.
+    async fn split_by_digest(
+        &self,
+        files: &[Arc<Entry>],
+        algorithm: HashAlgorithm,
+    ) -> (Vec<Vec<Arc<Entry>>>, Vec<(String, String)>) {
+        let mut sets: Vec<(FileHash, Vec<Arc<Entry>>)> = Vec::new();
+        let mut left_out = Vec::new();
+        for file in files {
+            match async_std::fs::metadata(&file.name).await {
+                Ok(metadata) if metadata.len() == file.len => (),
+                Ok(metadata) => {
+                    let why = format!("is {} bytes, indexed as {}", metadata.len(), file.len);
+                    left_out.push((file.name.clone(), why));
+                    continue;
+                }
+                Err(e) => {
+                    left_out.push((file.name.clone(), e.to_string()));
+                    continue;
+                }
+            }
+            let (path, len): (std::path::PathBuf, _) = (file.name.clone().into(), file.len);
+            let (chunking, stats) = (
+                Chunking::default(),
+                Stats::throttled(self.config.bwlimit.clone()),
+            );
+            let digest = self.config.hash_pool.run(None, move || {
+                hash_file(&path, len, algorithm, chunking, 1, false, None, &stats)
+            });
+            let digest = match digest.await {
+                Ok(Some(Ok((digest, _)))) => digest,
+                Ok(Some(Err(e))) | Err(e) => {
+                    left_out.push((file.name.clone(), e.to_string()));
+                    continue;
+                }
+                Ok(None) => unreachable!("no time limit"),
+            };
+            match sets.iter_mut().find(|(d, _)| *d == digest) {
+                Some((_, set)) => set.push(file.clone()),
+                None => sets.push((digest, vec![file.clone()])),
+            }
+        }
+        (sets.into_iter().map(|(_, set)| set).collect(), left_out)
+    }
+
+    /// Byte-compare the members of --hash-audit randomly picked groups
+    ///
+    ///   Copies made together, with the same mtime in one directory,
+    ///   tell little about the hash, so groups differing in either are
+    ///   weighted up by AUDIT_WEIGHT each.  Members changed since they
+    ///   were indexed are skipped, as are partial hash groups and empty
+    ///   files.  A member whose bytes differ from the group's first is
+    ///   only a collision if both still hash to the group's hash when
+    ///   read again.  The counts, and any collision, are logged to the
+    ///   run's events and findings and appended to file.audit.
+    pub async fn hash_audit(&self) -> Result<HashAudit> {
+        let started = std::time::Instant::now();
+        let mut audit = HashAudit::default();
+        if self.is_private() {
+            eprintln!("hash-audit: a private archive keeps no paths to compare");
+            return Ok(audit);
+        }
+        let groups: Vec<_> = self
+            .snapshot_groups()
+            .into_iter()
+            .filter(|(hash, files)| !hash.is_partial() && files[0].len > 0)
+            .collect();
+        let weight = |files: &[Arc<Entry>]| {
+            let mtime = |f: &Entry| (f.mod_secs, f.mod_nanos);
+            let dir = |f: &Entry| std::path::Path::new(&f.name).parent().map(|p| p.to_owned());
+            let mtimes = files.iter().any(|f| mtime(f) != mtime(&files[0]));
+            let dirs = files.iter().any(|f| dir(f) != dir(&files[0]));
+            AUDIT_WEIGHT.powi(mtimes as i32 + dirs as i32)
+        };
+        let picked: Vec<_> = {
+            use rand::seq::SliceRandom;
+
+            groups
+                .choose_multiple_weighted(
+                    &mut rand::thread_rng(),
+                    self.config.hash_audit,
+                    |(_, files)| weight(files),
+                )
+                .map_err(|e| format!("hash-audit: {}", e))?
+                .collect()
+        };
+        let mut found = Vec::new();
+        for (hash, files) in picked {
+            audit.groups += 1;
+            let mut first: Option<&Arc<Entry>> = None;
+            for file in files {
+                if !same_as_indexed(file).await {
+                    audit.skipped += 1;
+                    continue;
+                }
+                let first = match first {
+                    Some(first) => first,
+                    None => {
+                        first = Some(file);
+                        continue;
+                    }
+                };
+                let (a, b) = (PathBuf::from(&first.name), PathBuf::from(&file.name));
+                match same_content(&a, &b).await {
+                    Ok(true) => {
+                        audit.compared += 1;
+                        audit.bytes += file.len;
+                    }
+                    // changed in place with its mtime kept, not the hash's fault
+                    Ok(false) if !self.still_hash_to(*hash, [first, file]).await => {
+                        audit.skipped += 1;
+                    }
+                    Ok(false) => {
+                        audit.compared += 1;
+                        audit.bytes += file.len;
+                        found.push((*hash, first.name.clone(), file.name.clone()));
+                    }
+                    Err(e) => {
+                        eprintln!("hash-audit: {} vs {}: {}", first.name, file.name, e);
+                        audit.skipped += 1;
+                    }
+                }
+            }
+        }
+        audit.collisions = found.len();
+        audit.seconds = started.elapsed().as_secs_f64();
+        for (hash, a, b) in &found {
+            eprintln!(
+                "HASH COLLISION: {} and {} differ but both hash to {} with {}",
+                a,
+                b,
+                group_id(*hash, 16),
+                self.hash_algorithm()
+            );
+            let fields = || {
+                vec![
+                    ("hash", Json::Str(group_id(*hash, 16))),
+                    ("algorithm", Json::Str(self.hash_algorithm().to_string())),
+                    ("a", Json::Str(a.clone())),
+                    ("b", Json::Str(b.clone())),
+                ]
+            };
+            if self.config.format == Format::Json {
+                self.emit_json("hash_collision", fields());
+            }
+            if let Some(events) = self.config.events.as_ref().filter(|e| !e.on_stdout()) {
+                events.emit("hash_collision", fields());
+            }
+            if let Some(findings) = &self.config.findings {
+                findings.event("hash_collision", fields())?;
+            }
+        }
+        let fields = || {
+            vec![
+                ("algorithm", Json::Str(self.hash_algorithm().to_string())),
+                ("groups", Json::Num(audit.groups as u64)),
+                ("compared", Json::Num(audit.compared as u64)),
+                ("bytes", Json::Num(audit.bytes)),
+                ("skipped", Json::Num(audit.skipped as u64)),
+                ("collisions", Json::Num(audit.collisions as u64)),
+                ("seconds", Json::Float(audit.seconds)),
+            ]
+        };
+        if self.config.format == Format::Json {
+            self.emit_json("hash_audit", fields());
+        }
+        if let Some(events) = self.config.events.as_ref().filter(|e| !e.on_stdout()) {
+            events.emit("hash_audit", fields());
+        }
+        if let Some(findings) = &self.config.findings {
+            findings.event("hash_audit", fields())?;
+        }
+        self.log_audit(&audit, &found)?;
+        Ok(audit)
+    }
+
+    /// True if every file still hashes to `hash`, read afresh
+    async fn still_hash_to(&self, hash: FileHash, files: [&Entry; 2]) -> bool {
+        for file in files {
+            let (path, len, algorithm): (std::path::PathBuf, _, _) =
+                (file.name.clone().into(), file.len, self.hash_algorithm());
+            let (chunking, split) = (self.chunking(), self.split_for(len));
+            // a stats of its own, so the run's hashing counts stay the scan's
+            let stats = Stats::throttled(self.config.bwlimit.clone());
+            let rehash = self.config.hash_pool.run(None, move || {
+                hash_file(&path, len, algorithm, chunking, split, false, None, &stats)
+            });
+            match rehash.await {
+                Ok(Some(Ok((rehashed, _)))) if rehashed == hash => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Append a --hash-audit run to file.audit, and print the totals
+    ///
+    ///   Each run adds an `audit` line, after a `collision` line for
+    ///   each collision found, so the file is a record of how far the
+    ///   archive's hash has been checked over time.
+    fn log_audit(&self, audit: &HashAudit, found: &[(FileHash, String, String)]) -> Result<()> {
+        use std::io::Write;
+
+        let path = match crate::pack::is_packed(&self.archive) {
+            true => std::path::PathBuf::from(format!("{}.audit", self.archive)),
+            false => std::path::Path::new(&self.archive).join("file.audit"),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let algorithm = self.hash_algorithm();
+        let mut text = String::new();
+        if !path.exists() {
+            text += "# audit secs algorithm groups compared bytes skipped collisions seconds\n";
+            text += "# collision secs algorithm hash path path\n";
+        }
+        for (hash, a, b) in found {
+            text += &format!(
+                "collision {} {} {} {:?} {:?}\n",
+                now,
+                algorithm,
+                group_id(*hash, 16),
+                a,
+                b
+            );
+        }
+        text += &format!(
+            "audit {} {} {} {} {} {} {} {:.3}\n",
+            now,
+            algorithm,
+            audit.groups,
+            audit.compared,
+            audit.bytes,
+            audit.skipped,
+            audit.collisions,
+            audit.seconds
+        );
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("hash-audit: {}: {}", path.display(), e))?;
+        log.write_all(text.as_bytes())?;
+
+        // totals over every run with this algorithm, this one included
+        let (mut runs, mut groups, mut compared, mut collisions) = (0, 0, 0, 0);
+        let mut since = now;
+        for line in std::fs::read_to_string(&path)?.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields[..] {
+                ["audit", secs, named, g, c, _, _, n, _] if named == algorithm.to_string() => {
+                    runs += 1;
+                    since = since.min(secs.parse().unwrap_or(now));
+                    groups += g.parse::<u64>().unwrap_or(0);
+                    compared += c.parse::<u64>().unwrap_or(0);
+                    collisions += n.parse::<u64>().unwrap_or(0);
+                }
+                _ => (),
+            }
+        }
+        status!(
+            "hash-audit: {} groups, {} members compared ({}), {} skipped, {} collisions in {:.3} seconds",
+            audit.groups,
+            audit.compared,
+            format_size(audit.bytes),
+            audit.skipped,
+            audit.collisions,
+            audit.seconds
+        );
+        status!(
+            "hash-audit: {} over {} runs in {} days: {} groups, {} members compared, {} collisions",
+            algorithm,
+            runs,
+            (now - since) / 86400,
+            groups,
+            compared,
+            collisions
+        );
+        Ok(())
+    }
+
+    /// Refuse to compare hashes with an archive hashed otherwise
+    ///
+    ///   An empty archive has nothing to compare, so it passes.
+    pub fn comparable(&self, other: &FileStore) -> Result<()> {
+        if other.index.is_empty() {
+            return Ok(());
+        }
+        if other.hash_algorithm() != self.hash_algorithm() {
+            return Err(format!(
+                "{} was hashed with {} but {} with {}, they can't be checked together",
+                other.archive,
+                other.hash_algorithm(),
+                self.archive,
+                self.hash_algorithm()
+            )
+            .into());
+        }
+        if other.chunking() != self.chunking() {
+            return Err(format!(
+                "{} was chunked {} but {} {}, they can't be checked together",
+                other.archive,
+                other.chunking(),
+                self.archive,
+                self.chunking()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Load the archive and any further ones, all at once
+    ///
+    ///   Hashes are only compared within one algorithm and chunking, so
+    ///   the further archives must have been built with the same ones.
+    pub async fn read_all(&self) -> Result<()> {
+        futures::future::try_join_all(self.stores().map(|store| store.read())).await?;
+        for other in self.others.iter() {
+            self.comparable(other)?;
+        }
+        if self.config.verbose > 0 {
+            let (distinct, reused) = self.interner.counts();
+            eprintln!(
+                "loaded {} distinct entries, {} more shared an earlier allocation",
+                distinct, reused
+            );
+        }
+        Ok(())
+    }
+
+    /// The algorithm files are hashed with, the archive's once read
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        *self.algorithm.read().unwrap()
+    }
+
+    /// Where files are cut into chunks to hash, the archive's once read
+    pub fn chunking(&self) -> Chunking {
+        *self.chunking.read().unwrap()
+    }
+
+    /// How many ranges to hash a file of `len` bytes in at once
+    ///
+    ///   Ranges get no whole file digest, so --digest reads whole, nor
+    ///   checkpoints, so --checkpoint-hashing does too.
+    fn split_for(&self, len: u64) -> usize {
+        let whole = self.config.whole_digest || self.config.checkpoint_hashing;
+        match len >= self.config.big_file_size && !whole {
+            true => self.config.big_file_threads,
+            false => 1,
+        }
+    }
+
+    /// What a checkpoint of hashing `entry` is kept under, if it is big
+    /// enough to leave one, see --checkpoint-hashing
+    ///
+    ///   A resumed file has no whole file digest, so with --digest
+    ///   files are read whole.
+    fn checkpoint_key(&self, path: &PathBuf, entry: &Entry) -> Option<checkpoint::Key> {
+        let chunking = self.chunking();
+        let big = entry.len >= checkpoint::EVERY as u64 * chunking.chunk_size() as u64;
+        if !self.config.checkpoint_hashing || self.config.whole_digest || !big {
+            return None;
+        }
+        Some(checkpoint::Key {
+            path: path.to_string_lossy().into_owned(),
+            len: entry.len,
+            mod_secs: entry.mod_secs,
+            mod_nanos: entry.mod_nanos,
+            hashing: self.hashing(),
+        })
+    }
+
+    /// The hash algorithm and chunking, as the archive header has them
+    pub fn hashing(&self) -> String {
+        format!("{} {}", self.hash_algorithm(), self.chunking())
+    }
+
+    /// Consult these stores too when checking for presence
+    pub fn with_others(mut self, mut others: Vec<FileStore>) -> Self {
+        for other in others.iter_mut() {
+            other.interner = self.interner.clone();
+        }
+        self.others = Arc::new(others);
+        self
+    }
+
+    /// True if any archived file lives under `prefix`
+    pub fn archived_under(&self, prefix: &str) -> bool {
+        let prefix = std::path::Path::new(prefix);
+        self.index
+            .iter()
+            .any(|item| std::path::Path::new(&item.key().name).starts_with(prefix))
+    }
+
+    /// Print archived files with no file at their expected scanned path
+    ///
+    ///   Archived paths under each prefix of `path_roots` are rebased
+    ///   onto its scanned prefix, so a restored copy can be checked for
+    ///   holes even where the lost content is duplicated elsewhere.
+    ///   Returns the number of missing paths.
+    pub fn report_missing_by_path(&self) -> usize {
+        let mut missing = std::collections::BTreeSet::new();
+        for item in self.index.iter() {
+            let archived = &item.key().name;
+            for (from, to) in &self.config.path_roots {
+                if let Some(expected) = rebase_path(archived, from, to) {
+                    if !self.seen_paths.contains(&expected) {
+                        missing.insert((expected, self.shown_name(item.key())));
+                    }
+                    break;
+                }
+            }
+        }
+        for (expected, archived) in &missing {
+            if self.config.format == Format::Json {
+                self.emit_json(
+                    "missing",
+                    vec![
+                        ("path", Json::Str(expected.clone())),
+                        ("archived", Json::Str(archived.clone())),
+                    ],
+                );
+            } else if self.config.verbose > 1 {
+                self.emit_line(&format!(
+                    "{} is missing, archived as {}",
+                    expected, archived
+                ));
+            } else {
+                self.emit_line(expected);
+            }
+        }
+        missing.len()
+    }
+
+    /// Write a `<hash>  <path>` line per archived file, see --manifest
+    ///
+    ///   The layout is that of sha256sum and friends: the hash in hex,
+    ///   two spaces and the path, with a name holding a backslash or a
+    ///   line break escaped and its line started with a backslash.
+    ///   Directories, and files not hashed in full yet, are left out,
+    ///   as are those --media-mode hashed in part, whose hash no
+    ///   checksum tool would agree with.  Returns the number of lines.
+    pub fn write_checksum_manifest(&self, path: &str) -> Result<usize> {
+        use std::io::Write;
+
+        if self.is_private() {
+            return Err("a private archive has no paths for a manifest".into());
+        }
+        let mut lines: Vec<(String, String)> = self
+            .index
+            .iter()
+            .filter(|item| item.key().is_file && in_manifest(item.value()))
+            .map(|item| (self.shown_name(item.key()), item.value().hex()))
+            .collect();
+        lines.sort();
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for (name, hash) in &lines {
+            let escaped = escape_manifest_name(name);
+            let mark = if escaped.len() != name.len() {
+                "\\"
+            } else {
+                ""
+            };
+            writeln!(out, "{}{}  {}", mark, hash, escaped)?;
+        }
+        out.flush()?;
+        Ok(lines.len())
+    }
+
+    /// Print files whose hash in a --manifest file differs from the archive's
+    ///
+    ///   Files in the manifest that are no longer archived, or not
+    ///   hashed in full, are printed too.  Returns the number of lines
+    ///   read and of those printed.
+    pub fn check_checksum_manifest(&self, path: &str) -> Result<(usize, usize)> {
+        let archived: HashMap<String, String> = self
+            .index
+            .iter()
+            .filter(|item| item.key().is_file && in_manifest(item.value()))
+            .map(|item| (self.shown_name(item.key()), item.value().hex()))
+            .collect();
+        let (mut checked, mut bad) = (0, 0);
+        for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let parsed = line.split_once("  ").and_then(|(hex, name)| {
+                let hash = FileHash::from_hex(hex, false)?.hex();
+                Some((hash, name))
+            });
+            let (hash, name) = match parsed {
+                Some((hash, name)) if escaped => (hash, unescape_manifest_name(name)),
+                Some((hash, name)) => (hash, name.to_string()),
+                None => {
+                    return Err(format!(
+                        "{}:{}: expected <hash>  <path>, got {:?}",
+                        path,
+                        n + 1,
+                        line
+                    )
+                    .into())
+                }
+            };
+            checked += 1;
+            match archived.get(&name) {
+                Some(stored) if *stored == hash => (),
+                Some(stored) => {
+                    bad += 1;
+                    if self.config.format == Format::Json {
+                        self.emit_json(
+                            "changed",
+                            vec![
+                                ("path", Json::Str(name)),
+                                ("manifest", Json::Str(hash)),
+                                ("archive", Json::Str(stored.clone())),
+                            ],
+                        );
+                    } else if self.config.verbose > 0 {
+                        self.emit_line(&format!(
+                            "{} changed, manifest {}, archive {}",
+                            name, hash, stored
+                        ));
+                    } else {
+                        self.emit_line(&name);
+                    }
+                }
+                None => {
+                    bad += 1;
+                    if self.config.format == Format::Json {
+                        self.emit_json("unarchived", vec![("path", Json::Str(name))]);
+                    } else if self.config.verbose > 0 {
+                        self.emit_line(&format!("{} is not archived", name));
+                    } else {
+                        self.emit_line(&name);
+                    }
+                }
+            }
+        }
+        Ok((checked, bad))
+    }
+
+    /// Print which archived directories could be removed as a whole
+    ///
+    ///   A file blocks removing a directory when every copy of its
+    ///   content lies inside it, i.e. when the directory contains the
+    ///   deepest directory common to the file's hash group.  Each group
+    ///   is charged to that directory, so a directory is fully redundant
+    ///   when nothing in its subtree was charged.  The largest fully
+    ///   redundant subtrees are printed with a copy outside for each
+    ///   file, then the partially redundant ones held back by at most
+    ///   REMOVABLE_MAX_BLOCKERS files, each biggest first.
+    pub fn removable_dirs(&self) -> Result<()> {
+        use std::path::{Path, PathBuf};
+
+        if self.is_private() {
+            return Err("a private archive keeps no directories to plan over".into());
+        }
+        #[derive(Default)]
+        struct Tally {
+            files: usize,
+            bytes: u64,
+            blockers: usize,
+            blocked_bytes: u64,
+        }
+        let mut dirs: HashMap<PathBuf, Tally> = HashMap::new();
+        let mut charged: HashMap<PathBuf, Vec<Arc<Entry>>> = HashMap::new();
+        let mut charge = |files: Vec<Arc<Entry>>| {
+            let mut common: Option<PathBuf> = None;
+            for f in &files {
+                let parent = Path::new(&f.name).parent().unwrap_or_else(|| Path::new(""));
+                for dir in parent.ancestors() {
+                    let tally = dirs.entry(dir.to_path_buf()).or_default();
+                    tally.files += 1;
+                    tally.bytes += f.len;
+                }
+                common = Some(match common {
+                    None => parent.to_path_buf(),
+                    Some(common) => common
+                        .components()
+                        .zip(parent.components())
+                        .take_while(|(a, b)| a == b)
+                        .map(|(a, _)| a)
+                        .collect(),
+                });
+            }
+            if let Some(common) = common {
+                let bytes: u64 = files.iter().map(|f| f.len).sum();
+                for dir in common.ancestors() {
+                    let tally = dirs.entry(dir.to_path_buf()).or_default();
+                    tally.blockers += files.len();
+                    tally.blocked_bytes += bytes;
+                }
+                charged.entry(common).or_default().extend(files);
+            }
+        };
+        for item in self.hindex.iter() {
+            charge(item.value().iter().filter(|f| f.is_file).cloned().collect());
+        }
+        // files left waiting unhashed have nothing else like them
+        for item in self.index.iter().filter(|item| item.value().is_pending()) {
+            charge(vec![item.key().clone()]);
+        }
+
+        let parent_of = |dir: &Path| dir.parent().and_then(|p| dirs.get(p));
+        let mut full: Vec<(&PathBuf, &Tally)> = dirs
+            .iter()
+            .filter(|(dir, t)| {
+                // only the top of each fully redundant subtree
+                t.blockers == 0 && parent_of(dir).is_none_or(|p| p.blockers > 0)
+            })
+            .collect();
+        full.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+        let nearly = |t: &Tally| {
+            t.blockers > 0 && t.blockers <= REMOVABLE_MAX_BLOCKERS && t.blockers < t.files
+        };
+        let mut partial: Vec<(&PathBuf, &Tally)> = dirs
+            .iter()
+            .filter(|(dir, t)| nearly(t) && !parent_of(dir).is_some_and(nearly))
+            .collect();
+        let redundant = |t: &Tally| t.bytes - t.blocked_bytes;
+        partial.sort_by(|a, b| redundant(b.1).cmp(&redundant(a.1)).then(a.0.cmp(b.0)));
+
+        // the files of each removable directory, for the evidence
+        let mut members: HashMap<&PathBuf, Vec<(Arc<Entry>, FileHash)>> = HashMap::new();
+        for item in self.index.iter() {
+            let path = Path::new(&item.key().name);
+            if let Some((dir, _)) = full.iter().find(|(dir, _)| path.starts_with(dir)) {
+                members
+                    .entry(dir)
+                    .or_default()
+                    .push((item.key().clone(), *item.value()));
+            }
+        }
+        let limit = match self.config.group_limit {
+            0 => usize::MAX,
+            limit => limit,
+        };
+        // two copies of a tree are each redundant on their own, so the
+        // evidence may not point into a directory already planned
+        let mut planned: Vec<&PathBuf> = Vec::new();
+        for (dir, tally) in &full {
+            let mut files = members.remove(dir).unwrap_or_default();
+            files.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+            let evidence: Option<Vec<(Arc<Entry>, Arc<Entry>)>> = files
+                .iter()
+                .map(|(f, hash)| {
+                    let group = self.hindex.get(hash)?;
+                    let copy = group.iter().find(|g| {
+                        let path = Path::new(&g.name);
+                        !path.starts_with(dir) && !planned.iter().any(|p| path.starts_with(p))
+                    })?;
+                    Some((f.clone(), copy.clone()))
+                })
+                .collect();
+            let evidence = match evidence {
+                Some(evidence) => evidence,
+                None => {
+                    if self.config.verbose > 0 {
+                        eprintln!(
+                            "removable-dirs: {} only duplicates directories already planned",
+                            dir.display()
+                        );
+                    }
+                    continue;
+                }
+            };
+            planned.push(dir);
+            if self.config.format == Format::Json {
+                let evidence = evidence
+                    .iter()
+                    .take(limit)
+                    .map(|(f, copy)| {
+                        Json::Obj(vec![
+                            ("path", Json::Str(self.shown_name(f))),
+                            ("copy", Json::Str(self.shown_name(copy))),
+                        ])
+                    })
+                    .collect();
+                self.emit_json(
+                    "removable_dir",
+                    vec![
+                        ("path", Json::Str(dir.to_string_lossy().into_owned())),
+                        ("files", Json::Num(tally.files as u64)),
+                        ("bytes", Json::Num(tally.bytes)),
+                        ("evidence", Json::List(evidence)),
+                    ],
+                );
+                continue;
+            }
+            println!(
+                "removable {}: {} files, {}",
+                dir.display(),
+                tally.files,
+                format_gib(tally.bytes)
+            );
+            for (f, copy) in evidence.iter().take(limit) {
+                println!("  {} = {}", self.shown_name(f), self.shown_name(copy));
+            }
+            if evidence.len() > limit {
+                println!("  ... and {} more", evidence.len() - limit);
+            }
+        }
+        for (dir, tally) in &partial {
+            let blocking: Vec<&Arc<Entry>> = charged
+                .iter()
+                .filter(|(common, _)| common.starts_with(dir))
+                .flat_map(|(_, files)| files)
+                .collect();
+            let mut names: Vec<String> = blocking.iter().map(|f| self.shown_name(f)).collect();
+            names.sort();
+            if self.config.format == Format::Json {
+                self.emit_json(
+                    "partial_dir",
+                    vec![
+                        ("path", Json::Str(dir.to_string_lossy().into_owned())),
+                        ("files", Json::Num(tally.files as u64)),
+                        (
+                            "redundant_files",
+                            Json::Num((tally.files - tally.blockers) as u64),
+                        ),
+                        ("redundant_bytes", Json::Num(redundant(tally))),
+                        (
+                            "unique",
+                            Json::List(names.into_iter().map(Json::Str).collect()),
+                        ),
+                    ],
+                );
+                continue;
+            }
+            println!(
+                "partial {}: {} of {} files redundant, {}, blocked by {} files",
+                dir.display(),
+                tally.files - tally.blockers,
+                tally.files,
+                format_gib(redundant(tally)),
+                blocking.len()
+            );
+            for name in names {
+                println!("  unique {}", name);
+            }
+        }
+        let nfull = dirs.values().filter(|t| t.blockers == 0).count();
+        let nunique = dirs.values().filter(|t| t.blockers == t.files).count();
+        if self.config.format == Format::Json {
+            self.emit_json(
+                "removable_dirs",
+                vec![
+                    ("full", Json::Num(nfull as u64)),
+                    ("partial", Json::Num((dirs.len() - nfull - nunique) as u64)),
+                    ("unique", Json::Num(nunique as u64)),
+                ],
+            );
+            return Ok(());
+        }
+        println!(
+            "{} directories fully redundant, {} partially, {} unique",
+            nfull,
+            dirs.len() - nfull - nunique,
+            nunique
+        );
+        Ok(())
+    }
+
+    /// Insert an entry during the scan, counting it if it was refreshed
+    ///
+    ///   Returns the size of its group, see insert_entry.
+    fn replace_entry(&self, entry: Entry, hash: FileHash) -> usize {
+        let (replaced, members) = self.insert_entry(Arc::new(entry), hash);
+        if replaced {
+            self.refreshed.fetch_add(1, Ordering::SeqCst);
+        }
+        members
+    }
+
+    /// Insert an entry into both indexes, replacing an equal one
+    ///
+    ///   A plain insert would keep the old key, and with it the old
+    ///   mode and owner, and leave the old entry in its hash group.
+    ///   Returns true if an entry was replaced, and the size of the
+    ///   group right after the push.  The size is read under the
+    ///   group's lock, so concurrent inserts into a group each see a
+    ///   different size.  The entry is interned first, see Interner.
+    ///   An entry with a pending hash waits by size instead of joining
+    ///   a group, see defer_hash.
+    fn insert_entry(&self, entry: Arc<Entry>, hash: FileHash) -> (bool, usize) {
+        let entry = self.interner.intern(entry);
+        let old = self.index.remove(&entry);
+        if let Some((old_entry, old_hash)) = &old {
+            self.remove_from_group(old_entry, *old_hash);
+        }
+        self.index.insert(entry.clone(), hash);
+        // older archives kept symlinks and special files, all with the
+        // empty hash, which would group them all together
+        if !entry.is_file || !self.groups_len(entry.len) {
+            return (old.is_some(), 0);
+        }
+        if hash.is_pending() {
+            let mut waiting = self.pending.entry((entry.len, entry.head)).or_default();
+            waiting.retain(|f| **f != *entry);
+            waiting.push(entry);
+            return (old.is_some(), 1);
+        }
+        let mut group = self.hindex.entry(hash).or_default();
+        group.push(entry);
+        (old.is_some(), group.len())
+    }
+
+    /// Take an entry out of its hash group, dropping the group if empty
+    fn remove_from_group(&self, entry: &Entry, hash: FileHash) {
+        if let Some(mut files) = self.hindex.get_mut(&hash) {
+            files.retain(|f| **f != *entry);
+        }
+        self.hindex.remove_if(&hash, |_, files| files.is_empty());
+    }
+
+    /// Copy out every group with more than one member
+    ///
+    ///   Each group is copied under its own shard lock, so every group
+    ///   is internally consistent, while groups changed by concurrent
+    ///   adds during the snapshot may appear either before or after the
+    ///   change.  No locks are held once this returns.
+    pub fn snapshot_groups(&self) -> Vec<(FileHash, Vec<Arc<Entry>>)> {
+        self.hindex
+            .iter()
+            .filter(|item| item.value().len() > 1)
+            .map(|item| (*item.key(), item.value().clone()))
+            .collect()
+    }
+
+    /// True if --changed-only lets a file go unread
+    ///
+    ///   That is when it was last changed before the last complete
+    ///   injest of the deepest root it is under started.  Files under
+    ///   no such root are always looked at.
+    fn assumed_unchanged(&self, scanned: &Entry, metadata: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let by = match self.config.changed_only {
+            Some(by) if self.config.injest => by,
+            _ => return false,
+        };
+        let changed = match by {
+            ChangedBy::Mtime => scanned.mod_secs,
+            ChangedBy::Ctime => metadata.ctime().max(0) as u64,
+        };
+        let name = std::path::Path::new(&scanned.name);
+        let cutoff = self
+            .injested
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(root, _)| name.starts_with(root))
+            .max_by_key(|(root, _)| root.len())
+            .map(|(_, secs)| *secs);
+        match cutoff {
+            Some(cutoff) if changed < cutoff => {
+                self.unchanged.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The --changed-only cutoff for an injest root, if one was recorded
+    pub fn injested_at(&self, root: &str) -> Option<u64> {
+        let root = std::path::Path::new(root);
+        self.injested
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(recorded, _)| root.starts_with(recorded))
+            .max_by_key(|(recorded, _)| recorded.len())
+            .map(|(_, secs)| *secs)
+    }
+
+    /// Recorded injest roots overlapping `root`, with when each was
+    /// injested
+    ///
+    ///   Both are resolved through symlinks, relative ones from the
+    ///   current directory, and overlap either way round: a tree inside
+    ///   one injested, or one holding a tree injested.
+    pub fn injested_overlapping(&self, root: &str) -> Vec<(String, u64)> {
+        let root = crate::resolve_path(root);
+        self.injested
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(recorded, _)| {
+                let recorded = crate::resolve_path(recorded);
+                root.starts_with(&recorded) || recorded.starts_with(&root)
+            })
+            .map(|(recorded, secs)| (recorded.clone(), *secs))
+            .collect()
+    }
+
+    /// Files --changed-only took to be unchanged without reading them
+    pub fn unchanged(&self) -> usize {
+        self.unchanged.load(Ordering::Relaxed)
+    }
+
+    /// Record in the header that these roots were scanned to completion
+    ///
+    ///   `since` is when the scan started, so files changed while it ran
+    ///   are looked at again next time.  Format 1 archives have no
+    ///   header to keep it in until upgraded.
+    pub async fn mark_injested(&self, roots: &[String], since: u64) -> Result<()> {
+        if roots.is_empty() {
+            return Ok(());
+        }
+        let mut record = self.record.clone();
+        record.read_header().await?;
+        if record.version() < FORMAT_VERSION {
+            status!(
+                "archive is format version {}, find_dups upgrade it to record injest times",
+                record.version()
+            );
+            return Ok(());
+        }
+        let mut injested = self.injested.write().unwrap().clone();
+        for root in roots {
+            injested.insert(root.clone(), since);
+        }
+        record.set_injested(injested.clone());
+        record.write_header().await?;
+        *self.injested.write().unwrap() = injested;
+        Ok(())
+    }
+
+    /// Number of archived entries replaced by updated ones this run
+    pub fn refreshed(&self) -> usize {
+        self.refreshed.load(Ordering::SeqCst)
+    }
+
+    /// True if a file waits on its size before it is read, see defer_hash
+    ///
+    ///   Files bigger than a chunk do with --tiered-hash, and with
+    ///   --lazy-hash every file does.  Private archives keep no paths to
+    ///   read a file from later.
+    fn waits_by_size(&self, entry: &Entry) -> bool {
+        entry.is_file && !self.is_private() && (self.config.lazy_hash || self.waits_by_head(entry))
+    }
+
+    /// True if a file has its first chunk hashed before the whole of it
+    fn waits_by_head(&self, entry: &Entry) -> bool {
+        entry.is_file
+            && !self.is_private()
+            && self.config.tiered_hash
+            && entry.len > self.chunking().chunk_size() as u64
+    }
+
+    /// The hash to index a scanned file under, None if it went into the
+    /// index to wait for another file like it
+    ///
+    ///   With --tiered-hash, cheapest first: a file of a size no other
+    ///   file has waits unread, and one whose first chunk no other file
+    ///   of its size has waits with only that chunk read, keeping its
+    ///   hash in `entry.head`.  Only then is the whole file hashed.
+    ///   Files left waiting are hashed once a file like them shows up.
+    ///   Checking only reads ahead of the whole file to find archived
+    ///   files to hash, it never leaves a file waiting.
+    async fn tiered_hash(
+        &self,
+        path: &PathBuf,
+        entry: &mut Entry,
+        dev: Option<u64>,
+    ) -> Result<Option<FileHash>> {
+        if self.defer_hash(entry) {
+            return Ok(None);
+        }
+        self.hash_waiting_by_size(entry.len).await;
+        self.head_then_hash(path, entry, dev).await
+    }
+
+    /// Let later files of an archived file's size, and head if it was
+    /// hashed in full, go on without waiting, see defer_hash
+    fn note_hashed(&self, entry: &Entry, hash: FileHash) {
+        if hash.is_pending() {
+            if entry.head.is_some() {
+                self.sized.insert((entry.len, None));
+            }
+            return;
+        }
+        if self.waits_by_size(entry) {
+            self.sized.insert((entry.len, None));
+        }
+        if self.waits_by_head(entry) {
+            match entry.head {
+                Some(head) => self.sized.insert((entry.len, Some(head))),
+                None => self.headless.insert(entry.len),
+            };
+        }
+    }
+
+    /// The chunk and whole file tiers of tiered_hash
+    async fn head_then_hash(
+        &self,
+        path: &PathBuf,
+        entry: &mut Entry,
+        dev: Option<u64>,
+    ) -> Result<Option<FileHash>> {
+        if self.waits_by_head(entry) {
+            let (head_path, stats): (std::path::PathBuf, _) =
+                (path.clone().into(), self.stats.clone());
+            let size = self.chunking().chunk_size();
+            let head = self
+                .config
+                .hash_pool
+                .run(None, move || hash_head(&head_path, size, &stats));
+            // with no limit there is always a result
+            if let Some(head) = head.await? {
+                entry.head = Some(head?);
+            }
+            // an archived file of this size hashed without a head could match
+            if !self.headless.contains(&entry.len) && self.defer_hash(entry) {
+                return Ok(None);
+            }
+            self.hash_waiting_by_head(entry.len, entry.head).await;
+        }
+        let (hash, digest) = self.hash_entry(path, entry, dev).await?;
+        entry.digest = digest;
+        Ok(Some(hash))
+    }
+
+    /// When injesting, true if a scanned file is the first with its size,
+    /// and head if it has one, and has gone into the index unhashed
+    ///
+    ///   A file nothing else shares its size or first chunk with can't
+    ///   be a duplicate.  `sized` holds the keys already hashed past,
+    ///   and is only added to under the lock of the key's `pending`
+    ///   slot, so a file is never left waiting beside one that went on.
+    fn defer_hash(&self, entry: &Entry) -> bool {
+        use dashmap::mapref::entry::Entry as Slot;
+
+        let waits = match entry.head {
+            None => self.waits_by_size(entry),
+            Some(_) => self.waits_by_head(entry),
+        };
+        if !waits || !self.config.injest {
+            return false;
+        }
+        let key = (entry.len, entry.head);
+        let entry = match self.pending.entry(key) {
+            Slot::Vacant(slot) if !self.sized.contains(&key) => {
+                let entry = self.interner.intern(Arc::new(entry.clone()));
+                slot.insert(vec![entry.clone()]);
+                entry
+            }
+            Slot::Vacant(_) => return false,
+            Slot::Occupied(_) => {
+                self.sized.insert(key);
+                return false;
+            }
+        };
+        if let Some((old, old_hash)) = self.index.remove(&entry) {
+            self.remove_from_group(&old, old_hash);
+            if !old_hash.is_pending() {
+                self.refreshed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.index.insert(entry, FileHash::PENDING);
+        true
+    }
+
+    /// Move the files waiting unread on a size on to the later tiers,
+    /// in every store
+    async fn hash_waiting_by_size(&self, len: u64) {
+        for store in self.stores() {
+            if let Some((_, waiting)) = store.pending.remove(&(len, None)) {
+                for entry in waiting {
+                    if let Some(path) = store.still_waiting(&entry).await {
+                        let mut entry = (*entry).clone();
+                        match store.head_then_hash(&path, &mut entry, None).await {
+                            Ok(Some(hash)) => {
+                                store.insert_entry(Arc::new(entry), hash);
+                            }
+                            Ok(None) => (),
+                            Err(e) => eprintln!("hash: {:?} ({})", e, entry.name),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hash the files waiting on a size and head, in every store
+    async fn hash_waiting_by_head(&self, len: u64, head: Option<u64>) {
+        for store in self.stores() {
+            if let Some((_, waiting)) = store.pending.remove(&(len, head)) {
+                for entry in waiting {
+                    if let Some(path) = store.still_waiting(&entry).await {
+                        match store.hash_entry(&path, &entry, None).await {
+                            Ok((hash, digest)) => {
+                                let entry = Entry {
+                                    digest,
+                                    ..(*entry).clone()
+                                };
+                                store.insert_entry(Arc::new(entry), hash);
+                            }
+                            Err(e) => eprintln!("hash: {:?} ({})", e, entry.name),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hash the files waiting on these sizes and heads, in every store
+    ///
+    ///   Stops short once the run is interrupted, leaving the rest
+    ///   waiting.
+    async fn hash_waiting(&self, keys: Vec<WaitKey>) {
+        for (len, head) in keys {
+            if crate::interrupted() {
+                break;
+            }
+            match head {
+                None => self.hash_waiting_by_size(len).await,
+                Some(_) => self.hash_waiting_by_head(len, head).await,
+            }
+        }
+    }
+
+    /// The path to read a waiting entry from, None if it should not be
+    ///
+    ///   A file changed or gone since it was indexed keeps its pending
+    ///   hash, as does an entry already replaced or pruned.
+    async fn still_waiting(&self, entry: &Entry) -> Option<PathBuf> {
+        let path = PathBuf::from(&entry.name);
+        let metadata = async_std::fs::metadata(&path).await.ok()?;
+        let unchanged = Entry::new_from_path_meta(&path, &metadata).is_ok_and(|now| now == *entry);
+        let waiting = self
+            .index
+            .get(entry)
+            .is_some_and(|item| item.value().is_pending());
+        (unchanged && waiting).then_some(path)
+    }
+
+    /// Hash whatever was left waiting that has turned out to share its
+    /// size or head, returns how many files are still waiting unread
+    /// and with only their first chunk read
+    ///
+    ///   Catches archived files waiting on a key that an earlier run
+    ///   hashed past without waiting, as one without --tiered-hash does.
+    ///   With --manifest every file waiting is hashed, as a --hashes-from
+    ///   check against a manifest leaving them out finds them missing.
+    pub async fn finish_waiting(&self) -> (usize, usize) {
+        let keys: Vec<WaitKey> = self
+            .pending
+            .iter()
+            .filter(|item| {
+                item.value().len() > 1
+                    || self.sized.contains(item.key())
+                    || self.config.manifest.is_some()
+            })
+            .map(|item| *item.key())
+            .collect();
+        self.hash_waiting(keys).await;
+        let mut waiting = (0, 0);
+        for item in self.index.iter().filter(|item| item.value().is_pending()) {
+            match item.key().head {
+                None => waiting.0 += 1,
+                Some(_) => waiting.1 += 1,
+            }
+        }
+        waiting
+    }
+
+    /// Archived files --match full can't match, hashed but with no
+    /// digest
+    pub fn undigested(&self) -> usize {
+        self.index
+            .iter()
+            .filter(|item| {
+                item.key().is_file && item.key().digest.is_none() && !item.value().is_pending()
+            })
+            .count()
+    }
+
+    /// What the walk leaves out by name, see dir::PathFilter
+    pub fn path_filter(&self) -> crate::dir::PathFilter {
+        crate::dir::PathFilter::new(self.config.excludes.clone(), self.config.skip_hidden)
+    }
+
+    /// A buffer for the skips of a directory, kept with --log-skips
+    pub fn skips(&self) -> crate::skip::DirSkips {
+        crate::skip::DirSkips::new(self.config.skip_log.clone())
+    }
+
+    /// True if `metadata` is of the archive directory, found under an
+    /// injest root, warning the first time
+    pub fn is_archive_dir(&self, path: &PathBuf, metadata: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        let found =
+            metadata.is_dir() && self.config.archive_id == Some((metadata.dev(), metadata.ino()));
+        if found && !self.passed_archive.swap(true, Ordering::SeqCst) {
+            eprintln!(
+                "warning: {} is the archive directory, not injested",
+                path.to_string_lossy()
+            );
+        }
+        found
+    }
+
+    /// True if a file was modified outside --newer-than and --older-than
+    ///
+    ///   Its archived entry, if any, is kept by name, as the run says
+    ///   nothing of whether it is still there, see prune.
+    pub fn outside_times(&self, path: &PathBuf, metadata: &Metadata) -> bool {
+        let (newer, older) = (self.config.newer_than, self.config.older_than);
+        if newer.is_none() && older.is_none() {
+            return false;
+        }
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |t| t.as_secs());
+        let outside =
+            newer.is_some_and(|secs| mtime <= secs) || older.is_some_and(|secs| mtime >= secs);
+        if outside {
+            let name = normalize_path(path.to_str().unwrap());
+            let name = match self.private_key() {
+                Some(key) => key.path_mac(&name),
+                None => name,
+            };
+            self.seen_paths.insert(name);
+        }
+        outside
+    }
+
+    /// True if scanned directories are recorded or verified, see add_dir
+    pub fn records_dirs(&self) -> bool {
+        self.config.record_dirs && !self.config.missing_by_path
+    }
+
+    /// Record or verify a scanned directory, see --record-dirs
+    ///
+    ///   Directories are kept apart from the files, so they never join
+    ///   a hash group or count towards duplicates.  Injesting stores the
+    ///   entry, checking compares it with the archived one found through
+    ///   the check's path roots.  Directory sizes depend on the
+    ///   filesystem, so only the mode, owner and mtime are compared.  A
+    ///   private archive keeps no directories.
+    pub fn add_dir(&self, path: &PathBuf, metadata: &Metadata) -> Result<()> {
+        if self.is_private() {
+            return Ok(());
+        }
+        let scanned = Entry {
+            len: 0,
+            name: normalize_path(path.to_str().unwrap()),
+            ..Entry::new_from_path_meta(path, metadata)?
+        };
+        let archived_name = match self.config.injest {
+            true => Some(scanned.name.clone()),
+            false => self
+                .config
+                .path_roots
+                .iter()
+                .find_map(|(from, to)| rebase_path(&scanned.name, to, from)),
+        };
+        self.seen_dirs.insert(scanned.name.clone());
+        let archived = archived_name
+            .as_ref()
+            .and_then(|name| self.dirs.get(name).map(|dir| dir.clone()));
+        let renamed = |name: &String| Entry {
+            name: name.clone(),
+            ..scanned.clone()
+        };
+        match archived {
+            Some(dir) if dir.same_metadata(&renamed(&dir.name)) => (),
+            Some(dir) => {
+                self.dirs_changed.fetch_add(1, Ordering::SeqCst);
+                if self.config.verbose > 0 {
+                    status!("dir changed: {}", scanned.name);
+                }
+                if self.config.injest {
+                    self.dirs
+                        .insert(dir.name.clone(), Arc::new(renamed(&dir.name)));
+                }
+            }
+            None => {
+                self.dirs_created.fetch_add(1, Ordering::SeqCst);
+                if self.config.verbose > 0 && !self.config.injest {
+                    status!("dir not archived: {}", scanned.name);
+                }
+                if self.config.injest {
+                    self.dirs.insert(scanned.name.clone(), Arc::new(scanned));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a symlink itself, with its target, see --symlinks record
+    ///
+    ///   Links are kept whole on injest and, like directories, not kept
+    ///   in a private archive.
+    pub fn add_symlink(&self, path: &PathBuf, metadata: &Metadata, target: String) -> Result<()> {
+        if self.is_private() || !self.config.injest {
+            return Ok(());
+        }
+        let name = normalize_path(path.to_str().unwrap());
+        self.seen_symlinks.insert(name.clone());
+        let entry = Entry {
+            is_file: false,
+            is_dir: false,
+            name: name.clone(),
+            link: Some(target),
+            ..Entry::new_from_path_meta(path, metadata)?
+        };
+        let same = self
+            .symlinks
+            .get(&name)
+            .is_some_and(|old| old.same_metadata(&entry) && old.link == entry.link);
+        if !same {
+            self.symlinks_changed.fetch_add(1, Ordering::SeqCst);
+            self.symlinks.insert(name, Arc::new(entry));
+        }
+        Ok(())
+    }
+
+    /// Symlinks new or changed this run, see add_symlink
+    pub fn symlinks_changed(&self) -> usize {
+        self.symlinks_changed.load(Ordering::SeqCst)
+    }
+
+    /// What walks do with symlinks, see --symlinks
+    pub fn symlink_policy(&self) -> Symlinks {
+        self.config.symlinks
+    }
+
+    /// Device and inode of a directory for the broker to walk it once,
+    /// None unless with --walk-once or following symlinks
+    pub fn dir_id(&self, metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        match self.config.walk_once || self.config.symlinks == Symlinks::Follow {
+            true => Some((metadata.dev(), metadata.ino())),
+            false => None,
+        }
+    }
+
+    /// Directories scanned, changed and not archived, see add_dir
+    pub fn dir_counts(&self) -> (usize, usize, usize) {
+        (
+            self.seen_dirs.len(),
+            self.dirs_changed.load(Ordering::SeqCst),
+            self.dirs_created.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Count archived directories under the check's path roots that
+    /// were not scanned, listing them when verbose
+    pub fn missing_dirs(&self) -> usize {
+        let mut missing = std::collections::BTreeSet::new();
+        for dir in self.dirs.iter() {
+            let expected = self
+                .config
+                .path_roots
+                .iter()
+                .find_map(|(from, to)| rebase_path(dir.key(), from, to));
+            if let Some(expected) = expected.filter(|path| !self.seen_dirs.contains(path)) {
+                missing.insert(expected);
+            }
+        }
+        if self.config.verbose > 0 {
+            for path in &missing {
+                status!("dir missing: {}", path);
+            }
+        }
+        missing.len()
+    }
+
+    /// Compute the content identity of an entry per the configured mode,
+    /// from the hash cache if it has the file
+    ///
+    ///   Only full hashes are cached, so media mode always reads.
+    async fn hash_entry(&self, path: &PathBuf, entry: &Entry, dev: Option<u64>) -> Result<Hashed> {
+        use std::os::unix::fs::MetadataExt;
+
+        if !entry.is_file || self.config.media_mode.is_some() {
+            return self.read_hash(path, entry, dev).await;
+        }
+        let metadata = async_std::fs::metadata(path).await?;
+        if metadata.nlink() < 2 || self.config.count_hardlinks {
+            return self.hash_inode(path, entry, dev, &metadata).await;
+        }
+        // later links wait on the first and take its hash
+        let slot = self
+            .links
+            .entry((metadata.dev(), metadata.ino()))
+            .or_default()
+            .clone();
+        let mut linked = slot.lock().await;
+        if let Some(link) = linked.as_ref() {
+            if (link.len, link.mod_secs, link.mod_nanos)
+                == (entry.len, entry.mod_secs, entry.mod_nanos)
+            {
+                self.stats.add_link_hit();
+                return Ok((link.hash, link.digest));
+            }
+        }
+        let (hash, digest) = self.hash_inode(path, entry, dev, &metadata).await?;
+        if !hash.is_partial() {
+            *linked = Some(CachedHash {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+                len: entry.len,
+                mod_secs: entry.mod_secs,
+                mod_nanos: entry.mod_nanos,
+                hash,
+                digest,
+            });
+        }
+        Ok((hash, digest))
+    }
+
+    /// Hash a file's inode, from the hash cache if it is in use
+    async fn hash_inode(
+        &self,
+        path: &PathBuf,
+        entry: &Entry,
+        dev: Option<u64>,
+        metadata: &Metadata,
+    ) -> Result<Hashed> {
+        if !self.uses_cache() {
+            return self.read_hash(path, entry, dev).await;
+        }
+        if let Some(hashed) = self.cached_hash(metadata, entry) {
+            self.stats.add_cache_hit();
+            return Ok(hashed);
+        }
+        let hash = self.read_hash(path, entry, dev).await?;
+        self.remember_hash(metadata, entry, hash);
+        Ok(hash)
+    }
+
+    /// Compute the content identity of an entry per the configured mode
+    ///
+    ///   The file is read and hashed on a thread of the hash pool, and
+    ///   timed there, so the time spent queued for one is not counted.
+    ///   With the device the file is on, its timing is also kept per
+    ///   device to spot slow storage.
+    async fn read_hash(&self, path: &PathBuf, entry: &Entry, dev: Option<u64>) -> Result<Hashed> {
+        if !entry.is_file {
+            return Ok((FileHash::default(), None));
+        }
+        // what reading no bytes would give, without opening the file
+        if entry.len == 0 {
+            let digest = self.config.whole_digest.then(|| whole_digest(&[]));
+            return Ok((empty_hash(self.hash_algorithm()), digest));
+        }
+        let media = match self.config.media_mode {
+            Some(threshold) => entry.len > threshold.max(2 * MEDIA_SPAN as u64),
+            None => false,
+        };
+        let bytes = if media {
+            2 * MEDIA_SPAN as u64
+        } else {
+            entry.len
+        };
+        let (algorithm, chunking, len, split, digest) = (
+            self.hash_algorithm(),
+            self.chunking(),
+            entry.len,
+            self.split_for(entry.len),
+            self.config.whole_digest,
+        );
+        let (job_path, stats): (std::path::PathBuf, _) = (path.clone().into(), self.stats.clone());
+        let checkpoint = self
+            .checkpoint_key(path, entry)
+            .map(|key| (self.archive.clone(), key));
+        // private entries keep no mtime to compare
+        let stable = match self.config.include_unstable {
+            true => None,
+            false => Some((
+                entry.mod_secs,
+                entry.mod_nanos,
+                !self.is_private() || entry.mod_secs != 0,
+            )),
+        };
+        let hashing = move || {
+            let started = stats.hash_started();
+            let mut hash = match media {
+                true => hash_file_media(&job_path, len, algorithm, &stats).map(|hash| (hash, None)),
+                false => hash_file(
+                    &job_path, len, algorithm, chunking, split, digest, checkpoint, &stats,
+                ),
+            };
+            // a hash of other bytes than the size recorded is worse than none
+            if let (Ok(_), Some((secs, nanos, by_mtime))) = (&hash, stable) {
+                let now = std::fs::metadata(&job_path).ok().and_then(|m| {
+                    let mtime = m.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                    Some((m.len(), mtime.as_secs(), mtime.subsec_nanos()))
+                });
+                let same = now.is_some_and(|(now_len, now_secs, now_nanos)| {
+                    now_len == len && (!by_mtime || (now_secs, now_nanos) == (secs, nanos))
+                });
+                if !same {
+                    hash = Err(Box::new(Unstable));
+                }
+            }
+            stats.hash_finished(started, if hash.is_ok() { bytes } else { 0 });
+            (hash, started.elapsed())
+        };
+        // a hung network read would otherwise hold this task forever;
+        // under --bwlimit a read waits its turn inside the limit, so the
+        // rate allowed is no more than each thread's share of it
+        let rate = match &self.config.bwlimit {
+            Some(throttle) => {
+                let share = throttle.rate() as u64 / self.config.hash_pool.threads() as u64;
+                share.clamp(1, MIN_HASH_RATE)
+            }
+            None => MIN_HASH_RATE,
+        };
+        let limit = match self.config.file_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs + bytes / rate)),
+        };
+        let (hash, elapsed) = match self.config.hash_pool.run(limit, hashing).await? {
+            Some(done) => done,
+            None => {
+                self.stats.add_timed_out(&entry.name);
+                let limit = limit.unwrap_or_default();
+                (
+                    Err(format!("hash timed out after {}s", limit.as_secs()).into()),
+                    limit,
+                )
+            }
+        };
+        let read = if hash.is_ok() { bytes } else { 0 };
+        if let (Some(events), Some(_), Ok((hash, _))) = (&self.config.events, dev, &hash) {
+            let elapsed = elapsed.as_secs_f64();
+            events.emit(
+                "file_hashed",
+                vec![
+                    ("path", Json::Str(entry.name.clone())),
+                    ("hash", Json::Str(group_id(*hash, 16))),
+                    ("size", Json::Num(entry.len)),
+                    ("bytes_read", Json::Num(read)),
+                    ("ms", Json::Float(elapsed * 1000.0)),
+                    (
+                        "mb_per_sec",
+                        Json::Float(read as f64 / 1e6 / elapsed.max(f64::EPSILON)),
+                    ),
+                ],
+            );
+        }
+        if let Some(dev) = dev {
+            let dir = path
+                .parent()
+                .map(|p| p.to_string_lossy())
+                .unwrap_or_default();
+            self.stats.add_source_sample(dev, &dir, elapsed, read);
+        }
+        hash
+    }
+
+    /// Re-hash one small, unchanged archived file and compare
+    ///
+    ///   If the archive was built with different hashing parameters
+    ///   every file would look missing, so catch that before the scan.
+    pub async fn preflight(&self) -> Result<()> {
+        let mut candidates: Vec<(Arc<Entry>, FileHash)> = self
+            .index
+            .iter()
+            .filter(|item| item.key().is_file && item.key().len > 0 && !item.value().is_pending())
+            .map(|item| (item.key().clone(), *item.value()))
+            .collect();
+        candidates.sort_by_key(|(entry, _)| entry.len);
+
+        for (entry, stored) in candidates.iter().take(PREFLIGHT_TRIES) {
+            let path = PathBuf::from(&entry.name);
+            let metadata = match async_std::fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            // only a file unchanged since it was archived tells us anything
+            if Entry::new_from_path_meta(&path, &metadata)? != **entry {
+                continue;
+            }
+            let (hash, _) = self.read_hash(&path, entry, None).await?;
+            if self.config.verbose > 0 {
+                eprintln!("preflight: checked {}", entry.name);
+            }
+            if hash != *stored {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "archive parameters incompatible or archive corrupt (preflight on {})",
+                        entry.name
+                    ),
+                )));
+            }
+            return Ok(());
+        }
+        if self.config.verbose > 0 {
+            eprintln!("preflight: no unchanged archived file found, skipping");
+        }
+        Ok(())
+    }
+
+    /// Print what the archive knows of a path and its last known
+    /// disposition, see `find_dups why`
+    ///
+    ///   The index, the skips kept by --log-skips runs and the stored
+    ///   exclude patterns are searched by name, so the file need not
+    ///   exist any more.  A skipped directory counts for what is in it.
+    pub async fn why(&self, path: &str) -> Result<()> {
+        use crate::skip::Reason;
+
+        if self.is_private() {
+            return Err(format!(
+                "{} is a private archive, which keeps no paths",
+                self.archive
+            )
+            .into());
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let when = |secs: u64| {
+            format!(
+                "unix time {}, {:.1} hours ago",
+                secs,
+                now.saturating_sub(secs) as f64 / 3600.0
+            )
+        };
+        let archived: Vec<(Arc<Entry>, FileHash)> = self
+            .index
+            .iter()
+            .filter(|item| item.key().name == path)
+            .map(|item| (item.key().clone(), *item.value()))
+            .collect();
+        for (entry, hash) in &archived {
+            println!(
+                "index: archived, {} bytes, mtime {}.{:09}, hash {}",
+                entry.len,
+                entry.mod_secs,
+                entry.mod_nanos,
+                group_id(*hash, 16)
+            );
+        }
+        let skips: Vec<crate::skip::Skip> = crate::skip::stored(&self.archive)
+            .await?
+            .into_iter()
+            .filter(|skip| {
+                skip.path == path
+                    || (std::path::Path::new(path).starts_with(&skip.path)
+                        && matches!(
+                            skip.reason,
+                            Reason::Excluded | Reason::Unreadable | Reason::Overflow
+                        ))
+            })
+            .collect();
+        for skip in &skips {
+            let under = match skip.path == path {
+                true => String::new(),
+                false => format!("under {}, ", skip.path),
+            };
+            println!(
+                "skipped: {}{} in the run at {}",
+                under,
+                skip.describe(),
+                when(skip.run)
+            );
+        }
+        let excludes = crate::exclude::Excludes::new(
+            crate::exclude::stored(&self.archive).await?,
+            Vec::new(),
+        )?;
+        if excludes.matches(path, false) {
+            println!("exclude: matched by a pattern kept in the archive");
+        }
+        let injested = self.injested_at(path);
+        match injested {
+            Some(secs) => println!("injest: last complete injest over it at {}", when(secs)),
+            None => println!("injest: under no root recorded as injested"),
+        }
+
+        // a skip newer than the last complete injest outdates the index
+        let disposition = match (archived.is_empty(), skips.last()) {
+            (false, Some(skip)) if injested.is_some_and(|secs| skip.run >= secs) => {
+                format!("skipped, {}", skip.describe())
+            }
+            (false, _) => "archived".to_string(),
+            (true, Some(skip)) => format!("skipped, {}", skip.describe()),
+            (true, None) if injested.is_some() => {
+                "not archived, and no skip recorded, see --log-skips".to_string()
+            }
+            (true, None) => "never injested".to_string(),
+        };
+        println!("last known: {}", disposition);
+        Ok(())
+    }
+
+    /// Write each step add_file would take for one file, read-only
+    ///
+    ///   Asks the walk's filters and follows add_file's lookups over
+    ///   every archive given, then states what --missing, --present and
+    ///   --duplicate would print for it in a check.
+    pub async fn explain(&self, path: &PathBuf, out: &mut dyn std::io::Write) -> Result<()> {
+        let metadata = async_std::fs::metadata(path).await?;
+        let scanned = Entry::new_from_path_meta(path, &metadata)?;
+        writeln!(
+            out,
+            "name: {} (archived names must match this spelling)",
+            scanned.name
+        )?;
+        writeln!(
+            out,
+            "metadata: {} bytes, mtime {}.{:09}, mode {:o}, uid {}, gid {}",
+            scanned.len,
+            scanned.mod_secs,
+            scanned.mod_nanos,
+            scanned.perm,
+            scanned.uid,
+            scanned.gid
+        )?;
+
+        // the walk's filters, in the order process_dir asks them
+        let filter = self.path_filter();
+        let stored_excludes = crate::exclude::Excludes::new(
+            crate::exclude::stored(&self.archive).await?,
+            Vec::new(),
+        )?;
+        if !scanned.is_file {
+            writeln!(
+                out,
+                "filter: not a regular file, skipped when scanned and never archived"
+            )?;
+        }
+        if filter.hidden(path) {
+            writeln!(out, "filter: a dot file, left out by --skip-hidden")?;
+        }
+        if filter.excluded(path, false) {
+            writeln!(out, "filter: matched by an --exclude pattern, left out")?;
+        }
+        if self.config.stored_excludes && stored_excludes.matches(&scanned.name, false) {
+            writeln!(
+                out,
+                "filter: matched by a pattern kept in the archive, left out"
+            )?;
+        }
+        if scanned.is_file && self.outside_times(path, &metadata) {
+            writeln!(
+                out,
+                "filter: modified outside --newer-than/--older-than, not looked at"
+            )?;
+        }
+        if scanned.is_file && !filter.included(path) {
+            writeln!(out, "filter: matches no include pattern, not looked at")?;
+        }
+
+        // as add_file: a private archive keeps no mtime to tell an
+        // unchanged file by, so its files are always hashed
+        let (entry, fast_path) = match self.private_key() {
+            Some(key) => (scanned.private(key), false),
+            None => (scanned.clone(), true),
+        };
+        if !fast_path {
+            writeln!(
+                out,
+                "index: a private archive keeps no mtime, so the file is always hashed"
+            )?;
+        }
+        let stored = self.stores().filter(|_| fast_path).find_map(|store| {
+            store
+                .index
+                .get(&entry)
+                .map(|item| (store, item.key().clone(), *item.value()))
+        });
+        let same_path: Vec<Arc<Entry>> = self
+            .stores()
+            .filter(|_| fast_path)
+            .flat_map(|store| {
+                store
+                    .index
+                    .iter()
+                    .filter(|item| item.key().name == entry.name && **item.key() != entry)
+                    .map(|item| item.key().clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        match &stored {
+            Some((store, old, hash)) => {
+                writeln!(
+                    out,
+                    "index: archived in {} with the same name, size and mtime, hash {}",
+                    store.archive,
+                    group_id(*hash, 16)
+                )?;
+                if let Some(digest) = old.digest {
+                    writeln!(
+                        out,
+                        "index: digest {} (blake3 of the whole file)",
+                        digest.hex()
+                    )?;
+                }
+                if !old.same_metadata(&entry) {
+                    writeln!(
+                        out,
+                        "index: mode or owner differ (archived mode {:o}, uid {}, gid {})",
+                        old.perm, old.uid, old.gid
+                    )?;
+                }
+                if old.inode != entry.inode {
+                    writeln!(
+                        out,
+                        "index: inode differs or was never archived, an injest records it without re-hashing"
+                    )?;
+                }
+            }
+            None if fast_path => writeln!(
+                out,
+                "index: no archived file with this name, size and mtime"
+            )?,
+            None => (),
+        }
+        for old in &same_path {
+            writeln!(
+                out,
+                "index: same name archived with {} bytes, mtime {}.{:09}, so it counts as new",
+                old.len, old.mod_secs, old.mod_nanos
+            )?;
+        }
+
+        let stored = match stored {
+            Some((_, old, _)) if self.config.strict_metadata && !old.same_metadata(&entry) => {
+                writeln!(
+                    out,
+                    "decision: --strict-metadata and metadata differ, re-hashing"
+                )?;
+                None
+            }
+            stored => stored,
+        };
+        let (hash, fast) = match stored {
+            Some((_, _, hash)) => {
+                writeln!(
+                    out,
+                    "decision: unchanged archived file, using the stored hash"
+                )?;
+                (hash, true)
+            }
+            None => {
+                let media = match self.config.media_mode {
+                    Some(threshold) => entry.len > threshold.max(2 * MEDIA_SPAN as u64),
+                    None => false,
+                };
+                if media {
+                    writeln!(
+                        out,
+                        "hashing: media mode, size plus first and last {} bytes",
+                        MEDIA_SPAN
+                    )?;
+                } else {
+                    match self.chunking() {
+                        Chunking::Fixed { size } => {
+                            writeln!(out, "hashing: full contents in {} byte chunks", size)?
+                        }
+                        Chunking::Cdc { min, avg, max } => writeln!(
+                            out,
+                            "hashing: full contents in content-defined chunks of {} to {} bytes, {} on average",
+                            min, max, avg
+                        )?,
+                    }
+                }
+                let (hash, digest) = self.read_hash(path, &entry, None).await?;
+                writeln!(out, "hash: {}", group_id(hash, 16))?;
+                if let Some(digest) = digest {
+                    writeln!(out, "digest: {} (blake3 of the whole file)", digest.hex())?;
+                }
+                (hash, false)
+            }
+        };
+
+        let files: Vec<Arc<Entry>> = self
+            .stores()
+            .flat_map(|store| {
+                store
+                    .hindex
+                    .get(&hash)
+                    .map(|files| files.clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+        writeln!(
+            out,
+            "hindex: {} archived files with this hash (group {})",
+            files.len(),
+            group_id(hash, 0)
+        )?;
+        let shown = match self.config.group_limit {
+            0 => files.len(),
+            limit => limit.min(files.len()),
+        };
+        for f in &files[..shown] {
+            writeln!(out, "  {}", self.shown_name(f))?;
+        }
+        if shown < files.len() {
+            writeln!(out, "  and {} more", files.len() - shown)?;
+        }
+        if let Some(list) = &self.config.hashes_from {
+            if self.config.known_hashes.contains(&hash) {
+                writeln!(out, "hashes-from: listed in {}, counts as one more", list)?;
+            }
+        }
+
+        // as add_file decides: members count the file itself, archived
+        // or not, so a match needs two
+        let members = match fast {
+            true => self.members(hash),
+            false => self.members(hash) + 1,
+        };
+        let grouped = self.groups_len(entry.len);
+        if !grouped {
+            writeln!(
+                out,
+                "filter: empty, in no group without --include-empty, so never present or a duplicate"
+            )?;
+        }
+        let present = grouped && members >= 2;
+        let missing = match grouped {
+            true => members < 2,
+            false => !fast && !self.archives_empty(),
+        };
+        let yes_no = |b: bool| if b { "prints it" } else { "prints nothing" };
+        writeln!(out, "--missing: {}", yes_no(missing))?;
+        writeln!(out, "--present: {}", yes_no(present))?;
+        if fast {
+            writeln!(
+                out,
+                "--duplicate: prints nothing, unchanged archived files are not re-reported"
+            )?;
+        } else if present {
+            writeln!(
+                out,
+                "--duplicate: prints the archived group above, once for all its copies"
+            )?;
+        } else {
+            writeln!(out, "--duplicate: prints nothing")?;
+        }
+        Ok(())
+    }
+
+    /// Write the archived files under `under` to a tar file, see export-tar
+    ///
+    ///   The archive keeps no content, so each file is read from its
+    ///   archived path, streamed in through the tar builder rather than
+    ///   loaded whole.  A file whose full hash was already written goes
+    ///   in as a hard link to that first copy, so the tar holds each
+    ///   content once.  Files gone, unreadable or changed in size or
+    ///   mtime since they were archived are left out with a warning,
+    ///   and listed again at the end.  Returns the number written.
+    pub fn export_tar(&self, under: &str, out: &str) -> Result<usize> {
+        use std::os::unix::fs::MetadataExt;
+        use std::path::Path;
+
+        if self.is_private() {
+            return Err("a private archive has no paths to export".into());
+        }
+        let mut files: Vec<(Arc<Entry>, FileHash)> = self
+            .index
+            .iter()
+            .filter(|item| item.key().is_file && Path::new(&item.key().name).starts_with(under))
+            .map(|item| (item.key().clone(), *item.value()))
+            .collect();
+        files.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+
+        let mut tar = tar::Builder::new(std::io::BufWriter::new(std::fs::File::create(out)?));
+        let mut written: HashMap<FileHash, String> = HashMap::new();
+        let mut skipped: Vec<(String, String)> = Vec::new();
+        let mut nlinks = 0;
+        for (entry, hash) in &files {
+            // as tar itself does, leave off the leading /
+            let name = entry.name.trim_start_matches('/').to_string();
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(entry.perm & 0o7777);
+            header.set_uid(entry.uid as u64);
+            header.set_gid(entry.gid as u64);
+            header.set_mtime(entry.mod_secs);
+            // a partial hash is too weak to stand for the content
+            if let Some(first) = written.get(hash).filter(|_| !hash.is_partial()) {
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                tar.append_link(&mut header, &name, first)?;
+                nlinks += 1;
+                continue;
+            }
+            let opened = std::fs::File::open(&entry.name).and_then(|f| Ok((f.metadata()?, f)));
+            let reason = match &opened {
+                Err(e) => Some(e.to_string()),
+                Ok((meta, _)) if meta.len() != entry.len => Some(format!(
+                    "size {} is not the archived {}",
+                    meta.len(),
+                    entry.len
+                )),
+                Ok((meta, _)) if meta.mtime() as u64 != entry.mod_secs => {
+                    Some("modified since it was archived".to_string())
+                }
+                Ok(_) => None,
+            };
+            if let Some(reason) = reason {
+                eprintln!("export-tar: {}: {}, skipped", entry.name, reason);
+                skipped.push((entry.name.clone(), reason));
+                continue;
+            }
+            let (_, file) = opened?;
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(entry.len);
+            let mut data = ExactLen {
+                file,
+                left: entry.len,
+                padded: false,
+            };
+            tar.append_data(&mut header, &name, &mut data)?;
+            if data.padded {
+                // too late to leave it out, the header is written
+                eprintln!(
+                    "export-tar: {}: shrank while being read, padded with zeros",
+                    entry.name
+                );
+                skipped.push((entry.name.clone(), "shrank while being read".to_string()));
+            }
+            written.insert(*hash, name);
+        }
+        tar.into_inner()?;
+        status!(
+            "export-tar: {} files, {} of them as links to an earlier copy, to {}",
+            files.len() - skipped.len(),
+            nlinks,
+            out
+        );
+        if !skipped.is_empty() {
+            eprintln!("export-tar: {} files not exported in full:", skipped.len());
+            for (name, reason) in &skipped {
+                eprintln!("  {}: {}", name, reason);
+            }
+        }
+        Ok(files.len() - skipped.len())
+    }
+
+    /// Write the index out as the archive's new sets
+    ///
+    ///   The sets, header and manifest go to a `file.write` directory
+    ///   and are swapped in once all are written, see Archive::swap_in,
+    ///   so a run stopped while writing leaves the archive as it was,
+    ///   and one stopped during the swap is put right from `file.backup`.
+    ///   A packed archive is packed whole in place of the old one.
+    ///   Returns the bytes of records written, before and after
+    ///   compression.
+    pub async fn write(&self) -> Result<(u64, u64)> {
+        if self.is_private() && self.private_key().is_none() {
+            // scanned files went in under their real names
+            return Err(format!(
+                "{} is a private archive, give its --private-archive key to update it",
+                self.archive
+            )
+            .into());
+        }
+        let packed = crate::pack::is_packed(&self.archive);
+        let staging = PathBuf::from(&self.archive).join("file.write");
+        let mut record = match packed {
+            true => {
+                let record = self.record.clone();
+                record.backup().await?;
+                record
+            }
+            false => {
+                if staging.exists().await {
+                    // left by a write stopped before its swap was done
+                    status!("rm -r {}", staging.display());
+                    async_std::fs::remove_dir_all(&staging).await?;
+                }
+                async_std::fs::create_dir_all(&staging).await?;
+                Record::new(
+                    staging.to_str().unwrap(),
+                    "file".to_string(),
+                    ARCHIVE_SIZE,
+                    RECORD_SIZE,
+                )
+            }
+        };
+        record.set_mode(if self.is_private() {
+            ArchiveMode::Private
+        } else {
+            ArchiveMode::Plain
+        });
+        record.set_injested(self.injested.read().unwrap().clone());
+        record.set_hash_algorithm(self.hash_algorithm().to_string());
+        record.set_chunking(self.chunking().to_string());
+        for item in self.index.iter() {
+            let entry = self.archived_form(item.key());
+            record.write_item(&(entry, *item.value()))?;
+        }
+        for dir in self.dirs.iter() {
+            let entry = self.archived_form(dir.value());
+            record.write_item(&(entry, FileHash::default()))?;
+        }
+        for link in self.symlinks.iter() {
+            let entry = self.archived_form(link.value());
+            record.write_item(&(entry, FileHash::default()))?;
+        }
+        record.finish().await?;
+        record.write_header().await?;
+        record.write_manifest().await?;
+        if !packed {
+            self.record.swap_in(&staging).await?;
+        }
+        if self.uses_cache() {
+            self.write_cache().await?;
+        }
+        Ok(record.bytes_written())
+    }
+
+    /// Rewrite the archive in the current format, see `find_dups upgrade`
+    ///
+    ///   The new sets go to a `<type>.upgrade` directory first.  Only
+    ///   once they are all written are the old sets copied to the
+    ///   backup directory and the new ones moved in, see
+    ///   Archive::swap_in, so a failure while writing leaves the
+    ///   original archive as it was.  One during the swap can leave
+    ///   old and new sets mixed, to be put right from `file.backup`.
+    pub async fn upgrade(&self, to_version: u32) -> Result<()> {
+        if crate::pack::is_packed(&self.archive) {
+            return Err(
+                format!("{} is a single file archive, unpack it first", self.archive).into(),
+            );
+        }
+        let mut old = self.record.clone();
+        let sets = old
+            .check_sets(self.config.repair, self.config.verbose)
+            .await?;
+        let mode = old.read_header().await?;
+        // a set of any older version is upgraded with the rest
+        let from = old.oldest_version(sets);
+        let mix = old.version_mix(sets);
+        if to_version != FORMAT_VERSION {
+            return Err(format!("can only upgrade to format version {}", FORMAT_VERSION).into());
+        }
+        if from >= to_version {
+            return Err(format!("{} is already at format version {}", self.archive, from).into());
+        }
+        let before = old.sets_size().await?;
+
+        let staging = PathBuf::from(&self.archive).join("file.upgrade");
+        if staging.exists().await {
+            return Err(format!(
+                "{} is left from an earlier upgrade, remove it first",
+                staging.display()
+            )
+            .into());
+        }
+        async_std::fs::create_dir(&staging).await?;
+        let mut new: Record<FileTuple> = Record::new(
+            staging.to_str().unwrap(),
+            "file".to_string(),
+            ARCHIVE_SIZE,
+            RECORD_SIZE,
+        );
+        new.set_mode(mode);
+        let (mut entries, mut legacy) = (0, 0);
+        while let Some((item, was_legacy)) = old.read_tuple()? {
+            new.write_item(&item)?;
+            entries += 1;
+            if was_legacy {
+                legacy += 1;
+            }
+        }
+        new.finish().await?;
+        let after = new.sets_size().await?;
+        // the hashes are copied, not redone, so keep naming how they were made
+        new.set_hash_algorithm(old.hash_algorithm().to_string());
+        new.set_chunking(old.chunking().to_string());
+        new.set_injested(old.injested().clone());
+        new.write_header().await?;
+        new.write_manifest().await?;
+
+        // the new generation is complete, swap it in
+        old.swap_in(&staging).await?;
+
+        println!(
+            "upgraded {} from format {} to {}",
+            self.archive, from, to_version
+        );
+        if mix.len() > 1 {
+            println!("  sets before: {}", describe_version_mix(&mix));
+        }
+        println!("  entries migrated: {}", entries);
+        println!("  hashes defaulted to full: {}", legacy);
+        println!("  hash algorithm kept: {}", old.hash_algorithm());
+        println!("  set bytes: {} before, {} after", before, after);
+        Ok(())
+    }
+
+    pub async fn read(&self) -> Result<()> {
+        let mut record = self.record.clone();
+        let sets = record
+            .check_sets(self.config.repair, self.config.verbose)
+            .await?;
+        let mode = record.read_header().await?;
+        *self.injested.write().unwrap() = record.injested().clone();
+        let archived: HashAlgorithm = record
+            .hash_algorithm()
+            .parse()
+            .map_err(|e| format!("{}: {}", self.archive, e))?;
+        if sets > 0 {
+            // otherwise every file would silently come out missing
+            match self.config.hash {
+                Some(wanted) if wanted != archived => {
+                    return Err(format!(
+                        "{} was hashed with {}, not --hash {}",
+                        self.archive, archived, wanted
+                    )
+                    .into())
+                }
+                _ => *self.algorithm.write().unwrap() = archived,
+            }
+            let chunked: Chunking = record
+                .chunking()
+                .parse()
+                .map_err(|e| format!("{}: {}", self.archive, e))?;
+            match self.config.chunking {
+                Some(wanted) if wanted != chunked => {
+                    return Err(
+                        format!("{} was chunked {}, not {}", self.archive, chunked, wanted).into(),
+                    )
+                }
+                _ => *self.chunking.write().unwrap() = chunked,
+            }
+        }
+        // the other algorithms digest a file whole, chunks or not
+        if let Chunking::Cdc { .. } = self.chunking() {
+            if !self.hash_algorithm().is_chunked() {
+                return Err(format!(
+                    "{}: --cdc chunks are only hashed by seahash, not {}",
+                    self.archive,
+                    self.hash_algorithm()
+                )
+                .into());
+            }
+        }
+        let mix = record.version_mix(sets);
+        if self.config.verbose > 0 {
+            eprintln!("loading {} {} archive sets", sets, mode);
+            if mix.len() > 1 {
+                eprintln!("archive sets by format: {}", describe_version_mix(&mix));
+            }
+            let oldest = record.oldest_version(sets);
+            if oldest < FORMAT_VERSION {
+                eprintln!(
+                    "archive is format version {}, find_dups upgrade rewrites it as {}",
+                    oldest, FORMAT_VERSION
+                );
+            }
+        }
+        self.stats.set_version_mix(mix);
+        let key = self.private_key();
+        // the next write would drop every path for good
+        let converting = key.is_some() && mode == ArchiveMode::Plain && sets > 0;
+        if converting && self.config.injest && !self.config.convert_private {
+            return Err(format!(
+                "{} is a plain archive, give --convert-to-private to rewrite it \
+                 keeping only path HMACs",
+                self.archive
+            )
+            .into());
+        }
+        // with the key everything in memory takes the private form, so
+        // rescanned files replace their entries whatever the mode was
+        self.private.store(
+            mode == ArchiveMode::Private || key.is_some(),
+            Ordering::Relaxed,
+        );
+        loop {
+            match record.read_item() {
+                Ok(Some((mut i0, i1))) => {
+                    if let (Some(key), ArchiveMode::Plain) = (key, mode) {
+                        i0 = Arc::new(i0.private(key));
+                    }
+                    //println!("got {}, {} chunks", i0.name, i1.len());
+                    let owner = self.config.uid_map.get(&i0.uid).map(|uid| (*uid, i0.uid));
+                    if let Some((uid, _)) = owner {
+                        Arc::make_mut(&mut i0).uid = uid;
+                    }
+                    // map names here so every lookup sees the mapped path
+                    let mapped = self
+                        .config
+                        .prefix_map
+                        .iter()
+                        .find_map(|(old, new)| rebase_path(&i0.name, old, new));
+                    if let Some(name) = mapped {
+                        Arc::make_mut(&mut i0).name = name;
+                    }
+                    if let Some(owner) = owner {
+                        self.owners.insert(i0.name.clone(), owner);
+                    }
+                    if i0.is_dir {
+                        self.dirs.insert(i0.name.clone(), i0);
+                        continue;
+                    }
+                    if i0.link.is_some() {
+                        self.symlinks.insert(i0.name.clone(), i0);
+                        continue;
+                    }
+                    self.note_hashed(&i0, i1);
+                    self.insert_entry(i0, i1);
+                }
+                Ok(None) => {
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("read file::Entry:{}", e);
+                    break;
+                }
+            }
+        }
+        if self.uses_cache() {
+            self.read_cache().await;
+        }
+        Ok(())
+    }
+
+    /// Report archived files under config.orphan_roots that are gone
+    ///
+    ///   Only stats each path, so this is far cheaper than pruning by
+    ///   re-injesting.  Counts and bytes are summed per top level
+    ///   directory under each root.  With config.prune_orphans the
+    ///   entries are also removed.  Returns the number of orphans.
+    pub fn orphans(&self) -> usize {
+        let mut by_dir: std::collections::BTreeMap<String, (usize, u64)> =
+            std::collections::BTreeMap::new();
+        let mut orphans = Vec::new();
+        for item in self.index.iter() {
+            let entry = item.key();
+            let path = std::path::Path::new(&entry.name);
+            let root = match self
+                .config
+                .orphan_roots
+                .iter()
+                .find(|root| path.starts_with(root))
+            {
+                Some(root) => root,
+                None => continue,
+            };
+            match std::fs::symlink_metadata(path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => {
+                    if self.config.verbose > 0 {
+                        eprintln!("orphans: {} ({})", e, entry.name);
+                    }
+                    continue;
+                }
+                Ok(_) => continue,
+            }
+            if self.config.format == Format::Json {
+                self.emit_json(
+                    "orphan",
+                    vec![
+                        ("path", Json::Str(self.shown_name(entry))),
+                        ("size", Json::Num(entry.len)),
+                    ],
+                );
+            } else if self.config.verbose > 1 {
+                println!("orphan {}", self.shown_name(entry));
+            }
+            let top = match path.strip_prefix(root).ok().and_then(|p| p.iter().next()) {
+                Some(top) => std::path::Path::new(root).join(top),
+                None => std::path::PathBuf::from(root),
+            };
+            let dir = by_dir
+                .entry(top.to_string_lossy().into_owned())
+                .or_default();
+            dir.0 += 1;
+            dir.1 += entry.len;
+            orphans.push(entry.clone());
+        }
+        let total: u64 = by_dir.values().map(|(_, bytes)| bytes).sum();
+        if self.config.format == Format::Json {
+            for (dir, (count, bytes)) in by_dir {
+                self.emit_json(
+                    "orphan_dir",
+                    vec![
+                        ("path", Json::Str(dir)),
+                        ("files", Json::Num(count as u64)),
+                        ("bytes", Json::Num(bytes)),
+                    ],
+                );
+            }
+            self.emit_json(
+                "orphans",
+                vec![
+                    ("files", Json::Num(orphans.len() as u64)),
+                    ("bytes", Json::Num(total)),
+                    ("pruned", Json::Bool(self.config.prune_orphans)),
+                ],
+            );
+        } else {
+            for (dir, (count, bytes)) in &by_dir {
+                println!("{:9} orphans {:>14} bytes in {}", count, bytes, dir);
+            }
+            println!("{} orphans, {}", orphans.len(), format_gib(total));
+        }
+        if self.config.prune_orphans {
+            for entry in &orphans {
+                if let Some((entry, hash)) = self.index.remove(entry) {
+                    self.remove_from_group(&entry, hash);
+                }
+            }
+            if self.config.format != Format::Json {
+                println!("pruned {} orphans", orphans.len());
+            }
+        } else if !orphans.is_empty() && self.config.format != Format::Json {
+            println!("use --prune-orphans to remove them from the archive");
+        }
+        orphans.len()
+    }
+
+    /// An entry as the archive keeps it, in its own spelling of
+    /// --map-prefix paths and with the owner --uid-map mapped
+    ///
+    ///   An owner is only put back while it is still the mapped one, as
+    ///   an entry refreshed with another owner this run keeps that.
+    fn archived_form(&self, entry: &Arc<Entry>) -> Arc<Entry> {
+        let name = match self.is_private() {
+            true => None,
+            false => self.unmapped_name(&entry.name),
+        };
+        let uid = self
+            .owners
+            .get(&entry.name)
+            .and_then(|owner| (owner.0 == entry.uid).then_some(owner.1));
+        if name.is_none() && uid.is_none() {
+            return entry.clone();
+        }
+        Arc::new(Entry {
+            name: name.unwrap_or_else(|| entry.name.clone()),
+            uid: uid.unwrap_or(entry.uid),
+            ..(**entry).clone()
+        })
+    }
+
+    /// An archived name as spelled before --map-prefix, None if unmapped
+    fn unmapped_name(&self, name: &str) -> Option<String> {
+        self.config
+            .prefix_map
+            .iter()
+            .find_map(|(old, new)| rebase_path(name, new, old))
+    }
+
+    /// An archived name in the spelling chosen with --show
+    fn shown_name(&self, entry: &Entry) -> String {
+        if self.is_private() {
+            return "(match withheld)".to_string();
+        }
+        if self.config.show_original {
+            if let Some(name) = self.unmapped_name(&entry.name) {
+                return name;
+            }
+        }
+        entry.name.clone()
+    }
+
+    /// Remove entries not seen during the scan, returns how many
+    pub async fn prune(&self) -> Result<usize> {
+        let before = self.index.len();
+        if self.present.len() > 0 {
+            if self.config.verbose > 0 {
+                eprintln!("pruning files not injested");
+            }
+            // remove in place rather than gathering a list of entries
+            self.index.retain(|entry, hash| {
+                // by digest alone, deliberately: a collision keeps an
+                // entry that should go, never drops one that was seen,
+                // see PresentSet.  A file outside the times may have
+                // changed since it was archived, so it is kept by name,
+                // see outside_times
+                if self.present.contains(&entry.identity_digest())
+                    || self.seen_paths.contains(&entry.name)
+                {
+                    return true;
+                }
+                if self.config.verbose > 1 {
+                    eprintln!("pruning {}", self.shown_name(entry));
+                } else {
+                    self.emit_line(&self.shown_name(entry));
+                }
+                self.remove_from_group(entry, *hash);
+                false
+            });
+        } else {
+            eprintln!("Nothing found, will not prune entire archive!");
+        }
+        let mut pruned_dirs = 0;
+        if self.records_dirs() && !self.seen_dirs.is_empty() {
+            let dirs = self.dirs.len();
+            self.dirs.retain(|name, _| self.seen_dirs.contains(name));
+            pruned_dirs = dirs - self.dirs.len();
+        }
+        if self.config.symlinks == Symlinks::Record && !self.seen_symlinks.is_empty() {
+            let links = self.symlinks.len();
+            self.symlinks
+                .retain(|name, _| self.seen_symlinks.contains(name));
+            pruned_dirs += links - self.symlinks.len();
+        }
+        Ok(before - self.index.len() + pruned_dirs)
+    }
+
+    pub async fn report(&self) -> Result<()> {
+        let mut ndup_big = 0;
+        let mut ndup = 0;
+        let mut total_size = 0;
+        let mut total_shared = 0;
+        let mut total_reflinked = 0;
+        let mut nanomaly = 0;
+        let mut anomaly_members = 0;
+        let mut nunverified = 0;
+        let mut nweak = 0;
+        let mut nsplit = 0;
+        let mut paranoid_left_out = 0;
+        // groups split, and members left out and why, by --verify-groups
+        let mut nverify_split = 0;
+        let mut verify_left_out = Vec::new();
+        let mut nundecided = 0;
+        // groups left out once the run was interrupted
+        let mut cut_short = false;
+        // reclaimable bytes by action, and filesystems probed, for --feasibility
+        let mut feasible = Feasible::default();
+        let mut caps = HashMap::new();
+        // waste and redundant files per directory, for --by-dir
+        let mut by_dir: HashMap<String, (usize, u64)> = HashMap::new();
+        if self.config.list {
+            for item in self.index.iter() {
+                let entry = item.key();
+                if self.config.format == Format::Csv {
+                    self.emit_csv("", entry, *item.value(), "");
+                } else if self.is_private() {
+                    self.emit_line(&format!("{} {}", group_id(*item.value(), 16), entry.len));
+                } else if self.config.verbose > 1 {
+                    let mtime =
+                        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(entry.mod_secs));
+                    self.emit_line(&format!(
+                        "{:9} {:?} {}",
+                        entry.len,
+                        mtime,
+                        self.shown_name(entry)
+                    ));
+                } else {
+                    self.emit_line(&self.shown_name(entry));
+                }
+            }
+        }
+
+        if self.config.duplicate || self.config.report {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let score = |files: &[Arc<Entry>]| {
+                cleanup_score(
+                    files,
+                    now,
+                    self.config.age_weight,
+                    self.config.copies_weight,
+                )
+            };
+            let mut groups = self.snapshot_groups();
+            groups.retain(|(_, files)| self.groups_len(files[0].len));
+            let ids = group_ids(&groups);
+            if !self.config.groups.is_empty() {
+                let wanted = self
+                    .config
+                    .groups
+                    .iter()
+                    .map(|id| resolve_group_id(id, &ids))
+                    .collect::<Result<std::collections::HashSet<FileHash>>>()?;
+                groups.retain(|(hash, _)| wanted.contains(hash));
+            }
+            if self.config.duplicate
+                && self.config.injest
+                && self.config.format == Format::Plain
+                && !self.config.print0
+            {
+                status!("# verified: {}", self.config.verify_exports);
+            }
+            // members and groups both come out of DashMaps in no
+            // particular order, sort them so runs agree
+            for (_, files) in groups.iter_mut() {
+                files.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+            let order = self.config.order_by.unwrap_or(OrderBy::Waste);
+            let key = |files: &[Arc<Entry>]| match order {
+                OrderBy::Score => score(files),
+                OrderBy::Waste => group_waste(files) as f64,
+                OrderBy::Count => files.len() as f64,
+                OrderBy::Size => files.iter().map(|f| f.len).max().unwrap_or(0) as f64,
+            };
+            let mut keyed: Vec<_> = groups.into_iter().map(|g| (key(&g.1), g)).collect();
+            // largest first, ties by hash
+            keyed.sort_by(|a, b| {
+                b.0.partial_cmp(&a.0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| (a.1).0.hash().cmp(&(b.1).0.hash()))
+            });
+            groups = keyed.into_iter().map(|(_, g)| g).collect();
+            if self.config.sorted.is_some() && self.config.order_by.is_none() {
+                groups.sort_by(|a, b| a.1[0].name.cmp(&b.1[0].name));
+            }
+            // --paranoid splits groups where they stand in the order
+            let mut parts = Vec::new();
+            for (hash, files) in groups {
+                if crate::interrupted() {
+                    cut_short = true;
+                    break;
+                }
+                // --paranoid compares the bytes of a partial match itself
+                let byte_compared = self.config.paranoid && hash.is_partial();
+                if let Some(algorithm) = self
+                    .config
+                    .verify_groups
+                    .filter(|_| !self.is_private() && !byte_compared)
+                {
+                    let (mut sets, mut left_out) = self.split_by_digest(&files, algorithm).await;
+                    let split = sets.len() > 1;
+                    // a member with a digest of its own is a duplicate no more
+                    for set in sets.iter().filter(|set| set.len() == 1) {
+                        let why = format!("its {} digest matches no other member", algorithm);
+                        left_out.push((set[0].name.clone(), why));
+                    }
+                    sets.retain(|set| set.len() > 1);
+                    let (whole, verified) = match split {
+                        true => {
+                            nverify_split += 1;
+                            (sets.len() == 1, Verified::Split)
+                        }
+                        false => (true, Verified::Agreed),
+                    };
+                    verify_left_out.append(&mut left_out);
+                    for (n, set) in sets.into_iter().enumerate() {
+                        let id = match whole {
+                            true => ids[&hash].clone(),
+                            false => format!("{}/{}", ids[&hash], n + 1),
+                        };
+                        parts.push((hash, id, set, Some(verified), false));
+                    }
+                    continue;
+                }
+                if !self.config.paranoid || self.is_private() {
+                    parts.push((hash, ids[&hash].clone(), files, None, false));
+                    continue;
+                }
+                let (sets, left_out) = self.split_identical(&files).await;
+                paranoid_left_out += left_out;
+                if sets.iter().map(|set| set.len()).sum::<usize>() != files.len() {
+                    nsplit += 1;
+                }
+                let whole = sets.len() == 1;
+                for (n, set) in sets.into_iter().enumerate() {
+                    let id = match whole {
+                        true => ids[&hash].clone(),
+                        false => format!("{}/{}", ids[&hash], n + 1),
+                    };
+                    // its bytes were compared, partial hash or not
+                    parts.push((hash, id, set, None, true));
+                }
+            }
+            for (hash, id, files, verified, compared) in parts {
+                if crate::interrupted() {
+                    cut_short = true;
+                    break;
+                }
+                if self.config.anomaly_size > 0 && files.len() > self.config.anomaly_size {
+                    // far too many members to be real duplicates, keep them
+                    // out of the totals so they don't swamp the summary
+                    nanomaly += 1;
+                    anomaly_members += files.len();
+                    if self.config.verbose > 0 {
+                        eprintln!(
+                            "anomalous group {} of {} files of {} bytes, e.g. {}",
+                            id,
+                            files.len(),
+                            files[0].len,
+                            files[0].name
+                        );
+                    }
+                } else {
+                    // a private archive has no paths to open
+                    let private = self.is_private();
+                    let files = match self.config.count_hardlinks {
+                        true => files,
+                        false => group_hardlinks(files),
+                    };
+                    let mut shared = if self.config.detect_reflinks && !private {
+                        self.shared_with_earlier(&files)
+                    } else {
+                        vec![0; files.len()]
+                    };
+                    // deleting a link to a member kept frees nothing
+                    if !self.config.count_hardlinks {
+                        for (i, linked) in hardlinked(&files).into_iter().enumerate() {
+                            if linked {
+                                shared[i] = files[i].len;
+                            }
+                        }
+                    }
+                    let plan = self.plan(&id, &files, &mut nundecided);
+                    let actions = match self.config.feasibility && !private {
+                        true => self.feasibility(&files, &plan, &mut caps),
+                        false => Vec::new(),
+                    };
+                    feasible.add(Feasible::of(&files, &shared, &actions));
+                    if self.config.duplicate && self.config.injest {
+                        // if we are not checking and are reporting duplicates
+                        // do so here
+                        let mut header = if hash.is_partial() {
+                            "Archive duplicates (partial hash)".to_string()
+                        } else {
+                            "Archive duplicates".to_string()
+                        };
+                        if self.config.order_by == Some(OrderBy::Score) {
+                            header += &format!(" (score {:.0})", score(&files));
+                        }
+                        // these lists get fed to deletion tools, leave
+                        // out any group whose bytes turn out to differ
+                        if private
+                            || self
+                                .verify_group(hash, &files, now, self.config.verify_exports)
+                                .await
+                        {
+                            self.print_group(
+                                &header,
+                                hash,
+                                &id,
+                                &files,
+                                &shared,
+                                Some(&plan),
+                                &actions,
+                                verified,
+                            )?;
+                        } else {
+                            nunverified += 1;
+                        }
+                    }
+                    ndup += 1;
+                    if self.config.by_dir && !private {
+                        self.charge_dirs(&files, &shared, &plan, &mut by_dir);
+                    }
+                    let group_shared: u64 = shared.iter().sum();
+                    total_size += group_waste(&files).saturating_sub(group_shared);
+                    total_shared += group_shared;
+                    if self.config.reflink && !private {
+                        let evidence = Evidence::compared(hash, &files, compared);
+                        if evidence == Evidence::Full {
+                            total_reflinked += self.reflink_group(&files, &shared, &plan);
+                        } else if !self.config.allow_weak_evidence {
+                            nweak += 1;
+                            if self.config.verbose > 0 {
+                                eprintln!("not acting on group {} with {} evidence", id, evidence);
+                            }
+                        } else if self.verify_group(hash, &files, now, VerifyMode::Full).await {
+                            total_reflinked += self.reflink_group(&files, &shared, &plan);
+                        } else {
+                            nweak += 1;
+                        }
+                    }
+                    if files.iter().any(|f| f.len > self.config.big_threshold) {
+                        ndup_big += 1;
+                    }
+                }
+            }
+
+            if self.config.format == Format::Json {
+                let mut fields = vec![
+                    ("groups", Json::Num(ndup as u64)),
+                    ("big_groups", Json::Num(ndup_big as u64)),
+                    ("waste_bytes", Json::Num(total_size)),
+                    ("unverified_groups", Json::Num(nunverified as u64)),
+                    ("anomalous_groups", Json::Num(nanomaly as u64)),
+                    (
+                        "verified",
+                        Json::Str(self.config.verify_exports.to_string()),
+                    ),
+                ];
+                if self.config.detect_reflinks {
+                    fields.push(("shared_bytes", Json::Num(total_shared)));
+                }
+                if self.config.paranoid {
+                    fields.push(("paranoid_split_groups", Json::Num(nsplit as u64)));
+                    fields.push(("paranoid_left_out", Json::Num(paranoid_left_out as u64)));
+                }
+                if self.config.verify_groups.is_some() {
+                    fields.push(("verify_split_groups", Json::Num(nverify_split as u64)));
+                    fields.push(("verify_left_out", Json::Num(verify_left_out.len() as u64)));
+                    for (path, why) in verify_left_out {
+                        self.emit_json(
+                            "verify_left_out",
+                            vec![("path", Json::Str(path)), ("why", Json::Str(why))],
+                        );
+                    }
+                }
+                if self.config.policy.is_some() {
+                    fields.push(("policy_undecided_groups", Json::Num(nundecided as u64)));
+                }
+                if self.config.feasibility && !self.is_private() {
+                    fields.push(("feasible_bytes", feasible.json()));
+                }
+                if self.config.reflink {
+                    fields.push(("reflinked_bytes", Json::Num(total_reflinked)));
+                    fields.push(("weak_groups_skipped", Json::Num(nweak as u64)));
+                }
+                let ms = |d: Duration| Json::Float(d.as_secs_f64() * 1000.0);
+                let sources = self
+                    .stats
+                    .slowest_sources()
+                    .iter()
+                    .map(|s| {
+                        Json::Obj(vec![
+                            ("source", Json::Str(s.label())),
+                            ("files", Json::Num(s.files())),
+                            ("p50_ms", ms(s.quantile(0.50))),
+                            ("p95_ms", ms(s.quantile(0.95))),
+                            ("p99_ms", ms(s.quantile(0.99))),
+                            ("mb_per_sec", Json::Float(s.mb_per_sec())),
+                        ])
+                    })
+                    .collect();
+                fields.push(("hash_sources", Json::List(sources)));
+                self.print_by_dir(by_dir);
+                self.emit_json("summary", fields);
+                return Ok(());
+            }
+            // keep stdout to the rows or names when writing CSV, fdupes or NULs
+            let say = |line: String| {
+                if matches!(self.config.format, Format::Csv | Format::Fdupes) || self.config.print0
+                {
+                    status!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            };
+            say(format!(
+                "{} dup, {} dup big, {} total dup",
+                ndup,
+                ndup_big,
+                format_gib(total_size)
+            ));
+            if self.config.detect_reflinks {
+                say(format!(
+                    "{} bytes dup already sharing extents",
+                    total_shared
+                ));
+            }
+            if self.config.feasibility && self.is_private() {
+                eprintln!("feasibility: a private archive keeps no paths to look at");
+            } else if self.config.feasibility {
+                let mut line = format!(
+                    "{} hardlinkable, {} reflinkable only, {} requires deletion",
+                    format_size(feasible.hardlink),
+                    format_size(feasible.reflink),
+                    format_size(feasible.delete)
+                );
+                if feasible.unknown > 0 {
+                    line += &format!(", {} not looked at", format_size(feasible.unknown));
+                }
+                say(line);
+            }
+            if self.config.reflink {
+                say(format!("{} bytes deduplicated by reflink", total_reflinked));
+            }
+            if cut_short {
+                say("interrupted, groups after these were left out".to_string());
+            }
+            if nweak > 0 {
+                say(format!(
+                    "{} groups with weak evidence not acted on, --allow-weak-evidence \
+                     includes them after comparing their bytes",
+                    nweak
+                ));
+            }
+            if nunverified > 0 {
+                say(format!(
+                    "{} groups failed {} verification and were not listed",
+                    nunverified, self.config.verify_exports
+                ));
+            }
+            if nsplit > 0 {
+                say(format!(
+                    "{} groups were not all identical bytes and were split by --paranoid, \
+                     {} files left out",
+                    nsplit, paranoid_left_out
+                ));
+            }
+            if let Some(algorithm) = self.config.verify_groups.filter(|_| !self.is_private()) {
+                say(format!(
+                    "{} groups split by {}, {} members left out",
+                    nverify_split,
+                    algorithm,
+                    verify_left_out.len()
+                ));
+                for (path, why) in verify_left_out {
+                    status!("left out: {} ({})", path, why);
+                }
+            } else if self.config.verify_groups.is_some() {
+                eprintln!("verify-groups: a private archive keeps no paths to read");
+            }
+            if nundecided > 0 {
+                say(format!(
+                    "{} groups not decided by --policy kept their largest member instead",
+                    nundecided
+                ));
+            }
+            if nanomaly > 0 {
+                say(format!(
+                    "{} anomalous groups with {} files not counted, check the hashing mode",
+                    nanomaly, anomaly_members
+                ));
+            }
+            self.print_by_dir(by_dir);
+        }
+        return Ok(());
+    }
+
+    /// Report the pairs of files sharing at least --similar percent of
+    /// their chunks, see similar.rs
+    ///
+    ///   Files whose chunk hashes the archive doesn't have as they are
+    ///   now are read on the hash pool, and kept if injesting.
+    pub async fn report_similar(&self) -> Result<()> {
+        use futures::stream::FuturesUnordered;
+
+        let percent = match self.config.similar {
+            Some(percent) => percent,
+            None => return Ok(()),
+        };
+        if self.is_private() {
+            eprintln!("similar: a private archive keeps no paths to read");
+            return Ok(());
+        }
+        let chunking = self.chunking();
+        let mut kept: HashMap<String, similar::Chunked> = similar::stored(&self.archive)
+            .await?
+            .into_iter()
+            .map(|file| (file.name.clone(), file))
+            .collect();
+        let big: Vec<Arc<Entry>> = self
+            .index
+            .iter()
+            .map(|item| item.key().clone())
+            .filter(|f| f.is_file && f.len >= self.config.similar_min_size)
+            .collect();
+        let mut files = Vec::new();
+        let mut reading = FuturesUnordered::new();
+        for f in big {
+            match kept.remove(&f.name) {
+                Some(file)
+                    if (file.len, file.mod_secs, file.mod_nanos)
+                        == (f.len, f.mod_secs, f.mod_nanos)
+                        && file.chunking == chunking.to_string() =>
+                {
+                    files.push((f, file));
+                    continue;
+                }
+                _ => (),
+            }
+            let (path, stats): (std::path::PathBuf, _) =
+                (f.name.clone().into(), self.stats.clone());
+            let read = self.config.hash_pool.run(None, move || {
+                let chunks = chunk_hashes(&path, chunking, &stats);
+                (f, chunks)
+            });
+            reading.push(read);
+        }
+        let mut nread = 0;
+        while let Some(read) = reading.next().await {
+            // with no limit there is always a result
+            match read? {
+                Some((f, Ok(chunks))) => {
+                    nread += 1;
+                    let file = similar::Chunked {
+                        name: f.name.clone(),
+                        len: f.len,
+                        mod_secs: f.mod_secs,
+                        mod_nanos: f.mod_nanos,
+                        chunking: chunking.to_string(),
+                        chunks,
+                    };
+                    files.push((f, file));
+                }
+                Some((f, Err(e))) => eprintln!("similar: {} ({}), left out", e, f.name),
+                None => (),
+            }
+        }
+        files.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        let (entries, files): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+        if self.config.injest && nread > 0 && !crate::pack::is_packed(&self.archive) {
+            let n = similar::store(&self.archive, &files).await?;
+            status!("kept the chunk hashes of {} files", n);
+        }
+        let pairs = similar::pairs(&files, percent);
+        for pair in &pairs {
+            let (a, b) = (&entries[pair.a], &entries[pair.b]);
+            if self.config.format == Format::Json {
+                self.emit_json(
+                    "similar",
+                    vec![
+                        (
+                            "members",
+                            Json::List(vec![
+                                Json::Str(self.shown_name(a)),
+                                Json::Str(self.shown_name(b)),
+                            ]),
+                        ),
+                        (
+                            "sizes",
+                            Json::List(vec![Json::Num(a.len), Json::Num(b.len)]),
+                        ),
+                        ("percent", Json::Float(pair.percent)),
+                        ("shared_bytes", Json::Num(pair.shared_bytes)),
+                    ],
+                );
+            } else {
+                self.emit_line(&format!(
+                    "{:5.1}%  {:>10}  {}  {}",
+                    pair.percent,
+                    format_size(pair.shared_bytes),
+                    self.shown_name(a),
+                    self.shown_name(b)
+                ));
+            }
+        }
+        status!(
+            "similar: {} pairs sharing {}% or more of their chunks among {} files of {} or more, {} read",
+            pairs.len(),
+            percent,
+            files.len(),
+            format_size(self.config.similar_min_size),
+            nread
+        );
+        Ok(())
+    }
+
+    /// The member of a group to keep and those that may be acted on
+    ///
+    ///   Without --policy, or where it doesn't decide, the largest
+    ///   member is kept, the first by name among equals, as in
+    ///   group_waste.  Protect rules hold even then.  Undecided groups
+    ///   are counted in `undecided`.
+    fn plan(&self, id: &str, files: &[Arc<Entry>], undecided: &mut usize) -> Decision {
+        let largest = files
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.len.cmp(&b.1.len).then(b.0.cmp(&a.0)))
+            .map_or(0, |(i, _)| i);
+        let policy = match &self.config.policy {
+            // a private archive has no paths to judge by
+            Some(policy) if !self.is_private() => policy,
+            _ => return Decision::new(largest, &vec![false; files.len()]),
+        };
+        let names: Vec<String> = files.iter().map(|f| self.shown_name(f)).collect();
+        let members: Vec<Member<'_>> = files
+            .iter()
+            .zip(names.iter())
+            .map(|(f, name)| Member {
+                path: name,
+                size: f.len,
+                mtime: f.mod_secs,
+                uid: f.uid,
+            })
+            .collect();
+        policy.decide(&members).unwrap_or_else(|why| {
+            *undecided += 1;
+            if self.config.verbose > 0 {
+                eprintln!("policy: group {} not decided, {}", id, why);
+            }
+            Decision::new(largest, &policy.protected(&members))
+        })
+    }
+
+    /// Charge the waste of a group to the directories of its copies
+    ///
+    ///   Every member the plan may act on charges its size, less any
+    ///   extents it already shares, to its parent directory.
+    fn charge_dirs(
+        &self,
+        files: &[Arc<Entry>],
+        shared: &[u64],
+        plan: &Decision,
+        by_dir: &mut HashMap<String, (usize, u64)>,
+    ) {
+        for (i, f) in files.iter().enumerate() {
+            if !plan.act[i] {
+                continue;
+            }
+            let name = self.shown_name(f);
+            let dir = std::path::Path::new(&name)
+                .parent()
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let tally = by_dir.entry(dir).or_default();
+            tally.0 += 1;
+            tally.1 += f.len.saturating_sub(shared.get(i).copied().unwrap_or(0));
+        }
+    }
+
+    /// Print the directories with the most reclaimable bytes, for --by-dir
+    fn print_by_dir(&self, by_dir: HashMap<String, (usize, u64)>) {
+        if !self.config.by_dir {
+            return;
+        }
+        if self.is_private() {
+            eprintln!("by-dir: a private archive keeps no directories to total");
+            return;
+        }
+        let ndirs = by_dir.len();
+        let mut dirs: Vec<(String, (usize, u64))> = by_dir.into_iter().collect();
+        dirs.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then_with(|| a.0.cmp(&b.0)));
+        if self.config.top > 0 {
+            dirs.truncate(self.config.top);
+        }
+        if self.config.format == Format::Json {
+            for (dir, (files, bytes)) in dirs {
+                self.emit_json(
+                    "dir_waste",
+                    vec![
+                        ("path", Json::Str(dir)),
+                        ("files", Json::Num(files as u64)),
+                        ("waste_bytes", Json::Num(bytes)),
+                    ],
+                );
+            }
+            return;
+        }
+        status!("reclaimable by directory, top {} of {}:", dirs.len(), ndirs);
+        for (dir, (files, bytes)) in dirs {
+            self.emit_line(&format!(
+                "{:>10}  {:>6} files  {}",
+                format_size(bytes),
+                files,
+                dir
+            ));
+        }
+    }
+
+    /// Byte-compare members of a group per config.verify_exports
+    ///
+    ///   Sample compares one pair picked from `seed` and the group hash,
+    ///   full compares every member with the first.  Mismatches and
+    ///   read errors are listed on stderr, and make this return false.
+    async fn verify_group(
+        &self,
+        hash: FileHash,
+        files: &[Arc<Entry>],
+        seed: u64,
+        mode: VerifyMode,
+    ) -> bool {
+        let n = files.len();
+        let pairs: Vec<(usize, usize)> = match mode {
+            VerifyMode::None => Vec::new(),
+            VerifyMode::Sample => {
+                let r = seahash::hash(&(seed ^ hash.hash()).to_le_bytes()) as usize;
+                let i = r % n;
+                vec![(i, (i + 1 + (r >> 32) % (n - 1)) % n)]
+            }
+            VerifyMode::Full => (1..n).map(|j| (0, j)).collect(),
+        };
+        let mut ok = true;
+        for (i, j) in pairs {
+            let (a, b) = (&files[i], &files[j]);
+            match same_content(&PathBuf::from(&a.name), &PathBuf::from(&b.name)).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!(
+                        "verify: {} ({} bytes) differs from {} ({} bytes)",
+                        a.name, a.len, b.name, b.len
+                    );
+                    ok = false;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "verify: {} ({} bytes) vs {} ({} bytes): {}",
+                        a.name, a.len, b.name, b.len, e
+                    );
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    /// Print the members of a group, at most config.group_limit of them
+    ///
+    ///   Writes straight to stdout rather than joining names, as a
+    ///   group can have hundreds of thousands of members.  `shared`
+    ///   is either empty or holds the extent sharing for each member.
+    ///   The group id is in the header, or with -v a `# group` line in
+    ///   plain mode, which is otherwise a bare list of names.
+    ///   With --policy, JSON output names what the plan keeps and acts on,
+    ///   and with --feasibility, `actions` is each member's action.  With
+    ///   --verify-groups, `verified` says if the group held together.
+    #[allow(clippy::too_many_arguments)]
+    fn print_group(
+        &self,
+        header: &str,
+        hash: FileHash,
+        id: &str,
+        files: &[Arc<Entry>],
+        shared: &[u64],
+        plan: Option<&Decision>,
+        actions: &[Action],
+        verified: Option<Verified>,
+    ) -> Result<()> {
+        use std::io::Write;
+
+        let shown = match self.config.group_limit {
+            0 => files.len(),
+            limit => limit.min(files.len()),
+        };
+        // bytes freed by deleting all but one, less what is already shared
+        let reclaimable = group_waste(files).saturating_sub(shared.iter().sum());
+        let evidence = Evidence::of(hash, files);
+        if self.config.format == Format::Json {
+            let members = files[..shown]
+                .iter()
+                .map(|f| Json::Str(self.shown_name(f)))
+                .collect();
+            let mut fields = vec![
+                ("id", Json::Str(id.to_string())),
+                ("hash", Json::Str(group_id(hash, 16))),
+                ("partial", Json::Bool(hash.is_partial())),
+                (
+                    "size",
+                    Json::Num(files.iter().map(|f| f.len).max().unwrap_or(0)),
+                ),
+                ("count", Json::Num(files.len() as u64)),
+                ("reclaimable", Json::Num(reclaimable)),
+                ("evidence", Json::Str(evidence.to_string())),
+                ("members", Json::List(members)),
+            ];
+            if let (Some(verified), Some(algorithm)) = (verified, self.config.verify_groups) {
+                fields.push(("verified", Json::Str(verified.to_string())));
+                fields.push(("verified_by", Json::Str(algorithm.to_string())));
+            }
+            if let Some(plan) = plan.filter(|_| self.config.policy.is_some()) {
+                fields.push(("keep", Json::Str(self.shown_name(&files[plan.keep]))));
+                let acted = files
+                    .iter()
+                    .zip(plan.act.iter())
+                    .filter(|(_, act)| **act)
+                    .map(|(f, _)| Json::Str(self.shown_name(f)))
+                    .collect();
+                fields.push(("act_on", Json::List(acted)));
+            }
+            if !actions.is_empty() {
+                let listed = actions[..shown]
+                    .iter()
+                    .map(|a| Json::Str(a.to_string()))
+                    .collect();
+                fields.push(("actions", Json::List(listed)));
+                let possible = |action| Json::Bool(actions.contains(&action));
+                fields.push((
+                    "feasible",
+                    Json::Obj(vec![
+                        ("hardlink", possible(Action::Hardlink)),
+                        ("reflink", possible(Action::Reflink)),
+                        ("delete", possible(Action::Delete)),
+                    ]),
+                ));
+                fields.push((
+                    "feasible_bytes",
+                    Feasible::of(files, shared, actions).json(),
+                ));
+            }
+            if shared.iter().any(|s| *s > 0) {
+                let shared = shared[..shown].iter().map(|s| Json::Num(*s)).collect();
+                fields.push(("shared", Json::List(shared)));
+            }
+            if self.config.order_by == Some(OrderBy::Score) {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let score = cleanup_score(
+                    files,
+                    now,
+                    self.config.age_weight,
+                    self.config.copies_weight,
+                );
+                fields.push(("score", Json::Float(score)));
+            }
+            self.emit_json("group", fields);
+            return Ok(());
+        }
+        if self.config.format == Format::Csv {
+            for f in &files[..shown] {
+                self.emit_csv(id, f, hash, &evidence.to_string());
+            }
+            return Ok(());
+        }
+        // with --sort the group is held whole, keyed by its first member
+        let mut held = Vec::new();
+        let stdout = std::io::stdout();
+        let mut lock;
+        let out: &mut dyn Write = if self.holding() {
+            &mut held
+        } else {
+            lock = stdout.lock();
+            &mut lock
+        };
+        if self.config.print0 {
+            // as fdupes -z: names end in a NUL, groups in a second one
+            for f in &files[..shown] {
+                write!(out, "{}\0", self.shown_name(f))?;
+            }
+            write!(out, "\0")?;
+            if shown < files.len() {
+                status!("... and {} more", files.len() - shown);
+            }
+            self.hold_group(files, held);
+            return Ok(());
+        }
+        if self.config.format == Format::Fdupes {
+            if self.config.verbose > 0 {
+                let size = files.iter().map(|f| f.len).max().unwrap_or(0);
+                writeln!(out, "{} bytes each:", size)?;
+            }
+            for f in &files[..shown] {
+                writeln!(out, "{}", self.shown_name(f))?;
+            }
+            writeln!(out)?;
+            if shown < files.len() {
+                status!("... and {} more", files.len() - shown);
+            }
+            self.hold_group(files, held);
+            return Ok(());
+        }
+        let mut sizes = format!(
+            "{} files, {} reclaimable",
+            files.len(),
+            format_size(reclaimable)
+        );
+        if evidence != Evidence::Full {
+            sizes += &format!(", {} evidence", evidence);
+        }
+        if let (Some(verified), Some(algorithm)) = (verified, self.config.verify_groups) {
+            sizes += &format!(", {} by {}", verified, algorithm);
+        }
+        if self.config.verbose > 1 {
+            write!(out, "{} [{}], {}: ", header, id, sizes)?;
+        } else if self.config.verbose > 0 {
+            writeln!(out, "# group {}: {}", id, sizes)?;
+        }
+        let linked = match self.config.count_hardlinks {
+            true => vec![false; files.len()],
+            false => hardlinked(files),
+        };
+        for (i, f) in files[..shown].iter().enumerate() {
+            let mark = if linked[i] {
+                " [hardlink]"
+            } else if shared.get(i).copied().unwrap_or(0) > 0 {
+                " [shares extents]"
+            } else {
+                ""
+            };
+            if self.config.verbose > 1 {
+                let sep = if i > 0 { ", " } else { "" };
+                write!(out, "{}{}{}", sep, self.shown_name(f), mark)?;
+            } else {
+                writeln!(out, "{}{}", self.shown_name(f), mark)?;
+            }
+        }
+        if self.config.verbose > 1 {
+            if shown < files.len() {
+                write!(out, " and {} more", files.len() - shown)?;
+            }
+            writeln!(out)?;
+        } else if shown < files.len() {
+            // keep stdout a plain list of names
+            status!("... and {} more", files.len() - shown);
+        }
+        self.hold_group(files, held);
+        Ok(())
+    }
+
+    /// Hold a group written by print_group for --sort, if it was
+    fn hold_group(&self, files: &[Arc<Entry>], held: Vec<u8>) {
+        if let (Some(sorted), Some(first)) = (&self.config.sorted, files.first()) {
+            if !held.is_empty() {
+                sorted.hold(&self.shown_name(first), &held);
+            }
+        }
+    }
+
+    /// Bytes each group member shares physically with an earlier member
+    ///
+    ///   Hardlinks and reflinks both show up here, and deleting such a
+    ///   member frees only its unshared bytes.
+    fn shared_with_earlier(&self, files: &[Arc<Entry>]) -> Vec<u64> {
+        let extents: Vec<Vec<reflink::Extent>> = files
+            .iter()
+            .map(|f| match reflink::extents(std::path::Path::new(&f.name)) {
+                Ok(extents) => extents,
+                Err(e) => {
+                    if self.config.verbose > 0 {
+                        eprintln!("extents: {} ({})", e, f.name);
+                    }
+                    Vec::new()
+                }
+            })
+            .collect();
+        let mut ret = Vec::new();
+        for (i, f) in files.iter().enumerate() {
+            let shared = extents[..i]
+                .iter()
+                .map(|earlier| reflink::overlap(earlier, &extents[i]))
+                .max()
+                .unwrap_or(0);
+            ret.push(shared.min(f.len));
+        }
+        ret
+    }
+
+    /// The cheapest action that frees each member's bytes
+    ///
+    ///   A member the plan may act on can be made a hard link to the one
+    ///   kept if both are on a filesystem with hard links and have the
+    ///   same owner and mode, as one of them would lose its own.  Else
+    ///   it can share the kept one's extents if the filesystem has
+    ///   reflinks, else only deleting it frees anything.  Filesystems
+    ///   are probed once per device, kept in `caps`.
+    fn feasibility(
+        &self,
+        files: &[Arc<Entry>],
+        plan: &Decision,
+        caps: &mut HashMap<u64, Option<reflink::Caps>>,
+    ) -> Vec<Action> {
+        use std::os::unix::fs::MetadataExt;
+
+        let stat = |f: &Entry| match std::fs::metadata(&f.name) {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                if self.config.verbose > 0 {
+                    eprintln!("feasibility: {} ({})", e, f.name);
+                }
+                None
+            }
+        };
+        let kept = stat(&files[plan.keep]);
+        let kept_caps = kept.as_ref().and_then(|meta| {
+            *caps.entry(meta.dev()).or_insert_with(|| {
+                reflink::capabilities(std::path::Path::new(&files[plan.keep].name))
+                    .map_err(|e| eprintln!("feasibility: {} ({})", e, files[plan.keep].name))
+                    .ok()
+            })
+        });
+        files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                if i == plan.keep {
+                    return Action::Keep;
+                }
+                if !plan.act[i] {
+                    return Action::Protected;
+                }
+                let (kept, kept_caps, meta) = match (&kept, kept_caps, stat(f)) {
+                    (Some(kept), Some(caps), Some(meta)) => (kept, caps, meta),
+                    _ => return Action::Unknown,
+                };
+                if meta.dev() != kept.dev() {
+                    Action::Delete
+                } else if kept_caps.hardlinks
+                    && (meta.uid(), meta.gid(), meta.mode())
+                        == (kept.uid(), kept.gid(), kept.mode())
+                {
+                    Action::Hardlink
+                } else if kept_caps.reflinks {
+                    Action::Reflink
+                } else {
+                    Action::Delete
+                }
+            })
+            .collect()
+    }
+
+    /// Share the extents of the kept member with every member the
+    /// plan may act on
+    ///
+    ///   Returns the bytes the kernel actually deduplicated.
+    fn reflink_group(&self, files: &[Arc<Entry>], shared: &[u64], plan: &Decision) -> u64 {
+        let kept = std::path::Path::new(&files[plan.keep].name);
+        let mut deduped = 0;
+        for ((f, s), act) in files.iter().zip(shared.iter()).zip(plan.act.iter()) {
+            if !act || *s >= f.len {
+                continue;
+            }
+            match reflink::dedupe(kept, std::path::Path::new(&f.name), f.len) {
+                Ok(n) => {
+                    if self.config.verbose > 1 {
+                        eprintln!("reflinked {} bytes of {}", n, f.name);
+                    }
+                    deduped += n;
+                }
+                Err(e) => eprintln!("reflink: {} ({})", e, f.name),
+            }
+        }
+        deduped
+    }
+
+    /// Report the files of a second archive missing from this one, or
+    /// present in it
+    ///
+    ///   Files either archive left waiting unhashed are hashed first
+    ///   where the other has a file of their size, see defer_hash.  Those
+    ///   that can't be, their files changed or gone, leave the files of
+    ///   their size in the second archive unknown, which are counted on
+    ///   stderr rather than reported.
+    pub async fn find_dups_second_archive(&self, second: &FileStore) -> Result<()> {
+        self.comparable(second)?;
+        let sizes = |store: &FileStore| -> std::collections::HashSet<u64> {
+            store
+                .index
+                .iter()
+                .filter(|item| item.key().is_file)
+                .map(|item| item.key().len)
+                .collect()
+        };
+        for (store, other) in [(self, second), (second, self)] {
+            let other_sizes = sizes(other);
+            let keys: Vec<WaitKey> = store
+                .pending
+                .iter()
+                .map(|item| *item.key())
+                .filter(|(len, _)| other_sizes.contains(len))
+                .collect();
+            store.hash_waiting(keys).await;
+        }
+        let unhashed: std::collections::HashSet<u64> =
+            self.pending.iter().map(|item| item.key().0).collect();
+        let compared = sizes(self);
+        let mut unknown = 0;
+        for item in second.index.iter() {
+            let entry = item.key();
+            let present = self.hindex.contains_key(&item.value());
+            let unsure = match item.value().is_pending() {
+                true => compared.contains(&entry.len),
+                false => !present && unhashed.contains(&entry.len),
+            };
+            if unsure {
+                unknown += 1;
+                continue;
+            }
+            {
+                if self.config.missing && !present {
+                    if self.config.verbose > 1 {
+                        self.emit_line(&format!(
+                            "{} is present not in archive",
+                            second.shown_name(entry)
+                        ));
+                    } else {
+                        self.emit_line(&second.shown_name(entry));
+                    }
+                }
+                if self.config.present && present && entry.len > 0 {
+                    if self.config.verbose > 1 {
+                        let files = self.hindex.get(&item.value()).unwrap();
+                        let names: Vec<String> = files.iter().map(|f| self.shown_name(f)).collect();
+                        self.emit_line(&format!(
+                            "{} is present in archive at {}",
+                            second.shown_name(entry),
+                            names.join(", ")
+                        ));
+                    } else {
+                        self.emit_line(&second.shown_name(entry));
+                    }
+                }
+            }
+        }
+        if unknown > 0 {
+            eprintln!(
+                "{} files of the second archive not compared, left unhashed \
+                 beside a file of their size that can no longer be read",
+                unknown
+            );
+        }
+        Ok(())
+    }
+}
+
+/// How results are written to stdout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    Json,
+    Csv,
+    /// As fdupes prints them: names one per line, a blank line after each group
+    Fdupes,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "fdupes" => Ok(Format::Fdupes),
+            _ => Err(format!("unknown format {:?}, use plain|json|csv|fdupes", s)),
+        }
+    }
+}
+
+/// True if a file's hash goes in a checksum manifest, one of its
+/// whole contents
+fn in_manifest(hash: &FileHash) -> bool {
+    !hash.is_pending() && !hash.is_partial()
+}
+
+/// Escape a name for a checksum manifest line as coreutils does
+fn escape_manifest_name(name: &str) -> String {
+    name.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Undo escape_manifest_name
+fn unescape_manifest_name(name: &str) -> String {
+    let mut ret = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('r') => ret.push('\r'),
+            Some(c) => ret.push(c),
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+/// Quote a CSV field if it holds a separator, quote or line break
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+/// One JSON object from name, value pairs
+pub(crate) fn json_object(fields: Vec<(&str, Json)>) -> String {
+    let mut line = String::from("{");
+    for (i, (name, value)) in fields.into_iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line += &format!("{}:{}", Json::Str(name.to_string()), value);
+    }
+    line.push('}');
+    line
+}
+
+/// Where --events ndjson lines go, stdout or --events-file
+#[derive(Debug)]
+pub struct EventSink {
+    file: Option<std::sync::Mutex<std::io::LineWriter<std::fs::File>>>,
+}
+
+impl EventSink {
+    pub fn stdout() -> Self {
+        EventSink { file: None }
+    }
+
+    /// Events written to `path`, replacing any earlier trace
+    pub fn file(path: &str) -> Result<Self> {
+        Ok(EventSink {
+            file: Some(std::sync::Mutex::new(std::io::LineWriter::new(
+                std::fs::File::create(path)?,
+            ))),
+        })
+    }
+
+    /// True if events share stdout with the results
+    pub fn on_stdout(&self) -> bool {
+        self.file.is_none()
+    }
+
+    /// Write one line: the event, when, then `fields`
+    pub fn emit(&self, event: &str, fields: Vec<(&str, Json)>) {
+        use std::io::Write;
+
+        let line = event_line(event, fields);
+        match &self.file {
+            None => println!("{}", line),
+            Some(file) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    eprintln!("events-file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// One line of --events ndjson, also used by --findings
+pub fn event_line(event: &str, fields: Vec<(&str, Json)>) -> String {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as f64 / 1000.0)
+        .unwrap_or(0.0);
+    let mut object = vec![
+        ("event", Json::Str(event.to_string())),
+        ("ts", Json::Float(ts)),
+    ];
+    object.extend(fields);
+    json_object(object)
+}
+
+/// Just enough of JSON for --format json and --events ndjson
+#[derive(Debug)]
+pub enum Json {
+    Str(String),
+    Num(u64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Str(s) => {
+                // escape everything outside printable ASCII so odd
+                // paths survive any consumer
+                f.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        ' '..='~' => write!(f, "{}", c)?,
+                        _ => {
+                            let mut units = [0u16; 2];
+                            for unit in c.encode_utf16(&mut units) {
+                                write!(f, "\\u{:04x}", unit)?;
+                            }
+                        }
+                    }
+                }
+                f.write_str("\"")
+            }
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Float(x) if x.is_finite() => write!(f, "{}", x),
+            Json::Float(_) => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::List(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Obj(fields) => {
+                f.write_str("{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{}", Json::Str(name.to_string()), value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// How to order duplicate groups in the report, largest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderBy {
+    Score,
+    Waste,
+    Count,
+    Size,
+}
+
+impl std::str::FromStr for OrderBy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "score" => Ok(OrderBy::Score),
+            "waste" => Ok(OrderBy::Waste),
+            "count" => Ok(OrderBy::Count),
+            "size" => Ok(OrderBy::Size),
+            _ => Err(format!("unknown order {:?}, use score|waste|count|size", s)),
+        }
+    }
+}
+
+/// Shortest length of a group id, in hex digits
+const GROUP_ID_LEN: usize = 12;
+
+/// Group id of a hash, `len` digits long or all of it if shorter
+///
+///   Partial hashes get a trailing `p` so they never share an id with
+///   a full hash of the same value.
+pub fn group_id(hash: FileHash, len: usize) -> String {
+    let mut id = format!("{:016x}", hash.hash());
+    id.truncate(len.max(GROUP_ID_LEN));
+    if hash.is_partial() {
+        id.push('p');
+    }
+    id
+}
+
+/// Ids for a set of groups, extended past GROUP_ID_LEN where needed
+///
+///   An id only depends on the other groups when two share their first
+///   GROUP_ID_LEN digits, so ids stay put while the archive grows.
+pub fn group_ids(groups: &[(FileHash, Vec<Arc<Entry>>)]) -> HashMap<FileHash, String> {
+    let mut full: Vec<(String, FileHash)> = groups
+        .iter()
+        .map(|(hash, _)| (group_id(*hash, 16), *hash))
+        .collect();
+    full.sort_by(|a, b| a.0.cmp(&b.0));
+    let common = |a: &str, b: &str| a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    let mut ret = HashMap::new();
+    for i in 0..full.len() {
+        let mut len = 0;
+        if i > 0 {
+            len = len.max(common(&full[i - 1].0, &full[i].0) + 1);
+        }
+        if i + 1 < full.len() {
+            len = len.max(common(&full[i].0, &full[i + 1].0) + 1);
+        }
+        ret.insert(full[i].1, group_id(full[i].1, len));
+    }
+    ret
+}
+
+/// Find the group a user-supplied id names
+///
+///   Fails if no group or more than one group matches, which means
+///   the archive changed since the id was printed.
+pub fn resolve_group_id(id: &str, ids: &HashMap<FileHash, String>) -> Result<FileHash> {
+    let id = id.to_ascii_lowercase();
+    if let Some((hash, _)) = ids.iter().find(|(_, known)| **known == id) {
+        return Ok(*hash);
+    }
+    let (digits, partial) = match id.strip_suffix('p') {
+        Some(digits) => (digits, true),
+        None => (id.as_str(), false),
+    };
+    let matches: Vec<FileHash> = ids
+        .keys()
+        .filter(|hash| {
+            format!("{:016x}", (**hash).hash()).starts_with(digits)
+                && (!partial || hash.is_partial())
+        })
+        .copied()
+        .collect();
+    match matches.len() {
+        1 => Ok(matches[0]),
+        0 => Err(format!("no duplicate group with id {}", id).into()),
+        n => Err(format!("group id {} is ambiguous, it matches {} groups", id, n).into()),
+    }
+}
+
+/// How strongly a group's hash says its members are the same
+///
+///   Only full evidence is acted on without --allow-weak-evidence.
+///   Partial hashes only cover size, head and tail, every empty file
+///   matches every other, and members of differing sizes can't really
+///   be the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Evidence {
+    Full,
+    Partial,
+    Empty,
+    SizeMismatch,
+}
+
+impl Evidence {
+    pub fn of(hash: FileHash, files: &[Arc<Entry>]) -> Self {
+        let len = files.first().map_or(0, |f| f.len);
+        if files.iter().any(|f| f.len != len) {
+            Evidence::SizeMismatch
+        } else if len == 0 {
+            Evidence::Empty
+        } else if hash.is_partial() {
+            Evidence::Partial
+        } else {
+            Evidence::Full
+        }
+    }
+
+    /// The evidence of a group whose bytes --paranoid may have compared
+    ///
+    ///   A --media-mode partial match is full evidence once the bytes of
+    ///   its members are found to be the same.
+    pub fn compared(hash: FileHash, files: &[Arc<Entry>], compared: bool) -> Self {
+        match Evidence::of(hash, files) {
+            Evidence::Partial if compared => Evidence::Full,
+            evidence => evidence,
+        }
+    }
+}
+
+impl std::fmt::Display for Evidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Evidence::Full => "full",
+            Evidence::Partial => "partial",
+            Evidence::Empty => "empty",
+            Evidence::SizeMismatch => "size-mismatch",
+        })
+    }
+}
+
+/// What --verify-groups made of a group
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verified {
+    /// every member still there has the same digest
+    Agreed,
+    /// members disagreed, this is one of the sets that agree
+    Split,
+}
+
+impl std::fmt::Display for Verified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Verified::Agreed => "verified",
+            Verified::Split => "split",
+        })
+    }
+}
+
+/// The cheapest way to free a group member's bytes, see --feasibility
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Keep,
+    /// not to be acted on by the plan
+    Protected,
+    Hardlink,
+    Reflink,
+    Delete,
+    /// the member or the one kept couldn't be looked at
+    Unknown,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Action::Keep => "keep",
+            Action::Protected => "protected",
+            Action::Hardlink => "hardlink",
+            Action::Reflink => "reflink",
+            Action::Delete => "delete",
+            Action::Unknown => "unknown",
+        })
+    }
+}
+
+/// Reclaimable bytes by the cheapest action that frees them
+#[derive(Clone, Copy, Debug, Default)]
+struct Feasible {
+    hardlink: u64,
+    reflink: u64,
+    delete: u64,
+    unknown: u64,
+}
+
+impl Feasible {
+    /// Each member's unshared bytes, by its action
+    fn of(files: &[Arc<Entry>], shared: &[u64], actions: &[Action]) -> Self {
+        let mut feasible = Feasible::default();
+        for ((f, s), action) in files.iter().zip(shared).zip(actions) {
+            let bytes = f.len.saturating_sub(*s);
+            match action {
+                Action::Hardlink => feasible.hardlink += bytes,
+                Action::Reflink => feasible.reflink += bytes,
+                Action::Delete => feasible.delete += bytes,
+                Action::Unknown => feasible.unknown += bytes,
+                Action::Keep | Action::Protected => (),
+            }
+        }
+        feasible
+    }
+
+    fn add(&mut self, other: Feasible) {
+        self.hardlink += other.hardlink;
+        self.reflink += other.reflink;
+        self.delete += other.delete;
+        self.unknown += other.unknown;
+    }
+
+    fn json(&self) -> Json {
+        Json::Obj(vec![
+            ("hardlink", Json::Num(self.hardlink)),
+            ("reflink", Json::Num(self.reflink)),
+            ("delete", Json::Num(self.delete)),
+            ("unknown", Json::Num(self.unknown)),
+        ])
+    }
+}
+
+/// Results held back by --sort until the walk is done
+///
+///   Directories are walked concurrently, so results found along the
+///   way come out in a different order each run.  Only the results
+///   are kept, so memory grows with their number, not with the files
+///   scanned.  Each is kept with the key it is sorted by: its line,
+///   or the first member of a group.
+#[derive(Debug, Default)]
+pub struct SortedResults {
+    held: std::sync::Mutex<Vec<(String, Vec<u8>)>>,
+    released: AtomicBool,
+}
+
+impl SortedResults {
+    /// Keep a result for later, false if they are already released
+    fn hold(&self, key: &str, text: &[u8]) -> bool {
+        if self.released.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.held
+            .lock()
+            .unwrap()
+            .push((key.to_string(), text.to_vec()));
+        true
+    }
+
+    fn holding(&self) -> bool {
+        !self.released.load(Ordering::SeqCst)
+    }
+
+    /// Write the results held so far sorted, then stop holding them
+    pub fn release(&self) -> Result<()> {
+        use std::io::Write;
+
+        self.released.store(true, Ordering::SeqCst);
+        let mut held = std::mem::take(&mut *self.held.lock().unwrap());
+        held.sort_by(|a, b| a.0.cmp(&b.0));
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        for (_, text) in held {
+            out.write_all(&text)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a --hash-audit run found, see FileStore::hash_audit
+#[derive(Debug, Default)]
+pub struct HashAudit {
+    pub groups: usize,
+    /// members byte-compared with the first of their group
+    pub compared: usize,
+    pub bytes: u64,
+    /// members changed since indexed or unreadable
+    pub skipped: usize,
+    pub collisions: usize,
+    pub seconds: f64,
+}
+
+/// Content hash algorithm, see --hash
+///
+///   An archive keeps the one it was built with in its header, and
+///   hashes in different algorithms never match, so a store takes the
+///   archive's and refuses a different one given on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Seahash,
+    /// seahash of format 1 and 2 archives, chunk hashes XOR folded
+    SeahashXor,
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "seahash" => Ok(HashAlgorithm::Seahash),
+            "seahash-xor" => Ok(HashAlgorithm::SeahashXor),
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(format!(
+                "unknown hash {:?}, use seahash|xxh3|blake3|sha256",
+                s
+            )),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// True if the algorithm hashes a file chunk by chunk, so that
+    /// where it is cut matters, see Chunking
+    pub fn is_chunked(self) -> bool {
+        matches!(self, HashAlgorithm::Seahash | HashAlgorithm::SeahashXor)
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Seahash => "seahash",
+            HashAlgorithm::SeahashXor => "seahash-xor",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        })
+    }
+}
+
+/// Where files are cut into the chunks seahash hashes, see --cdc
+///
+///   Fixed chunks are CHUNK_SIZE bytes unless --chunk-size, so a byte
+///   inserted near the front of a file moves every boundary after it.
+///   Content defined boundaries are cut where a rolling hash of the
+///   bytes just read hits a pattern, so they move with the data around
+///   them.  Like the algorithm, an archive keeps its chunking in its
+///   header, as files chunked differently hash differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunking {
+    Fixed { size: usize },
+    Cdc { min: usize, avg: usize, max: usize },
+}
+
+/// Random values a byte adds to the --cdc rolling hash, generated by
+/// splitmix64 from a fixed seed so boundaries never change by build
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Chunking::Fixed { size: CHUNK_SIZE }
+    }
+}
+
+impl Chunking {
+    /// Fixed chunks of --chunk-size bytes
+    pub fn fixed(size: usize) -> Result<Self> {
+        if !(4096..=MAX_CHUNK_SIZE).contains(&size) {
+            return Err(format!("chunk size {}: need 4K <= SIZE <= 8M", size).into());
+        }
+        Ok(Chunking::Fixed { size })
+    }
+
+    /// Bytes in a fixed chunk, and in the head of a file, see hash_head
+    ///
+    ///   Content defined chunks vary, so heads stay CHUNK_SIZE there.
+    pub fn chunk_size(self) -> usize {
+        match self {
+            Chunking::Fixed { size } => size,
+            Chunking::Cdc { .. } => CHUNK_SIZE,
+        }
+    }
+
+    /// Content defined chunking of --cdc-sizes MIN,AVG,MAX
+    pub fn cdc(sizes: &str) -> Result<Self> {
+        let parsed = sizes
+            .split(',')
+            .map(|size| crate::parse_size(size).map(|size| size as usize))
+            .collect::<Result<Vec<_>>>()?;
+        match parsed[..] {
+            [min, avg, max] => Chunking::checked(min, avg, max),
+            _ => Err(format!("cdc sizes {:?}: expected MIN,AVG,MAX", sizes).into()),
+        }
+    }
+
+    fn checked(min: usize, avg: usize, max: usize) -> Result<Self> {
+        let ordered = 64 <= min && min <= avg && avg <= max && max <= MAX_CHUNK_SIZE;
+        if !(ordered && avg.is_power_of_two()) {
+            return Err(format!(
+                "cdc sizes {},{},{}: need 64 <= MIN <= AVG <= MAX <= 8M, AVG a power of two",
+                min, avg, max
+            )
+            .into());
+        }
+        Ok(Chunking::Cdc { min, avg, max })
+    }
+
+    /// Length of the chunk at the front of `data`, which holds at least
+    /// the maximum chunk unless the file ends sooner
+    ///
+    ///   A content defined cut is harder to make before the average
+    ///   and easier after, as FastCDC does, keeping chunks nearer the
+    ///   average than a single pattern would.  The rolling hash starts
+    ///   afresh past the minimum, which is never cut inside.
+    fn cut(self, data: &[u8]) -> usize {
+        let (min, avg, max) = match self {
+            Chunking::Fixed { size } => return data.len().min(size),
+            Chunking::Cdc { min, avg, max } => (min, avg, max),
+        };
+        if data.len() <= min {
+            return data.len();
+        }
+        let end = data.len().min(max);
+        let bits = avg.trailing_zeros();
+        // the top bits of the hash depend on the last 64 bytes
+        let (before, after) = (!0u64 << (63 - bits), !0u64 << (65 - bits));
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(end).skip(min) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let pattern = if i < avg { before } else { after };
+            if hash & pattern == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+impl std::str::FromStr for Chunking {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bad = || {
+            format!(
+                "unknown chunking {:?}, use fixed [SIZE] or cdc MIN AVG MAX",
+                s
+            )
+        };
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            ["fixed"] => Ok(Chunking::default()),
+            ["fixed", size] => {
+                Chunking::fixed(size.parse().map_err(|_| bad())?).map_err(|e| e.to_string())
+            }
+            ["cdc", min, avg, max] => {
+                let size = |s: &str| s.parse::<usize>().map_err(|_| bad());
+                Chunking::checked(size(min)?, size(avg)?, size(max)?).map_err(|e| e.to_string())
+            }
+            _ => Err(bad()),
+        }
+    }
+}
+
+impl std::fmt::Display for Chunking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // the default as older builds wrote it, which they can read
+            Chunking::Fixed { size: CHUNK_SIZE } => f.write_str("fixed"),
+            Chunking::Fixed { size } => write!(f, "fixed {}", size),
+            Chunking::Cdc { min, avg, max } => write!(f, "cdc {} {} {}", min, avg, max),
+        }
+    }
+}
+
+/// Which time --changed-only compares with the last injest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangedBy {
+    Mtime,
+    /// also catches mode and owner changes, and mtimes set back
+    Ctime,
+}
+
+impl std::str::FromStr for ChangedBy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mtime" => Ok(ChangedBy::Mtime),
+            "ctime" => Ok(ChangedBy::Ctime),
+            _ => Err(format!("unknown time {:?}, use mtime|ctime", s)),
+        }
+    }
+}
+
+/// What a walk does with symlinks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symlinks {
+    /// leave them out, counted
+    Skip,
+    /// walk and hash what they point to, each directory once
+    Follow,
+    /// keep the link itself and its target, see add_symlink
+    Record,
+}
+
+impl std::str::FromStr for Symlinks {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Symlinks::Skip),
+            "follow" => Ok(Symlinks::Follow),
+            "record" => Ok(Symlinks::Record),
+            _ => Err(format!(
+                "unknown symlink policy {:?}, use skip|follow|record",
+                s
+            )),
+        }
+    }
+}
+
+/// How much byte comparison to do before listing a group for deletion
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyMode {
+    None,
+    Sample,
+    Full,
+}
+
+impl std::str::FromStr for VerifyMode {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(VerifyMode::None),
+            "sample" => Ok(VerifyMode::Sample),
+            "full" => Ok(VerifyMode::Full),
+            _ => Err(format!("unknown verify mode {:?}, use sample|full|none", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for VerifyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VerifyMode::None => "none",
+            VerifyMode::Sample => "sample",
+            VerifyMode::Full => "full",
+        })
+    }
+}
+
+/// How worthwhile cleaning up a group is
+///
+///   The wasted bytes, scaled up by how long ago the newest member was
+///   modified (in years, times `age_weight`) and by the number of
+///   extra copies beyond one (times `copies_weight`).
+pub fn cleanup_score(files: &[Arc<Entry>], now: u64, age_weight: f64, copies_weight: f64) -> f64 {
+    let newest = files.iter().map(|f| f.mod_secs).max().unwrap_or(0);
+    let age_years = now.saturating_sub(newest) as f64 / (365.25 * 24.0 * 3600.0);
+    let staleness = 1.0 + age_weight * age_years;
+    let copies = 1.0 + copies_weight * files.len().saturating_sub(1) as f64;
+    group_waste(files) as f64 * staleness * copies
+}
+
+/// Bytes freed by keeping only the largest member of a group
+///
+///   Members of a group normally all have the same size, but don't
+///   count on the first one being representative.
+pub fn group_waste(files: &[Arc<Entry>]) -> u64 {
+    let total: u64 = files.iter().map(|f| f.len).sum();
+    total - files.iter().map(|f| f.len).max().unwrap_or(0)
+}
+
+/// Move each link to an inode up behind the first member linked to it,
+/// keeping the order otherwise
+fn group_hardlinks(files: Vec<Arc<Entry>>) -> Vec<Arc<Entry>> {
+    let mut first = HashMap::new();
+    let mut keyed: Vec<(usize, Arc<Entry>)> = files
+        .into_iter()
+        .enumerate()
+        .map(|(i, f)| {
+            (
+                f.inode.map_or(i, |inode| *first.entry(inode).or_insert(i)),
+                f,
+            )
+        })
+        .collect();
+    keyed.sort_by_key(|(key, _)| *key);
+    keyed.into_iter().map(|(_, f)| f).collect()
+}
+
+/// True for each member that is a link to the inode of an earlier one
+fn hardlinked(files: &[Arc<Entry>]) -> Vec<bool> {
+    files
+        .iter()
+        .enumerate()
+        .map(|(i, f)| f.inode.is_some() && files[..i].iter().any(|e| e.inode == f.inode))
+        .collect()
+}
+
+/// Group size for a scanned file and the archived files confirmed
+/// to have its bytes, see FileStore::confirm_matches
+fn confirmed_members(confirmed: &Vec<(&FileStore, Vec<Arc<Entry>>)>) -> usize {
+    1 + confirmed
+        .iter()
+        .map(|(_, files)| files.len())
+        .sum::<usize>()
+}
+
+/// Hash a whole file, blocking, so run on the hash pool
+///
+///   With `split` above 1 a seahash file is read as that many ranges
+///   at once, see hash_chunks_split.  The other algorithms digest the
+///   stream whole, so can't be split without changing the hash, nor
+///   can content defined chunks, whose boundaries depend on the bytes
+///   before them.  With a checkpoint key a seahash file read whole
+///   resumes from, and leaves, checkpoints, see --checkpoint-hashing.
+///   With `digest` the whole file digest is taken too, see --digest.
+#[allow(clippy::too_many_arguments)]
+fn hash_file(
+    path: &std::path::Path,
+    len: u64,
+    algorithm: HashAlgorithm,
+    chunking: Chunking,
+    split: usize,
+    digest: bool,
+    checkpoint: Option<(String, checkpoint::Key)>,
+    stats: &Stats,
+) -> Result<Hashed> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let resumable = matches!(
+        algorithm,
+        HashAlgorithm::Seahash | HashAlgorithm::SeahashXor
+    );
+    let (mut checkpoint, resume) = match checkpoint {
+        Some((archive, key)) if resumable && split <= 1 => {
+            match checkpoint::Checkpoint::open(&archive, &key) {
+                Ok((checkpoint, resume)) => (Some(checkpoint), resume),
+                Err(e) => {
+                    eprintln!("checkpoint: {} ({})", e, path.display());
+                    (None, checkpoint::Resume::default())
+                }
+            }
+        }
+        _ => (None, checkpoint::Resume::default()),
+    };
+    // the bytes before a checkpoint aren't read again to digest
+    let digested = digest && resume.offset == 0;
+    // blake3 hashes are whole file digests already
+    let mut whole = (algorithm != HashAlgorithm::Blake3 && digested).then(blake3::Hasher::new);
+    let finish = |digest: Digest,
+                  whole: Option<blake3::Hasher>,
+                  checkpoint: Option<checkpoint::Checkpoint>| {
+        if let Some(Err(e)) = checkpoint.map(checkpoint::Checkpoint::done) {
+            eprintln!("checkpoint: {} ({})", e, path.display());
+        }
+        let hash = digest.finish(len, false);
+        let whole = whole.map_or(hash, |whole| {
+            FileHash::wide(*whole.finalize().as_bytes(), false)
+        });
+        (hash, digested.then_some(whole))
+    };
+    if let Chunking::Cdc { max, .. } = chunking {
+        let mut digest = Digest::resume(algorithm, resume.chunks);
+        let mut f = open_regular(path)?;
+        // content defined chunks are cut afresh after each one
+        let mut pos = f.seek(SeekFrom::Start(resume.offset))?;
+        let mut buf = Vec::with_capacity(2 * max);
+        let mut eof = false;
+        loop {
+            // a chunk is cut from at least max bytes, unless at the end
+            if !eof && buf.len() < max {
+                let want = max - buf.len();
+                eof = f.by_ref().take(want as u64).read_to_end(&mut buf)? < want;
+            }
+            let n = chunking.cut(&buf);
+            stats.add_bytes_read(n as u64);
+            digest.update(&buf[..n]);
+            if let Some(whole) = &mut whole {
+                whole.update(&buf[..n]);
+            }
+            buf.drain(..n);
+            pos += n as u64;
+            if eof && buf.is_empty() {
+                return Ok(finish(digest, whole, checkpoint));
+            }
+            save_checkpoint(&mut checkpoint, pos, &digest, path);
+        }
+    }
+    let size = chunking.chunk_size();
+    if split > 1 && len > size as u64 {
+        match algorithm {
+            HashAlgorithm::Seahash => {
+                let chunks = hash_chunks_split(path, len, size, split, stats)?;
+                return Ok((Digest::Seahash(chunks).finish(len, false), None));
+            }
+            HashAlgorithm::SeahashXor => {
+                let chunks = hash_chunks_split(path, len, size, split, stats)?;
+                return Ok((Digest::SeahashXor(chunks).finish(len, false), None));
+            }
+            _ => (),
+        }
+    }
+    let mut digest = Digest::resume(algorithm, resume.chunks);
+    let mut f = open_regular(path)?;
+    let mut pos = f.seek(SeekFrom::Start(resume.offset))? as usize;
+    let mut buf = vec![0; size];
+    // first we store full chunks until only partial one left
+    while pos + size < len as usize {
+        f.read_exact(&mut buf)?;
+        stats.add_bytes_read(size as u64);
+        digest.update(&buf);
+        if let Some(whole) = &mut whole {
+            whole.update(&buf);
+        }
+        pos += size;
+        save_checkpoint(&mut checkpoint, pos as u64, &digest, path);
+    }
+
+    buf.clear();
+    f.read_to_end(&mut buf)?;
+    stats.add_bytes_read(buf.len() as u64);
+    digest.update(&buf);
+    if let Some(whole) = &mut whole {
+        whole.update(&buf);
+    }
+    Ok(finish(digest, whole, checkpoint))
+}
+
+/// Save a checkpoint each checkpoint::EVERY chunks, giving up on them
+/// for a file once one can't be written
+fn save_checkpoint(
+    checkpoint: &mut Option<checkpoint::Checkpoint>,
+    offset: u64,
+    digest: &Digest,
+    path: &std::path::Path,
+) {
+    let chunks = digest.chunks();
+    let saved = match checkpoint {
+        Some(checkpoint) if chunks.len().is_multiple_of(checkpoint::EVERY) => {
+            checkpoint.save(offset, chunks)
+        }
+        _ => return,
+    };
+    if let Err(e) = saved {
+        eprintln!("checkpoint: {} ({})", e, path.display());
+        *checkpoint = None;
+    }
+}
+
+/// The whole file digest of some bytes, see Entry::digest
+fn whole_digest(bytes: &[u8]) -> FileHash {
+    FileHash::wide(*blake3::hash(bytes).as_bytes(), false)
+}
+
+/// The hash of an empty file, whatever the chunking
+fn empty_hash(algorithm: HashAlgorithm) -> FileHash {
+    let mut digest = Digest::new(algorithm);
+    digest.update(&[]);
+    digest.finish(0, false)
+}
+
+/// The chunk hashes of a file, read as `split` ranges on threads of
+/// their own, see --big-file-threads
+///
+///   Each range is whole chunks, and the last chunk whatever is left
+///   as hash_file reads it, so stitched back in order the hashes are
+///   the ones hash_file gets reading the chunks one by one.
+fn hash_chunks_split(
+    path: &std::path::Path,
+    len: u64,
+    size: usize,
+    split: usize,
+    stats: &Stats,
+) -> Result<Vec<ChunkHash>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let chunks = len.div_ceil(size as u64);
+    let per = chunks.div_ceil(split as u64);
+    let ranges = std::thread::scope(|scope| {
+        let ranges: Vec<_> = (0..chunks)
+            .step_by(per as usize)
+            .map(|first| {
+                scope.spawn(move || -> Result<Vec<ChunkHash>> {
+                    let last = (first + per).min(chunks);
+                    let mut f = open_regular(path)?;
+                    f.seek(SeekFrom::Start(first * size as u64))?;
+                    let mut hashes = Vec::with_capacity((last - first) as usize);
+                    let mut buf = vec![0; size];
+                    for chunk in first..last {
+                        match chunk + 1 == chunks {
+                            true => {
+                                buf.clear();
+                                f.read_to_end(&mut buf)?;
+                            }
+                            false => f.read_exact(&mut buf)?,
+                        }
+                        stats.add_bytes_read(buf.len() as u64);
+                        hashes.push(seahash::hash(&buf));
+                    }
+                    Ok(hashes)
+                })
+            })
+            .collect();
+        ranges
+            .into_iter()
+            .map(|range| {
+                range
+                    .join()
+                    .map_err(|_| Error::other("hash range panicked"))?
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(ranges.concat())
+}
+
+/// The seahash of each chunk of a file, cut as `chunking` cuts it,
+/// see --similar
+fn chunk_hashes(
+    path: &std::path::Path,
+    chunking: Chunking,
+    stats: &Stats,
+) -> Result<Vec<ChunkHash>> {
+    use std::io::Read;
+
+    let max = match chunking {
+        Chunking::Fixed { size } => size,
+        Chunking::Cdc { max, .. } => max,
+    };
+    let mut f = open_regular(path)?;
+    let mut chunks = Vec::new();
+    let mut buf = Vec::with_capacity(2 * max);
+    let mut eof = false;
+    loop {
+        if !eof && buf.len() < max {
+            let want = max - buf.len();
+            eof = f.by_ref().take(want as u64).read_to_end(&mut buf)? < want;
+        }
+        if buf.is_empty() {
+            return Ok(chunks);
+        }
+        let n = chunking.cut(&buf);
+        stats.add_bytes_read(n as u64);
+        chunks.push(seahash::hash(&buf[..n]));
+        buf.drain(..n);
+    }
+}
+
+/// Seahash of the first `size` bytes of a file, see tiered_hash
+fn hash_head(path: &std::path::Path, size: usize, stats: &Stats) -> Result<u64> {
+    use std::io::Read;
+
+    let mut f = open_regular(path)?;
+    let mut buf = vec![0; size];
+    f.read_exact(&mut buf)?;
+    stats.add_bytes_read(buf.len() as u64);
+    Ok(seahash::hash(&buf))
+}
+
+/// True if a file still has the size and mtime it was indexed with
+async fn same_as_indexed(file: &Entry) -> bool {
+    match async_std::fs::metadata(&file.name).await {
+        Ok(metadata) => {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+            metadata.len() == file.len
+                && mtime.is_some_and(|d| {
+                    (d.as_secs(), d.subsec_nanos()) == (file.mod_secs, file.mod_nanos)
+                })
+        }
+        Err(_) => false,
+    }
+}
+
+/// True if two files have exactly the same bytes
+async fn same_content(a: &PathBuf, b: &PathBuf) -> Result<bool> {
+    let mut fa = File::open(a).await?;
+    let mut fb = File::open(b).await?;
+    let len = fa.metadata().await?.len();
+    if fb.metadata().await?.len() != len {
+        return Ok(false);
+    }
+    let mut ba = vec![0; CHUNK_SIZE];
+    let mut bb = vec![0; CHUNK_SIZE];
+    let mut pos = 0;
+    while pos < len {
+        let n = (len - pos).min(CHUNK_SIZE as u64) as usize;
+        fa.read_exact(&mut ba[..n]).await?;
+        fb.read_exact(&mut bb[..n]).await?;
+        if ba[..n] != bb[..n] {
+            return Ok(false);
+        }
+        pos += n as u64;
+    }
+    Ok(true)
+}
+
+/// Hash only the size, first and last MEDIA_SPAN bytes of a file
+///
+///   Used for --media-mode, where reading gigantic files in full is
+///   not worth it.  Caller ensures len is at least twice MEDIA_SPAN.
+///   Blocking, so run on the hash pool.
+fn hash_file_media(
+    path: &std::path::Path,
+    len: u64,
+    algorithm: HashAlgorithm,
+    stats: &Stats,
+) -> Result<FileHash> {
+    use std::io::{Read, Seek};
+
+    let mut f = open_regular(path)?;
+    let mut head = vec![0; MEDIA_SPAN];
+    f.read_exact(&mut head)?;
+    stats.add_bytes_read(MEDIA_SPAN as u64);
+    f.seek(SeekFrom::Start(len - MEDIA_SPAN as u64))?;
+    let mut tail = vec![0; MEDIA_SPAN];
+    f.read_exact(&mut tail)?;
+    stats.add_bytes_read(MEDIA_SPAN as u64);
+    if algorithm == HashAlgorithm::Seahash {
+        return Ok(FileHash::partial(
+            len ^ seahash::hash(&head) ^ seahash::hash(&tail).rotate_left(32),
+        ));
+    }
+    let mut digest = Digest::new(algorithm);
+    digest.update(&len.to_le_bytes());
+    digest.update(&head);
+    digest.update(&tail);
+    Ok(digest.finish(len, true))
+}
+
+/// A file read as exactly the archived number of bytes for a tar entry
+///
+///   The tar header already holds the size, so a file that grows is
+///   cut off there and one that shrinks is made up with zeros, keeping
+///   the rest of the tar readable.
+struct ExactLen {
+    file: std::fs::File,
+    left: u64,
+    padded: bool,
+}
+
+impl std::io::Read for ExactLen {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let want = (buf.len() as u64).min(self.left) as usize;
+        if want == 0 {
+            return Ok(0);
+        }
+        let mut n = self.file.read(&mut buf[..want])?;
+        if n == 0 {
+            buf[..want].fill(0);
+            self.padded = true;
+            n = want;
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// A running digest in one of the --hash algorithms
+///
+///   Seahash hashes each chunk on its own and folds them into the
+///   length, as archives have always held; the others digest the
+///   stream whole.
+enum Digest {
+    Seahash(Vec<ChunkHash>),
+    SeahashXor(Vec<ChunkHash>),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Digest {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Seahash => Digest::Seahash(Vec::new()),
+            HashAlgorithm::SeahashXor => Digest::SeahashXor(Vec::new()),
+            HashAlgorithm::Xxh3 => Digest::Xxh3(Box::default()),
+            HashAlgorithm::Blake3 => Digest::Blake3(Box::default()),
+            HashAlgorithm::Sha256 => Digest::Sha256(sha2::Sha256::default()),
+        }
+    }
+
+    /// A seahash digest of the chunks hashed before a checkpoint, see
+    /// --checkpoint-hashing, the others can't be resumed
+    fn resume(algorithm: HashAlgorithm, chunks: Vec<ChunkHash>) -> Self {
+        match algorithm {
+            HashAlgorithm::Seahash => Digest::Seahash(chunks),
+            HashAlgorithm::SeahashXor => Digest::SeahashXor(chunks),
+            _ => Digest::new(algorithm),
+        }
+    }
+
+    /// The chunk hashes so far, none for a stream digest
+    fn chunks(&self) -> &[ChunkHash] {
+        match self {
+            Digest::Seahash(chunks) | Digest::SeahashXor(chunks) => chunks,
+            _ => &[],
+        }
+    }
+
+    /// Add the next chunk of the file, whole chunks but the last
+    fn update(&mut self, data: &[u8]) {
+        use sha2::Digest as _;
+
+        match self {
+            Digest::Seahash(chunks) | Digest::SeahashXor(chunks) => {
+                chunks.push(seahash::hash(data))
+            }
+            Digest::Xxh3(hasher) => hasher.update(data),
+            Digest::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Digest::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self, len: u64, partial: bool) -> FileHash {
+        use sha2::Digest as _;
+
+        let hash = match self {
+            Digest::Seahash(chunks) => {
+                // in order, so the same chunks rearranged hash differently
+                let mut all = Vec::with_capacity(8 * (chunks.len() + 1));
+                all.extend_from_slice(&len.to_le_bytes());
+                for chunk in chunks {
+                    all.extend_from_slice(&chunk.to_le_bytes());
+                }
+                FileHash::full(seahash::hash(&all))
+            }
+            Digest::SeahashXor(chunks) => FileHash::full(chunks.iter().fold(len, |acc, x| acc ^ x)),
+            Digest::Xxh3(hasher) => FileHash::full(hasher.digest()),
+            Digest::Blake3(hasher) => FileHash::wide(*hasher.finalize().as_bytes(), partial),
+            Digest::Sha256(hasher) => FileHash::wide(hasher.finalize().into(), partial),
+        };
+        FileHash { partial, ..hash }
+    }
+}
+
+/// A file whose size or modification time by the time it was hashed
+/// were not those scanned, see --include-unstable
+#[derive(Debug)]
+pub struct Unstable;
+
+impl std::fmt::Display for Unstable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("changed while being hashed, left out")
+    }
+}
+
+impl std::error::Error for Unstable {}
+
+/// A FIFO, socket or device node, whose contents are never read
+#[derive(Debug)]
+pub struct Special;
+
+impl std::fmt::Display for Special {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("not a regular file, left unread")
+    }
+}
+
+impl std::error::Error for Special {}
+
+/// Open a file to read what is in it, refusing all but regular files
+///
+///   It is opened without blocking, so a FIFO found where a file was
+///   listed is refused here rather than waited on for a writer.
+fn open_regular(path: &std::path::Path) -> Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    if !f.metadata()?.is_file() {
+        return Err(Special.into());
+    }
+    Ok(f)
+}
+
+/// The hash of an inode with more than one link, once the first of
+/// them has been hashed, see --count-hardlinks
+type LinkSlot = Arc<async_std::sync::Mutex<Option<CachedHash>>>;
+
+/// A file's hash remembered by device and inode, see --no-cache
+///
+///   Kept as the archive's `cache` sets beside the `file` ones.  It
+///   stands for the file only while its size and modification time
+///   still match, so a file renamed or moved within its filesystem, or
+///   hard linked, isn't read again, but one edited is.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct CachedHash {
+    #[n(0)]
+    dev: u64,
+    #[n(1)]
+    ino: u64,
+    #[n(2)]
+    len: u64,
+    #[n(3)]
+    mod_secs: u64,
+    #[n(4)]
+    mod_nanos: u32,
+    #[n(5)]
+    hash: FileHash,
+    /// see Entry::digest, None in caches written before it
+    #[n(6)]
+    digest: Option<FileHash>,
+}
+
+impl ItemReadWrite for Record<CachedHash> {
+    type T = CachedHash;
+    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
+        self.push(minicbor::to_vec(item)?)
+    }
+    fn read_item(&mut self) -> Result<Option<Self::T>> {
+        match &self.pull()? {
+            Some(v) => Ok(Some(minicbor::decode(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ItemReadWrite for Record<FileTuple> {
+    type T = FileTuple;
+    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
+        let loc = match self.mode() {
+            ArchiveMode::Plain => self.push(minicbor::to_vec(&item.0.as_ref())?)?,
+            ArchiveMode::Private => self.push(minicbor::to_vec(&PrivateEntry {
+                len: item.0.len,
+                path_mac: item.0.name.clone(),
+            })?)?,
+        };
+        self.push(minicbor::to_vec(&item.1)?)?;
+        Ok(loc)
+    }
+    fn read_item(&mut self) -> Result<Option<Self::T>> {
+        Ok(self.read_tuple()?.map(|(item, _)| item))
+    }
+}
+
+impl Record<FileTuple> {
+    /// Read an item, true if it came in a legacy encoding
+    fn read_tuple(&mut self) -> Result<Option<(FileTuple, bool)>> {
+        if let Some(v0) = &self.pull()? {
+            let i0 = match self.mode() {
+                ArchiveMode::Plain => minicbor::decode(v0)?,
+                ArchiveMode::Private => {
+                    let private: PrivateEntry = minicbor::decode(v0)?;
+                    Entry {
+                        is_file: true,
+                        len: private.len,
+                        name: private.path_mac,
+                        ..Default::default()
+                    }
+                }
+            };
+            if let Some(v1) = &self.pull()? {
+                let (i1, legacy) = crate::legacy::decode_file_hash(v1, self.read_set_version())?;
+                Ok(Some(((Arc::new(i0), i1), legacy)))
+            } else {
+                Err(std::boxed::Box::new(Error::new(
+                    ErrorKind::Other,
+                    "Out of data half way through read_item?",
+                )))
+            }
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, scratch};
+    use async_std::task;
+
+    /// An archive of two files, `new` and `old`, the latter last
+    /// modified in 1999
+    fn archived_pair(name: &str) -> (String, std::path::PathBuf) {
+        let dir = scratch(name);
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        let files = dir.join("files");
+        std::fs::create_dir(&archive).unwrap();
+        std::fs::create_dir(&files).unwrap();
+        std::fs::write(files.join("new"), b"new contents").unwrap();
+        std::fs::write(files.join("old"), b"old contents").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(files.join("old"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(915148800))
+            .unwrap();
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        task::block_on(async {
+            for file in ["new", "old"] {
+                let path = PathBuf::from(files.join(file));
+                store
+                    .add_file(&path, &path.metadata().await.unwrap())
+                    .await
+                    .unwrap();
+            }
+            store.write().await.unwrap();
+        });
+        (archive, files)
+    }
+
+    /// Prune after injesting only `new`, --newer-than 2000-01-01 or not
+    fn prune_with(name: &str, args: &[&str]) -> usize {
+        let (archive, files) = archived_pair(name);
+        let mut given = vec!["-a", &archive, "--prune"];
+        given.extend_from_slice(args);
+        let store = FileStore::new(&archive, config(&given));
+        task::block_on(async {
+            store.read_all().await.unwrap();
+            assert_eq!(store.index().len(), 2);
+            for file in ["new", "old"] {
+                let path = PathBuf::from(files.join(file));
+                let metadata = path.metadata().await.unwrap();
+                if !store.outside_times(&path, &metadata) {
+                    store.add_file(&path, &metadata).await.unwrap();
+                }
+            }
+            store.prune().await.unwrap();
+        });
+        store.index().len()
+    }
+
+    #[test]
+    fn prune_keeps_files_outside_the_times() {
+        assert_eq!(
+            prune_with("prune-times", &["--newer-than", "2000-01-01"]),
+            2
+        );
+    }
+
+    #[test]
+    fn prune_keeps_files_changed_only_passes_over() {
+        let (archive, files) = archived_pair("prune-changed-only");
+        let mut config = config(&["-a", &archive, "--prune"]);
+        config.changed_only = Some(ChangedBy::Mtime);
+        let store = FileStore::new(&archive, config);
+        task::block_on(async {
+            store.read_all().await.unwrap();
+            let root = files.to_str().unwrap().to_string();
+            store.injested.write().unwrap().insert(root, 946684800);
+            let path = PathBuf::from(files.join("old"));
+            let metadata = path.metadata().await.unwrap();
+            store.add_file(&path, &metadata).await.unwrap();
+            store.prune().await.unwrap();
+        });
+        assert_eq!(store.unchanged.load(Ordering::SeqCst), 1);
+        assert!(store.seen_paths.is_empty());
+        let kept: Vec<String> = store
+            .index()
+            .iter()
+            .map(|item| item.key().name.clone())
+            .collect();
+        assert_eq!(kept, [files.join("old").to_str().unwrap()]);
+    }
+
+    /// Bytes read, and files hashed or taken from the hash cache,
+    /// injesting the pair again after a chmod of both
+    fn read_after_chmod(name: &str, args: &[&str]) -> (u64, usize) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (archive, files) = archived_pair(name);
+        for file in ["new", "old"] {
+            let perms = std::fs::Permissions::from_mode(0o600);
+            std::fs::set_permissions(files.join(file), perms).unwrap();
+        }
+        let mut given = vec!["-a", &archive];
+        given.extend_from_slice(args);
+        let store = FileStore::new(&archive, config(&given));
+        task::block_on(async {
+            store.read_all().await.unwrap();
+            for file in ["new", "old"] {
+                let path = PathBuf::from(files.join(file));
+                store
+                    .add_file(&path, &path.metadata().await.unwrap())
+                    .await
+                    .unwrap();
+            }
+            store.finish_waiting().await;
+        });
+        assert_eq!(store.index().len(), 2);
+        let stats = store.stats();
+        (stats.bytes_read(), stats.hash_files() + stats.cache_hits())
+    }
+
+    #[test]
+    fn a_chmod_reads_nothing_again() {
+        assert_eq!(read_after_chmod("chmod", &[]), (0, 0));
+        // hashed again, if only from the hash cache, as a chmod leaves
+        // the inode and mtime it goes by alone
+        let (_, hashed) = read_after_chmod("chmod-strict", &["--strict-metadata"]);
+        assert_eq!(hashed, 2);
+    }
+
+    #[test]
+    fn concurrent_adds_of_one_content_lose_no_member() {
+        let dir = scratch("concurrent-adds");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        std::fs::create_dir(&archive).unwrap();
+        let paths: Vec<PathBuf> = (0..300)
+            .map(|i| {
+                let path = dir.join(format!("copy{}", i));
+                std::fs::write(&path, b"the same contents").unwrap();
+                PathBuf::from(path)
+            })
+            .collect();
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        task::block_on(async {
+            let adds: Vec<_> = paths
+                .iter()
+                .map(|path| {
+                    let (store, path) = (store.clone(), path.clone());
+                    task::spawn(async move {
+                        let metadata = path.metadata().await.unwrap();
+                        store.add_file(&path, &metadata).await.unwrap();
+                    })
+                })
+                .collect();
+            futures::future::join_all(adds).await;
+            store.finish_waiting().await;
+        });
+        let groups = store.snapshot_groups();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].1.len(), 300);
+        assert_eq!(store.index().len(), 300);
+    }
+
+    #[test]
+    fn one_insert_sees_a_new_group_reach_two() {
+        let dir = scratch("group-of-two");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        let hash = FileHash {
+            hash: 7,
+            partial: false,
+            rest: None,
+        };
+        let inserts: Vec<_> = (0..200)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let entry = Entry {
+                        name: format!("/copies/{}", i),
+                        len: 10,
+                        is_file: true,
+                        ..Default::default()
+                    };
+                    store.replace_entry(entry, hash)
+                })
+            })
+            .collect();
+        let mut sizes: Vec<usize> = inserts.into_iter().map(|t| t.join().unwrap()).collect();
+        sizes.sort_unstable();
+        assert_eq!(sizes, (1..=200).collect::<Vec<_>>());
+        assert_eq!(store.members(hash), 200);
+    }
+
+    #[test]
+    fn archives_loaded_together_share_entries() {
+        let dir = scratch("interned");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        std::fs::create_dir(&first).unwrap();
+        std::fs::create_dir(&second).unwrap();
+        let first = first.to_str().unwrap().to_string();
+        let store = FileStore::new(&first, config(&["-a", &first]));
+        for i in 0..500u64 {
+            let entry = Entry {
+                name: format!("/photos/{}.jpg", i),
+                len: 1000 + i % 50,
+                is_file: true,
+                ..Default::default()
+            };
+            let hash = FileHash {
+                hash: i % 50,
+                partial: false,
+                rest: None,
+            };
+            store.insert_entry(Arc::new(entry), hash);
+        }
+        task::block_on(store.write()).unwrap();
+        for set in std::fs::read_dir(&first).unwrap() {
+            let set = set.unwrap();
+            if set.file_type().unwrap().is_file() {
+                std::fs::copy(set.path(), dir.join("second").join(set.file_name())).unwrap();
+            }
+        }
+
+        let second = second.to_str().unwrap();
+        let config = config(&["-a", &first, "-a", second, "-c", "/photos"]);
+        let other = FileStore::new(second, config.clone());
+        let store = FileStore::new(&first, config).with_others(vec![other]);
+        task::block_on(store.read_all()).unwrap();
+        let other = &store.others[0];
+        assert_eq!((store.index().len(), other.index().len()), (500, 500));
+        // one allocation for each entry, where there would be four
+        assert_eq!(store.interner.counts(), (500, 500));
+        for item in store.index().iter() {
+            let (key, hash) = (item.key(), item.value());
+            let copy = other.index().get(key).unwrap();
+            assert!(Arc::ptr_eq(key, copy.key()));
+            let group = store.hindex.get(hash).unwrap();
+            assert!(group.iter().any(|member| Arc::ptr_eq(key, member)));
+        }
+    }
+
+    #[test]
+    fn a_single_file_archive_reads_back_as_a_directory_does() {
+        let dir = scratch("single-file");
+        let (directory, packed) = (dir.join("archive"), dir.join("archive.fda"));
+        std::fs::create_dir(&directory).unwrap();
+        let archives = [directory.to_str().unwrap(), packed.to_str().unwrap()];
+        for archive in archives {
+            let store = FileStore::new(archive, config(&["-a", archive]));
+            for i in 0..20_000u64 {
+                let entry = Entry {
+                    name: format!("/data/{}/file", i),
+                    len: 100 + i % 7,
+                    is_file: true,
+                    ..Default::default()
+                };
+                let hash = FileHash {
+                    hash: i % 7,
+                    partial: false,
+                    rest: None,
+                };
+                store.insert_entry(Arc::new(entry), hash);
+            }
+            task::block_on(store.write()).unwrap();
+        }
+        assert!(packed.is_file());
+        let entries = |archive: &str| {
+            let store = FileStore::new(archive, config(&["-a", archive]));
+            task::block_on(store.read_all()).unwrap();
+            let mut entries: Vec<(String, FileHash)> = store
+                .index()
+                .iter()
+                .map(|item| (item.key().name.clone(), *item.value()))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+        let from_dir = entries(archives[0]);
+        assert_eq!(from_dir.len(), 20_000);
+        assert_eq!(entries(archives[1]), from_dir);
+    }
+
+    /// An archive of three sets, of format 1, 2 and 3, holding ten
+    /// entries each, the hash of each its number
+    fn mixed_versions(dir: &std::path::Path) -> String {
+        std::fs::create_dir(dir).unwrap();
+        let archive = dir.to_str().unwrap().to_string();
+        for (set, version) in [1u32, 2, 3].iter().enumerate() {
+            let mut record: Record<()> =
+                Record::new(&archive, "file".to_string(), ARCHIVE_SIZE, RECORD_SIZE);
+            record.archive_set_write_serial_number(set);
+            for i in 0..10 {
+                let n = set as u64 * 10 + i;
+                let entry = Entry {
+                    name: format!("/v{}/{}", version, i),
+                    len: 100 + n,
+                    is_file: true,
+                    ..Default::default()
+                };
+                record.push(minicbor::to_vec(&entry).unwrap()).unwrap();
+                let hash = match version {
+                    // a bare ChunkHash, from before FileHash
+                    1 => minicbor::to_vec(n).unwrap(),
+                    _ => minicbor::to_vec(FileHash::full(n)).unwrap(),
+                };
+                record.push(hash).unwrap();
+            }
+            task::block_on(record.finish()).unwrap();
+        }
+        let header = "# find_dups file archive\nversion 3\nmode plain\n\
+                      set 0 version 1\nset 1 version 2\n";
+        std::fs::write(dir.join("file.header"), header).unwrap();
+        archive
+    }
+
+    #[test]
+    fn sets_of_each_version_decode_as_their_own() {
+        let dir = scratch("mixed-versions");
+        let archive = mixed_versions(&dir.join("archive"));
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        task::block_on(store.read_all()).unwrap();
+        assert_eq!(store.index().len(), 30);
+        for item in store.index().iter() {
+            assert_eq!(*item.value(), FileHash::full(item.key().len - 100));
+        }
+        let mix: Vec<_> = store.stats().version_mix().into_iter().collect();
+        assert_eq!(mix, [(1, 1), (2, 1), (3, 1)]);
+        assert_eq!(store.hash_algorithm().to_string(), "seahash-xor");
+
+        task::block_on(store.upgrade(FORMAT_VERSION)).unwrap();
+        let upgraded = FileStore::new(&archive, config(&["-a", &archive]));
+        task::block_on(upgraded.read_all()).unwrap();
+        assert_eq!(upgraded.index().len(), 30);
+        let mix: Vec<_> = upgraded.stats().version_mix().into_iter().collect();
+        assert_eq!(mix, [(FORMAT_VERSION, 1)]);
+        assert_eq!(upgraded.hash_algorithm().to_string(), "seahash-xor");
+        assert!(task::block_on(upgraded.upgrade(FORMAT_VERSION)).is_err());
+    }
+
+    /// Legacy archives as the format 1 and 2 releases wrote them, six
+    /// photos in three pairs of copies
+    const LEGACY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/legacy");
+
+    #[test]
+    fn legacy_archives_upgrade_in_place() {
+        let base = scratch("legacy-upgrade");
+        for version in ["v1", "v2"] {
+            let fixture = std::path::Path::new(LEGACY).join(version);
+            let dir = base.join(version);
+            std::fs::create_dir(&dir).unwrap();
+            for file in std::fs::read_dir(&fixture).unwrap() {
+                let file = file.unwrap().path();
+                std::fs::copy(&file, dir.join(file.file_name().unwrap())).unwrap();
+            }
+            let archive = dir.to_str().unwrap().to_string();
+            let entries = |store: &FileStore| {
+                let mut entries: Vec<_> = store
+                    .index()
+                    .iter()
+                    .map(|item| (item.key().name.clone(), item.key().len, *item.value()))
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                entries
+            };
+
+            let store = FileStore::new(&archive, config(&["-a", &archive]));
+            task::block_on(store.read_all()).unwrap();
+            let before = entries(&store);
+            assert_eq!(before.len(), 6, "{}", version);
+            for (i, (name, len, hash)) in before.iter().enumerate() {
+                let i = i as u64;
+                assert_eq!(*name, format!("/home/user/photos/img{}.jpg", i));
+                assert_eq!(*len, 1000 + (i / 2) * 10);
+                assert_eq!(*hash, FileHash::full(0x1000 + i / 2));
+            }
+
+            task::block_on(store.upgrade(FORMAT_VERSION)).unwrap();
+            let upgraded = FileStore::new(&archive, config(&["-a", &archive]));
+            task::block_on(upgraded.read_all()).unwrap();
+            assert_eq!(entries(&upgraded), before, "{}", version);
+            let mix: Vec<_> = upgraded.stats().version_mix().into_iter().collect();
+            assert_eq!(mix, [(FORMAT_VERSION, 1)]);
+            assert_eq!(upgraded.hash_algorithm().to_string(), "seahash-xor");
+            // the sets as they were are kept
+            assert_eq!(
+                std::fs::read(dir.join("file.backup/0000_file.cbor")).unwrap(),
+                std::fs::read(fixture.join("0000_file.cbor")).unwrap()
+            );
+            assert!(!dir.join("file.upgrade").exists());
+            assert!(task::block_on(upgraded.upgrade(FORMAT_VERSION)).is_err());
+        }
+    }
+
+    #[test]
+    fn chunks_stay_within_a_few_mib() {
+        assert!(Chunking::fixed(4096).is_ok());
+        assert!(Chunking::fixed(MAX_CHUNK_SIZE).is_ok());
+        assert!(Chunking::fixed(MAX_CHUNK_SIZE + 1).is_err());
+        assert!(Chunking::fixed(1 << 30).is_err());
+        assert!(Chunking::fixed(4095).is_err());
+        assert!(Chunking::cdc("1M,4M,8M").is_ok());
+        assert!(Chunking::cdc("16K,64K,1G").is_err());
+        assert!(Chunking::cdc("16K,16M,16M").is_err());
+    }
+
+    #[test]
+    fn partial_hashes_stay_out_of_manifests() {
+        let dir = scratch("manifest-partial");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        for (name, hash) in [
+            ("/full", FileHash::full(1)),
+            ("/media", FileHash::partial(2)),
+        ] {
+            let entry = Entry {
+                name: name.to_string(),
+                len: 10,
+                is_file: true,
+                ..Default::default()
+            };
+            store.replace_entry(entry, hash);
+        }
+        let manifest = dir.join("manifest").to_str().unwrap().to_string();
+        assert_eq!(store.write_checksum_manifest(&manifest).unwrap(), 1);
+        let written = std::fs::read_to_string(&manifest).unwrap();
+        assert!(written.ends_with("  /full\n") && !written.contains("/media"));
+        // a partial hash is no answer to the manifest's whole one
+        let line = format!("{}  /media\n", FileHash::full(2).hex());
+        std::fs::write(&manifest, line).unwrap();
+        assert_eq!(store.check_checksum_manifest(&manifest).unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn a_matched_group_streams_once() {
+        let dir = scratch("stream-once");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        let store = FileStore::new(&archive, config(&["-a", &archive, "-c", "/x", "-d"]));
+        let (a, b) = (FileHash::full(1), FileHash::full(2));
+        assert!(store.streams_group(a));
+        assert!(store.streams_group(b));
+        // more scanned copies of the same archived files
+        assert!(!store.streams_group(a));
+        assert!(!store.clone().streams_group(b));
+    }
+
+    #[test]
+    fn a_plain_archive_turns_private_only_when_asked() {
+        let dir = scratch("convert-private");
+        let archive = dir.to_str().unwrap().to_string();
+        let key = dir.join("key");
+        std::fs::write(&key, "secret\n").unwrap();
+        let key = key.to_str().unwrap();
+        let plain = FileStore::new(&archive, config(&["-a", &archive]));
+        let entry = Entry {
+            name: "/home/a".to_string(),
+            len: 10,
+            is_file: true,
+            ..Default::default()
+        };
+        plain.replace_entry(entry, FileHash::full(1));
+        task::block_on(plain.write()).unwrap();
+
+        let keyed = |args: &[&str]| {
+            let args = [&["-a", &archive, "--private-archive", key], args].concat();
+            FileStore::new(&archive, config(&args))
+        };
+        let refused = task::block_on(keyed(&[]).read()).unwrap_err();
+        assert!(refused.to_string().contains("--convert-to-private"));
+        // a check writes nothing, so may look with the key
+        task::block_on(keyed(&["-c", "/x"]).read()).unwrap();
+        let converting = keyed(&["--convert-to-private"]);
+        task::block_on(converting.read()).unwrap();
+        assert!(converting.is_private());
+    }
+
+    #[test]
+    fn one_csv_header_for_all_archives() {
+        let dir = scratch("csv-header");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        let config = config(&["-a", a, "-a", b, "-c", "/x"]);
+        let other = FileStore::new(b, config.clone());
+        let store = FileStore::new(a, config).with_others(vec![other]);
+        let entry = Entry {
+            name: "/a".to_string(),
+            ..Default::default()
+        };
+        store.others[0].emit_csv("", &entry, FileHash::full(1), "");
+        assert!(store.config.csv_header.is_completed());
+    }
+
+    #[test]
+    fn compared_partial_matches_are_full_evidence() {
+        let sized = |len| {
+            Arc::new(Entry {
+                len,
+                ..Default::default()
+            })
+        };
+        let files = [sized(10), sized(10)];
+        let (partial, full) = (FileHash::partial(1), FileHash::full(1));
+        assert_eq!(
+            Evidence::compared(partial, &files, false),
+            Evidence::Partial
+        );
+        assert_eq!(Evidence::compared(partial, &files, true), Evidence::Full);
+        assert_eq!(Evidence::compared(full, &files, false), Evidence::Full);
+        let empty = [sized(0), sized(0)];
+        assert_eq!(Evidence::compared(partial, &empty, true), Evidence::Empty);
+    }
+
+    #[test]
+    fn explain_says_what_a_check_prints() {
+        let dir = scratch("explain");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        std::fs::create_dir(&archive).unwrap();
+        let path = |name: &str| PathBuf::from(dir.join(name));
+        for (name, contents) in [
+            ("kept", "same"),
+            ("copy", "same"),
+            ("other", "other"),
+            ("twice", "twice"),
+            ("twice-too", "twice"),
+            ("empty", ""),
+        ] {
+            std::fs::write(path(name), contents).unwrap();
+        }
+        task::block_on(async {
+            let store = FileStore::new(&archive, config(&["-a", &archive]));
+            for name in ["kept", "twice", "twice-too"] {
+                let metadata = path(name).metadata().await.unwrap();
+                store.add_file(&path(name), &metadata).await.unwrap();
+            }
+            store.finish_waiting().await;
+            store.write().await.unwrap();
+
+            for (flag, mode) in [("-m", "--missing"), ("-p", "--present")] {
+                for name in ["kept", "copy", "other", "twice", "empty"] {
+                    let mut config = config(&["-a", &archive, "-c", "/x", flag]);
+                    let sorted: Arc<SortedResults> = Arc::default();
+                    config.sorted = Some(sorted.clone());
+                    let check = FileStore::new(&archive, config);
+                    check.read_all().await.unwrap();
+                    let mut explained = Vec::new();
+                    check.explain(&path(name), &mut explained).await.unwrap();
+                    let metadata = path(name).metadata().await.unwrap();
+                    check.add_file(&path(name), &metadata).await.unwrap();
+                    let said = match sorted.held.lock().unwrap().is_empty() {
+                        true => format!("{}: prints nothing", mode),
+                        false => format!("{}: prints it", mode),
+                    };
+                    let explained = String::from_utf8(explained).unwrap();
+                    assert!(
+                        explained.contains(&said),
+                        "{} {}:\n{}",
+                        flag,
+                        name,
+                        explained
+                    );
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn whole_file_digests_are_kept_only_with_digest() {
+        let dir = scratch("digest");
+        let archive = dir.to_str().unwrap();
+        let (path, empty) = (
+            PathBuf::from(dir.join("file")),
+            PathBuf::from(dir.join("empty")),
+        );
+        std::fs::write(&path, b"contents").unwrap();
+        std::fs::write(&empty, b"").unwrap();
+        for (args, digests) in [
+            (&[][..], [None, None]),
+            (
+                &["--digest"][..],
+                [Some(whole_digest(b"contents")), Some(whole_digest(b""))],
+            ),
+        ] {
+            let store = FileStore::new(archive, config(&[&["-a", archive], args].concat()));
+            task::block_on(async {
+                for path in [&path, &empty] {
+                    let metadata = path.metadata().await.unwrap();
+                    store.add_file(path, &metadata).await.unwrap();
+                }
+                store.finish_waiting().await;
+            });
+            let digest = |path: &PathBuf| {
+                let name = path.to_str().unwrap();
+                let item = store.index().iter().find(|item| item.key().name == name);
+                item.unwrap().key().digest
+            };
+            assert_eq!([digest(&path), digest(&empty)], digests, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn cleanup_score_weighs_each_copy_beyond_one() {
+        let files: Vec<Arc<Entry>> = (0..3)
+            .map(|_| {
+                Arc::new(Entry {
+                    len: 100,
+                    mod_secs: 1000,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        // no age weight, so only the waste and the copies count
+        assert_eq!(cleanup_score(&files[..1], 1000, 0.0, 0.5), 0.0);
+        assert_eq!(cleanup_score(&files[..2], 1000, 0.0, 0.5), 100.0 * 1.5);
+        assert_eq!(cleanup_score(&files, 1000, 0.0, 0.5), 200.0 * 2.0);
+        assert_eq!(cleanup_score(&files, 1000, 0.0, 0.0), 200.0);
+    }
+
+    #[test]
+    fn orphans_go_out_as_json() {
+        let dir = scratch("orphans-json");
+        let archive = dir.to_str().unwrap();
+        let mut config = config(&["-a", archive]);
+        config.format = Format::Json;
+        config.orphan_roots = vec![format!("{}/gone", archive)];
+        let sorted: Arc<SortedResults> = Arc::default();
+        config.sorted = Some(sorted.clone());
+        let store = FileStore::new(archive, config);
+        let entry = Entry {
+            name: format!("{}/gone/a", archive),
+            len: 3,
+            is_file: true,
+            ..Default::default()
+        };
+        store.replace_entry(entry, FileHash::full(1));
+        assert_eq!(store.orphans(), 1);
+        let held = sorted.held.lock().unwrap();
+        let kinds: Vec<String> = held
+            .iter()
+            .map(|(_, text)| {
+                let text = String::from_utf8(text.clone()).unwrap();
+                assert!(text.starts_with("{\"type\":\""), "{}", text);
+                text.split('"').nth(3).unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(kinds, ["orphan", "orphan_dir", "orphans"]);
+    }
+
+    #[test]
+    fn group_lines_only_when_verbose() {
+        let dir = scratch("group-lines");
+        let archive = dir.to_str().unwrap();
+        let files: Vec<_> = ["/a", "/b"]
+            .iter()
+            .map(|name| {
+                Arc::new(Entry {
+                    name: name.to_string(),
+                    len: 10,
+                    is_file: true,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let printed = |args: &[&str]| {
+            let mut config = config(&[&["-a", archive], args].concat());
+            let sorted: Arc<SortedResults> = Arc::default();
+            config.sorted = Some(sorted.clone());
+            let store = FileStore::new(archive, config);
+            let hash = FileHash::full(1);
+            let group = store.print_group(
+                "Archive duplicates",
+                hash,
+                "abc",
+                &files,
+                &[],
+                None,
+                &[],
+                None,
+            );
+            group.unwrap();
+            let held = sorted.held.lock().unwrap();
+            String::from_utf8(held[0].1.clone()).unwrap()
+        };
+        assert_eq!(printed(&[]), "/a\n/b\n");
+        assert!(printed(&["-v"]).starts_with("# group abc: 2 files"));
+    }
+
+    #[test]
+    fn reflink_leaves_groups_of_weak_evidence_alone() {
+        let dir = scratch("weak-evidence");
+        let archive = dir.to_str().unwrap();
+        // partial, empty, of differing sizes, and full for contrast
+        let groups = [
+            (FileHash::partial(1), ["same", "same"]),
+            (FileHash::full(2), ["", ""]),
+            (FileHash::full(3), ["abc", "abcd"]),
+            (FileHash::full(4), ["full", "full"]),
+        ];
+        let skipped = |allow_weak_evidence| {
+            let mut config = config(&["-a", archive, "-d"]);
+            config.reflink = true;
+            config.allow_weak_evidence = allow_weak_evidence;
+            config.include_empty = true;
+            config.format = Format::Json;
+            let sorted: Arc<SortedResults> = Arc::default();
+            config.sorted = Some(sorted.clone());
+            let store = FileStore::new(archive, config);
+            for (n, (hash, contents)) in groups.iter().enumerate() {
+                for (m, content) in contents.iter().enumerate() {
+                    let path = dir.join(format!("{}-{}", n, m));
+                    std::fs::write(&path, content).unwrap();
+                    let entry = Entry {
+                        name: path.to_str().unwrap().to_string(),
+                        len: content.len() as u64,
+                        is_file: true,
+                        ..Default::default()
+                    };
+                    store.insert_entry(Arc::new(entry), *hash);
+                }
+            }
+            task::block_on(store.report()).unwrap();
+            let held = sorted.held.lock().unwrap();
+            let summary = held
+                .iter()
+                .map(|(_, text)| String::from_utf8(text.clone()).unwrap())
+                .find(|text| text.contains("weak_groups_skipped"))
+                .unwrap();
+            let count = summary.split("\"weak_groups_skipped\":").nth(1).unwrap();
+            count
+                .split(|c: char| !c.is_ascii_digit())
+                .next()
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        };
+        assert_eq!(skipped(false), 3);
+        // only the sizes that differ fail the comparison of their bytes
+        assert_eq!(skipped(true), 1);
+    }
+
+    #[test]
+    fn writes_are_staged_then_swapped_in() {
+        let dir = scratch("write-staged");
+        let archive = dir.to_str().unwrap();
+        let written = |names: &[&str]| {
+            let store = FileStore::new(archive, config(&["-a", archive]));
+            for (n, name) in names.iter().enumerate() {
+                let entry = Entry {
+                    name: name.to_string(),
+                    len: 10,
+                    is_file: true,
+                    ..Default::default()
+                };
+                store.replace_entry(entry, FileHash::full(n as u64));
+            }
+            task::block_on(store.write()).unwrap();
+        };
+        let read = || {
+            let store = FileStore::new(archive, config(&["-a", archive]));
+            task::block_on(store.read_all()).unwrap();
+            let mut names: Vec<String> = store
+                .index()
+                .iter()
+                .map(|item| item.key().name.clone())
+                .collect();
+            names.sort();
+            names
+        };
+        written(&["/a", "/b"]);
+        assert!(!dir.join("file.write").exists());
+        assert_eq!(read(), ["/a", "/b"]);
+
+        // what a write stopped part way leaves is cleared, not read
+        std::fs::create_dir(dir.join("file.write")).unwrap();
+        std::fs::write(dir.join("file.write/0000_file.cbor"), b"part").unwrap();
+        written(&["/c"]);
+        assert!(!dir.join("file.write").exists());
+        assert_eq!(read(), ["/c"]);
+        assert!(dir.join("file.backup/0000_file.cbor").exists());
+    }
+
+    #[test]
+    fn waste_goes_by_each_member_size() {
+        let sized = |len| {
+            Arc::new(Entry {
+                len,
+                ..Default::default()
+            })
+        };
+        assert_eq!(group_waste(&[sized(10), sized(10), sized(10)]), 20);
+        // mixed sizes, as an audit can find, keep the largest
+        assert_eq!(group_waste(&[sized(4), sized(10), sized(6)]), 10);
+        assert_eq!(group_waste(&[sized(10)]), 0);
+        assert_eq!(group_waste(&[]), 0);
+    }
+
+    #[test]
+    fn entries_archived_without_an_inode_are_refreshed() {
+        let dir = scratch("refresh-inode");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        std::fs::create_dir(&archive).unwrap();
+        let path = PathBuf::from(dir.join("file"));
+        std::fs::write(&path, b"contents").unwrap();
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        task::block_on(async {
+            let metadata = path.metadata().await.unwrap();
+            let scanned = Entry::new_from_path_meta(&path, &metadata).unwrap();
+            let old = Entry {
+                inode: None,
+                ..scanned.clone()
+            };
+            let hash = FileHash {
+                hash: 1,
+                partial: false,
+                rest: None,
+            };
+            store.insert_entry(Arc::new(old), hash);
+            store.add_file(&path, &metadata).await.unwrap();
+            assert_eq!(store.refreshed(), 1);
+            let item = store.index().iter().next().unwrap();
+            assert_eq!(item.key().inode, scanned.inode);
+            assert_eq!(*item.value(), hash);
+        });
+    }
+
+    #[test]
+    fn uid_map_leaves_archived_owners_alone() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch("uid-map");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        std::fs::create_dir(&archive).unwrap();
+        let path = PathBuf::from(dir.join("file"));
+        std::fs::write(&path, b"contents").unwrap();
+        let owner = std::fs::metadata(&path).unwrap().uid();
+        let map = dir.join("uids");
+        std::fs::write(&map, format!("{}:{}\n", owner + 1, owner)).unwrap();
+        let map = map.to_str().unwrap();
+        let archived = |store: &FileStore| store.index().iter().next().unwrap().key().uid;
+        task::block_on(async {
+            let metadata = path.metadata().await.unwrap();
+            let store = FileStore::new(&archive, config(&["-a", &archive]));
+            let entry = Entry::new_from_path_meta(&path, &metadata).unwrap();
+            let hash = FileHash {
+                hash: 1,
+                partial: false,
+                rest: None,
+            };
+            // as archived on a host where the owner is one uid up
+            store.insert_entry(
+                Arc::new(Entry {
+                    uid: owner + 1,
+                    ..entry
+                }),
+                hash,
+            );
+            store.write().await.unwrap();
+
+            let mapped = FileStore::new(&archive, config(&["-a", &archive, "--uid-map", map]));
+            mapped.read_all().await.unwrap();
+            assert_eq!(archived(&mapped), owner);
+            mapped.add_file(&path, &metadata).await.unwrap();
+            assert_eq!(mapped.refreshed(), 0);
+            mapped.write().await.unwrap();
+
+            let unmapped = FileStore::new(&archive, config(&["-a", &archive]));
+            unmapped.read_all().await.unwrap();
+            assert_eq!(archived(&unmapped), owner + 1);
+        });
+    }
+
+    #[test]
+    fn prune_tracks_seen_files_in_a_few_percent_of_the_index() {
+        let dir = scratch("prune-memory");
+        let archive = dir.join("archive").to_str().unwrap().to_string();
+        let files = dir.join("a-directory-name-of-typical-length/and-another-below-it");
+        std::fs::create_dir(&archive).unwrap();
+        std::fs::create_dir_all(&files).unwrap();
+        let paths: Vec<PathBuf> = (0..2000)
+            .map(|i| {
+                let path = files.join(format!("photo-{:05}.jpg", i));
+                std::fs::write(&path, format!("contents {}", i)).unwrap();
+                PathBuf::from(path)
+            })
+            .collect();
+        let injest = |store: &FileStore, paths: &[PathBuf]| {
+            task::block_on(async {
+                for path in paths {
+                    let metadata = path.metadata().await.unwrap();
+                    store.add_file(path, &metadata).await.unwrap();
+                }
+                store.finish_waiting().await;
+            })
+        };
+        let store = FileStore::new(&archive, config(&["-a", &archive]));
+        injest(&store, &paths);
+        task::block_on(store.write()).unwrap();
+
+        let store = FileStore::new(&archive, config(&["-a", &archive, "--prune"]));
+        task::block_on(store.read_all()).unwrap();
+        injest(&store, &paths[..1500]);
+        let present = store.present.len() * std::mem::size_of::<u64>();
+        let index: usize = store
+            .index()
+            .iter()
+            .map(|item| std::mem::size_of::<Entry>() + item.key().name.len())
+            .sum();
+        assert_eq!(store.present.len(), 1500);
+        assert!(present * 20 < index, "{} bytes against {}", present, index);
+        assert_eq!(task::block_on(store.prune()).unwrap(), 500);
+        assert_eq!(store.index().len(), 1500);
+    }
+
+    #[test]
+    fn prune_drops_files_not_seen() {
+        let (archive, files) = archived_pair("prune-unseen");
+        let store = FileStore::new(&archive, config(&["-a", &archive, "--prune"]));
+        task::block_on(async {
+            store.read_all().await.unwrap();
+            let path = PathBuf::from(files.join("new"));
+            store
+                .add_file(&path, &path.metadata().await.unwrap())
+                .await
+                .unwrap();
+            store.prune().await.unwrap();
+        });
+        assert_eq!(store.index().len(), 1);
+    }
 }
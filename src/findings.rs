@@ -0,0 +1,137 @@
+//! findings file for --findings, results that survive an interrupted run
+
+use crate::file::{event_line, Json};
+use crate::Result;
+use std::collections::HashSet;
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+
+/// Results appended as --events ndjson lines to a file
+///
+///   Each run adds a section: a `run_started` line with the run's
+///   parameters, its findings, a `dir_done` line as each directory is
+///   finished, and a `run_completed` line only if the run got to the
+///   end.  A section without that footer is from a run that died.
+///   With --resume the `dir_done` lines since the last completed run
+///   name the directories whose files need not be looked at again.
+#[derive(Debug)]
+pub struct Findings {
+    out: Mutex<LineWriter<std::fs::File>>,
+    done: HashSet<String>,
+}
+
+impl Findings {
+    /// Open `path` for appending, reading what was done first on resume
+    pub fn open(path: &str, resume: bool) -> Result<Self> {
+        let mut done = HashSet::new();
+        let mut torn = false;
+        if resume {
+            match std::fs::read_to_string(path) {
+                Ok(text) => {
+                    let mut completed = false;
+                    for line in text.lines() {
+                        match str_field(line, "event").as_deref() {
+                            Some("run_started") if !line.contains("\"resume\":true") => {
+                                done.clear();
+                                completed = false;
+                            }
+                            Some("dir_done") => {
+                                if let Some(dir) = str_field(line, "path") {
+                                    done.insert(dir);
+                                }
+                            }
+                            Some("run_completed") => {
+                                done.clear();
+                                completed = true;
+                            }
+                            _ => (),
+                        }
+                    }
+                    if completed {
+                        status!(
+                            "{} ends with a completed run, nothing to resume, starting over",
+                            path
+                        );
+                    }
+                    // a run killed mid write leaves half a line
+                    torn = !text.is_empty() && !text.ends_with('\n');
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut out = LineWriter::new(file);
+        if torn {
+            writeln!(out)?;
+        }
+        Ok(Findings {
+            out: Mutex::new(out),
+            done,
+        })
+    }
+
+    /// Append one event line
+    pub fn event(&self, event: &str, fields: Vec<(&str, Json)>) -> Result<()> {
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{}", event_line(event, fields))?;
+        Ok(())
+    }
+
+    /// Mark a directory's files as all looked at, and flush
+    pub fn dir_done(&self, path: &str) -> Result<()> {
+        self.event("dir_done", vec![("path", Json::Str(path.to_string()))])?;
+        self.out.lock().unwrap().flush()?;
+        Ok(())
+    }
+
+    /// True if an earlier run finished the files of this directory
+    pub fn is_done(&self, path: &str) -> bool {
+        self.done.contains(path)
+    }
+
+    /// Number of directories done by earlier runs
+    pub fn resumed(&self) -> usize {
+        self.done.len()
+    }
+}
+
+/// The string value of `name` in a line written by `event_line`
+///
+///   Only the escapes Json writes are understood.  Keys can't be
+///   confused with values, whose quotes are always escaped.
+fn str_field(line: &str, name: &str) -> Option<String> {
+    let key = format!("\"{}\":\"", name);
+    let mut chars = line[line.find(&key)? + key.len()..].chars();
+    let mut ret = String::new();
+    let mut units: Vec<u16> = Vec::new();
+    loop {
+        let c = chars.next()?;
+        if c == '\\' {
+            let e = chars.next()?;
+            if e == 'u' {
+                let hex: String = chars.by_ref().take(4).collect();
+                units.push(u16::from_str_radix(&hex, 16).ok()?);
+                continue;
+            }
+            ret.push_str(&String::from_utf16_lossy(&units));
+            units.clear();
+            ret.push(match e {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                e => e,
+            });
+            continue;
+        }
+        ret.push_str(&String::from_utf16_lossy(&units));
+        units.clear();
+        if c == '"' {
+            return Some(ret);
+        }
+        ret.push(c);
+    }
+}
@@ -0,0 +1,334 @@
+//! gitignore rules, see --respect-gitignore and --ignore-file
+//!
+//! With --respect-gitignore each directory task reads the `.gitignore`
+//! and `.ignore` in its directory, as ripgrep does, and leaves out what
+//! they and those of the directories above it ignore.  Directories are
+//! walked in no particular order, so the rules in force travel down
+//! with each NewDir as an Ignores, one per directory with rules of its
+//! own, pointing at the one for the directory above.
+//!
+//! Rules follow gitignore: a pattern with a `/` but at the end is
+//! anchored to the directory of its file, one without matches a name at
+//! any depth under it, a `/` at the end matches only directories and
+//! `!` takes back an earlier rule.  The last matching rule of the
+//! deepest file with one wins, and `.ignore` comes after `.gitignore`.
+//! Nothing under an ignored directory is seen, so it can't be taken
+//! back.  `.git` itself is not ignored, leave it out with --exclude.
+//!
+//! Rules read from --ignore-file apply as if in a `.gitignore` at each
+//! injest root, below any found there.
+
+use crate::Result;
+use regex::RegexSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The files read in each directory, later ones taking precedence
+pub const FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// One pattern of an ignore file
+#[derive(Debug)]
+struct Rule {
+    negate: bool,
+    dir_only: bool,
+}
+
+/// The rules of one directory, and those in force above it
+#[derive(Debug)]
+pub struct Ignores {
+    parent: Option<Arc<Ignores>>,
+    /// the directory the rules are relative to
+    base: String,
+    rules: Vec<Rule>,
+    set: RegexSet,
+    /// read the ignore files of each directory, with --respect-gitignore
+    read_files: bool,
+}
+
+impl Ignores {
+    /// The rules at an injest root, from the --ignore-file files
+    pub fn root(root: &str, ignore_files: &[String], read_files: bool) -> Result<Arc<Self>> {
+        let mut text = String::new();
+        for file in ignore_files {
+            let read = std::fs::read_to_string(file)
+                .map_err(|e| format!("--ignore-file {}: {}", file, e))?;
+            text += &read;
+            text.push('\n');
+        }
+        Ok(Arc::new(Ignores::parse(None, root, &text, read_files)?))
+    }
+
+    fn parse(
+        parent: Option<Arc<Ignores>>,
+        base: &str,
+        text: &str,
+        read_files: bool,
+    ) -> Result<Self> {
+        let mut rules = Vec::new();
+        let mut regexes = Vec::new();
+        for line in text.lines() {
+            if let Some((rule, regex)) = parse_line(line) {
+                rules.push(rule);
+                regexes.push(regex);
+            }
+        }
+        Ok(Ignores {
+            parent,
+            base: base.to_string(),
+            rules,
+            set: RegexSet::new(regexes)?,
+            read_files,
+        })
+    }
+
+    /// The rules in force in `dir`, with its own ignore files read
+    ///
+    ///   Without --respect-gitignore, or with no ignore files in `dir`,
+    ///   these are the rules from above.  An ignore file that can't be
+    ///   read is reported and passed over.
+    pub async fn descend(self: &Arc<Self>, dir: &Path) -> Arc<Self> {
+        if !self.read_files {
+            return self.clone();
+        }
+        let mut text = String::new();
+        for name in FILES {
+            let file = dir.join(name);
+            match async_std::fs::read_to_string(&file).await {
+                Ok(read) => {
+                    text += &read;
+                    text.push('\n');
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => eprintln!("ignore: {} ({})", e, file.display()),
+            }
+        }
+        if text.is_empty() {
+            return self.clone();
+        }
+        let base = dir.to_string_lossy();
+        match Ignores::parse(Some(self.clone()), &base, &text, true) {
+            Ok(ignores) => Arc::new(ignores),
+            Err(e) => {
+                eprintln!("ignore: {} ({})", e, base);
+                self.clone()
+            }
+        }
+    }
+
+    /// The rules a walk from `root` has when it queues `dir`, under it
+    pub async fn above(
+        root: &str,
+        dir: &Path,
+        ignore_files: &[String],
+        read_files: bool,
+    ) -> Result<Arc<Self>> {
+        let mut ignores = Ignores::root(root, ignore_files, read_files)?;
+        let mut above = PathBuf::from(root);
+        for name in dir.strip_prefix(root).unwrap_or(Path::new("")) {
+            ignores = ignores.descend(&above).await;
+            above.push(name);
+        }
+        Ok(ignores)
+    }
+
+    /// True if the rules leave out `path`
+    pub fn ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignores = Some(self);
+        while let Some(level) = ignores {
+            let rel = path
+                .strip_prefix(&level.base)
+                .ok()
+                .and_then(|rel| rel.to_str());
+            if let Some(rel) = rel.filter(|rel| !rel.is_empty()) {
+                let last = level
+                    .set
+                    .matches(rel)
+                    .iter()
+                    .rfind(|i| is_dir || !level.rules[*i].dir_only);
+                if let Some(i) = last {
+                    return !level.rules[i].negate;
+                }
+            }
+            ignores = level.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// The rule and regex of a line of an ignore file, None for blank
+/// lines and comments
+fn parse_line(line: &str) -> Option<(Rule, String)> {
+    let mut pattern = line.trim_end();
+    if pattern.ends_with('\\') {
+        // an escaped trailing space
+        pattern = &line[..pattern.len() + 1];
+    }
+    if pattern.is_empty() || pattern.starts_with('#') {
+        return None;
+    }
+    let negate = pattern.starts_with('!');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let dir_only = pattern.ends_with('/');
+    pattern = pattern.trim_end_matches('/');
+    if pattern.is_empty() {
+        return None;
+    }
+    let mut regex = String::from(match pattern.contains('/') {
+        true => "^",
+        false => "(^|/)",
+    });
+    pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+    if let Some(rest) = pattern.strip_prefix("**/") {
+        regex += "(.*/)?";
+        pattern = rest;
+    }
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => regex += &regex::escape(&c.to_string()),
+                None => break,
+            },
+            '/' if chars.peek() == Some(&'*') => {
+                // `a/**/b` matches `a/b` too
+                let mut ahead = chars.clone();
+                ahead.next();
+                if ahead.next() == Some('*') && ahead.peek() == Some(&'/') {
+                    chars = ahead;
+                    chars.next();
+                    regex += "/(.*/)?";
+                } else {
+                    regex += "/";
+                }
+            }
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex += ".*";
+            }
+            '*' => regex += "[^/]*",
+            '?' => regex += "[^/]",
+            '[' => {
+                let mut class = String::from("[");
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    if matches!(c, '\\' | '[' | '&' | '~') {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                if !closed {
+                    // not a class after all, gitignore drops the pattern
+                    return None;
+                }
+                regex += &class;
+                regex.push(']');
+            }
+            c => regex += &regex::escape(&c.to_string()),
+        }
+    }
+    regex += "$";
+    Some((Rule { negate, dir_only }, regex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the one rule in `line` matches `rel`, None if it doesn't
+    /// apply, else whether it ignores
+    fn rule(line: &str, rel: &str, is_dir: bool) -> Option<bool> {
+        let (rule, regex) = parse_line(line)?;
+        let matched = regex::Regex::new(&regex).unwrap().is_match(rel);
+        (matched && (is_dir || !rule.dir_only)).then_some(!rule.negate)
+    }
+
+    #[test]
+    fn blank_lines_and_comments() {
+        assert!(parse_line("").is_none());
+        assert!(parse_line("   ").is_none());
+        assert!(parse_line("# a comment").is_none());
+        assert!(parse_line("/").is_none());
+        assert_eq!(rule("\\#name", "#name", false), Some(true));
+    }
+
+    #[test]
+    fn a_name_matches_at_any_depth() {
+        assert_eq!(rule("*.o", "a.o", false), Some(true));
+        assert_eq!(rule("*.o", "src/deep/a.o", false), Some(true));
+        assert_eq!(rule("*.o", "a.o.txt", false), None);
+        assert_eq!(rule("a?c", "x/abc", false), Some(true));
+        assert_eq!(rule("a?c", "a/c", false), None);
+    }
+
+    #[test]
+    fn a_slash_anchors_to_the_directory() {
+        assert_eq!(rule("/build", "build", true), Some(true));
+        assert_eq!(rule("/build", "src/build", true), None);
+        assert_eq!(rule("doc/*.html", "doc/a.html", false), Some(true));
+        assert_eq!(rule("doc/*.html", "x/doc/a.html", false), None);
+        assert_eq!(rule("doc/*.html", "doc/sub/a.html", false), None);
+    }
+
+    #[test]
+    fn a_trailing_slash_matches_only_directories() {
+        assert_eq!(rule("target/", "target", true), Some(true));
+        assert_eq!(rule("target/", "target", false), None);
+        assert_eq!(rule("target/", "a/target", true), Some(true));
+    }
+
+    #[test]
+    fn double_stars() {
+        assert_eq!(rule("**/logs", "logs", true), Some(true));
+        assert_eq!(rule("**/logs", "a/b/logs", true), Some(true));
+        assert_eq!(rule("a/**/b", "a/b", false), Some(true));
+        assert_eq!(rule("a/**/b", "a/x/y/b", false), Some(true));
+        assert_eq!(rule("a/**", "a/x/y", false), Some(true));
+        assert_eq!(rule("a/**", "b/a/x", false), None);
+    }
+
+    #[test]
+    fn negation_takes_back() {
+        assert_eq!(rule("!keep.o", "keep.o", false), Some(false));
+        assert_eq!(rule("\\!bang", "!bang", false), Some(true));
+        let ignores = Ignores::parse(None, "/r", "*.o\n!keep.o\n", false).unwrap();
+        assert!(ignores.ignored(Path::new("/r/a.o"), false));
+        assert!(!ignores.ignored(Path::new("/r/keep.o"), false));
+        assert!(!ignores.ignored(Path::new("/elsewhere/a.o"), false));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(rule("[ab].txt", "b.txt", false), Some(true));
+        assert_eq!(rule("[ab].txt", "c.txt", false), None);
+        assert_eq!(rule("[!ab].txt", "c.txt", false), Some(true));
+        assert_eq!(rule("[a-c]x", "bx", false), Some(true));
+        assert!(parse_line("[abc").is_none());
+    }
+
+    #[test]
+    fn trailing_spaces() {
+        assert_eq!(rule("name  ", "name", false), Some(true));
+        assert_eq!(rule("name\\ ", "name ", false), Some(true));
+        assert_eq!(rule("name\\ ", "name", false), None);
+    }
+
+    #[test]
+    fn deeper_rules_win() {
+        let root = Arc::new(Ignores::parse(None, "/r", "*.log\n", true).unwrap());
+        let sub = Ignores::parse(Some(root.clone()), "/r/keep", "!*.log\n", true).unwrap();
+        assert!(sub.ignored(Path::new("/r/a.log"), false));
+        assert!(!sub.ignored(Path::new("/r/keep/a.log"), false));
+        assert!(root.ignored(Path::new("/r/keep/a.log"), false));
+    }
+}
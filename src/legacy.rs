@@ -0,0 +1,28 @@
+//! decoders for older archive formats
+//!
+//! Reading keeps working on old archives through these, and
+//! `find_dups upgrade` rewrites such archives so they are no longer
+//! needed for them.  See archive::FORMAT_VERSION for the formats.
+
+use crate::file::{ChunkHash, FileHash};
+use crate::Result;
+
+/// Decode the hash half of an item from a set of format `version`
+///
+///   Format 1 sets stored a bare full ChunkHash, from before the
+///   partial flag of FileHash existed; such a hash comes back full.
+///   Returns true if the item was in that legacy form.  Later sets
+///   only hold a FileHash, and only format 3 ones a pending hash.
+pub fn decode_file_hash(v: &[u8], version: u32) -> Result<(FileHash, bool)> {
+    match minicbor::decode::<FileHash>(v) {
+        Ok(hash) if hash.is_pending() && version < 3 => {
+            Err(format!("pending hash in a format {} set", version).into())
+        }
+        Ok(hash) => Ok((hash, false)),
+        Err(_) if version == 1 => {
+            let hash: ChunkHash = minicbor::decode(v)?;
+            Ok((FileHash::full(hash), true))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -10,28 +10,81 @@
 use crate::dir::{dir_broker_loop, DirBrokerMessage};
 use async_std::path::PathBuf;
 use async_std::prelude::*;
+use async_std::sync::Arc;
 use async_std::task;
 use clap::ArgMatches;
 use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::SinkExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 
+/// Print a status line on stderr, unless --quiet was given
+///
+///   Results go to stdout and hard errors straight to stderr, this is
+///   for everything else.  Lines gated on -v need no more than that,
+///   as --quiet and -v can't be given together.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
 pub mod archive;
+pub mod checkpoint;
 pub mod dir;
+pub mod exclude;
 pub mod file;
+pub mod findings;
+pub mod ignore;
+pub mod legacy;
+pub mod pack;
+pub mod policy;
+pub mod pool;
 pub mod record;
+pub mod reflink;
+pub mod resume;
+pub mod similar;
+pub mod skip;
+pub mod stats;
+pub mod throttle;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 pub const RECORD_SIZE: usize = 64 * 1024;
 pub const CHUNK_SIZE: usize = 64 * 1024;
-pub const MAX_COMPRESSED_CHUNK_SIZE: usize = (64 * 1024) + 384; // allow for LZ4 worst case
+/// Largest chunk a file is hashed in, see --chunk-size and --cdc-sizes
+///
+///   Each file being hashed holds a chunk in memory, and the hash pool
+///   runs many at once, so chunks are kept to a few MiB much as items
+///   are kept under MAX_ITEM_SIZE.
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// Most bytes a record of `len` bytes takes in a set once compressed
+///
+///   LZ4's worst case is len + len / 255 + 16, then come the size LZ4
+///   prefixes and the record's own length, with room to spare.
+pub const fn max_compressed_size(len: usize) -> usize {
+    len + len / 255 + 64
+}
 pub const ARCHIVE_SIZE: usize = 4 * 1024 * 1024;
+/// Largest item a record will store
+///
+///   Items larger than a record, or an archive set, are split across
+///   as many as they need, but each is read back whole into memory.
+///   The limit keeps a runaway path from growing without bound, and
+///   keeps a corrupt length from being taken for the rest of the
+///   archive.  It must stay below the 4GiB the length prefix can hold.
+pub const MAX_ITEM_SIZE: usize = 64 * 1024 * 1024;
+pub const MEDIA_SPAN: usize = 1024 * 1024;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     archive: String,
+    other_archives: Vec<String>,
     dir_broker_sender: Sender<DirBrokerMessage>,
+    shutdown: Shutdown,
     injest: bool,
     missing: bool,
     present: bool,
@@ -39,47 +92,944 @@ pub struct Config {
     list: bool,
     report: bool,
     prune: bool,
+    changed_only: Option<file::ChangedBy>,
+    repair: bool,
     concurrency: usize,
+    /// metadata calls a directory task has in flight, see process_dir
+    metadata_window: usize,
+    /// shared by every read for hashing, see --bwlimit
+    bwlimit: Option<Arc<throttle::Throttle>>,
+    /// files a directory task adds at once, see process_dir
+    file_concurrency: usize,
+    hash_pool: Arc<pool::HashPool>,
+    big_file_threads: usize,
+    big_file_size: u64,
     timeout: u64,
+    file_timeout: u64,
     verbose: u64,
+    media_mode: Option<u64>,
+    preflight: bool,
+    detect_reflinks: bool,
+    reflink: bool,
+    allow_weak_evidence: bool,
+    group_limit: usize,
+    by_dir: bool,
+    /// sort reclaimable bytes by the action that frees them, see --feasibility
+    feasibility: bool,
+    top: usize,
+    anomaly_size: usize,
+    strict_metadata: bool,
+    uid_map: Arc<HashMap<u32, u32>>,
+    hashes_from: Option<String>,
+    hash: Option<file::HashAlgorithm>,
+    /// chunking asked for by --cdc or --chunk-size
+    chunking: Option<file::Chunking>,
+    known_hashes: Arc<std::collections::HashSet<file::FileHash>>,
+    read_archive: bool,
+    prefix_map: Arc<Vec<(String, String)>>,
+    show_original: bool,
+    big_threshold: u64,
+    order_by: Option<file::OrderBy>,
+    groups: Vec<String>,
+    age_weight: f64,
+    copies_weight: f64,
+    verify_exports: file::VerifyMode,
+    paranoid: bool,
+    /// share of chunks files must have in common, see similar.rs
+    similar: Option<f64>,
+    similar_min_size: u64,
+    /// digest to read duplicate groups again with, see --verify-groups
+    verify_groups: Option<file::HashAlgorithm>,
+    match_full: bool,
+    /// keep a blake3 digest of each whole file, with --digest or
+    /// --match full, see Entry::digest
+    whole_digest: bool,
+    hash_audit: usize,
+    policy: Option<Arc<policy::Policy>>,
+    lazy_hash: bool,
+    hash_cache: bool,
+    include_unstable: bool,
+    include_empty: bool,
+    count_hardlinks: bool,
+    excludes: Arc<exclude::Excludes>,
+    stored_excludes: bool,
+    respect_gitignore: bool,
+    skip_hidden: bool,
+    /// more roots come from stdin until RootsDone, see read_roots
+    roots_from_stdin: bool,
+    /// leave out roots inside another, see canonical_roots
+    canonicalize: bool,
+    /// walk each directory once by device and inode, see --walk-once
+    walk_once: bool,
+    /// a list of files to add instead of walking, see process_list
+    files_from: Option<String>,
+    /// unix seconds files must be modified after, and before
+    newer_than: Option<u64>,
+    older_than: Option<u64>,
+    /// device and inode of the archive directory when it is under an
+    /// injest root, so the walk passes it by
+    archive_id: Option<(u64, u64)>,
+    symlinks: file::Symlinks,
+    ignore_files: Vec<String>,
+    /// hash by size and first chunk before the whole, see tiered_hash
+    tiered_hash: bool,
+    format: file::Format,
+    events: Option<Arc<file::EventSink>>,
+    print0: bool,
+    /// CSV output has one header, whichever store prints first
+    csv_header: Arc<std::sync::Once>,
+    status_json: Option<String>,
+    progress_json: bool,
+    sorted: Option<Arc<file::SortedResults>>,
+    orphan_roots: Vec<String>,
+    prune_orphans: bool,
+    missing_by_path: bool,
+    removable_dirs: bool,
+    record_dirs: bool,
+    manifest: Option<String>,
+    check_manifest: Option<String>,
+    output: Option<String>,
+    findings: Option<Arc<findings::Findings>>,
+    skip_log: Option<Arc<skip::SkipLog>>,
+    /// keep resumable progress hashing big files, see checkpoint.rs
+    checkpoint_hashing: bool,
+    /// how often to keep what is left of the walk, see resume.rs
+    checkpoint_every: Option<Duration>,
+    resume: bool,
+    /// what is left of the walk resumed, read by launch_brokers
+    resumed: Option<Arc<resume::Saved>>,
+    path_roots: Vec<(String, String)>,
+    private_key: Option<Arc<file::PrivateKey>>,
+    convert_private: bool,
 }
 
 impl Config {
-    pub fn new(matches: &ArgMatches) -> (Self, Receiver<DirBrokerMessage>) {
+    pub fn new(matches: &ArgMatches) -> Result<(Self, Receiver<DirBrokerMessage>)> {
         let (dir_broker_sender, dir_broker_receiver) = channel(100);
-        let present = matches.occurrences_of("present") > 0;
-        let duplicate = matches.occurrences_of("duplicate") > 0;
-        let injest = matches.occurrences_of("check") == 0;
-        let missing = matches.occurrences_of("missing") > 0 || (!injest && !present && !duplicate);
-        (
+        set_quiet(occurrences(matches, "quiet") > 0);
+        let present = occurrences(matches, "present") > 0;
+        let duplicate = occurrences(matches, "duplicate") > 0;
+        let injest = occurrences(matches, "check") + occurrences(matches, "check-listed") == 0;
+        let missing_by_path = occurrences(matches, "missing-by-path") > 0;
+        let missing = occurrences(matches, "missing") > 0
+            || missing_by_path
+            || (!injest && !present && !duplicate);
+        let reflink = occurrences(matches, "reflink") > 0;
+        let events = match (
+            value_of(matches, "events-file"),
+            value_of(matches, "events"),
+        ) {
+            (Some(path), _) => Some(Arc::new(
+                file::EventSink::file(path).map_err(|e| bad_option("events-file", e))?,
+            )),
+            (None, Some("ndjson")) => Some(Arc::new(file::EventSink::stdout())),
+            _ => None,
+        };
+        let (archive, other_archives) = archives(matches, injest)?;
+        Ok((
             Config {
-                archive: matches
-                    .value_of("archive")
-                    .expect("need to specify archive")
-                    .to_string(),
+                archive,
+                other_archives,
                 dir_broker_sender,
+                shutdown: Shutdown::default(),
                 injest,
                 present,
                 missing,
                 duplicate,
-                list: matches.occurrences_of("list") > 0,
-                report: matches.occurrences_of("report") > 0,
-                prune: matches.occurrences_of("prune") > 0,
-                verbose: matches.occurrences_of("verbose"),
-                concurrency: matches
-                    .value_of("concurrency")
-                    .unwrap_or("10")
-                    .parse()
-                    .expect("concurrency"),
-                timeout: matches
-                    .value_of("timeout")
-                    .unwrap_or("600")
-                    .parse()
-                    .expect("timeout"),
+                list: occurrences(matches, "list") > 0,
+                report: occurrences(matches, "report") > 0,
+                prune: occurrences(matches, "prune") > 0,
+                // --full is there to override --changed-only kept in a script
+                changed_only: match occurrences(matches, "changed-only") > 0
+                    && occurrences(matches, "full") == 0
+                {
+                    true => Some(parsed_or(matches, "changed-by", "mtime")?),
+                    false => None,
+                },
+                repair: occurrences(matches, "repair") > 0,
+                verbose: occurrences(matches, "verbose"),
+                concurrency: parsed_or(matches, "concurrency", "10")?,
+                metadata_window: parsed_or(matches, "metadata-window", "16")?,
+                bwlimit: parsed::<f64>(matches, "bwlimit")?
+                    .filter(|mb| *mb > 0.0)
+                    .map(|mb| Arc::new(throttle::Throttle::new(mb * 1e6))),
+                file_concurrency: parsed_or(matches, "file-concurrency", "8")?,
+                hash_pool: Arc::new(pool::HashPool::new(
+                    match parsed(matches, "hash-threads")? {
+                        Some(n) => n,
+                        None => std::thread::available_parallelism().map_or(4, |n| n.get()),
+                    },
+                )),
+                big_file_threads: parsed_or::<usize>(matches, "big-file-threads", "1")?.max(1),
+                big_file_size: converted(matches, "big-file-size", parse_size)?.unwrap_or(1 << 30),
+                timeout: parsed_or(matches, "timeout", "600")?,
+                file_timeout: parsed_or(matches, "file-timeout", "300")?,
+                media_mode: converted(matches, "media-mode", parse_size)?,
+                preflight: occurrences(matches, "no-preflight") == 0,
+                detect_reflinks: reflink || occurrences(matches, "detect-reflinks") > 0,
+                reflink,
+                allow_weak_evidence: occurrences(matches, "allow-weak-evidence") > 0,
+                by_dir: occurrences(matches, "by-dir") > 0,
+                feasibility: occurrences(matches, "feasibility") > 0,
+                top: parsed_or(matches, "top", "20")?,
+                group_limit: parsed_or(matches, "group-limit", "100")?,
+                anomaly_size: parsed_or(matches, "anomaly-size", "10000")?,
+                strict_metadata: occurrences(matches, "strict-metadata") > 0,
+                uid_map: Arc::new(converted(matches, "uid-map", read_uid_map)?.unwrap_or_default()),
+                hashes_from: value_of(matches, "hashes-from").map(String::from),
+                hash: parsed(matches, "hash")?,
+                chunking: match (
+                    occurrences(matches, "cdc") > 0,
+                    value_of(matches, "chunk-size"),
+                ) {
+                    (true, _) => Some(
+                        file::Chunking::cdc(
+                            value_of(matches, "cdc-sizes").unwrap_or("16K,64K,256K"),
+                        )
+                        .map_err(|e| bad_option("cdc-sizes", e))?,
+                    ),
+                    (false, Some(size)) => Some(
+                        parse_size(size)
+                            .and_then(|size| file::Chunking::fixed(size as usize))
+                            .map_err(|e| bad_option("chunk-size", e))?,
+                    ),
+                    (false, None) => None,
+                },
+                known_hashes: Arc::new(
+                    converted(matches, "hashes-from", read_known_hashes)?.unwrap_or_default(),
+                ),
+                // a list of hashes alone needs no archive
+                read_archive: value_of(matches, "hashes-from").is_none()
+                    || occurrences(matches, "archive") > 0,
+                prefix_map: Arc::new(
+                    parse_prefix_map(&values_of(matches, "map-prefix"))
+                        .map_err(|e| bad_option("map-prefix", e))?,
+                ),
+                show_original: value_of(matches, "show") == Some("original"),
+                big_threshold: converted(matches, "big-threshold", parse_size)?
+                    .unwrap_or(1_000_000),
+                groups: values_of(matches, "group")
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                order_by: parsed(matches, "order-by")?,
+                age_weight: parsed_or(matches, "age-weight", "1.0")?,
+                copies_weight: parsed_or(matches, "copies-weight", "0.1")?,
+                paranoid: occurrences(matches, "paranoid") > 0,
+                similar: parsed(matches, "similar")?,
+                similar_min_size: converted(matches, "similar-min-size", parse_size)?
+                    .unwrap_or(16 << 20),
+                verify_groups: parsed(matches, "verify-groups")?,
+                match_full: value_of(matches, "match") == Some("full"),
+                whole_digest: occurrences(matches, "digest") > 0
+                    || value_of(matches, "match") == Some("full"),
+                hash_audit: parsed_or(matches, "hash-audit", "0")?,
+                // a policy that can't be read leaves the built-in rules
+                policy: value_of(matches, "policy").and_then(
+                    |path| match policy::Policy::from_file(path) {
+                        Ok(policy) => Some(Arc::new(policy)),
+                        Err(e) => {
+                            eprintln!("policy: {} ({}), using the built-in keep rules", e, path);
+                            None
+                        }
+                    },
+                ),
+                lazy_hash: occurrences(matches, "lazy-hash") > 0,
+                hash_cache: occurrences(matches, "no-cache") == 0,
+                include_unstable: occurrences(matches, "include-unstable") > 0,
+                include_empty: occurrences(matches, "include-empty") > 0,
+                count_hardlinks: occurrences(matches, "count-hardlinks") > 0,
+                excludes: Arc::new(
+                    exclude::Excludes::new(
+                        Vec::new(),
+                        values_of(matches, "exclude")
+                            .into_iter()
+                            .map(String::from)
+                            .collect(),
+                    )
+                    .map_err(|e| bad_option("exclude", e))?
+                    .with_includes(
+                        values_of(matches, "include")
+                            .into_iter()
+                            .map(String::from)
+                            .collect(),
+                    )
+                    .map_err(|e| bad_option("include", e))?
+                    .with_regexes(
+                        values_of(matches, "exclude-regex")
+                            .into_iter()
+                            .map(String::from)
+                            .collect(),
+                        values_of(matches, "include-regex")
+                            .into_iter()
+                            .map(String::from)
+                            .collect(),
+                    )
+                    .map_err(|e| bad_option("exclude-regex", e))?,
+                ),
+                stored_excludes: occurrences(matches, "no-stored-excludes") == 0,
+                respect_gitignore: occurrences(matches, "respect-gitignore") > 0,
+                skip_hidden: occurrences(matches, "skip-hidden") > 0,
+                roots_from_stdin: false,
+                canonicalize: occurrences(matches, "no-canonicalize") == 0,
+                walk_once: occurrences(matches, "walk-once") > 0,
+                files_from: value_of(matches, "files-from").map(String::from),
+                newer_than: converted(matches, "newer-than", parse_time)?,
+                older_than: converted(matches, "older-than", parse_time)?,
+                archive_id: None,
+                symlinks: parsed_or(matches, "symlinks", "skip")?,
+                ignore_files: values_of(matches, "ignore-file")
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                tiered_hash: occurrences(matches, "tiered-hash") > 0,
+                verify_exports: parsed_or(matches, "verify-exports", "none")?,
+                orphan_roots: values_of(matches, "orphans")
+                    .into_iter()
+                    .map(normalize_path)
+                    .collect(),
+                prune_orphans: occurrences(matches, "prune-orphans") > 0,
+                // events on stdout carry the same results as JSON output
+                format: match events.as_ref().is_some_and(|e| e.on_stdout()) {
+                    true => file::Format::Json,
+                    false => parsed_or(matches, "format", "plain")?,
+                },
+                events,
+                print0: occurrences(matches, "print0") > 0,
+                csv_header: Arc::new(std::sync::Once::new()),
+                status_json: value_of(matches, "status-json").map(String::from),
+                progress_json: value_of(matches, "progress") == Some("json"),
+                sorted: match occurrences(matches, "sort") > 0 {
+                    true => Some(Arc::default()),
+                    false => None,
+                },
+                missing_by_path,
+                removable_dirs: occurrences(matches, "removable-dirs") > 0,
+                record_dirs: occurrences(matches, "record-dirs") > 0,
+                manifest: value_of(matches, "manifest").map(String::from),
+                check_manifest: value_of(matches, "check-manifest").map(String::from),
+                output: converted(matches, "output", |path| {
+                    redirect_stdout(path, occurrences(matches, "append") > 0)?;
+                    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(path.to_string())
+                })?,
+                findings: converted(matches, "findings", |path| {
+                    findings::Findings::open(path, occurrences(matches, "resume") > 0)
+                })?
+                .map(Arc::new),
+                skip_log: match occurrences(matches, "log-skips") {
+                    0 => None,
+                    _ => Some(Arc::new(skip::SkipLog::default())),
+                },
+                checkpoint_hashing: occurrences(matches, "checkpoint-hashing") > 0,
+                checkpoint_every: parsed::<f64>(matches, "checkpoint-every")?
+                    .map(|minutes| Duration::from_secs_f64(minutes * 60.0)),
+                resume: occurrences(matches, "resume") > 0,
+                resumed: None,
+                path_roots: path_roots(matches)?,
+                convert_private: occurrences(matches, "convert-to-private") > 0,
+                private_key: converted(matches, "private-archive", file::PrivateKey::from_file)?
+                    .map(Arc::new),
             },
             dir_broker_receiver,
+        ))
+    }
+
+    /// True if this run leaves out files a plain walk would take
+    ///
+    ///   Such a walk mustn't be recorded as a complete injest, or
+    ///   --changed-only would take the files it left out as unchanged
+    ///   and never look at them.  Excludes kept in the archive apply
+    ///   to every run, so they don't count.
+    pub fn filters_walk(&self) -> bool {
+        self.excludes.narrows()
+            || self.skip_hidden
+            || self.respect_gitignore
+            || !self.ignore_files.is_empty()
+            || self.newer_than.is_some()
+            || self.older_than.is_some()
+    }
+
+    /// Normalized path of the archive directory
+    pub fn archive(&self) -> &str {
+        &self.archive
+    }
+
+    /// Where to write how the run ended, see Outcome
+    pub fn status_json(&self) -> Option<&str> {
+        self.status_json.as_deref()
+    }
+}
+
+/// How a run ended, for the exit status and --status-json
+///
+///   A walk that stalled or met unreadable files is partial even if
+///   the archive was written, so a script retrying on a non-zero exit
+///   gets to finish the job.  Updated and no-change both succeed, the
+///   status file tells them apart.  An interrupted run, cancelled by
+///   SIGINT, SIGTERM or ScanHandle::cancel, wrote what it had, and
+///   exits apart from a partial one so a wrapper can tell a user
+///   stopping it from a walk going wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Updated,
+    NoChange,
+    Partial,
+    Interrupted,
+    Failed,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 5] = [
+        Outcome::Updated,
+        Outcome::NoChange,
+        Outcome::Partial,
+        Outcome::Interrupted,
+        Outcome::Failed,
+    ];
+
+    /// Name used in --status-json
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Updated => "updated",
+            Outcome::NoChange => "no-change",
+            Outcome::Partial => "partial",
+            Outcome::Interrupted => "interrupted",
+            Outcome::Failed => "failed",
+        }
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Updated | Outcome::NoChange => 0,
+            Outcome::Failed => 1,
+            Outcome::Partial => 3,
+            Outcome::Interrupted => 4,
+        }
+    }
+
+    fn meaning(self) -> &'static str {
+        match self {
+            Outcome::Updated => "the walk finished and the archive was written",
+            Outcome::NoChange => "the walk finished with nothing new to archive",
+            Outcome::Partial => "the walk stalled or had errors, results are incomplete",
+            Outcome::Interrupted => {
+                "the run was cancelled, results are incomplete, an injest wrote what it hashed"
+            }
+            Outcome::Failed => "the run stopped on an error",
+        }
+    }
+
+    /// The outcomes and their exit status, as listed by --help
+    pub fn help() -> String {
+        let mut help = String::from("OUTCOMES (--status-json and exit status):\n");
+        for outcome in Outcome::ALL {
+            help += &format!(
+                "    {:<10} exit {}  {}\n",
+                outcome.name(),
+                outcome.exit_code(),
+                outcome.meaning()
+            );
+        }
+        help
+    }
+}
+
+/// Write the --status-json file, one JSON object naming the outcome
+pub fn write_status(path: &str, outcome: Outcome, fields: Vec<(&str, file::Json)>) -> Result<()> {
+    let mut object = vec![("outcome", file::Json::Str(outcome.name().to_string()))];
+    object.extend(fields);
+    std::fs::write(path, file::json_object(object) + "\n")
+        .map_err(|e| format!("status-json {}: {}", path, e))?;
+    Ok(())
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// True if status output is suppressed, see `status!`
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Suppress status output, set from --quiet by Config::new
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Times an option was given, zero if this binary does not define it
+fn occurrences(matches: &ArgMatches, name: &str) -> u64 {
+    match matches.try_contains_id(name) {
+        Ok(_) => matches.occurrences_of(name),
+        Err(_) => 0,
+    }
+}
+
+/// Value of an option, None if this binary does not define it
+fn value_of<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    match matches.try_contains_id(name) {
+        Ok(_) => matches.value_of(name),
+        Err(_) => None,
+    }
+}
+
+/// All values of an option, empty if this binary does not define it
+fn values_of<'a>(matches: &'a ArgMatches, name: &str) -> Vec<&'a str> {
+    match matches.try_contains_id(name) {
+        Ok(_) => matches
+            .values_of(name)
+            .map(|v| v.collect())
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// An error in the value given an option, naming the option
+fn bad_option(name: &str, e: impl std::fmt::Display) -> Box<dyn std::error::Error + Send + Sync> {
+    format!("--{}: {}", name, e).into()
+}
+
+/// An option's value converted by `convert`, None if not given or if
+/// this binary does not define it
+fn converted<T, E: std::fmt::Display>(
+    matches: &ArgMatches,
+    name: &str,
+    convert: impl FnOnce(&str) -> std::result::Result<T, E>,
+) -> Result<Option<T>> {
+    value_of(matches, name)
+        .map(|value| convert(value).map_err(|e| bad_option(name, e)))
+        .transpose()
+}
+
+/// An option's value parsed, None if not given
+fn parsed<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    converted(matches, name, |value| {
+        value.parse().map_err(|e| format!("{:?}: {}", value, e))
+    })
+}
+
+/// An option's value parsed, `default` parsed if not given
+fn parsed_or<T: std::str::FromStr>(matches: &ArgMatches, name: &str, default: &str) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = value_of(matches, name).unwrap_or(default);
+    value
+        .parse()
+        .map_err(|e| bad_option(name, format!("{:?}: {}", value, e)))
+}
+
+/// The archive to use and any further ones to consult for presence
+///
+///   Several -a are only allowed for checks, unless --write-archive
+///   picks the one to injest into, in which case the rest are unused.
+fn archives(matches: &ArgMatches, injest: bool) -> Result<(String, Vec<String>)> {
+    let require_absolute = occurrences(matches, "require-absolute-archive") > 0;
+    let normalize = |path| normalize_archive_path(path, require_absolute);
+    if let Some(file) = value_of(matches, "archive-file").map(normalize) {
+        let file = file?;
+        if !pack::is_packed(&file) {
+            return Err(format!("--archive-file {} must end in {}", file, pack::SUFFIX).into());
+        }
+        return Ok((file, Vec::new()));
+    }
+    let mut archives = values_of(matches, "archive")
+        .into_iter()
+        .map(normalize)
+        .collect::<Result<Vec<String>>>()?;
+    if let Some(target) = value_of(matches, "write-archive").map(normalize) {
+        let target = target?;
+        if !archives.contains(&target) {
+            return Err(format!("--write-archive {} is not one of the -a archives", target).into());
+        }
+        return Ok((target, Vec::new()));
+    }
+    if archives.is_empty() {
+        return Err("need to specify archive".into());
+    }
+    if injest && archives.len() > 1 {
+        return Err("injesting with several archives needs --write-archive".into());
+    }
+    let first = archives.remove(0);
+    Ok((first, archives))
+}
+
+/// Archived and scanned prefix pairs for --missing-by-path
+///
+///   Each --under A=B says archived paths under A are expected under B.
+///   Without any, the check paths are expected where they were archived.
+fn path_roots(matches: &ArgMatches) -> Result<Vec<(String, String)>> {
+    let under = values_of(matches, "under");
+    if under.is_empty() {
+        return Ok(values_of(matches, "check")
+            .into_iter()
+            .map(|path| (normalize_path(path), normalize_path(path)))
+            .collect());
+    }
+    under
+        .into_iter()
+        .map(|mapping| match mapping.split_once('=') {
+            Some((from, to)) => Ok((normalize_path(from), normalize_path(to))),
+            None => Err(bad_option(
+                "under",
+                format!("expected A=B, got {:?}", mapping),
+            )),
+        })
+        .collect()
+}
+
+/// Parse OLD=NEW prefix pairs, rejecting prefixes that overlap
+///
+///   Overlapping OLD prefixes would make the mapping depend on order,
+///   and overlapping NEW ones would make it impossible to undo.
+pub fn parse_prefix_map(values: &[&str]) -> Result<Vec<(String, String)>> {
+    let mut map: Vec<(String, String)> = Vec::new();
+    for value in values {
+        let (old, new) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected OLD=NEW, got {:?}", value))?;
+        let (old, new) = (normalize_path(old), normalize_path(new));
+        let overlaps = |a: &str, b: &str| {
+            let (a, b) = (std::path::Path::new(a), std::path::Path::new(b));
+            a.starts_with(b) || b.starts_with(a)
+        };
+        for (o, n) in &map {
+            if overlaps(o, &old) || overlaps(n, &new) {
+                return Err(format!("prefix mapping {:?} overlaps {}={}", value, o, n).into());
+            }
+        }
+        map.push((old, new));
+    }
+    Ok(map)
+}
+
+/// Rewrite `path` from under `from` to under `to`, None if not under `from`
+pub fn rebase_path(path: &str, from: &str, to: &str) -> Option<String> {
+    let rest = std::path::Path::new(path).strip_prefix(from).ok()?;
+    if rest.as_os_str().is_empty() {
+        Some(to.to_string())
+    } else {
+        Some(
+            std::path::Path::new(to)
+                .join(rest)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+/// Path with doubled and trailing separators and `.` components removed
+pub fn normalize_path(path: &str) -> String {
+    std::path::Path::new(path)
+        .components()
+        .collect::<std::path::PathBuf>()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Absolute form of `path` with symlinks resolved, as far as it exists
+///
+///   The longest existing ancestor is canonicalized and the rest, which
+///   can hold no links, joined back on, so a tree that has gone since
+///   it was injested still compares by where it was.
+pub fn resolve_path(path: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(path);
+    let mut rest = Vec::new();
+    for ancestor in path.ancestors() {
+        let ancestor = match ancestor.as_os_str().is_empty() {
+            true => std::path::Path::new("."),
+            false => ancestor,
+        };
+        if let Ok(resolved) = std::fs::canonicalize(ancestor) {
+            return rest
+                .iter()
+                .rev()
+                .fold(resolved, |path, name| path.join(name));
+        }
+        if let Some(name) = ancestor.file_name() {
+            rest.push(name.to_owned());
+        }
+    }
+    path.components().collect()
+}
+
+/// Device and inode of the archive directory if an injest root holds
+/// it, as a walk would injest its own sets as they are written
+///
+///   Paths are compared with symlinks resolved, and a root that is the
+///   archive directory, or inside it, is an error.  With roots still to
+///   come from stdin any may hold it.
+fn archive_under_roots(archive: &str, roots: &[&str], more: bool) -> Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+    let resolved = resolve_path(archive);
+    let mut under = more;
+    for root in roots {
+        under |= check_root(archive, root)?;
+    }
+    Ok(match under {
+        true => std::fs::metadata(&resolved)
+            .ok()
+            .map(|metadata| (metadata.dev(), metadata.ino())),
+        false => None,
+    })
+}
+
+/// Refuse a root that is the archive directory or inside it, true if
+/// the archive directory is under it
+fn check_root(archive: &str, root: &str) -> Result<bool> {
+    let (resolved, root_resolved) = (resolve_path(archive), resolve_path(root));
+    if root_resolved.starts_with(&resolved) {
+        return Err(format!(
+            "injest root {} is the archive directory {} or inside it",
+            root, archive
+        )
+        .into());
+    }
+    Ok(resolved.starts_with(&root_resolved))
+}
+
+/// Canonical spelling of an archive path given on the command line
+///
+///   Expands a leading `~` to $HOME and drops trailing and doubled
+///   separators and `.` components, so every spelling of the same
+///   directory names the same set files.  Empty paths, and relative
+///   ones when `require_absolute` is set, are errors.
+pub fn normalize_archive_path(archive: &str, require_absolute: bool) -> Result<String> {
+    if archive.trim().is_empty() {
+        return Err("archive path is empty".into());
+    }
+    let expanded = match archive.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home =
+                std::env::var("HOME").map_err(|_| "archive path uses ~ but HOME is unset")?;
+            format!("{}{}", home, rest)
+        }
+        _ => archive.to_string(),
+    };
+    let mut path: std::path::PathBuf = std::path::Path::new(&expanded)
+        .components()
+        .filter(|c| *c != std::path::Component::CurDir)
+        .collect();
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    if require_absolute && !path.is_absolute() {
+        return Err(format!("archive path {:?} is not absolute", archive).into());
+    }
+    path.into_os_string()
+        .into_string()
+        .map_err(|_| format!("archive path {:?} is not valid UTF-8", archive).into())
+}
+
+/// Read a uid map file of `from:to` lines, blank lines and # comments allowed
+///
+///   Mapping one uid to two different uids is an error.
+pub fn read_uid_map(path: &str) -> Result<HashMap<u32, u32>> {
+    let mut map = HashMap::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || format!("{}:{}: expected from:to, got {:?}", path, n + 1, line);
+        let (from, to) = line.split_once(':').ok_or_else(bad)?;
+        let from: u32 = from.trim().parse().map_err(|_| bad())?;
+        let to: u32 = to.trim().parse().map_err(|_| bad())?;
+        if let Some(prev) = map.insert(from, to) {
+            if prev != to {
+                return Err(format!(
+                    "{}:{}: uid {} mapped to both {} and {}",
+                    path,
+                    n + 1,
+                    from,
+                    prev,
+                    to
+                )
+                .into());
+            }
+        }
+    }
+    Ok(map)
+}
+
+/// Read a --hashes-from file of hex hashes, one per line
+///
+///   Only the first word of a line counts, so a --manifest file can be
+///   read as well; blank lines and # comments are allowed.  A hash is
+///   the 16 hex digits find_dups prints, 64 for --hash blake3 and
+///   sha256, with a trailing `p` for a --media-mode hash.  Other lines
+///   are listed on stderr with their line numbers and counted, but
+///   don't stop the run.
+pub fn read_known_hashes(path: &str) -> Result<std::collections::HashSet<file::FileHash>> {
+    let mut hashes = std::collections::HashSet::new();
+    let mut malformed = 0;
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // a manifest line for an escaped name starts with a backslash
+        let word = line.split_whitespace().next().unwrap_or_default();
+        let word = word.strip_prefix('\\').unwrap_or(word);
+        let (digits, partial) = match word.strip_suffix('p') {
+            Some(digits) => (digits, true),
+            None => (word, false),
+        };
+        let hex = !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit());
+        match file::FileHash::from_hex(digits, partial) {
+            Some(hash) => {
+                hashes.insert(hash);
+            }
+            None => {
+                malformed += 1;
+                if hex {
+                    eprintln!(
+                        "{}:{}: {} hex digits, expected 16, or 64 for blake3 and sha256",
+                        path,
+                        n + 1,
+                        digits.len()
+                    );
+                } else {
+                    eprintln!("{}:{}: expected a hex hash, got {:?}", path, n + 1, line);
+                }
+            }
+        }
+    }
+    status!(
+        "hashes-from {}: {} hashes, {} malformed lines",
+        path,
+        hashes.len(),
+        malformed
+    );
+    Ok(hashes)
+}
+
+/// Send stdout, where all results go, to a file from here on
+///
+///   Done with dup2 so every result line follows however it is
+///   written, while status stays on stderr.  Opening it up front makes
+///   a bad path fail before a long scan rather than after it.
+pub fn redirect_stdout(path: &str, append: bool) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    std::io::stdout().flush()?;
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Parse a byte count with an optional K/M/G/T (powers of 1024) suffix
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1u64 << 20),
+        Some('G') => (&s[..s.len() - 1], 1u64 << 30),
+        Some('T') => (&s[..s.len() - 1], 1u64 << 40),
+        _ => (s, 1),
+    };
+    Ok(num.parse::<u64>()? * mult)
+}
+
+/// Parse a time as unix seconds: a UTC date, `2023-01-01` or
+/// `2023-01-01T12:00:00`, or a time ago, a count with an s, m, h, d or
+/// w suffix such as `90d`
+pub fn parse_time(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let bad = || {
+        format!(
+            "{:?} is neither a date like 2023-01-01 nor a time ago like 90d",
+            s
         )
+    };
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        Some('w') => 7 * 86400,
+        _ => 0,
+    };
+    if unit > 0 {
+        let count: u64 = s[..s.len() - 1].parse().map_err(|_| bad())?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        return Ok(now.saturating_sub(count * unit));
     }
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+    let numbers = |text: &str, sep: char| -> Result<Vec<u64>> {
+        text.split(sep)
+            .map(|n| n.parse::<u64>().map_err(|_| bad().into()))
+            .collect()
+    };
+    match (&numbers(date, '-')?[..], &numbers(time, ':')?[..]) {
+        ([y, mo, d], [h, mi, sec])
+            if *y >= 1970
+                && (1..=12).contains(mo)
+                && (1..=days_in_month(*y, *mo)).contains(d)
+                && *h < 24
+                && *mi < 60
+                && *sec < 61 =>
+        {
+            Ok(days_from_civil(*y, *mo, *d) * 86400 + h * 3600 + mi * 60 + sec)
+        }
+        _ => Err(bad().into()),
+    }
+}
+
+/// Days in a month of a year, February counting leap years
+fn days_in_month(y: u64, m: u64) -> u64 {
+    match m {
+        2 if y.is_multiple_of(4) && (!y.is_multiple_of(100) || y.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to a date, after Howard Hinnant's algorithm
+fn days_from_civil(y: u64, m: u64, d: u64) -> u64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Format a byte count as GiB with two decimals plus the exact count
+pub fn format_gib(bytes: u64) -> String {
+    format!(
+        "{:.2} GiB ({} bytes)",
+        bytes as f64 / (1u64 << 30) as f64,
+        bytes
+    )
+}
+
+/// Format a byte count briefly, e.g. 1.2 GiB
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
 }
 
 pub trait ItemReadWrite {
@@ -88,44 +1038,452 @@ pub trait ItemReadWrite {
     fn read_item(&mut self) -> Result<Option<Self::T>>;
 }
 
+/// Start a run walking `injests`, returning a handle to steer it and
+/// await its outcome
 pub async fn launch_brokers(
-    config: Config,
+    mut config: Config,
     dir_receiver: Receiver<DirBrokerMessage>,
     injests: Vec<&str>,
-) -> Result<()> {
+) -> Result<ScanHandle> {
+    // `-` reads more roots from stdin, see read_roots
+    config.roots_from_stdin = injests.contains(&"-");
+    let injests: Vec<&str> = injests.into_iter().filter(|root| *root != "-").collect();
+    let injests = match config.canonicalize {
+        true => canonical_roots(&injests),
+        false => injests,
+    };
+    if config.skip_log.is_some() {
+        if config.private_key.is_some() {
+            return Err("--log-skips records paths, which a private archive keeps none of".into());
+        }
+        if pack::is_packed(&config.archive) {
+            return Err(format!(
+                "{} is a single file archive, which keeps no skip records",
+                config.archive
+            )
+            .into());
+        }
+    }
+    if config.injest && !pack::is_packed(&config.archive) {
+        config.archive_id =
+            archive_under_roots(&config.archive, &injests, config.roots_from_stdin)?;
+    }
+    if config.checkpoint_hashing {
+        if config.private_key.is_some() {
+            return Err(
+                "--checkpoint-hashing records paths, which a private archive keeps none of".into(),
+            );
+        }
+        if pack::is_packed(&config.archive) {
+            return Err(format!(
+                "{} is a single file archive, which keeps no checkpoints",
+                config.archive
+            )
+            .into());
+        }
+    }
+    if config.resume && !config.injest && config.findings.is_none() {
+        return Err("--resume continues a check from its --findings file, give one".into());
+    }
+    if config.injest && (config.resume || config.checkpoint_every.is_some()) {
+        if config.private_key.is_some() {
+            return Err(
+                "run checkpoints record paths, which a private archive keeps none of".into(),
+            );
+        }
+        if config.roots_from_stdin {
+            return Err(
+                "run checkpoints need the roots on the command line, not from stdin".into(),
+            );
+        }
+        if pack::is_packed(&config.archive) {
+            return Err(format!(
+                "{} is a single file archive, which keeps no run checkpoints",
+                config.archive
+            )
+            .into());
+        }
+    }
+    if config.injest && config.resume {
+        match resume::stored(&config.archive).await? {
+            Some(saved) => {
+                let roots: Vec<String> = injests.iter().map(|root| normalize_path(root)).collect();
+                saved.check_roots(&config.archive, &roots)?;
+                status!(
+                    "resuming: {} directories left of the walk checkpointed in {}",
+                    saved.pending.len(),
+                    config.archive
+                );
+                config.resumed = Some(Arc::new(saved));
+            }
+            None => status!("no run checkpoint in {}, starting over", config.archive),
+        }
+    }
+    if config.stored_excludes {
+        let stored = exclude::stored(&config.archive).await?;
+        if !stored.is_empty() {
+            config.excludes = Arc::new(config.excludes.with_stored(stored)?);
+        }
+    }
+    if !config.excludes.is_empty() {
+        config.excludes = Arc::new(config.excludes.with_roots(&injests));
+    }
+    if config.verbose > 0 && !config.excludes.is_empty() {
+        for pattern in config.excludes.describe() {
+            eprintln!("exclude {}", pattern);
+        }
+        for pattern in config.excludes.includes() {
+            eprintln!("include {} (--include)", pattern);
+        }
+        let (exclude, include) = config.excludes.regexes();
+        for regex in exclude {
+            eprintln!("exclude {} (--exclude-regex)", regex);
+        }
+        for regex in include {
+            eprintln!("include {} (--include-regex)", regex);
+        }
+    }
     if config.verbose > 2 {
         eprintln!("Config: {:?}", config)
     }
+    if let Some(findings) = &config.findings {
+        findings.event(
+            "run_started",
+            vec![
+                (
+                    "mode",
+                    file::Json::Str(if config.injest { "injest" } else { "check" }.to_string()),
+                ),
+                ("archive", file::Json::Str(config.archive.clone())),
+                (
+                    "paths",
+                    file::Json::List(
+                        injests
+                            .iter()
+                            .map(|path| file::Json::Str(path.to_string()))
+                            .collect(),
+                    ),
+                ),
+                ("missing", file::Json::Bool(config.missing)),
+                ("present", file::Json::Bool(config.present)),
+                ("duplicate", file::Json::Bool(config.duplicate)),
+                (
+                    "excludes",
+                    file::Json::List(
+                        config
+                            .excludes
+                            .patterns()
+                            .map(|pattern| file::Json::Str(pattern.to_string()))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "includes",
+                    file::Json::List(
+                        config
+                            .excludes
+                            .includes()
+                            .map(|pattern| file::Json::Str(pattern.to_string()))
+                            .collect(),
+                    ),
+                ),
+                ("resume", file::Json::Bool(findings.resumed() > 0)),
+                ("resumed_dirs", file::Json::Num(findings.resumed() as u64)),
+            ],
+        )?;
+    }
     let mut sender = config.dir_broker_sender.clone();
-    for injest in injests {
+    if let Some(list) = &config.files_from {
         sender
-            .send(DirBrokerMessage::NewDir {
-                path: PathBuf::from(injest),
-                depth: 0,
-            })
+            .send(DirBrokerMessage::NewList { list: list.clone() })
             .await?
     }
-    let d = spawn_and_log_error(dir_broker_loop(config.clone(), dir_receiver));
-    let t = spawn_and_log_error(timer_broker_loop(config.clone()));
-    d.await;
-    t.cancel().await;
+    // a resumed walk starts where the checkpoint left it, see resume.rs
+    for injest in injests.into_iter().filter(|_| config.resumed.is_none()) {
+        send_root(&config, &mut sender, injest).await?;
+    }
+    if config.roots_from_stdin {
+        spawn_until_shutdown(config.shutdown.clone(), read_roots(config.clone()));
+    }
+    let (broker_config, shutdown) = (config.clone(), config.shutdown.clone());
+    let d = task::spawn(async move {
+        let mut dir_receiver = dir_receiver;
+        let outcome = dir_broker_loop(broker_config, &mut dir_receiver).await;
+        // before the receiver goes, so sends failing from here on are
+        // known to be the shutdown and not reported
+        shutdown.begin();
+        outcome
+    });
+    let t = spawn_until_shutdown(config.shutdown.clone(), timer_broker_loop(config.clone()));
+    Ok(ScanHandle {
+        sender: config.dir_broker_sender.clone(),
+        shutdown: config.shutdown.clone(),
+        broker: d,
+        tasks: vec![t],
+    })
+}
+
+/// A run started by launch_brokers
+///
+///   Cancelling writes what was hashed, as an interrupted run does, and
+///   the run ends Interrupted.  Pausing stops new directories being
+///   walked, those under way still finish, and doesn't count towards
+///   the stall timeout.  Once the run is over these do nothing.
+#[derive(Debug)]
+pub struct ScanHandle {
+    sender: Sender<DirBrokerMessage>,
+    shutdown: Shutdown,
+    broker: task::JoinHandle<Result<Outcome>>,
+    /// auxiliary tasks, stopped with the broker
+    tasks: Vec<task::JoinHandle<()>>,
+}
+
+impl ScanHandle {
+    pub async fn cancel(&self) -> Result<()> {
+        self.send(DirBrokerMessage::Shutdown).await
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.send(DirBrokerMessage::Pause).await
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.send(DirBrokerMessage::Resume).await
+    }
+
+    async fn send(&self, msg: DirBrokerMessage) -> Result<()> {
+        match self.sender.clone().send(msg).await {
+            Err(_) if self.shutdown.has_begun() => Ok(()),
+            sent => Ok(sent?),
+        }
+    }
+
+    /// Cancel the run on SIGINT or SIGTERM, see catch_signals
+    pub fn cancel_on_signals(&mut self) {
+        catch_signals();
+        let signals = signal_broker_loop(self.sender.clone(), self.shutdown.clone());
+        self.tasks
+            .push(spawn_until_shutdown(self.shutdown.clone(), signals));
+    }
+
+    /// Wait for the run to end
+    pub async fn wait(self) -> Result<Outcome> {
+        let outcome = self.broker.await;
+        for t in self.tasks {
+            t.cancel().await;
+        }
+        outcome
+    }
+}
+
+/// The roots to walk, none inside another
+///
+///   A root inside another, or the same directory spelled another way,
+///   is left out with a notice, as the walk of the other reaches it.
+///   Roots are compared with symlinks resolved, but walked, and so
+///   archived, as given, so an archive keeps the paths it always had.
+fn canonical_roots<'a>(roots: &[&'a str]) -> Vec<&'a str> {
+    let resolved: Vec<std::path::PathBuf> = roots.iter().map(|root| resolve_path(root)).collect();
+    let mut kept = Vec::new();
+    for (i, root) in roots.iter().enumerate() {
+        let outer = resolved.iter().enumerate().find(|(j, other)| {
+            *j != i && resolved[i].starts_with(other) && (resolved[i] != **other || *j < i)
+        });
+        match outer {
+            Some((j, _)) => status!(
+                "roots: {} is inside {}, walked once as part of it",
+                root,
+                roots[j]
+            ),
+            None => kept.push(*root),
+        }
+    }
+    kept
+}
+
+/// Queue an injest or check root for the broker
+async fn send_root(
+    config: &Config,
+    sender: &mut Sender<DirBrokerMessage>,
+    root: &str,
+) -> Result<()> {
+    let ignores = match config.respect_gitignore || !config.ignore_files.is_empty() {
+        true => Some(ignore::Ignores::root(
+            root,
+            &config.ignore_files,
+            config.respect_gitignore,
+        )?),
+        false => None,
+    };
+    // a root reached again, by a link or with --walk-once another root,
+    // is not walked twice
+    let id = match config.walk_once || config.symlinks == file::Symlinks::Follow {
+        true => {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(root)
+                .ok()
+                .map(|metadata| (metadata.dev(), metadata.ino()))
+        }
+        false => None,
+    };
+    sender
+        .send(DirBrokerMessage::NewDir {
+            path: PathBuf::from(root),
+            depth: 0,
+            ignores,
+            id,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Queue the roots read from stdin, one per line, as they come, then
+/// tell the broker there are no more
+///
+///   A root that can't be walked is reported and passed over, as the
+///   walk is already under way.
+///
+///   A root inside one read before is left out as canonical_roots
+///   leaves it out, but one read after the roots inside it were is
+///   walked all the same, their trees twice unless with --walk-once.
+async fn read_roots(config: Config) -> Result<()> {
+    let mut sender = config.dir_broker_sender.clone();
+    let mut sent: Vec<std::path::PathBuf> = Vec::new();
+    let mut lines = async_std::io::BufReader::new(async_std::io::stdin()).lines();
+    while let Some(line) = lines.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("roots: reading stdin: {}", e);
+                break;
+            }
+        };
+        let root = line.trim_end_matches('\r');
+        if root.is_empty() {
+            continue;
+        }
+        if config.injest && !pack::is_packed(&config.archive) {
+            if let Err(e) = check_root(&config.archive, root) {
+                eprintln!("roots: {}", e);
+                continue;
+            }
+        }
+        let resolved = resolve_path(root);
+        if let Some(outer) = sent.iter().find(|outer| resolved.starts_with(outer)) {
+            if config.canonicalize {
+                status!(
+                    "roots: {} is inside {}, walked once as part of it",
+                    root,
+                    outer.display()
+                );
+                continue;
+            }
+        }
+        sent.push(resolved);
+        if let Err(e) = send_root(&config, &mut sender, root).await {
+            eprintln!("roots: {} ({})", e, root);
+        }
+    }
+    sender.send(DirBrokerMessage::RootsDone).await?;
     Ok(())
 }
 
 /// Timer loop, simply sends Report messages to other loops
-/// periodcially.  Need to mark as allow unreachable because this task
-/// is simply canceled after other loops exit.
-#[allow(unreachable_code)]
+/// periodcially until the run shuts down.
 pub async fn timer_broker_loop(config: Config) -> Result<()> {
+    let mut sender = config.dir_broker_sender.clone();
     loop {
         task::sleep(Duration::from_millis(1500)).await;
-        config
-            .dir_broker_sender
-            .clone()
-            .send(DirBrokerMessage::Report)
-            .await?;
+        if config.shutdown.has_begun() {
+            return Ok(());
+        }
+        sender.send(DirBrokerMessage::Report).await?;
+    }
+}
+
+/// SIGINT and SIGTERM received, see catch_signals
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Exit status of a run stopped by a second signal, as a shell has it
+const ABORTED: i32 = 128 + libc::SIGINT;
+
+/// True once SIGINT or SIGTERM was caught, see catch_signals
+pub fn interrupted() -> bool {
+    SIGNALS.load(Ordering::SeqCst) > 0
+}
+
+/// Count SIGINT and SIGTERM rather than die of them
+///
+///   The first is only counted.  signal_broker_loop cancels the walk,
+///   and the phases after it check interrupted, so the run finishes up
+///   early and writes the archive.  A second, as when that takes too
+///   long for whoever pressed Ctrl+C, exits at once.  The archive is
+///   written aside and only then swapped in, see FileStore::write, so
+///   unless the swap is under way that leaves it as it was.
+fn catch_signals() {
+    extern "C" fn on_signal(_: libc::c_int) {
+        if SIGNALS.fetch_add(1, Ordering::SeqCst) > 0 {
+            // only async-signal-safe calls in here
+            unsafe { libc::_exit(ABORTED) }
+        }
+    }
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
     }
-    Ok(())
+}
+
+/// Watch for a signal caught by catch_signals, and pass it on to the
+/// dir broker as a Shutdown
+async fn signal_broker_loop(
+    mut sender: Sender<DirBrokerMessage>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    loop {
+        task::sleep(Duration::from_millis(100)).await;
+        if shutdown.has_begun() {
+            return Ok(());
+        }
+        if SIGNALS.load(Ordering::SeqCst) > 0 {
+            status!("interrupted, writing what was hashed; again to stop without writing");
+            sender.send(DirBrokerMessage::Shutdown).await?;
+            return Ok(());
+        }
+    }
+}
+
+/// Signal that the dir broker is done, see spawn_until_shutdown
+///
+///   Auxiliary tasks check it before sending to the broker, and an
+///   error once it is set is only the broker having gone, so they
+///   stop without a word rather than report it.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn begin(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn has_begun(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Spawn an auxiliary task, reporting its error unless the run was
+/// shutting down by then
+pub fn spawn_until_shutdown<F>(shutdown: Shutdown, fut: F) -> task::JoinHandle<()>
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    task::spawn(async move {
+        if let Err(e) = fut.await {
+            if !shutdown.has_begun() {
+                eprintln!("spawn: {}", e)
+            }
+        }
+    })
 }
 
 pub fn spawn_and_log_error<F>(fut: F) -> task::JoinHandle<()>
@@ -138,3 +1496,228 @@ where
         }
     })
 }
+
+/// Helpers for the unit tests of every module
+#[cfg(test)]
+pub(crate) mod testing {
+    use clap::{arg, ArgMatches, Command};
+
+    /// A Config as find_dups would build it from these arguments
+    ///
+    ///   Only the options the tests need are defined, Config::new
+    ///   takes the rest as not given.
+    pub fn config(args: &[&str]) -> crate::Config {
+        try_config(args).unwrap()
+    }
+
+    /// The Config, or the error Config::new gives for these arguments
+    pub fn try_config(args: &[&str]) -> crate::Result<crate::Config> {
+        Ok(crate::Config::new(&matches(args))?.0)
+    }
+
+    /// The outcome of a whole run of find_dups with these arguments
+    /// over `roots`
+    pub fn scan(args: &[&str], roots: &[&str]) -> crate::Outcome {
+        let (config, dir_receiver) = crate::Config::new(&matches(args)).unwrap();
+        async_std::task::block_on(async {
+            let scan = crate::launch_brokers(config, dir_receiver, roots.to_vec()).await;
+            scan.unwrap().wait().await.unwrap()
+        })
+    }
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        Command::new("find_dups")
+            .arg(arg!(-a --archive <path> ... "archive").required(false))
+            .arg(arg!(-c --check <path> ... "check").required(false))
+            .arg(arg!(--"write-archive" <path> "write archive").required(false))
+            .arg(arg!(--"archive-file" <file> "archive file").required(false))
+            .arg(arg!(-m --missing "missing"))
+            .arg(arg!(-p --present "present"))
+            .arg(arg!(-d --duplicate "duplicate"))
+            .arg(arg!(--prune "prune"))
+            .arg(arg!(--"newer-than" <when> "newer").required(false))
+            .arg(arg!(--"older-than" <when> "older").required(false))
+            .arg(arg!(--"uid-map" <file> "uid map").required(false))
+            .arg(arg!(--"strict-metadata" "strict metadata"))
+            .arg(arg!(--digest "digest"))
+            .arg(arg!(--"private-archive" <keyfile> "private").required(false))
+            .arg(arg!(--"convert-to-private" "convert"))
+            .arg(arg!(-v --verbose ... "verbose"))
+            .arg(arg!(-q --quiet "quiet"))
+            .get_matches_from(std::iter::once("find_dups").chain(args.iter().copied()))
+    }
+
+    /// An empty directory of its own for a test
+    pub fn scratch(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("find_dups-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_are_listed_with_their_exit_status() {
+        let help = Outcome::help();
+        for outcome in Outcome::ALL {
+            let line = help
+                .lines()
+                .find(|line| line.trim_start().starts_with(outcome.name()))
+                .unwrap();
+            assert!(line.contains(&format!("exit {}", outcome.exit_code())));
+            assert!(line.ends_with(outcome.meaning()));
+        }
+    }
+
+    #[test]
+    fn dates_parse_to_unix_time() {
+        assert_eq!(parse_time("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_time("2000-03-01").unwrap(), 951868800);
+        assert_eq!(parse_time("2024-02-29").unwrap(), 1709164800);
+        assert_eq!(parse_time("2024-02-29T12:30:15").unwrap(), 1709209815);
+    }
+
+    #[test]
+    fn impossible_dates_are_refused() {
+        for date in [
+            "2024-02-30",
+            "2024-02-31",
+            "2023-02-29",
+            "2100-02-29",
+            "2023-04-31",
+            "2023-13-01",
+            "2023-00-10",
+            "2023-01-00",
+            "1969-12-31",
+            "2023-01-01T24:00:00",
+        ] {
+            assert!(parse_time(date).is_err(), "{} parsed", date);
+        }
+        assert!(parse_time("2000-02-29").is_ok());
+    }
+
+    #[test]
+    fn times_ago_count_back_from_now() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let ago = parse_time("2d").unwrap();
+        assert!(now - ago >= 2 * 86400 && now - ago < 2 * 86400 + 5);
+        assert!(parse_time("1w").unwrap() < parse_time("6d").unwrap());
+        assert!(parse_time("xd").is_err());
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("").is_err());
+    }
+
+    #[test]
+    fn totals_under_a_gigabyte_still_show() {
+        assert_eq!(format_gib(0), "0.00 GiB (0 bytes)");
+        assert_eq!(format_gib(999_999_999), "0.93 GiB (999999999 bytes)");
+        assert_eq!(format_gib(5 << 20), "0.00 GiB (5242880 bytes)");
+        assert_eq!(format_gib(3 << 29), "1.50 GiB (1610612736 bytes)");
+        assert_eq!(format_size(1023), "1023 bytes");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 << 40), "5.0 TiB");
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("1000000").unwrap(), 1_000_000);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size(" 2M ").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn bad_archive_options_are_errors() {
+        let error = |args: &[&str]| testing::try_config(args).unwrap_err().to_string();
+        assert_eq!(error(&[]), "need to specify archive");
+        assert_eq!(error(&["-a", " "]), "archive path is empty");
+        assert!(error(&["-a", "/a", "-a", "/b"]).contains("--write-archive"));
+        assert!(error(&["-a", "/a", "--write-archive", "/c"]).contains("not one of"));
+        assert!(error(&["--archive-file", "/a.txt"]).contains("must end in"));
+        let config = testing::config(&["-a", "/a", "-a", "/b", "--write-archive", "/b/"]);
+        assert_eq!(config.archive(), "/b");
+    }
+
+    #[test]
+    fn bad_option_values_are_errors() {
+        let error = |args: &[&str]| testing::try_config(args).unwrap_err().to_string();
+        let given = |option: &str, value: &str| error(&["-a", "/a", option, value]);
+        assert!(given("--newer-than", "yesterday").starts_with("--newer-than: "));
+        assert!(given("--uid-map", "/nonexistent/uids").starts_with("--uid-map: "));
+        assert!(given("--private-archive", "/nonexistent/key").starts_with("--private-archive: "));
+    }
+
+    fn id(path: &std::path::Path) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(path).unwrap();
+        Some((metadata.dev(), metadata.ino()))
+    }
+
+    #[test]
+    fn an_archive_under_a_root_is_found() {
+        let dir = testing::scratch("under-roots");
+        let archive = dir.join("data/.finddups");
+        std::fs::create_dir_all(&archive).unwrap();
+        let (root, archive_str) = (dir.join("data"), archive.to_str().unwrap());
+        let found = archive_under_roots(archive_str, &[root.to_str().unwrap()], false).unwrap();
+        assert_eq!(found, id(&archive));
+        let elsewhere = dir.join("elsewhere");
+        std::fs::create_dir(&elsewhere).unwrap();
+        let roots = [elsewhere.to_str().unwrap()];
+        assert_eq!(
+            archive_under_roots(archive_str, &roots, false).unwrap(),
+            None
+        );
+        assert_eq!(
+            archive_under_roots(archive_str, &roots, true).unwrap(),
+            id(&archive)
+        );
+    }
+
+    #[test]
+    fn a_symlinked_archive_under_a_root_is_found() {
+        let dir = testing::scratch("symlinked-archive");
+        let archive = dir.join("data/.finddups");
+        std::fs::create_dir_all(&archive).unwrap();
+        std::os::unix::fs::symlink(&archive, dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("data"), dir.join("root")).unwrap();
+        let link = dir.join("link");
+        let root = dir.join("data");
+        let found =
+            archive_under_roots(link.to_str().unwrap(), &[root.to_str().unwrap()], false).unwrap();
+        assert_eq!(found, id(&archive));
+        // a root reached through a link holds it too
+        let root = dir.join("root");
+        let found =
+            archive_under_roots(archive.to_str().unwrap(), &[root.to_str().unwrap()], false)
+                .unwrap();
+        assert_eq!(found, id(&archive));
+    }
+
+    #[test]
+    fn a_root_in_the_archive_is_refused() {
+        let dir = testing::scratch("root-in-archive");
+        let archive = dir.join("archive");
+        std::fs::create_dir_all(archive.join("inside")).unwrap();
+        std::os::unix::fs::symlink(&archive, dir.join("link")).unwrap();
+        let archive_str = archive.to_str().unwrap();
+        for root in [archive.clone(), archive.join("inside"), dir.join("link")] {
+            let roots = [root.to_str().unwrap()];
+            assert!(archive_under_roots(archive_str, &roots, false).is_err());
+        }
+        // a spelling of it not yet made resolves through the link too
+        let later = dir.join("link/later");
+        let roots = [later.to_str().unwrap()];
+        assert!(archive_under_roots(archive_str, &roots, false).is_err());
+    }
+}
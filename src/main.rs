@@ -1,10 +1,20 @@
 use async_std::task;
-use clap::{app_from_crate, arg};
+use clap::{app_from_crate, arg, ArgMatches, Command};
 
-use find_dups::{launch_brokers, Config};
+use async_std::path::PathBuf;
+use find_dups::{
+    archive::{Archive, FORMAT_VERSION},
+    exclude,
+    file::FileStore,
+    launch_brokers, normalize_archive_path, normalize_path, write_status, Config, Outcome, Result,
+    ARCHIVE_SIZE,
+};
 
 fn main() {
+    let format_version = FORMAT_VERSION.to_string();
+    let outcomes = Outcome::help();
     let matches = app_from_crate!()
+        .after_help(outcomes.as_str())
         .arg(
             arg!(-i --injest <path> ... "Path to injest")
                 .required(false)
@@ -15,6 +25,16 @@ fn main() {
                 .required(false)
                 .conflicts_with("injest"),
         )
+        .arg(
+            arg!(--"files-from" <path> "Injest, or with --check-listed check, the files listed one per line in this file, - for stdin, walking no directories")
+                .required(false)
+                .conflicts_with_all(&["injest", "check"]),
+        )
+        .arg(
+            arg!(--"check-listed" "Check rather than injest the files of --files-from")
+                .required(false)
+                .requires("files-from"),
+        )
         .arg(
             arg!(-m --missing "Report check/injest files which are missing from archive [default with --check]")
                 .required(false)
@@ -22,6 +42,19 @@ fn main() {
                 .conflicts_with("duplicate")
                 .conflicts_with("present"),
         )
+        .arg(
+            arg!(--"missing-by-path" "Report archived paths with no file at the same place in the check paths")
+                .required(false)
+                .requires("check")
+                .conflicts_with("list")
+                .conflicts_with("duplicate")
+                .conflicts_with("present"),
+        )
+        .arg(
+            arg!(--under <mapping> ... "With --missing-by-path, check archived paths under A at B, given as A=B")
+                .required(false)
+                .requires("missing-by-path"),
+        )
         .arg(
             arg!(-p --present "Report check/injest files which are present in archive")
                 .required(false)
@@ -49,24 +82,720 @@ fn main() {
                 .requires("injest")
         )
         .arg(
-            arg!(-a --archive <path> "Path to archive")
+            arg!(--"removable-dirs" "Plan which archived directories have a copy of every file elsewhere")
+                .required(false),
+        )
+        .arg(
+            arg!(--"record-dirs" "Archive directories (path, mode, owner, mtime) when injesting and verify them when checking")
+                .required(false),
+        )
+        .arg(
+            arg!(--findings <path> "Append results as ndjson events to this file, with a footer once the run completes")
+                .required(false),
+        )
+        .arg(
+            arg!(--resume "Continue the last incomplete run, an injest from its --checkpoint-every checkpoint, a check from its --findings file")
+                .required(false),
+        )
+        .arg(
+            arg!(--"checkpoint-every" <minutes> "Write the archive and what is left of the walk this often, so an injest that dies continues with --resume")
+                .required(false)
+                .requires("injest")
+                .conflicts_with("files-from")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(m) if m > 0.0 => Ok(()),
+                    _ => Err("a number of minutes"),
+                }),
+        )
+        .arg(
+            arg!(--"log-skips" "Record in the archive each file the injest leaves out and why, see find_dups why")
+                .required(false)
+                .requires("injest"),
+        )
+        .arg(
+            arg!(--"checkpoint-hashing" "Record progress hashing files of a GiB or more in the archive, so an interrupted injest resumes them")
+                .required(false)
+                .requires("injest"),
+        )
+        .arg(
+            arg!(--"changed-only" "Injest only files changed since the last complete injest of the same paths")
+                .required(false)
+                .conflicts_with("check"),
+        )
+        .arg(
+            arg!(--"changed-by" <time> "Time --changed-only compares, ctime also catches mtimes set back")
+                .required(false)
+                .possible_values(["mtime", "ctime"])
+                .default_value("mtime"),
+        )
+        .arg(
+            arg!(--full "Look at every file, overriding --changed-only")
+                .required(false),
+        )
+        .arg(
+            arg!(--manifest <path> "Write a `<hash>  <path>` line per archived file to this file")
+                .required(false),
+        )
+        .arg(
+            arg!(--"check-manifest" <path> "Report files whose hash in this manifest differs from the archive's")
+                .required(false),
+        )
+        .arg(
+            arg!(--orphans <root> ... "Report archived files under these roots that no longer exist")
+                .required(false),
+        )
+        .arg(
+            arg!(--"prune-orphans" "Remove the entries found by --orphans from the archive")
+                .required(false)
+                .requires("orphans"),
+        )
+        .arg(
+            arg!(-a --archive <path> ... "Path to archive, repeat to check against several")
                 .required(false)
                 .default_value("/tmp/finddups"),
         )
+        .arg(
+            arg!(--"archive-file" <path> "Single file archive (.fda) to use instead of an archive directory")
+                .required(false)
+                .conflicts_with("write-archive"),
+        )
+        .arg(
+            arg!(--"write-archive" <path> "Archive to injest into when several -a are given")
+                .required(false),
+        )
+        .arg(
+            arg!(--"require-absolute-archive" "Refuse a relative archive path")
+                .required(false),
+        )
+        .arg(
+            arg!(--"private-archive" <keyfile> "Store only sizes, hashes and an HMAC of each path keyed by this file, hiding the paths; it does not guard the archive against changes")
+                .required(false),
+        )
+        .arg(
+            arg!(--"convert-to-private" "With --private-archive, rewrite a plain archive keeping only path HMACs, losing its paths for good")
+                .required(false)
+                .requires("private-archive"),
+        )
+        .arg(
+            arg!(--repair "Move leftover or ambiguous set files out of the archive instead of stopping")
+                .required(false),
+        )
+        .arg(
+            arg!(--format <format> "Write results as plain lines, JSON objects one per line, CSV rows (--list and duplicates), or duplicate groups as fdupes prints them")
+                .required(false)
+                .possible_values(["plain", "json", "csv", "fdupes"])
+                .default_value("plain"),
+        )
+        .arg(
+            arg!(-o --output <path> "Write results to this file instead of stdout, status stays on stderr")
+                .required(false),
+        )
+        .arg(
+            arg!(--append "With --output, add to the file instead of replacing it")
+                .required(false)
+                .requires("output"),
+        )
+        .arg(
+            arg!(-'0' --print0 "End each plain result with a NUL, and each duplicate group with a second one, and read --files-from lists NUL separated")
+                .required(false)
+                .conflicts_with("events"),
+        )
+        .arg(
+            arg!(--events <format> "Stream scan progress and results as JSON events on stdout, status on stderr")
+                .required(false)
+                .possible_values(["ndjson"]),
+        )
+        .arg(
+            arg!(--progress <format> "Print each progress tick as a JSON object on stderr, not only with -v")
+                .required(false)
+                .possible_values(["json"]),
+        )
+        .arg(
+            arg!(--"status-json" <path> "Write how the run ended to this file as JSON, see OUTCOMES")
+                .required(false),
+        )
+        .arg(
+            arg!(--sort "Print results sorted by path once the walk is done, rather than as found")
+                .required(false)
+                .conflicts_with("events"),
+        )
+        .arg(
+            arg!(--"events-file" <path> "Write the --events ndjson stream to this file, leaving stdout to the results")
+                .required(false),
+        )
         .arg(arg!(-r --report "Produce a report summarizing duplicate files").required(false))
+        .arg(
+            arg!(--"big-threshold" <bytes> "Size above which a duplicate counts as big (K/M/G suffix allowed)")
+                .required(false)
+                .default_value("1000000"),
+        )
+        .arg(
+            arg!(--group <id> ... "Only report and act on the duplicate groups with these ids")
+                .required(false),
+        )
+        .arg(
+            arg!(--"order-by" <order> "Order duplicate groups by score, waste, count or size")
+                .required(false)
+                .possible_values(["score", "waste", "count", "size"]),
+        )
+        .arg(
+            arg!(--"verify-exports" <mode> "Byte-compare one pair (sample) or all members (full) of listed duplicate groups, or none")
+                .required(false)
+                .possible_values(["sample", "full", "none"])
+                .default_value("none"),
+        )
+        .arg(
+            arg!(--"lazy-hash" "When injesting, only read a file once another file of its size shows up")
+                .required(false)
+                .conflicts_with("check"),
+        )
+        .arg(
+            arg!(--"tiered-hash" "When injesting, leave files over a chunk unread until another shares their size, then their first chunk; a later check needs those files mounted to compare them")
+                .required(false)
+                .conflicts_with("check"),
+        )
+        .arg(
+            arg!(--"include-unstable" "Keep files whose size or mtime changed while they were hashed")
+                .required(false),
+        )
+        .arg(
+            arg!(--"include-empty" "Group empty files like any others, to report them as duplicates, present or missing")
+                .required(false),
+        )
+        .arg(
+            arg!(--"count-hardlinks" "Hash every link to an inode and count links as reclaimable")
+                .required(false),
+        )
+        .arg(
+            arg!(--exclude <pattern> ... "Leave out paths matching this glob, with any kept in the archive by find_dups exclude")
+                .required(false),
+        )
+        .arg(
+            arg!(--include <pattern> ... "Take only files matching this glob, after the excludes, still walking every directory")
+                .required(false),
+        )
+        .arg(
+            arg!(--"exclude-regex" <regex> ... "Leave out paths matching this regex anywhere in the whole path")
+                .required(false)
+                .validator(exclude::check_regex),
+        )
+        .arg(
+            arg!(--"include-regex" <regex> ... "Take only files matching this regex or an --include glob")
+                .required(false)
+                .validator(exclude::check_regex),
+        )
+        .arg(
+            arg!(--symlinks <policy> "Leave symlinks out, follow them to what they point to, each directory walked once, or keep the links themselves")
+                .required(false)
+                .possible_values(["skip", "follow", "record"])
+                .default_value("skip"),
+        )
+        .arg(
+            arg!(--"newer-than" <time> "Only look at files modified after this UTC date, 2023-01-01, or time ago, 90d")
+                .required(false)
+                .validator(|s| find_dups::parse_time(s).map(|_| ())),
+        )
+        .arg(
+            arg!(--"older-than" <time> "Only look at files modified before this UTC date or time ago")
+                .required(false)
+                .validator(|s| find_dups::parse_time(s).map(|_| ())),
+        )
+        .arg(
+            arg!(--"skip-hidden" "Leave out files and directories whose name starts with a dot, but for the roots given")
+                .required(false),
+        )
+        .arg(
+            arg!(--"respect-gitignore" "Leave out what the .gitignore and .ignore files met on the way down ignore")
+                .required(false),
+        )
+        .arg(
+            arg!(--"ignore-file" <path> ... "Leave out what this file's gitignore rules ignore, as if at each injest root")
+                .required(false),
+        )
+        .arg(
+            arg!(--"no-stored-excludes" "Ignore the exclude patterns kept in the archive")
+                .required(false),
+        )
+        .arg(
+            arg!(--"no-canonicalize" "Walk every root given, even one inside another")
+                .required(false),
+        )
+        .arg(
+            arg!(--"walk-once" "Walk a directory reached twice, by another root or a bind mount, once, by device and inode; not for filesystems whose inode numbers change, such as some FUSE and CIFS mounts")
+                .required(false),
+        )
+        .arg(
+            arg!(--"no-cache" "Read every file to hash rather than take hashes cached by device and inode")
+                .required(false),
+        )
+        .arg(
+            arg!(--paranoid "Byte-compare duplicates before reporting or reflinking them, splitting groups into truly identical files; a --media-mode partial match so compared counts as full evidence")
+                .required(false),
+        )
+        .arg(
+            arg!(--"verify-groups" <digest> "Read duplicate group members again with a stronger digest before reporting them, splitting groups whose members disagree")
+                .required(false)
+                .possible_values(["blake3", "sha256"])
+                .conflicts_with("paranoid"),
+        )
+        .arg(
+            arg!(--digest "Also keep a blake3 digest of each whole file hashed, from the same reads, to check with b3sum")
+                .required(false)
+                .conflicts_with("media-mode"),
+        )
+        .arg(
+            arg!(--match <by> "Match checked files by hash, or by whole file digest as well (full), which implies --digest")
+                .required(false)
+                .possible_values(["hash", "full"])
+                .default_value("hash")
+                .conflicts_with("media-mode"),
+        )
+        .arg(
+            arg!(--"age-weight" <weight> "Weight of years since last change in the cleanup score")
+                .required(false)
+                .default_value("1.0"),
+        )
+        .arg(
+            arg!(--"copies-weight" <weight> "Weight of each extra copy in the cleanup score")
+                .required(false)
+                .default_value("0.1"),
+        )
         .arg(arg!(-v --verbose ... "increase verbosity level").required(false))
+        .arg(
+            arg!(-q --quiet "Print only results and errors, no status")
+                .required(false)
+                .conflicts_with("verbose"),
+        )
         .arg(
             arg!(-t --timeout <sec> "Timeout after a certain time of no activity")
                 .required(false)
                 .default_value("600"),
         )
+        .arg(
+            arg!(--"media-mode" <bytes> "Hash only size, head and tail of files larger than this (K/M/G suffix allowed)")
+                .required(false),
+        )
+        .arg(
+            arg!(--"by-dir" "With --report, total the reclaimable bytes of redundant copies per directory")
+                .required(false)
+                .requires("report"),
+        )
+        .arg(
+            arg!(--feasibility "With --report, total the reclaimable bytes by whether hardlinking, reflinking or only deleting frees them")
+                .required(false)
+                .requires("report"),
+        )
+        .arg(
+            arg!(--similar <percent> "With --report, list pairs of files sharing at least this percent of their chunks")
+                .required(false)
+                .requires("report")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(p) if (0.0..=100.0).contains(&p) => Ok(()),
+                    _ => Err("a percent from 0 to 100"),
+                }),
+        )
+        .arg(
+            arg!(--"similar-min-size" <bytes> "Smallest file --similar looks at, K/M/G suffix allowed")
+                .required(false)
+                .default_value("16M"),
+        )
+        .arg(
+            arg!(--top <n> "Most directories to print with --by-dir, 0 for all")
+                .required(false)
+                .default_value("20"),
+        )
+        .arg(
+            arg!(--"group-limit" <n> "Most members to print per duplicate group, 0 for all")
+                .required(false)
+                .default_value("100"),
+        )
+        .arg(
+            arg!(--"anomaly-size" <n> "Groups with more members are reported as anomalies, 0 to disable")
+                .required(false)
+                .default_value("10000"),
+        )
+        .arg(
+            arg!(--"detect-reflinks" "Discount duplicate bytes already sharing extents (Linux)")
+                .required(false),
+        )
+        .arg(
+            arg!(--reflink "Deduplicate duplicate groups in place by sharing extents (Linux)")
+                .required(false),
+        )
+        .arg(
+            arg!(--"hash-audit" <n> "Byte-compare the members of n random duplicate groups, logging any hash collision to the archive")
+                .required(false),
+        )
+        .arg(
+            arg!(--policy <file> "Choose the member of each group to keep, and those never acted on, by the rules of a TOML policy file")
+                .required(false),
+        )
+        .arg(
+            arg!(--"allow-weak-evidence" "Also act on groups of partial hashes, empty files or differing sizes, after comparing their bytes")
+                .required(false),
+        )
+        .arg(
+            arg!(--"strict-metadata" "Re-hash archived files whose mode or owner changed")
+                .required(false),
+        )
+        .arg(
+            arg!(--"map-prefix" <mapping> ... "Treat archived paths under OLD as under NEW, given as OLD=NEW")
+                .required(false),
+        )
+        .arg(
+            arg!(--show <spelling> "Print archived paths as archived (original) or after --map-prefix (mapped)")
+                .required(false)
+                .possible_values(["original", "mapped"])
+                .default_value("mapped"),
+        )
+        .arg(
+            arg!(--hash <algorithm> "Hash file contents with this algorithm, a new archive keeps it [default: the archive's, else seahash]")
+                .required(false)
+                .possible_values(["seahash", "xxh3", "blake3", "sha256"]),
+        )
+        .arg(
+            arg!(--cdc "Cut files into content-defined chunks to hash rather than fixed 64K ones, a new archive keeps it")
+                .required(false),
+        )
+        .arg(
+            arg!(--"chunk-size" <bytes> "Size of the fixed chunks files are hashed in, 4K to 8M (K/M suffix allowed), a new archive keeps it [default: 64K]")
+                .required(false)
+                .conflicts_with("cdc"),
+        )
+        .arg(
+            arg!(--"cdc-sizes" <sizes> "Minimum, average and maximum --cdc chunk sizes as MIN,AVG,MAX (K/M suffix allowed) [default: 16K,64K,256K]")
+                .required(false)
+                .requires("cdc"),
+        )
+        .arg(
+            arg!(--"hashes-from" <file> "Also count files as archived if their hash is listed in this file, one hex hash per line; without -a no archive is read")
+                .required(false)
+                .requires("check"),
+        )
+        .arg(
+            arg!(--"uid-map" <file> "Map archived owner uids through a file of from:to lines")
+                .required(false),
+        )
+        .arg(
+            arg!(--"no-preflight" "Skip checking that a known archived file still hashes the same")
+                .required(false),
+        )
+        .arg(
+            arg!(--"file-timeout" <seconds> "Give up hashing a file after this long plus a second per MiB, longer under --bwlimit, 0 for never")
+                .required(false)
+                .default_value("300"),
+        )
+        .arg(
+            arg!(--"hash-threads" <n> "Threads reading and hashing files [default: one per CPU]")
+                .required(false),
+        )
+        .arg(
+            arg!(--"big-file-threads" <n> "Threads reading each file of --big-file-size or more, seahash only")
+                .required(false)
+                .default_value("1"),
+        )
+        .arg(
+            arg!(--"big-file-size" <bytes> "Size from which a file is hashed by --big-file-threads (K/M/G suffix allowed)")
+                .required(false)
+                .default_value("1G"),
+        )
         .arg(
             arg!(--concurrency "Number of simultaneous directories to process")
                 .required(false)
                 .default_value("10"),
         )
+        .arg(
+            arg!(--"metadata-window" <n> "Metadata calls each directory task keeps in flight, more for network filesystems")
+                .required(false)
+                .default_value("16"),
+        )
+        .arg(
+            arg!(--bwlimit <mbps> "Most MB/s to read files at for hashing, across all tasks, 0 for no limit")
+                .required(false)
+                .default_value("0"),
+        )
+        .arg(
+            arg!(--"file-concurrency" <n> "Files each directory task adds at once, 1 for one at a time")
+                .required(false)
+                .default_value("8"),
+        )
+        .subcommand(
+            Command::new("archive-manifest")
+                .about("Write or check the manifest of the archive's set files")
+                .arg(
+                    arg!(-a --archive <path> "Path to archive")
+                        .required(false)
+                        .default_value("/tmp/finddups"),
+                )
+                .arg(
+                    arg!(--"check-manifest" "Report sets added, missing or modified since the manifest was written")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("exclude")
+                .about("Keep exclude patterns in the archive, applied to every injest and check against it")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Add glob patterns, ** matching across directories")
+                        .arg(arg!(<pattern> ... "Pattern to add"))
+                        .arg(
+                            arg!(-a --archive <path> "Path to archive")
+                                .required(false)
+                                .default_value("/tmp/finddups"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Remove patterns as they were added")
+                        .arg(arg!(<pattern> ... "Pattern to remove"))
+                        .arg(
+                            arg!(-a --archive <path> "Path to archive")
+                                .required(false)
+                                .default_value("/tmp/finddups"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("list").about("List the patterns kept").arg(
+                        arg!(-a --archive <path> "Path to archive")
+                            .required(false)
+                            .default_value("/tmp/finddups"),
+                    ),
+                ),
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("Rewrite an archive in the current format, keeping the old sets as a backup")
+                .arg(
+                    arg!(-a --archive <path> "Path to archive")
+                        .required(false)
+                        .default_value("/tmp/finddups"),
+                )
+                .arg(
+                    arg!(--"to-version" <n> "Format version to upgrade to")
+                        .required(false)
+                        .default_value(&format_version),
+                )
+                .arg(
+                    arg!(--repair "Move leftover or ambiguous set files out of the archive instead of stopping")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("explain")
+                .about("Trace how a single file is looked up in the archive")
+                .arg(
+                    arg!(-a --archive <path> "Path to archive")
+                        .required(false)
+                        .default_value("/tmp/finddups"),
+                )
+                .arg(
+                    arg!(--"media-mode" <bytes> "Hash only size, head and tail of files larger than this (K/M/G suffix allowed)")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"strict-metadata" "Re-hash archived files whose mode or owner changed")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"map-prefix" <mapping> ... "Treat archived paths under OLD as under NEW, given as OLD=NEW")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--exclude <pattern> ... "Leave out paths matching this glob, with any kept in the archive by find_dups exclude")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--include <pattern> ... "Take only files matching this glob, after the excludes")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"newer-than" <time> "Only look at files modified after this UTC date, 2023-01-01, or time ago, 90d")
+                        .required(false)
+                        .validator(|s| find_dups::parse_time(s).map(|_| ())),
+                )
+                .arg(
+                    arg!(--"older-than" <time> "Only look at files modified before this UTC date or time ago")
+                        .required(false)
+                        .validator(|s| find_dups::parse_time(s).map(|_| ())),
+                )
+                .arg(
+                    arg!(--"skip-hidden" "Leave out files whose name starts with a dot")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"include-empty" "Group empty files like any others, to report them as duplicates, present or missing")
+                        .required(false),
+                )
+                .arg(arg!(<path> "File to explain")),
+        )
+        .subcommand(
+            Command::new("why")
+                .about("Say whether a path was archived or why it was skipped, see --log-skips")
+                .arg(
+                    arg!(-a --archive <path> "Path to archive")
+                        .required(false)
+                        .default_value("/tmp/finddups"),
+                )
+                .arg(arg!(<path> "Path to look up")),
+        )
+        .subcommand(
+            Command::new("export-tar")
+                .about("Write archived files under a prefix to a tar, each content once, reading them from disk")
+                .arg(
+                    arg!(-a --archive <path> "Path to archive")
+                        .required(false)
+                        .default_value("/tmp/finddups"),
+                )
+                .arg(arg!(prefix: --under <prefix> "Export the archived files under this path"))
+                .arg(arg!(-o --output <path> "Tar file to write"))
+                .arg(
+                    arg!(--"map-prefix" <mapping> ... "Treat archived paths under OLD as under NEW, given as OLD=NEW")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("pack")
+                .about("Copy an archive directory's header, sets and manifest into a single .fda file")
+                .arg(
+                    arg!(-a --archive <path> "Path to archive")
+                        .required(false)
+                        .default_value("/tmp/finddups"),
+                )
+                .arg(arg!(-o --output <path> "Single file archive to write")),
+        )
+        .subcommand(
+            Command::new("unpack")
+                .about("Copy the files of a single .fda file archive into a new archive directory")
+                .arg(arg!(<file> "Single file archive to read"))
+                .arg(arg!(-o --output <path> "Archive directory to create")),
+        )
         .get_matches();
 
+    if let Some(sub) = matches.subcommand_matches("archive-manifest") {
+        let path = or_exit(normalize_archive_path(
+            sub.value_of("archive").unwrap(),
+            false,
+        ));
+        let archive = Archive::new(&path, "file".to_string(), ARCHIVE_SIZE);
+        let checked = task::block_on(async {
+            if sub.occurrences_of("check-manifest") > 0 {
+                archive.check_manifest().await
+            } else {
+                archive.write_manifest().await.map(|_| true)
+            }
+        });
+        let ok = checked.unwrap_or_else(|e| {
+            eprintln!("archive-manifest: {}", e);
+            std::process::exit(1);
+        });
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("exclude") {
+        if let Err(e) = task::block_on(exclude_command(sub)) {
+            eprintln!("exclude: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("pack") {
+        let path = or_exit(normalize_archive_path(
+            sub.value_of("archive").unwrap(),
+            false,
+        ));
+        let archive = Archive::new(&path, "file".to_string(), ARCHIVE_SIZE);
+        let output = sub.value_of("output").unwrap();
+        if let Err(e) = task::block_on(archive.pack(output)) {
+            eprintln!("pack: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("unpack") {
+        let output = or_exit(normalize_archive_path(
+            sub.value_of("output").unwrap(),
+            false,
+        ));
+        match find_dups::pack::unpack(sub.value_of("file").unwrap().as_ref(), output.as_ref()) {
+            Ok(n) => println!("unpacked {} files into {}", n, output),
+            Err(e) => {
+                eprintln!("unpack: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("upgrade") {
+        let (config, _dir_receiver) = or_exit(Config::new(sub));
+        let file_store = FileStore::new(config.archive(), config.clone());
+        let to_version = match sub.value_of("to-version").unwrap().parse() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!(
+                    "upgrade: --to-version {} is not a version number",
+                    sub.value_of("to-version").unwrap()
+                );
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = task::block_on(file_store.upgrade(to_version)) {
+            eprintln!("upgrade: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("explain") {
+        let (config, _dir_receiver) = or_exit(Config::new(sub));
+        let file_store = FileStore::new(config.archive(), config.clone());
+        or_exit(task::block_on(async {
+            file_store.read().await?;
+            let path = PathBuf::from(sub.value_of("path").unwrap());
+            file_store
+                .explain(&path, &mut std::io::stdout().lock())
+                .await
+        }));
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("why") {
+        let (config, _dir_receiver) = or_exit(Config::new(sub));
+        let file_store = FileStore::new(config.archive(), config.clone());
+        let path = normalize_path(sub.value_of("path").unwrap());
+        let found = task::block_on(async {
+            file_store.read().await?;
+            file_store.why(&path).await
+        });
+        if let Err(e) = found {
+            eprintln!("why: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("export-tar") {
+        let (config, _dir_receiver) = or_exit(Config::new(sub));
+        let file_store = FileStore::new(config.archive(), config.clone());
+        let under = normalize_path(sub.value_of("prefix").unwrap());
+        let exported = task::block_on(async {
+            file_store.read().await?;
+            file_store.export_tar(&under, sub.value_of("output").unwrap())
+        });
+        if let Err(e) = exported {
+            eprintln!("export-tar: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let paths = if matches.occurrences_of("check") > 0 {
         matches.values_of("check").unwrap().collect()
     } else if matches.occurrences_of("injest") > 0 {
@@ -76,13 +805,74 @@ fn main() {
     };
 
     // Get the configuration
-    let (config, dir_receiver) = Config::new(&matches);
+    let (config, dir_receiver) = or_exit(Config::new(&matches));
 
     // Now start the loops
-    task::block_on(async {
-        launch_brokers(config.clone(), dir_receiver, paths.clone())
-            .await
-            .unwrap();
+    let run = async {
+        let mut scan = launch_brokers(config.clone(), dir_receiver, paths.clone()).await?;
+        scan.cancel_on_signals();
+        scan.wait().await
+    };
+    let outcome = task::block_on(run).unwrap_or_else(|e| {
+        eprintln!("find_dups: {}", e);
+        if let Some(path) = config.status_json() {
+            let error = vec![("error", find_dups::file::Json::Str(e.to_string()))];
+            if let Err(e) = write_status(path, Outcome::Failed, error) {
+                eprintln!("{}", e);
+            }
+        }
+        Outcome::Failed
     });
-    // All done!
+    // All done!  Results may be going to a file with --output
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+    if outcome.exit_code() != 0 {
+        std::process::exit(outcome.exit_code());
+    }
+}
+
+/// The value, or exit as a failed run after printing the error
+fn or_exit<T>(result: Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("find_dups: {}", e);
+        std::process::exit(Outcome::Failed.exit_code());
+    })
+}
+
+/// `find_dups exclude add|remove|list`, see exclude.rs
+async fn exclude_command(sub: &ArgMatches) -> Result<()> {
+    let (action, sub) = sub.subcommand().unwrap();
+    let archive = normalize_archive_path(sub.value_of("archive").unwrap(), false)?;
+    let mut patterns = exclude::stored(&archive).await?;
+    if action == "list" {
+        for pattern in &patterns {
+            println!("{}", pattern);
+        }
+        return Ok(());
+    }
+    let given = sub.values_of("pattern").unwrap().map(String::from);
+    match action {
+        "add" => {
+            for pattern in given {
+                exclude::check_pattern(&pattern)?;
+                if patterns.contains(&pattern) {
+                    println!("already kept: {}", pattern);
+                } else {
+                    patterns.push(pattern);
+                }
+            }
+        }
+        _ => {
+            for pattern in given {
+                match patterns.iter().position(|kept| *kept == pattern) {
+                    Some(i) => {
+                        patterns.remove(i);
+                    }
+                    None => return Err(format!("{} is not kept in {}", pattern, archive).into()),
+                }
+            }
+        }
+    }
+    exclude::store(&archive, &patterns).await?;
+    println!("{} exclude patterns kept in {}", patterns.len(), archive);
+    Ok(())
 }
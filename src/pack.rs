@@ -0,0 +1,387 @@
+//! single file archives, see --archive-file
+//!
+//! A `.fda` file holds the files of an archive directory, the header,
+//! the sets and the manifest, byte for byte under the same names:
+//!
+//! ```text
+//! MAGIC  file bytes ...  index  index offset (u64 LE)  MAGIC
+//! ```
+//!
+//! The index is a line of `name offset len` per file, so a set is
+//! read with one seek and one read, without loading the rest.
+
+use crate::Result;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"FDAPACK1";
+
+/// Suffix naming a single file archive
+pub const SUFFIX: &str = ".fda";
+
+/// True if an archive path names a single file archive
+pub fn is_packed(archive: &str) -> bool {
+    archive.ends_with(SUFFIX)
+}
+
+/// The index of a single file archive
+#[derive(Debug)]
+pub struct Packed {
+    path: PathBuf,
+    index: Vec<(String, u64, u64)>,
+}
+
+impl Packed {
+    /// Read the index, None if there is no such file
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let mut f = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let bad = |why: &str| format!("{}: not a single file archive, {}", path.display(), why);
+        let len = f.metadata()?.len();
+        if len < 24 {
+            return Err(bad("too short").into());
+        }
+        let mut magic = [0; 8];
+        f.read_exact(&mut magic)?;
+        let mut trailer = [0; 16];
+        f.seek(SeekFrom::End(-16))?;
+        f.read_exact(&mut trailer)?;
+        if &magic != MAGIC || &trailer[8..] != MAGIC {
+            return Err(bad("no magic").into());
+        }
+        let start = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        if start < 8 || start > len - 16 {
+            return Err(bad("index out of range").into());
+        }
+        let mut text = String::new();
+        f.seek(SeekFrom::Start(start))?;
+        f.take(len - 16 - start).read_to_string(&mut text)?;
+        let mut index = Vec::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.rsplitn(3, ' ').collect();
+            match fields[..] {
+                [size, offset, name] => {
+                    let (offset, size): (u64, u64) = (
+                        offset.parse().map_err(|_| bad("bad index line"))?,
+                        size.parse().map_err(|_| bad("bad index line"))?,
+                    );
+                    if offset < 8 || offset.checked_add(size).is_none_or(|end| end > start) {
+                        return Err(bad("file out of range").into());
+                    }
+                    // unpack writes each under its name, which must stay
+                    // in the directory it unpacks to
+                    if !packable(name) {
+                        return Err(bad(&format!("bad file name {:?}", name)).into());
+                    }
+                    index.push((name.to_string(), offset, size));
+                }
+                _ => return Err(bad("bad index line").into()),
+            }
+        }
+        Ok(Some(Packed {
+            path: path.to_owned(),
+            index,
+        }))
+    }
+
+    /// Names and sizes of the files held, in the order written
+    pub fn files(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.index
+            .iter()
+            .map(|(name, _, len)| (name.as_str(), *len))
+    }
+
+    /// The bytes of one file, None if it isn't held
+    pub fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let (offset, len) = match self.index.iter().find(|(n, _, _)| n == name) {
+            Some((_, offset, len)) => (*offset, *len),
+            None => return Ok(None),
+        };
+        let mut f = std::fs::File::open(&self.path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len as usize];
+        f.read_exact(&mut buf)?;
+        Ok(Some(buf))
+    }
+
+    /// Write a new file in place of this one, keeping the files held
+    /// but those in `replace`, which are replaced where they stand,
+    /// added at the end or, if None, dropped
+    ///
+    ///   Kept files are copied across without being held in memory, and
+    ///   keep their order so `pack` of an unpacked file gives it back.
+    pub fn rewrite(&self, mut replace: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        let mut from = std::fs::File::open(&self.path)?;
+        let mut files = Vec::new();
+        for (name, offset, len) in &self.index {
+            match replace.iter().position(|(n, _)| n == name) {
+                Some(i) => {
+                    if let (name, Some(bytes)) = replace.remove(i) {
+                        files.push((name, Source::Bytes(bytes)));
+                    }
+                }
+                None => files.push((name.clone(), Source::Range(*offset, *len))),
+            }
+        }
+        for (name, bytes) in replace {
+            if let Some(bytes) = bytes {
+                files.push((name, Source::Bytes(bytes)));
+            }
+        }
+        write_files(&self.path, files, Some(&mut from))
+    }
+}
+
+/// Where write_files gets a file's bytes
+enum Source {
+    Bytes(Vec<u8>),
+    /// offset and length in the file being replaced
+    Range(u64, u64),
+    File(PathBuf),
+}
+
+/// Write a single file archive of named files, replacing any there
+pub fn write(path: &Path, files: Vec<(String, Vec<u8>)>) -> Result<()> {
+    let files = files
+        .into_iter()
+        .map(|(name, bytes)| (name, Source::Bytes(bytes)))
+        .collect();
+    write_files(path, files, None)
+}
+
+/// Write to a temporary file beside `path` and rename it over, so a
+/// failure part way leaves what was there
+fn write_files(
+    path: &Path,
+    files: Vec<(String, Source)>,
+    mut from: Option<&mut std::fs::File>,
+) -> Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+    let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    out.write_all(MAGIC)?;
+    let mut offset = MAGIC.len() as u64;
+    let mut index = String::new();
+    for (name, source) in files {
+        if !packable(&name) {
+            return Err(format!("can't pack a file named {:?}", name).into());
+        }
+        let len = match source {
+            Source::Bytes(bytes) => {
+                out.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Source::Range(start, len) => {
+                let from = from.as_mut().ok_or("nothing to copy from")?;
+                from.seek(SeekFrom::Start(start))?;
+                let copied = std::io::copy(&mut (&mut **from).take(len), &mut out)?;
+                if copied != len {
+                    return Err(format!("{}: {} cut short", path.display(), name).into());
+                }
+                len
+            }
+            Source::File(file) => std::io::copy(&mut std::fs::File::open(file)?, &mut out)?,
+        };
+        index += &format!("{} {} {}\n", name, offset, len);
+        offset += len;
+    }
+    out.write_all(index.as_bytes())?;
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(MAGIC)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// True if a file can be held under `name`: a plain name in one
+/// directory, which the index can spell
+fn packable(name: &str) -> bool {
+    !(name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\n'])
+        || name.ends_with(' '))
+}
+
+/// Pack an archive directory into a single file
+///
+///   Only the header, sets and manifest are taken, anything else in
+///   the directory, backups included, is left behind.
+pub fn pack(dir: &Path, sets: &[PathBuf], record_type: &str, to: &Path) -> Result<usize> {
+    let mut files = Vec::new();
+    for name in [
+        format!("{}.header", record_type),
+        format!("{}.manifest", record_type),
+    ] {
+        if dir.join(&name).exists() {
+            files.push((name.clone(), Source::File(dir.join(name))));
+        }
+    }
+    for set in sets {
+        let name = set.file_name().unwrap().to_string_lossy().into_owned();
+        files.push((name, Source::File(set.clone())));
+    }
+    let n = files.len();
+    write_files(to, files, None)?;
+    Ok(n)
+}
+
+/// Unpack a single file archive into a new or empty directory
+pub fn unpack(from: &Path, dir: &Path) -> Result<usize> {
+    let packed = Packed::open(from)?.ok_or_else(|| format!("{}: no such file", from.display()))?;
+    match std::fs::read_dir(dir) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                return Err(format!("{} is not empty", dir.display()).into());
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(dir)?,
+        Err(e) => return Err(e.into()),
+    }
+    for (name, _) in packed.files() {
+        let bytes = packed.read(name)?.unwrap();
+        std::fs::write(dir.join(name), bytes)?;
+    }
+    Ok(packed.index.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Record;
+    use crate::testing::scratch;
+    use async_std::task;
+
+    /// An archive directory with a header, manifest and several sets,
+    /// returning the sets
+    fn archive(dir: &Path) -> Vec<PathBuf> {
+        std::fs::create_dir(dir).unwrap();
+        let archive = dir.to_str().unwrap();
+        let mut record: Record<()> = Record::new(archive, "file".to_string(), 64 * 1024, 4096);
+        task::block_on(async {
+            for i in 0..20_000u32 {
+                let item: Vec<u8> = (0..20).map(|j| (i * 31 + j * 7) as u8).collect();
+                record.push(item).unwrap();
+            }
+            record.finish().await.unwrap();
+            record.write_header().await.unwrap();
+            record.write_manifest().await.unwrap();
+        });
+        let mut sets: Vec<PathBuf> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().ends_with("_file.cbor"))
+            .collect();
+        sets.sort();
+        sets
+    }
+
+    #[test]
+    fn names_and_ranges_out_of_bounds_are_refused() {
+        let dir = scratch("pack-hostile");
+        // one file byte, then the index, as write_files lays them out
+        let crafted = |index: &str| {
+            let path = dir.join("crafted.fda");
+            let mut bytes = MAGIC.to_vec();
+            bytes.push(b'x');
+            bytes.extend_from_slice(index.as_bytes());
+            bytes.extend_from_slice(&9u64.to_le_bytes());
+            bytes.extend_from_slice(MAGIC);
+            std::fs::write(&path, bytes).unwrap();
+            path
+        };
+        assert!(Packed::open(&crafted("a 8 1\n")).unwrap().is_some());
+        for index in [
+            "../x 8 1\n",
+            "/etc/x 8 1\n",
+            ".. 8 1\n",
+            ". 8 1\n",
+            " 8 1\n",
+            "a 8 18446744073709551615\n",
+        ] {
+            assert!(Packed::open(&crafted(index)).is_err(), "{:?}", index);
+            assert!(unpack(&crafted(index), &dir.join("out")).is_err());
+        }
+        assert!(!dir.join("x").exists());
+        assert!(!dir.join("out").exists());
+    }
+
+    #[test]
+    fn pack_and_unpack_round_trip() {
+        let dir = scratch("pack-round-trip");
+        let sets = archive(&dir.join("archive"));
+        assert!(sets.len() > 1);
+        let packed = dir.join("archive.fda");
+        assert_eq!(
+            pack(&dir.join("archive"), &sets, "file", &packed).unwrap(),
+            sets.len() + 2
+        );
+        let unpacked = dir.join("unpacked");
+        assert_eq!(unpack(&packed, &unpacked).unwrap(), sets.len() + 2);
+        for entry in std::fs::read_dir(&unpacked).unwrap() {
+            let name = entry.unwrap().file_name();
+            let original = std::fs::read(dir.join("archive").join(&name)).unwrap();
+            assert_eq!(std::fs::read(unpacked.join(&name)).unwrap(), original);
+        }
+        let sets: Vec<PathBuf> = sets
+            .iter()
+            .map(|set| unpacked.join(set.file_name().unwrap()))
+            .collect();
+        let repacked = dir.join("repacked.fda");
+        pack(&unpacked, &sets, "file", &repacked).unwrap();
+        assert_eq!(
+            std::fs::read(&repacked).unwrap(),
+            std::fs::read(&packed).unwrap()
+        );
+        assert!(unpack(&packed, &unpacked).is_err());
+    }
+
+    #[test]
+    fn files_that_are_not_packs_are_refused() {
+        let dir = scratch("pack-bad");
+        assert!(Packed::open(&dir.join("missing.fda")).unwrap().is_none());
+        let short = dir.join("short.fda");
+        std::fs::write(&short, b"FDAPACK1").unwrap();
+        assert!(Packed::open(&short).is_err());
+        let garbage = dir.join("garbage.fda");
+        std::fs::write(&garbage, vec![7; 100]).unwrap();
+        assert!(Packed::open(&garbage).is_err());
+        let packed = dir.join("one.fda");
+        write(&packed, vec![("a".to_string(), b"bytes".to_vec())]).unwrap();
+        let mut bytes = std::fs::read(&packed).unwrap();
+        let at = bytes.len() - 16;
+        bytes[at] ^= 0x40;
+        std::fs::write(&packed, bytes).unwrap();
+        assert!(Packed::open(&packed).is_err());
+        assert!(write(&packed, vec![("a/b".to_string(), Vec::new())]).is_err());
+    }
+
+    #[test]
+    fn rewrite_replaces_in_place() {
+        let dir = scratch("pack-rewrite");
+        let packed = dir.join("p.fda");
+        let file = |name: &str, bytes: &[u8]| (name.to_string(), bytes.to_vec());
+        write(
+            &packed,
+            vec![file("a", b"one"), file("b", b"two"), file("c", b"3")],
+        )
+        .unwrap();
+        let p = Packed::open(&packed).unwrap().unwrap();
+        p.rewrite(vec![
+            ("b".to_string(), Some(b"TWO!".to_vec())),
+            ("c".to_string(), None),
+            ("d".to_string(), Some(b"four".to_vec())),
+        ])
+        .unwrap();
+        let p = Packed::open(&packed).unwrap().unwrap();
+        let files: Vec<_> = p.files().map(|(n, len)| (n.to_string(), len)).collect();
+        let expected = [("a", 3), ("b", 4), ("d", 4)].map(|(n, l)| (n.to_string(), l));
+        assert_eq!(files, expected);
+        assert_eq!(p.read("b").unwrap().unwrap(), b"TWO!");
+        assert!(p.read("c").unwrap().is_none());
+    }
+}
@@ -0,0 +1,351 @@
+//! --policy files, which member of a duplicate group to keep
+//!
+//! A policy is a small TOML file of rules, each a table of tests that
+//! must all hold of a member:
+//!
+//! ```toml
+//! # picks among members the keep rules leave tied, top level keys
+//! # go before the first [[table]] or TOML puts them in it
+//! tie = "oldest"
+//!
+//! # never act on these, even if they are not the one kept
+//! [[protect]]
+//! uid = 0
+//!
+//! # tried in order, each narrows the members still in the running
+//! # to those it matches, unless it matches none of them
+//! [[keep]]
+//! path = "^/home/me/photos/"
+//!
+//! [[keep]]
+//! name = "^copy of "
+//! not = true
+//! ```
+//!
+//! A rule tests `path` (a regex on the whole path), `name` (a regex on
+//! the file name) and `uid`, and `not = true` inverts it.  `tie` is one
+//! of oldest, newest, shortest-path, largest or first.  A group the
+//! rules leave with more than one member and no `tie` is not decided.
+
+use crate::Result;
+use regex::Regex;
+
+/// What a policy sees of one group member
+#[derive(Debug)]
+pub struct Member<'a> {
+    pub path: &'a str,
+    pub size: u64,
+    pub mtime: u64,
+    pub uid: u32,
+}
+
+/// The member to keep and the members that may be acted on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub keep: usize,
+    pub act: Vec<bool>,
+}
+
+impl Decision {
+    /// Keep `keep` and act on every other member not protected
+    pub fn new(keep: usize, protected: &[bool]) -> Self {
+        Decision {
+            keep,
+            act: protected
+                .iter()
+                .enumerate()
+                .map(|(i, p)| i != keep && !p)
+                .collect(),
+        }
+    }
+}
+
+/// A set of tests that must all hold of a member
+#[derive(Debug)]
+struct Rule {
+    path: Option<Regex>,
+    name: Option<Regex>,
+    uid: Option<u32>,
+    not: bool,
+}
+
+impl Rule {
+    fn parse(what: &str, value: &toml::Value) -> Result<Self> {
+        let table = value
+            .as_table()
+            .ok_or_else(|| format!("{} rules must be tables", what))?;
+        let regex = |key: &str| -> Result<Option<Regex>> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(toml::Value::String(s)) => Ok(Some(
+                    Regex::new(s).map_err(|e| format!("{} {}: {}", what, key, e))?,
+                )),
+                Some(_) => Err(format!("{} {} must be a string", what, key).into()),
+            }
+        };
+        let rule = Rule {
+            path: regex("path")?,
+            name: regex("name")?,
+            uid: match table.get("uid") {
+                None => None,
+                Some(v) => Some(
+                    v.as_integer()
+                        .and_then(|uid| u32::try_from(uid).ok())
+                        .ok_or_else(|| format!("{} uid must be a uid", what))?,
+                ),
+            },
+            not: match table.get("not") {
+                None => false,
+                Some(v) => v
+                    .as_bool()
+                    .ok_or_else(|| format!("{} not must be true or false", what))?,
+            },
+        };
+        if let Some(key) = table
+            .keys()
+            .find(|k| !["path", "name", "uid", "not"].contains(&k.as_str()))
+        {
+            return Err(format!("unknown {} test {}", what, key).into());
+        }
+        if rule.path.is_none() && rule.name.is_none() && rule.uid.is_none() {
+            return Err(format!("a {} rule has no tests", what).into());
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, member: &Member<'_>) -> bool {
+        let name = member.path.rsplit('/').next().unwrap_or(member.path);
+        let all = self.path.as_ref().is_none_or(|r| r.is_match(member.path))
+            && self.name.as_ref().is_none_or(|r| r.is_match(name))
+            && self.uid.is_none_or(|uid| uid == member.uid);
+        all != self.not
+    }
+}
+
+/// How to pick among members the keep rules leave tied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Tie {
+    Oldest,
+    Newest,
+    ShortestPath,
+    Largest,
+    First,
+}
+
+impl std::str::FromStr for Tie {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "oldest" => Ok(Tie::Oldest),
+            "newest" => Ok(Tie::Newest),
+            "shortest-path" => Ok(Tie::ShortestPath),
+            "largest" => Ok(Tie::Largest),
+            "first" => Ok(Tie::First),
+            _ => Err(format!(
+                "unknown tie {}, expected oldest, newest, shortest-path, largest or first",
+                s
+            )),
+        }
+    }
+}
+
+impl Tie {
+    /// The member to keep of `running`, the earliest among equals
+    fn pick(self, members: &[Member<'_>], running: &[usize]) -> usize {
+        use std::cmp::Reverse;
+
+        let mut running = running.iter().copied();
+        let picked = match self {
+            Tie::Oldest => running.min_by_key(|i| members[*i].mtime),
+            Tie::Newest => running.min_by_key(|i| Reverse(members[*i].mtime)),
+            Tie::ShortestPath => running.min_by_key(|i| members[*i].path.len()),
+            Tie::Largest => running.min_by_key(|i| Reverse(members[*i].size)),
+            Tie::First => running.next(),
+        };
+        picked.unwrap_or(0)
+    }
+}
+
+/// Rules read from a --policy file
+#[derive(Debug)]
+pub struct Policy {
+    keep: Vec<Rule>,
+    protect: Vec<Rule>,
+    tie: Option<Tie>,
+}
+
+impl Policy {
+    pub fn from_file(path: &str) -> Result<Self> {
+        Policy::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let table: toml::Table = text.parse()?;
+        let rules = |what: &str| -> Result<Vec<Rule>> {
+            match table.get(what) {
+                None => Ok(Vec::new()),
+                Some(toml::Value::Array(rules)) => {
+                    rules.iter().map(|rule| Rule::parse(what, rule)).collect()
+                }
+                Some(_) => Err(format!("{} must be an array of tables, [[{}]]", what, what).into()),
+            }
+        };
+        let policy = Policy {
+            keep: rules("keep")?,
+            protect: rules("protect")?,
+            tie: match table.get("tie") {
+                None => None,
+                Some(toml::Value::String(s)) => Some(s.parse()?),
+                Some(_) => return Err("tie must be a string".into()),
+            },
+        };
+        if let Some(key) = table
+            .keys()
+            .find(|k| !["keep", "protect", "tie"].contains(&k.as_str()))
+        {
+            return Err(format!("unknown policy key {}", key).into());
+        }
+        Ok(policy)
+    }
+
+    /// Members a protect rule matches, never to be acted on
+    pub fn protected(&self, members: &[Member<'_>]) -> Vec<bool> {
+        members
+            .iter()
+            .map(|m| self.protect.iter().any(|rule| rule.matches(m)))
+            .collect()
+    }
+
+    /// Decide a group, or say why the policy doesn't
+    pub fn decide(&self, members: &[Member<'_>]) -> std::result::Result<Decision, String> {
+        let mut running: Vec<usize> = (0..members.len()).collect();
+        for rule in &self.keep {
+            if running.len() < 2 {
+                break;
+            }
+            let matched: Vec<usize> = running
+                .iter()
+                .copied()
+                .filter(|i| rule.matches(&members[*i]))
+                .collect();
+            if !matched.is_empty() {
+                running = matched;
+            }
+        }
+        let keep = match (running.len(), self.tie) {
+            (1, _) => running[0],
+            (_, Some(tie)) => tie.pick(members, &running),
+            (n, None) => return Err(format!("{} members still tied and no tie rule", n)),
+        };
+        Ok(Decision::new(keep, &self.protected(members)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(name: &str) -> Policy {
+        let text = match name {
+            "keep-photos" => include_str!("../examples/policies/keep-photos.toml"),
+            "no-copy-of" => include_str!("../examples/policies/no-copy-of.toml"),
+            "protect-root" => include_str!("../examples/policies/protect-root.toml"),
+            "retention" => include_str!("../examples/policies/retention.toml"),
+            _ => unreachable!(),
+        };
+        Policy::parse(text).unwrap()
+    }
+
+    fn member(path: &str, mtime: u64, uid: u32) -> Member<'_> {
+        Member {
+            path,
+            size: 100,
+            mtime,
+            uid,
+        }
+    }
+
+    #[test]
+    fn keep_photos_keeps_the_library_copy() {
+        let policy = example("keep-photos");
+        let group = [
+            member("/backup/a.jpg", 10, 1000),
+            member("/photos/2020/a.jpg", 30, 1000),
+            member("/tmp/a.jpg", 20, 1000),
+        ];
+        let decision = policy.decide(&group).unwrap();
+        assert_eq!(decision, Decision::new(1, &[false, true, false]));
+        // none in the library, the oldest
+        let group = [member("/b/a.jpg", 20, 1000), member("/c/a.jpg", 10, 1000)];
+        assert_eq!(policy.decide(&group).unwrap().keep, 1);
+        // two in the library, the older of those
+        let group = [
+            member("/c/a.jpg", 1, 1000),
+            member("/photos/x/a.jpg", 30, 1000),
+            member("/photos/y/a.jpg", 20, 1000),
+        ];
+        let decision = policy.decide(&group).unwrap();
+        assert_eq!((decision.keep, decision.act), (2, vec![true, false, false]));
+    }
+
+    #[test]
+    fn no_copy_of_prefers_the_original_name() {
+        let policy = example("no-copy-of");
+        let group = [
+            member("/a/Copy of b.txt", 0, 0),
+            member("/a/long/path/b.txt", 0, 0),
+            member("/a/x/b.txt", 0, 0),
+        ];
+        assert_eq!(policy.decide(&group).unwrap().keep, 2);
+        // every member a copy, the rule narrows none out
+        let group = [
+            member("/a/long/copy of b", 0, 0),
+            member("/a/copy of b", 0, 0),
+        ];
+        assert_eq!(policy.decide(&group).unwrap().keep, 1);
+    }
+
+    #[test]
+    fn protect_root_decides_nothing_but_protects() {
+        let policy = example("protect-root");
+        let group = [member("/a", 0, 0), member("/b", 0, 1000)];
+        assert!(policy.decide(&group).is_err());
+        assert_eq!(policy.protected(&group), vec![true, false]);
+    }
+
+    #[test]
+    fn retention_narrows_in_order() {
+        let policy = example("retention");
+        let group = [
+            member("/photos/copy of a.jpg", 5, 1000),
+            member("/photos/a.jpg", 50, 1000),
+            member("/photos/old/a.jpg", 40, 1000),
+            member("/home/a.jpg", 1, 0),
+            member("/home/b.jpg", 1, 1000),
+        ];
+        let decision = policy.decide(&group).unwrap();
+        assert_eq!(decision.keep, 2);
+        assert_eq!(decision.act, vec![false, false, false, false, true]);
+    }
+
+    #[test]
+    fn bad_policies_are_refused() {
+        for text in [
+            "tie = \"biggest\"",
+            "tie = 3",
+            "keep = 1",
+            "[[keep]]\npath = \"(\"",
+            "[[keep]]\nsize = 3",
+            "[[keep]]\nnot = true",
+            "[[protect]]\nuid = -1",
+            "[[keep]]\nname = 3",
+            "[[keep]]\nuid = 0\nnot = \"yes\"",
+            "[[retain]]\nuid = 0",
+            "not toml at all",
+        ] {
+            assert!(Policy::parse(text).is_err(), "{}", text);
+        }
+        assert!(Policy::from_file("/no/such/policy.toml").is_err());
+    }
+}
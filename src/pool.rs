@@ -0,0 +1,138 @@
+//! hash worker threads, see --hash-threads
+
+use crate::Result;
+use futures::channel::oneshot;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// A job, returning true if its worker should retire after it
+type Job = Box<dyn FnOnce() -> bool + Send>;
+
+/// States of a job, see HashPool::run
+const RUNNING: u8 = 0;
+const FINISHED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+/// A fixed set of threads reading and hashing files off the executor
+///
+///   Hashing a big file reads and digests for a long while without
+///   yielding, which inline on the async executor holds up the
+///   directory broker and its progress reports.  Jobs queue in the
+///   order given and each runs to the end, so a hash given up on by
+///   --file-timeout holds its thread until its read returns.  Another
+///   thread takes its place meanwhile, and the one held retires once
+///   its read does return, so a hung mount never eats the pool.
+#[derive(Debug)]
+pub struct HashPool {
+    jobs: mpsc::Sender<Job>,
+    queue: Arc<Mutex<mpsc::Receiver<Job>>>,
+    threads: usize,
+    /// threads started, for naming them
+    started: AtomicUsize,
+}
+
+impl HashPool {
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let pool = HashPool {
+            jobs,
+            queue: Arc::new(Mutex::new(queue)),
+            threads,
+            started: AtomicUsize::new(0),
+        };
+        for _ in 0..threads {
+            pool.spawn_worker();
+        }
+        pool
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    fn spawn_worker(&self) {
+        let queue = self.queue.clone();
+        let n = self.started.fetch_add(1, Ordering::SeqCst);
+        std::thread::Builder::new()
+            .name(format!("hash-{}", n))
+            .spawn(move || loop {
+                let job = queue.lock().unwrap().recv();
+                match job {
+                    // a panicking job drops its sender, which run reports
+                    Ok(job) => {
+                        let retire = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                        if retire.unwrap_or(false) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            })
+            .expect("hash thread");
+    }
+
+    /// Run `work` on a worker thread, None if it took longer than `limit`
+    ///
+    ///   The limit counts from when a worker takes the job up, not from
+    ///   when it was queued, so a long queue doesn't time files out.  A
+    ///   job timed out has another thread started in its place.
+    pub async fn run<T, F>(&self, limit: Option<Duration>, work: F) -> Result<Option<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (started_tx, started) = oneshot::channel();
+        let (done_tx, mut done) = oneshot::channel();
+        let state = Arc::new(AtomicU8::new(RUNNING));
+        let job_state = state.clone();
+        self.jobs
+            .send(Box::new(move || {
+                let _ = started_tx.send(());
+                let _ = done_tx.send(work());
+                job_state.swap(FINISHED, Ordering::SeqCst) == ABANDONED
+            }))
+            .map_err(|_| "hash threads gone")?;
+        started.await.map_err(|_| "hash job dropped")?;
+        let done = match limit {
+            None => done.await,
+            Some(limit) => match async_std::future::timeout(limit, &mut done).await {
+                Ok(done) => done,
+                Err(_) => {
+                    let abandoned = state
+                        .compare_exchange(RUNNING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok();
+                    if abandoned {
+                        self.spawn_worker();
+                        return Ok(None);
+                    }
+                    // it finished just as it timed out
+                    done.await
+                }
+            },
+        };
+        Ok(Some(done.map_err(|_| "hash job failed")?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn a_timed_out_job_is_replaced() {
+        let pool = HashPool::new(1);
+        task::block_on(async {
+            let hung = pool.run(Some(Duration::from_millis(50)), || {
+                std::thread::sleep(Duration::from_millis(500))
+            });
+            assert!(hung.await.unwrap().is_none());
+            // the one thread is still asleep, so this runs on another
+            let next = pool.run(Some(Duration::from_millis(200)), || 7);
+            assert_eq!(next.await.unwrap(), Some(7));
+        });
+        assert_eq!(pool.started.load(Ordering::SeqCst), 2);
+    }
+}
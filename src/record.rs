@@ -1,5 +1,5 @@
-use crate::archive::{Archive, ArchiveLocation};
-use crate::Result;
+use crate::archive::{Archive, ArchiveLocation, ArchiveMode};
+use crate::{Result, MAX_ITEM_SIZE};
 use lz4::block::{compress, decompress};
 use minicbor_derive::{Decode, Encode};
 use std::io::{Error, ErrorKind};
@@ -30,13 +30,19 @@ impl ReadBuf {
         }
     }
 
+    /// Drop any buffered data, as if just created
+    fn reset(&mut self) {
+        self.data = None;
+        self.pos = 0;
+    }
+
     fn is_none(&self) -> bool {
         self.data.is_none()
     }
 
     #[allow(dead_code)]
     fn is_some(&self) -> bool {
-        self.data.is_none()
+        self.data.is_some()
     }
 
     fn len_left(&self) -> usize {
@@ -55,6 +61,10 @@ impl ReadBuf {
     }
 
     fn get_slice(&mut self, len: usize) -> Vec<u8> {
+        // an empty item can follow a length that ended the data
+        if len == 0 {
+            return Vec::new();
+        }
         let pos = self.pos;
         let data = match &self.data {
             Some(data) => data,
@@ -101,6 +111,8 @@ pub struct Record<T> {
     limit: usize,
     read_offset: usize,
     archive: Archive,
+    /// bytes of records flushed, before and after compression
+    written: (u64, u64),
     _marker: PhantomData<T>,
 }
 
@@ -114,6 +126,7 @@ impl<T> std::fmt::Debug for Record<T> {
             .field("limit", &self.limit)
             .field("read_offset", &self.read_offset)
             .field("archive", &self.archive)
+            .field("written", &self.written)
             .finish()
     }
 }
@@ -121,12 +134,15 @@ impl<T> std::fmt::Debug for Record<T> {
 impl<T> Record<T> {
     /// Create a new record reader/writer
     pub fn new(archive: &str, record_type: String, file_limit: usize, record_limit: usize) -> Self {
+        let mut archive = Archive::new(archive, record_type, file_limit);
+        archive.set_record_limit(record_limit);
         Record {
             write_buffer: Vec::new(),
             read_buffer: ReadBuf::new(),
             read_offset: 0,
             limit: record_limit,
-            archive: Archive::new(archive, record_type, file_limit),
+            archive,
+            written: (0, 0),
             _marker: PhantomData,
         }
     }
@@ -145,12 +161,22 @@ impl<T> Record<T> {
 
     /// push an item into the record, checking for need to flush
     ///
-    ///   Note: can push any size item, even larger than record or
-    ///   archive_set size, but this may not be efficient
+    ///   Note: can push any size item up to MAX_ITEM_SIZE, even larger
+    ///   than record or archive_set size, but this may not be efficient.
+    ///   An item and its length start a new record unless they fit in
+    ///   this one, so the location returned is always within the record
+    ///   it names; items larger than a record then run on into the
+    ///   following records, and sets.
     pub fn push(&mut self, v: Vec<u8>) -> Result<RecordLocation> {
-        // if bigger than our size, we will be split so may as well
-        // finish this record off
-        if v.len() <= self.limit && (self.write_buffer.len() + v.len() > self.limit) {
+        if v.len() > MAX_ITEM_SIZE {
+            return Err(format!(
+                "item of {} bytes is larger than the {} byte limit",
+                v.len(),
+                MAX_ITEM_SIZE
+            )
+            .into());
+        }
+        if self.write_buffer.len() + LEN_SIZE + v.len() > self.limit {
             self.flush()?;
         }
         let ret = RecordLocation {
@@ -184,6 +210,8 @@ impl<T> Record<T> {
             self.archive.write(&usize_to_slice_u8(compressed.len()))?;
             // write compressed data
             self.archive.write(&compressed)?;
+            self.written.0 += self.write_buffer.len() as u64;
+            self.written.1 += (LEN_SIZE + compressed.len()) as u64;
             self.write_buffer = Vec::new();
         }
         Ok(())
@@ -193,9 +221,36 @@ impl<T> Record<T> {
     ///
     ///  1. flush any records we have at this level
     ///  2. call finish at the archive level
+    ///  3. forget read state, the sets it came from may be rewritten
     pub async fn finish(&mut self) -> Result<()> {
         self.flush()?;
-        self.archive.finish().await
+        self.archive.finish().await?;
+        self.reset_read();
+        Ok(())
+    }
+
+    /// Start reading again from the first record of the first set
+    pub fn reset_read(&mut self) {
+        self.read_buffer.reset();
+        self.read_offset = 0;
+        self.archive.reset_read();
+    }
+
+    /// Bytes of records flushed so far, before and after compression
+    ///
+    ///   The compressed count includes each record's length prefix, so
+    ///   it is what went into the sets.
+    pub fn bytes_written(&self) -> (u64, u64) {
+        self.written
+    }
+
+    /// Start writing again at the first set, dropping unflushed data
+    ///
+    ///   Records already flushed to the archive are written regardless,
+    ///   so call finish first unless they are meant to be overwritten.
+    pub fn reset_write(&mut self) {
+        self.write_buffer = Vec::new();
+        self.archive.reset_write();
     }
 
     /// pull an item from the next record
@@ -208,10 +263,27 @@ impl<T> Record<T> {
             return Ok(None);
         }
 
-        let bytes_to_get = slice_u8_to_usize(&self.read_buffer.get_slice(4));
+        if self.read_buffer.len_left() < LEN_SIZE {
+            return Err("Archive ends in the middle of an item length".into());
+        }
+        let bytes_to_get = slice_u8_to_usize(&self.read_buffer.get_slice(LEN_SIZE));
+        if bytes_to_get > MAX_ITEM_SIZE {
+            return Err(format!(
+                "item length {} is over the {} byte limit, archive corrupt?",
+                bytes_to_get, MAX_ITEM_SIZE
+            )
+            .into());
+        }
 
         while bytes_to_get > self.read_buffer.len_left() {
+            let had = self.read_buffer.len_left();
             self.read_next_record()?;
+            if self.read_buffer.len_left() == had {
+                return Err(std::boxed::Box::new(Error::new(
+                    ErrorKind::Other,
+                    "Archive ends in the middle of an item",
+                )));
+            }
         }
 
         Ok(Some(self.read_buffer.get_slice(bytes_to_get)))
@@ -236,18 +308,109 @@ impl<T> Record<T> {
         }
         Ok(())
     }
+    /// pull the item pushed at `location`
+    ///
+    ///   Later pulls carry on with the items pushed after it.
+    pub fn read_at(&mut self, location: &RecordLocation) -> Result<Option<Vec<u8>>> {
+        self.seek(location.archive_location.clone())?;
+        self.read_next_record()?;
+        if self.read_buffer.len_left() < location.uncompressed_offset + LEN_SIZE {
+            return Err(format!("location {:?} is past the end of its record", location).into());
+        }
+        self.read_buffer.get_slice(location.uncompressed_offset);
+        self.pull()
+    }
     /// seek to a specific record
+    ///
+    ///   Buffered data belongs to the old position, so drop it all.
     pub fn seek(&mut self, location: ArchiveLocation) -> Result<()> {
+        self.read_buffer.reset();
+        self.read_offset = 0;
         self.archive.seek(location)?;
         Ok(())
     }
+    /// check the archive set files, see Archive::check_sets
+    pub async fn check_sets(&self, repair: bool, verbose: u64) -> Result<usize> {
+        Ok(self.archive.check_sets(repair, verbose).await?.len())
+    }
+    /// serial number for the next set appended, see Archive::next_free_serial
+    pub async fn next_free_serial(&self) -> Result<usize> {
+        self.archive.next_free_serial().await
+    }
+    /// how items are encoded, see Archive::read_header
+    pub fn mode(&self) -> ArchiveMode {
+        self.archive.mode()
+    }
+    pub fn set_mode(&mut self, mode: ArchiveMode) {
+        self.archive.set_mode(mode);
+    }
+    /// --hash algorithm of the items, see Archive::hash_algorithm
+    pub fn hash_algorithm(&self) -> &str {
+        self.archive.hash_algorithm()
+    }
+    pub fn set_hash_algorithm(&mut self, hash: String) {
+        self.archive.set_hash_algorithm(hash);
+    }
+    /// chunking of the items' hashes, see Archive::chunking
+    pub fn chunking(&self) -> &str {
+        self.archive.chunking()
+    }
+    pub fn set_chunking(&mut self, chunking: String) {
+        self.archive.set_chunking(chunking);
+    }
+    /// format version found by read_header
+    pub fn version(&self) -> u32 {
+        self.archive.version()
+    }
+    /// format version of the set the last item pulled came from
+    pub fn read_set_version(&self) -> u32 {
+        self.archive.read_set_version()
+    }
+    /// oldest format version of the first `sets` sets, see Archive::oldest_version
+    pub fn oldest_version(&self, sets: usize) -> u32 {
+        self.archive.oldest_version(sets)
+    }
+    /// sets of each format version, see Archive::version_mix
+    pub fn version_mix(&self, sets: usize) -> std::collections::BTreeMap<u32, usize> {
+        self.archive.version_mix(sets)
+    }
+    /// when each injest root was last scanned, see Archive::injested
+    pub fn injested(&self) -> &std::collections::BTreeMap<String, u64> {
+        self.archive.injested()
+    }
+    pub fn set_injested(&mut self, injested: std::collections::BTreeMap<String, u64>) {
+        self.archive.set_injested(injested);
+    }
+    /// total bytes of the archive set files
+    pub async fn sets_size(&self) -> Result<u64> {
+        self.archive.sets_size().await
+    }
+    /// load the mode and version from the archive header
+    pub async fn read_header(&mut self) -> Result<ArchiveMode> {
+        self.archive.read_header().await
+    }
+    /// record the mode in the archive header
+    pub async fn write_header(&self) -> Result<()> {
+        self.archive.write_header().await
+    }
+    /// write the manifest of archive set files
+    pub async fn write_manifest(&self) -> Result<()> {
+        self.archive.write_manifest().await
+    }
     /// backup an archive
     pub async fn backup(&self) -> Result<()> {
         self.archive.backup().await?;
         Ok(())
     }
+    /// replace the sets with those staged, see Archive::swap_in
+    pub async fn swap_in(&self, staging: &async_std::path::Path) -> Result<()> {
+        self.archive.swap_in(staging).await
+    }
 }
 
+/// bytes in the length pushed before each item
+const LEN_SIZE: usize = 4;
+
 fn slice_u8_to_usize(b: &[u8]) -> usize {
     (b[0] as usize) | (b[1] as usize) << 8 | (b[2] as usize) << 16 | (b[3] as usize) << 24
 }
@@ -260,3 +423,132 @@ fn usize_to_slice_u8(len: usize) -> Vec<u8> {
     v.push(((len >> 24) & 0xff) as u8);
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use async_std::task;
+
+    /// Small records and sets, so items run across both
+    fn record(dir: &std::path::Path) -> Record<()> {
+        Record::new(dir.to_str().unwrap(), "test".to_string(), 8 * 1024, 1024)
+    }
+
+    /// An item of `len` bytes that doesn't compress away
+    fn item(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed.wrapping_mul(2654435761) | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn items_round_trip_across_records_and_sets() {
+        let dir = scratch("record-round-trip");
+        let items: Vec<Vec<u8>> = [0, 10, 1020, 1021, 3000, 20_000, 5, 9000]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| item(*len, i as u32))
+            .collect();
+        let mut record = record(&dir);
+        let mut locations = Vec::new();
+        task::block_on(async {
+            for item in &items {
+                locations.push(record.push(item.clone()).unwrap());
+            }
+            record.finish().await.unwrap();
+            assert!(record.check_sets(false, 0).await.unwrap() > 2);
+        });
+        for item in &items {
+            assert_eq!(record.pull().unwrap().as_ref(), Some(item));
+        }
+        assert!(record.pull().unwrap().is_none());
+        for (i, location) in locations.iter().enumerate().rev() {
+            assert_eq!(record.read_at(location).unwrap().as_ref(), Some(&items[i]));
+            for item in &items[i + 1..] {
+                assert_eq!(record.pull().unwrap().as_ref(), Some(item));
+            }
+        }
+    }
+
+    /// Push `items` as the whole of the record, the sets it had backed
+    /// up first as FileStore::write does, and finish it
+    fn write_all(record: &mut Record<()>, items: &[Vec<u8>]) -> Vec<RecordLocation> {
+        task::block_on(record.backup()).unwrap();
+        record.reset_write();
+        let locations = items
+            .iter()
+            .map(|i| record.push(i.clone()).unwrap())
+            .collect();
+        task::block_on(record.finish()).unwrap();
+        locations
+    }
+
+    fn read_all(record: &mut Record<()>) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| record.pull().unwrap()).collect()
+    }
+
+    #[test]
+    fn a_finished_write_reads_from_the_start() {
+        let dir = scratch("record-write-read");
+        let items: Vec<_> = (0..50).map(|i| item(300, i)).collect();
+        let mut record = record(&dir);
+        write_all(&mut record, &items);
+        assert_eq!(read_all(&mut record), items);
+    }
+
+    #[test]
+    fn a_rewrite_mid_read_reads_the_new_items() {
+        let dir = scratch("record-read-write-read");
+        let (old, new): (Vec<_>, Vec<_>) = ((0..50).map(|i| item(300, i)).collect(), {
+            (100..110).map(|i| item(700, i)).collect()
+        });
+        let mut record = record(&dir);
+        write_all(&mut record, &old);
+        assert_eq!(record.pull().unwrap().as_ref(), Some(&old[0]));
+        assert_eq!(record.pull().unwrap().as_ref(), Some(&old[1]));
+        write_all(&mut record, &new);
+        assert_eq!(read_all(&mut record), new);
+        assert!(task::block_on(record.check_sets(false, 0)).unwrap() >= 1);
+    }
+
+    #[test]
+    fn a_seek_mid_read_drops_what_was_buffered() {
+        let dir = scratch("record-seek");
+        let items: Vec<_> = (0..40).map(|i| item(500, i)).collect();
+        let mut record = record(&dir);
+        let locations = write_all(&mut record, &items);
+        for _ in 0..7 {
+            record.pull().unwrap();
+        }
+        assert_eq!(
+            record.read_at(&locations[3]).unwrap().as_ref(),
+            Some(&items[3])
+        );
+        assert_eq!(record.pull().unwrap().as_ref(), Some(&items[4]));
+        assert_eq!(
+            record.read_at(&locations[30]).unwrap().as_ref(),
+            Some(&items[30])
+        );
+        assert_eq!(read_all(&mut record), items[31..]);
+        record.reset_read();
+        assert_eq!(read_all(&mut record), items);
+    }
+
+    #[test]
+    fn items_over_the_limit_are_refused() {
+        let dir = scratch("record-too-big");
+        let mut record = record(&dir);
+        assert!(record.push(vec![0; MAX_ITEM_SIZE + 1]).is_err());
+        let location = record.push(vec![7; 3]).unwrap();
+        task::block_on(record.finish()).unwrap();
+        assert_eq!(record.read_at(&location).unwrap(), Some(vec![7; 3]));
+        assert!(record.pull().unwrap().is_none());
+    }
+}
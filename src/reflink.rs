@@ -0,0 +1,269 @@
+//! extent sharing (reflink) detection and in-place dedupe
+//!
+//! On btrfs and XFS two files can share physical extents, so removing
+//! one of them frees nothing.  FIEMAP tells us where a file's data
+//! lives and FIDEDUPERANGE asks the kernel to share identical ranges.
+//! Both are Linux only, as is telling a filesystem's kind by statfs.
+
+use std::io;
+use std::path::Path;
+
+/// A physical extent of a file, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+/// What a filesystem can do to make two files one, see --feasibility
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Caps {
+    pub hardlinks: bool,
+    pub reflinks: bool,
+}
+
+/// Bytes of two extent lists that live on the same physical ranges
+pub fn overlap(a: &[Extent], b: &[Extent]) -> u64 {
+    let mut ea = a.to_vec();
+    let mut eb = b.to_vec();
+    ea.sort_by_key(|e| e.physical);
+    eb.sort_by_key(|e| e.physical);
+
+    // walk both sorted lists summing the overlap of physical ranges
+    let mut shared = 0;
+    let (mut i, mut j) = (0, 0);
+    while i < ea.len() && j < eb.len() {
+        let (sa, fa) = (ea[i].physical, ea[i].physical + ea[i].length);
+        let (sb, fb) = (eb[j].physical, eb[j].physical + eb[j].length);
+        if sa.max(sb) < fa.min(fb) {
+            shared += fa.min(fb) - sa.max(sb);
+        }
+        if fa < fb {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    shared
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{Caps, Extent};
+    use std::ffi::CString;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    const EXTENT_BATCH: usize = 128;
+    // kernels clamp a single dedupe request, btrfs to 16MiB
+    const DEDUPE_STEP: u64 = 16 * 1024 * 1024;
+    // statfs f_type of filesystems that share extents, or lack hard
+    // links, from linux/magic.h
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123_683E;
+    const XFS_SUPER_MAGIC: u32 = 0x5846_5342;
+    const BCACHEFS_SUPER_MAGIC: u32 = 0xCA45_1A4E;
+    const OCFS2_SUPER_MAGIC: u32 = 0x7461_636F;
+    const MSDOS_SUPER_MAGIC: u32 = 0x4D44;
+    const EXFAT_SUPER_MAGIC: u32 = 0x2011_BAB0;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; EXTENT_BATCH],
+    }
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [FileDedupeRangeInfo; 1],
+    }
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    pub fn extents(path: &Path) -> io::Result<Vec<Extent>> {
+        let f = File::open(path)?;
+        let mut ret = Vec::new();
+        let mut start = 0;
+        loop {
+            let mut fm = Fiemap {
+                fm_start: start,
+                fm_length: u64::MAX - start,
+                fm_flags: FIEMAP_FLAG_SYNC,
+                fm_mapped_extents: 0,
+                fm_extent_count: EXTENT_BATCH as u32,
+                fm_reserved: 0,
+                fm_extents: [FiemapExtent::default(); EXTENT_BATCH],
+            };
+            // SAFETY: fm is a correctly sized fiemap with room for
+            // fm_extent_count extents, and lives across the call
+            if unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fm) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mapped = &fm.fm_extents[..fm.fm_mapped_extents as usize];
+            for e in mapped {
+                // inline or not yet placed data has no meaningful address
+                if e.fe_flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) == 0 {
+                    ret.push(Extent {
+                        logical: e.fe_logical,
+                        physical: e.fe_physical,
+                        length: e.fe_length,
+                    });
+                }
+            }
+            match mapped.last() {
+                Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                    start = last.fe_logical + last.fe_length;
+                }
+                _ => break,
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn dedupe(src: &Path, dst: &Path, len: u64) -> io::Result<u64> {
+        let s = File::open(src)?;
+        let d = OpenOptions::new().write(true).open(dst)?;
+        let mut offset = 0;
+        while offset < len {
+            let mut req = FileDedupeRange {
+                src_offset: offset,
+                src_length: (len - offset).min(DEDUPE_STEP),
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                info: [FileDedupeRangeInfo {
+                    dest_fd: d.as_raw_fd() as i64,
+                    dest_offset: offset,
+                    bytes_deduped: 0,
+                    status: 0,
+                    reserved: 0,
+                }],
+            };
+            // SAFETY: req is a file_dedupe_range with exactly dest_count
+            // info records, and lives across the call
+            if unsafe { libc::ioctl(s.as_raw_fd(), FIDEDUPERANGE as _, &mut req) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let info = &req.info[0];
+            if info.status < 0 {
+                return Err(io::Error::from_raw_os_error(-info.status));
+            }
+            if info.status == FILE_DEDUPE_RANGE_DIFFERS {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "contents differ, not deduplicated",
+                ));
+            }
+            if info.bytes_deduped == 0 {
+                break;
+            }
+            offset += info.bytes_deduped;
+        }
+        Ok(offset)
+    }
+
+    pub fn capabilities(path: &Path) -> io::Result<Caps> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let mut st = std::mem::MaybeUninit::<libc::statfs>::uninit();
+        // SAFETY: path is NUL terminated and st is a statfs for the
+        // kernel to fill, read only once the call succeeds
+        if unsafe { libc::statfs(path.as_ptr(), st.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: statfs succeeded, so st is filled in
+        let kind = unsafe { st.assume_init() }.f_type as u32;
+        Ok(Caps {
+            hardlinks: !matches!(kind, MSDOS_SUPER_MAGIC | EXFAT_SUPER_MAGIC),
+            reflinks: matches!(
+                kind,
+                BTRFS_SUPER_MAGIC | XFS_SUPER_MAGIC | BCACHEFS_SUPER_MAGIC | OCFS2_SUPER_MAGIC
+            ),
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::{Caps, Extent};
+    use std::io;
+    use std::path::Path;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "reflinks are only supported on Linux")
+    }
+
+    pub fn extents(_path: &Path) -> io::Result<Vec<Extent>> {
+        Err(unsupported())
+    }
+
+    pub fn dedupe(_src: &Path, _dst: &Path, _len: u64) -> io::Result<u64> {
+        Err(unsupported())
+    }
+
+    pub fn capabilities(_path: &Path) -> io::Result<Caps> {
+        Ok(Caps {
+            hardlinks: true,
+            reflinks: false,
+        })
+    }
+}
+
+/// Physical extents of a file
+pub fn extents(path: &Path) -> io::Result<Vec<Extent>> {
+    sys::extents(path)
+}
+
+/// Ask the kernel to share `dst`'s first `len` bytes with `src`
+///
+///   Returns the bytes the kernel reports as deduplicated.  The kernel
+///   compares the ranges itself, so differing content is never merged.
+pub fn dedupe(src: &Path, dst: &Path, len: u64) -> io::Result<u64> {
+    sys::dedupe(src, dst, len)
+}
+
+/// What the filesystem holding `path` can do
+///
+///   Told by the kind of filesystem, so an XFS made without reflink
+///   support is taken to have it.  Elsewhere than Linux, hard links
+///   only.
+pub fn capabilities(path: &Path) -> io::Result<Caps> {
+    sys::capabilities(path)
+}
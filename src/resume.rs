@@ -0,0 +1,187 @@
+//! resumable injest runs, see --checkpoint-every and --resume
+//!
+//! With --checkpoint-every an injest writes the file store every so
+//! many minutes, or every ENTRIES new entries, and after it what is
+//! left of the walk to the archive's `resume` sets: the directories
+//! queued and those under way, and the subdirectories those under way
+//! queued already.  Those under way are walked again, passing by the
+//! subdirectories they queued before.  A run that stalls or is
+//! interrupted leaves them too, and one that finishes drops them.
+//!
+//! An injest with --resume walks those directories rather than its
+//! roots, so those finished before aren't walked again.  They only say
+//! what is left of one walk, so a run of other roots, or hashing
+//! otherwise, refuses them.  A resumed run saw only part of the tree
+//! and doesn't --prune.
+//!
+//! The sets are a Run, then a Pending for each directory and a Reached
+//! for each subdirectory, each CBOR.
+
+use crate::record::{Record, RecordLocation};
+use crate::{ItemReadWrite, Result, ARCHIVE_SIZE, RECORD_SIZE};
+use minicbor_derive::{Decode, Encode};
+
+/// New entries between checkpoints, whatever the time
+pub const ENTRIES: usize = 100_000;
+
+/// The walk a checkpoint is of
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Run {
+    #[n(0)]
+    pub roots: Vec<String>,
+    /// the hash algorithm and chunking, as the archive header has them
+    #[n(1)]
+    pub hashing: String,
+    /// unix time the walk began, for marking the roots injested
+    #[n(2)]
+    pub since: u64,
+}
+
+/// A directory left to walk
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Pending {
+    #[n(0)]
+    pub path: String,
+    #[n(1)]
+    pub depth: u64,
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+enum Item {
+    #[n(0)]
+    Run(#[n(0)] Run),
+    #[n(1)]
+    Pending(#[n(0)] Pending),
+    /// a subdirectory a directory left to walk queued already
+    #[n(2)]
+    Reached(#[n(0)] String),
+}
+
+/// What is left of a walk, as read back
+#[derive(Clone, Debug)]
+pub struct Saved {
+    pub run: Run,
+    pub pending: Vec<Pending>,
+    pub reached: Vec<String>,
+}
+
+impl Saved {
+    /// Refuse to resume a walk of other roots
+    pub fn check_roots(&self, archive: &str, roots: &[String]) -> Result<()> {
+        let (mut saved, mut given) = (self.run.roots.clone(), roots.to_vec());
+        saved.sort();
+        given.sort();
+        if saved != given {
+            return Err(format!(
+                "the run checkpoint in {} is of an injest of {}, not {}",
+                archive,
+                saved.join(" "),
+                given.join(" ")
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Refuse to resume a walk hashing otherwise, as the entries of
+    /// the directories it finished wouldn't compare
+    pub fn check_hashing(&self, archive: &str, hashing: &str) -> Result<()> {
+        if self.run.hashing != hashing {
+            return Err(format!(
+                "the run checkpoint in {} hashed with {}, this run hashes with {}",
+                archive, self.run.hashing, hashing
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl ItemReadWrite for Record<Item> {
+    type T = Item;
+    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
+        self.push(minicbor::to_vec(item)?)
+    }
+    fn read_item(&mut self) -> Result<Option<Self::T>> {
+        match &self.pull()? {
+            Some(v) => Ok(Some(minicbor::decode(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn record(archive: &str) -> Result<Record<Item>> {
+    if crate::pack::is_packed(archive) {
+        return Err(format!(
+            "{} is a single file archive, which keeps no run checkpoints",
+            archive
+        )
+        .into());
+    }
+    Ok(Record::new(
+        archive,
+        "resume".to_string(),
+        ARCHIVE_SIZE,
+        RECORD_SIZE,
+    ))
+}
+
+/// What is left of the walk checkpointed in an archive, None if no
+/// walk is
+pub async fn stored(archive: &str) -> Result<Option<Saved>> {
+    if crate::pack::is_packed(archive) {
+        return Ok(None);
+    }
+    let mut record = record(archive)?;
+    record.check_sets(false, 0).await?;
+    let run = match record.read_item()? {
+        Some(Item::Run(run)) => run,
+        Some(_) => return Err("run checkpoint without its run".into()),
+        None => return Ok(None),
+    };
+    let (mut pending, mut reached) = (Vec::new(), Vec::new());
+    while let Some(item) = record.read_item()? {
+        match item {
+            Item::Pending(dir) => pending.push(dir),
+            Item::Reached(path) => reached.push(path),
+            Item::Run(_) => (),
+        }
+    }
+    Ok(Some(Saved {
+        run,
+        pending,
+        reached,
+    }))
+}
+
+/// Replace the checkpoint kept in an archive, the old one backed up
+///
+///   The file store should be written first, so a checkpoint never
+///   names fewer directories than the store is missing.
+pub async fn store(
+    archive: &str,
+    run: &Run,
+    pending: &[Pending],
+    reached: &[String],
+) -> Result<()> {
+    let mut record = record(archive)?;
+    record.backup().await?;
+    record.write_item(&Item::Run(run.clone()))?;
+    for dir in pending {
+        record.write_item(&Item::Pending(dir.clone()))?;
+    }
+    for path in reached {
+        record.write_item(&Item::Reached(path.clone()))?;
+    }
+    record.finish().await
+}
+
+/// Drop the checkpoint kept in an archive, if there is one
+pub async fn clear(archive: &str) -> Result<()> {
+    if stored(archive).await?.is_none() {
+        return Ok(());
+    }
+    let mut record = record(archive)?;
+    record.backup().await?;
+    record.finish().await
+}
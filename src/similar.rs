@@ -0,0 +1,246 @@
+//! near duplicate files, see --similar
+//!
+//! Files of at least --similar-min-size are cut into chunks as the
+//! archive's chunking cuts them and the seahash of each chunk kept.
+//! An injest keeps them in the archive's `chunks` sets, so a later
+//! report only reads the files changed since.  An inverted index from
+//! chunk hash to the files holding it then counts the distinct chunks
+//! each pair of files shares.  A chunk held by more than FANOUT files,
+//! a block of zeros say, tells little about any pair of them and would
+//! make the count quadratic, so it is left out.
+
+use crate::file::ChunkHash;
+use crate::record::{Record, RecordLocation};
+use crate::{ItemReadWrite, Result, ARCHIVE_SIZE, MAX_ITEM_SIZE, RECORD_SIZE};
+use minicbor_derive::{Decode, Encode};
+use std::collections::HashMap;
+
+/// Files a chunk may be held by and still count
+pub const FANOUT: usize = 64;
+
+/// Chunk hashes written in one item, at most nine bytes each in CBOR,
+/// so an item stays well under MAX_ITEM_SIZE
+const PIECE: usize = MAX_ITEM_SIZE / 16;
+
+/// The chunk hashes of a file, as it was when it was read
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Chunked {
+    #[n(0)]
+    pub name: String,
+    #[n(1)]
+    pub len: u64,
+    #[n(2)]
+    pub mod_secs: u64,
+    #[n(3)]
+    pub mod_nanos: u32,
+    /// as the archive header has it, chunks cut otherwise don't compare
+    #[n(4)]
+    pub chunking: String,
+    #[n(5)]
+    pub chunks: Vec<ChunkHash>,
+}
+
+/// Two files sharing at least the asked for share of their chunks
+#[derive(Clone, Debug)]
+pub struct Pair {
+    pub a: usize,
+    pub b: usize,
+    /// of the distinct chunks of whichever has more
+    pub percent: f64,
+    /// the chunks shared, at the pair's average chunk size
+    pub shared_bytes: u64,
+}
+
+/// The pairs of `files` sharing at least `percent` of their chunks,
+/// most bytes shared first
+///
+///   Pairs with the same chunks are duplicates, reported as such, and
+///   left out here.
+pub fn pairs(files: &[Chunked], percent: f64) -> Vec<Pair> {
+    let mut index: HashMap<ChunkHash, Vec<usize>> = HashMap::new();
+    let mut distinct = Vec::with_capacity(files.len());
+    for (i, file) in files.iter().enumerate() {
+        let mut chunks = file.chunks.clone();
+        chunks.sort_unstable();
+        chunks.dedup();
+        distinct.push(chunks.len());
+        for chunk in chunks {
+            index.entry(chunk).or_default().push(i);
+        }
+    }
+    let mut shared: HashMap<(usize, usize), u64> = HashMap::new();
+    for holders in index.values().filter(|h| h.len() > 1 && h.len() <= FANOUT) {
+        for (n, a) in holders.iter().enumerate() {
+            for b in &holders[n + 1..] {
+                *shared.entry((*a, *b)).or_default() += 1;
+            }
+        }
+    }
+    let mut pairs: Vec<Pair> = shared
+        .into_iter()
+        .filter(|((a, b), _)| {
+            files[*a].len != files[*b].len || files[*a].chunks != files[*b].chunks
+        })
+        .filter_map(|((a, b), n)| {
+            let share = 100.0 * n as f64 / distinct[a].max(distinct[b]) as f64;
+            let (fa, fb) = (&files[a], &files[b]);
+            let average = (fa.len + fb.len) / (fa.chunks.len() + fb.chunks.len()).max(1) as u64;
+            (share >= percent).then(|| Pair {
+                a,
+                b,
+                percent: share,
+                shared_bytes: (n * average).min(fa.len.min(fb.len)),
+            })
+        })
+        .collect();
+    pairs.sort_by(|x, y| {
+        y.shared_bytes.cmp(&x.shared_bytes).then_with(|| {
+            (&files[x.a].name, &files[x.b].name).cmp(&(&files[y.a].name, &files[y.b].name))
+        })
+    });
+    pairs
+}
+
+impl ItemReadWrite for Record<Chunked> {
+    type T = Chunked;
+    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
+        self.push(minicbor::to_vec(item)?)
+    }
+    fn read_item(&mut self) -> Result<Option<Self::T>> {
+        match &self.pull()? {
+            Some(v) => Ok(Some(minicbor::decode(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn record(archive: &str) -> Record<Chunked> {
+    Record::new(archive, "chunks".to_string(), ARCHIVE_SIZE, RECORD_SIZE)
+}
+
+/// A file's chunk hashes as items of at most `per` chunks each
+fn pieces(file: &Chunked, per: usize) -> Vec<Chunked> {
+    if file.chunks.len() <= per {
+        return vec![file.clone()];
+    }
+    file.chunks
+        .chunks(per)
+        .map(|chunks| Chunked {
+            chunks: chunks.to_vec(),
+            ..file.clone()
+        })
+        .collect()
+}
+
+/// Join the pieces of each file, written one after another, into one
+fn joined(items: impl IntoIterator<Item = Chunked>) -> Vec<Chunked> {
+    let mut chunked: Vec<Chunked> = Vec::new();
+    for item in items {
+        match chunked.last_mut() {
+            Some(last)
+                if (
+                    &last.name,
+                    last.len,
+                    last.mod_secs,
+                    last.mod_nanos,
+                    &last.chunking,
+                ) == (
+                    &item.name,
+                    item.len,
+                    item.mod_secs,
+                    item.mod_nanos,
+                    &item.chunking,
+                ) =>
+            {
+                last.chunks.extend(item.chunks)
+            }
+            _ => chunked.push(item),
+        }
+    }
+    chunked
+}
+
+/// The chunk hashes kept in an archive, none for a single file archive
+pub async fn stored(archive: &str) -> Result<Vec<Chunked>> {
+    if crate::pack::is_packed(archive) {
+        return Ok(Vec::new());
+    }
+    let mut record = record(archive);
+    record.check_sets(false, 0).await?;
+    let mut items = Vec::new();
+    while let Some(item) = record.read_item()? {
+        items.push(item);
+    }
+    Ok(joined(items))
+}
+
+/// Replace the chunk hashes kept in an archive, the old ones backed up
+///
+///   A file with more chunks than fit in one item is written as
+///   several, which stored joins again.
+pub async fn store(archive: &str, chunked: &[Chunked]) -> Result<usize> {
+    if crate::pack::is_packed(archive) {
+        return Err(format!(
+            "{} is a single file archive, which keeps no chunk hashes",
+            archive
+        )
+        .into());
+    }
+    let mut record = record(archive);
+    record.backup().await?;
+    let mut written = 0;
+    for file in chunked {
+        let kept = pieces(file, PIECE)
+            .iter()
+            .try_for_each(|piece| record.write_item(piece).map(|_| ()));
+        match kept {
+            Ok(()) => written += 1,
+            Err(e) => eprintln!("similar: {} ({}), chunk hashes not kept", e, file.name),
+        }
+    }
+    record.finish().await?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    fn chunked(name: &str, chunks: std::ops::Range<u64>) -> Chunked {
+        Chunked {
+            name: name.to_string(),
+            len: chunks.end * 4096,
+            mod_secs: 1,
+            mod_nanos: 0,
+            chunking: "fixed".to_string(),
+            chunks: chunks.collect(),
+        }
+    }
+
+    #[test]
+    fn files_too_big_for_one_item_are_split_and_joined() {
+        let (big, small) = (chunked("/big", 0..10), chunked("/small", 0..3));
+        let items: Vec<_> = [&big, &small].iter().flat_map(|f| pieces(f, 4)).collect();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[2].chunks, [8, 9]);
+        let files = joined(items);
+        assert_eq!(files.len(), 2);
+        assert_eq!((&files[0].name, &files[0].chunks), (&big.name, &big.chunks));
+        assert_eq!(files[1].chunks, small.chunks);
+    }
+
+    #[test]
+    fn more_chunks_than_an_item_holds_are_kept() {
+        let dir = crate::testing::scratch("similar-split");
+        let archive = dir.to_str().unwrap();
+        let files = [chunked("/vm.img", 0..PIECE as u64 + 5), chunked("/a", 0..2)];
+        task::block_on(async {
+            assert_eq!(store(archive, &files).await.unwrap(), 2);
+            let kept = stored(archive).await.unwrap();
+            assert_eq!(kept.len(), 2);
+            assert_eq!(kept[0].chunks, files[0].chunks);
+            assert_eq!(kept[1].chunks, files[1].chunks);
+        });
+    }
+}
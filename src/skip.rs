@@ -0,0 +1,289 @@
+//! skipped file records for --log-skips, see `find_dups why`
+//!
+//! Each injest run with --log-skips adds what its walk left out, and
+//! why, to the archive's `skip` sets.  A directory task gathers its
+//! skips in a buffer of at most DIR_LIMIT, past which it only counts
+//! them, and hands them over in one go when it finishes.  They are
+//! written once, at the end of the run, along with those of the last
+//! KEEP_RUNS runs before it.
+
+use crate::record::{Record, RecordLocation};
+use crate::{ItemReadWrite, Result, ARCHIVE_SIZE, RECORD_SIZE};
+use async_std::path::Path;
+use minicbor_derive::{Decode, Encode};
+use std::sync::{Arc, Mutex};
+
+/// Skips recorded per directory, past this only counted
+pub const DIR_LIMIT: usize = 1000;
+
+/// Runs whose skips are kept besides the current one
+pub const KEEP_RUNS: usize = 9;
+
+/// Why a walk left a file out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum Reason {
+    #[n(0)]
+    Excluded,
+    #[n(1)]
+    Symlink,
+    #[n(2)]
+    Special,
+    #[n(3)]
+    Unreadable,
+    #[n(4)]
+    Vanished,
+    #[n(5)]
+    Unstable,
+    #[n(6)]
+    Error,
+    /// a directory with more than DIR_LIMIT skips, the rest uncounted
+    #[n(7)]
+    Overflow,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Reason::Excluded => "excluded",
+            Reason::Symlink => "symlink",
+            Reason::Special => "special file",
+            Reason::Unreadable => "unreadable",
+            Reason::Vanished => "vanished",
+            Reason::Unstable => "changed while being hashed",
+            Reason::Error => "error",
+            Reason::Overflow => "too many skips to record",
+        })
+    }
+}
+
+/// A file left out, by the run that left it out
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Skip {
+    /// unix time the run's walk began, which identifies it and is the
+    /// time it records its roots injested at
+    #[n(0)]
+    pub run: u64,
+    #[n(1)]
+    pub path: String,
+    #[n(2)]
+    pub reason: Reason,
+    /// the error, if there was one
+    #[n(3)]
+    pub detail: String,
+}
+
+impl Skip {
+    /// The reason, and the detail if any
+    pub fn describe(&self) -> String {
+        match self.detail.is_empty() {
+            true => self.reason.to_string(),
+            false => format!("{}, {}", self.reason, self.detail),
+        }
+    }
+}
+
+/// Skips gathered by a run, see --log-skips
+#[derive(Debug, Default)]
+pub struct SkipLog {
+    skips: Mutex<Vec<Skip>>,
+}
+
+impl SkipLog {
+    /// Write this run's skips after those of the last KEEP_RUNS runs,
+    /// returning how many were this run's
+    ///
+    ///   `run` is when the walk began, before it was resumed if it
+    ///   was, so `why` can tell a skip from an injest recorded since.
+    ///   A resumed run adds to the skips its walk recorded before,
+    ///   less those of paths it recorded again.
+    pub async fn store(&self, archive: &str, run: u64) -> Result<usize> {
+        let mut skips = self.skips.lock().unwrap().clone();
+        for skip in &mut skips {
+            skip.run = run;
+        }
+        let mut kept = stored(archive).await?;
+        let mut runs: Vec<u64> = kept.iter().map(|skip| skip.run).collect();
+        runs.sort_unstable();
+        runs.dedup();
+        let oldest = runs.iter().rev().nth(KEEP_RUNS - 1).copied().unwrap_or(0);
+        kept.retain(|skip| {
+            skip.run >= oldest
+                && !(skip.run == run && skips.iter().any(|again| again.path == skip.path))
+        });
+        let mut record = record(archive)?;
+        record.backup().await?;
+        for skip in kept.iter().chain(&skips) {
+            record.write_item(skip)?;
+        }
+        record.finish().await?;
+        Ok(skips.len())
+    }
+}
+
+/// The skips of one directory, handed to the log when it is done
+///
+///   Without --log-skips there is no log and nothing is kept.
+#[derive(Debug)]
+pub struct DirSkips {
+    log: Option<Arc<SkipLog>>,
+    skips: Vec<Skip>,
+    more: usize,
+}
+
+impl DirSkips {
+    pub fn new(log: Option<Arc<SkipLog>>) -> Self {
+        DirSkips {
+            log,
+            skips: Vec::new(),
+            more: 0,
+        }
+    }
+
+    pub fn push(&mut self, path: &Path, reason: Reason, detail: String) {
+        if self.log.is_none() {
+            return;
+        }
+        if self.skips.len() >= DIR_LIMIT {
+            self.more += 1;
+            return;
+        }
+        self.skips.push(Skip {
+            // stamped when stored, see SkipLog::store
+            run: 0,
+            path: path.to_string_lossy().into_owned(),
+            reason,
+            detail,
+        });
+    }
+
+    /// Hand the skips of `dir` to the log
+    pub fn finish(mut self, dir: &Path) {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return,
+        };
+        if self.more > 0 {
+            self.skips.push(Skip {
+                run: 0,
+                path: dir.to_string_lossy().into_owned(),
+                reason: Reason::Overflow,
+                detail: format!("{} more skips in this directory", self.more),
+            });
+        }
+        if !self.skips.is_empty() {
+            log.skips.lock().unwrap().append(&mut self.skips);
+        }
+    }
+}
+
+impl ItemReadWrite for Record<Skip> {
+    type T = Skip;
+    fn write_item(&mut self, item: &Self::T) -> Result<RecordLocation> {
+        self.push(minicbor::to_vec(item)?)
+    }
+    fn read_item(&mut self) -> Result<Option<Self::T>> {
+        match &self.pull()? {
+            Some(v) => Ok(Some(minicbor::decode(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn record(archive: &str) -> Result<Record<Skip>> {
+    if crate::pack::is_packed(archive) {
+        return Err(format!(
+            "{} is a single file archive, which keeps no skip records",
+            archive
+        )
+        .into());
+    }
+    Ok(Record::new(
+        archive,
+        "skip".to_string(),
+        ARCHIVE_SIZE,
+        RECORD_SIZE,
+    ))
+}
+
+/// The skips kept in an archive, oldest run first, none for a single
+/// file archive
+pub async fn stored(archive: &str) -> Result<Vec<Skip>> {
+    if crate::pack::is_packed(archive) {
+        return Ok(Vec::new());
+    }
+    let mut record = record(archive)?;
+    record.check_sets(false, 0).await?;
+    let mut skips = Vec::new();
+    while let Some(skip) = record.read_item()? {
+        skips.push(skip);
+    }
+    skips.sort_by_key(|skip| skip.run);
+    Ok(skips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use async_std::task;
+
+    fn log_of(paths: &[&str]) -> SkipLog {
+        let log = Arc::new(SkipLog::default());
+        let mut dir = DirSkips::new(Some(log.clone()));
+        for path in paths {
+            dir.push(Path::new(path), Reason::Unreadable, String::new());
+        }
+        dir.finish(Path::new("/d"));
+        Arc::try_unwrap(log).unwrap()
+    }
+
+    #[test]
+    fn skips_are_stamped_with_the_walk_start() {
+        let dir = scratch("skips-stamp");
+        let archive = dir.to_str().unwrap();
+        task::block_on(async {
+            assert_eq!(log_of(&["/d/a"]).store(archive, 1000).await.unwrap(), 1);
+            let skips = stored(archive).await.unwrap();
+            assert_eq!(skips.len(), 1);
+            assert_eq!(skips[0].run, 1000);
+        });
+    }
+
+    #[test]
+    fn a_resumed_run_adds_to_its_skips() {
+        let dir = scratch("skips-resumed");
+        let archive = dir.to_str().unwrap();
+        task::block_on(async {
+            log_of(&["/d/a", "/d/b"])
+                .store(archive, 1000)
+                .await
+                .unwrap();
+            log_of(&["/d/b", "/d/c"])
+                .store(archive, 1000)
+                .await
+                .unwrap();
+            let mut paths: Vec<String> = stored(archive)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|skip| skip.path)
+                .collect();
+            paths.sort();
+            assert_eq!(paths, ["/d/a", "/d/b", "/d/c"]);
+        });
+    }
+
+    #[test]
+    fn only_the_last_runs_are_kept() {
+        let dir = scratch("skips-runs");
+        let archive = dir.to_str().unwrap();
+        task::block_on(async {
+            for run in 0..KEEP_RUNS as u64 + 3 {
+                log_of(&["/d/a"]).store(archive, 1000 + run).await.unwrap();
+            }
+            let skips = stored(archive).await.unwrap();
+            assert_eq!(skips.len(), KEEP_RUNS + 1);
+            assert_eq!(skips[0].run, 1002);
+        });
+    }
+}
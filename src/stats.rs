@@ -0,0 +1,317 @@
+//! run statistics shared between the brokers and the file store
+
+use crate::throttle::Throttle;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters updated concurrently by the hashing tasks
+#[derive(Debug, Default)]
+pub struct Stats {
+    hash_files: AtomicUsize,
+    hash_bytes: AtomicU64,
+    bytes_read: AtomicU64,
+    hash_task_nanos: AtomicU64,
+    hash_wall: Mutex<WallClock>,
+    send_blocked_nanos: AtomicU64,
+    timed_out: Mutex<Vec<String>>,
+    cache_hits: AtomicUsize,
+    link_hits: AtomicUsize,
+    version_mix: Mutex<BTreeMap<u32, usize>>,
+    sources: Mutex<HashMap<u64, SourceStats>>,
+    /// paid for each read, see --bwlimit
+    throttle: Option<Arc<Throttle>>,
+}
+
+/// Latency buckets per doubling of hash time, see SourceStats
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+/// Enough buckets for hashes of up to 2^32us, over an hour
+const LATENCY_BUCKETS: usize = 128;
+
+/// Hash timings of the files on one device
+///
+///   Latencies go into buckets a quarter doubling wide, so quantiles
+///   cost a fixed few hundred bytes per device whatever the number of
+///   files, and come out within about 20%.
+#[derive(Debug, Clone)]
+pub struct SourceStats {
+    dev: u64,
+    dir: String,
+    files: u64,
+    bytes: u64,
+    nanos: u64,
+    buckets: Vec<u64>,
+}
+
+impl SourceStats {
+    fn new(dev: u64, dir: &str) -> Self {
+        SourceStats {
+            dev,
+            dir: dir.to_string(),
+            files: 0,
+            bytes: 0,
+            nanos: 0,
+            buckets: vec![0; LATENCY_BUCKETS],
+        }
+    }
+
+    fn add(&mut self, dir: &str, elapsed: Duration, bytes: u64) {
+        // the shallowest directory seen names the device best
+        let depth = |d: &str| d.matches('/').count();
+        if (depth(dir), dir) < (depth(&self.dir), self.dir.as_str()) {
+            self.dir = dir.to_string();
+        }
+        self.files += 1;
+        self.bytes += bytes;
+        self.nanos += elapsed.as_nanos() as u64;
+        let micros = (elapsed.as_micros() as f64).max(1.0);
+        let bucket = (micros.log2() * BUCKETS_PER_DOUBLING) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    /// The device as major:minor and the shallowest directory hashed on it
+    pub fn label(&self) -> String {
+        // glibc's encoding of dev_t
+        let major = ((self.dev >> 8) & 0xfff) | ((self.dev >> 32) & !0xfff);
+        let minor = (self.dev & 0xff) | ((self.dev >> 12) & !0xff);
+        format!("dev {}:{} ({})", major, minor, self.dir)
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Hash time below which a fraction `q` of the files fell
+    pub fn quantile(&self, q: f64) -> Duration {
+        let wanted = (q * self.files as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                let micros = 2f64.powf((bucket + 1) as f64 / BUCKETS_PER_DOUBLING);
+                return Duration::from_micros(micros as u64);
+            }
+        }
+        Duration::ZERO
+    }
+
+    /// Bytes hashed per second of hashing time, in MB/s
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 * 1000.0 / self.nanos.max(1) as f64
+    }
+}
+
+/// Time during which at least one task was busy
+#[derive(Debug, Default)]
+struct WallClock {
+    active: usize,
+    since: Option<Instant>,
+    total: Duration,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    /// Counters whose reads are held to `throttle`, see --bwlimit
+    pub fn throttled(throttle: Option<Arc<Throttle>>) -> Self {
+        Stats {
+            throttle,
+            ..Stats::default()
+        }
+    }
+
+    /// Note a hash starting, returns the start time to hand back
+    pub fn hash_started(&self) -> Instant {
+        let now = Instant::now();
+        let mut wall = self.hash_wall.lock().unwrap();
+        if wall.active == 0 {
+            wall.since = Some(now);
+        }
+        wall.active += 1;
+        now
+    }
+
+    /// Note a hash finishing after reading `bytes`
+    pub fn hash_finished(&self, started: Instant, bytes: u64) {
+        let elapsed = started.elapsed();
+        self.hash_files.fetch_add(1, Ordering::SeqCst);
+        self.hash_bytes.fetch_add(bytes, Ordering::SeqCst);
+        self.hash_task_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::SeqCst);
+        let mut wall = self.hash_wall.lock().unwrap();
+        wall.active -= 1;
+        if wall.active == 0 {
+            if let Some(since) = wall.since.take() {
+                wall.total += since.elapsed();
+            }
+        }
+    }
+
+    /// Hashes running on the hash pool right now
+    pub fn hashing(&self) -> usize {
+        self.hash_wall.lock().unwrap().active
+    }
+
+    pub fn hash_files(&self) -> usize {
+        self.hash_files.load(Ordering::SeqCst)
+    }
+
+    pub fn hash_bytes(&self) -> u64 {
+        self.hash_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Note `bytes` read by a hash still going, and with --bwlimit wait
+    /// until they are paid for, so only off the executor
+    pub fn add_bytes_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::SeqCst);
+        if let Some(throttle) = &self.throttle {
+            throttle.take(bytes);
+        }
+    }
+
+    /// Bytes read for hashing so far, including hashes not yet done
+    ///
+    ///   Unlike hash_bytes this moves during a large file, and counts
+    ///   reads of files whose hash then failed.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::SeqCst)
+    }
+
+    /// Sum of the time each hashing task took
+    pub fn hash_task_time(&self) -> Duration {
+        Duration::from_nanos(self.hash_task_nanos.load(Ordering::SeqCst))
+    }
+
+    /// Add time a directory task spent waiting on the broker channel
+    pub fn add_send_blocked(&self, blocked: Duration) {
+        self.send_blocked_nanos
+            .fetch_add(blocked.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Total time directory tasks waited on the broker channel
+    ///
+    ///   Large values mean the channel is too small for the tree.
+    pub fn send_blocked_time(&self) -> Duration {
+        Duration::from_nanos(self.send_blocked_nanos.load(Ordering::SeqCst))
+    }
+
+    /// Note a file hashed from the hash cache without reading it
+    pub fn add_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Files whose hash came from the hash cache
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits.load(Ordering::SeqCst)
+    }
+
+    /// Note a file hashed by taking the hash of another link to its inode
+    pub fn add_link_hit(&self) {
+        self.link_hits.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Files whose hash came from another link hashed this run
+    pub fn link_hits(&self) -> usize {
+        self.link_hits.load(Ordering::SeqCst)
+    }
+
+    /// Note how many archive sets read were of each format version
+    pub fn set_version_mix(&self, mix: BTreeMap<u32, usize>) {
+        *self.version_mix.lock().unwrap() = mix;
+    }
+
+    /// Archive sets read by format version, see Archive::version_mix
+    pub fn version_mix(&self) -> BTreeMap<u32, usize> {
+        self.version_mix.lock().unwrap().clone()
+    }
+
+    /// Note a file abandoned because hashing it took too long
+    pub fn add_timed_out(&self, name: &str) {
+        self.timed_out.lock().unwrap().push(name.to_string());
+    }
+
+    /// Files abandoned by --file-timeout, in the order they timed out
+    pub fn timed_out(&self) -> Vec<String> {
+        self.timed_out.lock().unwrap().clone()
+    }
+
+    /// Note one file on device `dev` in directory `dir` taking `elapsed`
+    /// to hash `bytes`
+    pub fn add_source_sample(&self, dev: u64, dir: &str, elapsed: Duration, bytes: u64) {
+        self.sources
+            .lock()
+            .unwrap()
+            .entry(dev)
+            .or_insert_with(|| SourceStats::new(dev, dir))
+            .add(dir, elapsed, bytes);
+    }
+
+    /// Devices hashed on, slowest 95th percentile first
+    pub fn slowest_sources(&self) -> Vec<SourceStats> {
+        let mut sources: Vec<SourceStats> =
+            self.sources.lock().unwrap().values().cloned().collect();
+        sources.sort_by(|a, b| {
+            b.quantile(0.95)
+                .cmp(&a.quantile(0.95))
+                .then(a.mb_per_sec().total_cmp(&b.mb_per_sec()))
+        });
+        sources
+    }
+
+    /// Time during which any hashing was going on
+    pub fn hash_wall_time(&self) -> Duration {
+        let wall = self.hash_wall.lock().unwrap();
+        match wall.since {
+            Some(since) => wall.total + since.elapsed(),
+            None => wall.total,
+        }
+    }
+}
+
+/// Wall time spent in each phase of a run, in order
+#[derive(Debug, Default)]
+pub struct Phases {
+    phases: Vec<(&'static str, Duration, String)>,
+}
+
+impl Phases {
+    pub fn new() -> Self {
+        Phases::default()
+    }
+
+    /// Add a phase with a short description of the work done in it
+    pub fn record(&mut self, name: &'static str, elapsed: Duration, detail: String) {
+        self.phases.push((name, elapsed, detail));
+    }
+
+    /// Each phase with its time and description, in order
+    pub fn phases(&self) -> &[(&'static str, Duration, String)] {
+        &self.phases
+    }
+
+    /// Print each phase with its share of `total`
+    pub fn print(&self, total: Duration) {
+        let total = total.as_secs_f64().max(f64::EPSILON);
+        for (name, elapsed, detail) in &self.phases {
+            let line = format!(
+                "  {:<7} {:9.3}s {:5.1}%  {}",
+                name,
+                elapsed.as_secs_f64(),
+                elapsed.as_secs_f64() * 100.0 / total,
+                detail
+            );
+            status!("{}", line.trim_end());
+        }
+    }
+}
+
+/// Sets per format version, e.g. "2 of format 2, 5 of format 3"
+pub fn describe_version_mix(mix: &BTreeMap<u32, usize>) -> String {
+    mix.iter()
+        .map(|(version, sets)| format!("{} of format {}", sets, version))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,48 @@
+//! read bandwidth limit, see --bwlimit
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket shared by every thread reading files to hash
+///
+///   A reader takes tokens for the bytes it has just read and, if that
+///   leaves the bucket in debt, sleeps until the debt would be paid
+///   back, so reads across all threads average out at the rate.  The
+///   bucket holds a second's worth, so a burst after an idle spell is
+///   never longer than that.
+#[derive(Debug)]
+pub struct Throttle {
+    /// bytes per second
+    rate: f64,
+    /// tokens, negative when in debt, as of the instant
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: f64) -> Self {
+        Throttle {
+            rate: bytes_per_sec,
+            bucket: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    /// The limit, in bytes per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Pay for `bytes` read, blocking, so only off the executor
+    pub fn take(&self, bytes: u64) {
+        let debt = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.1).as_secs_f64() * self.rate;
+            let tokens = (bucket.0 + refill).min(self.rate) - bytes as f64;
+            *bucket = (tokens, now);
+            -tokens
+        };
+        if debt > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(debt / self.rate));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! whole runs of find_dups, for what only shows on stderr
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// An empty directory of its own for a test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("find_dups-exit-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run find_dups, returning its stderr once it exits successfully
+fn run(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_find_dups"))
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert!(out.status.success(), "{:?} failed: {}", args, stderr);
+    stderr
+}
+
+/// Run find_dups, returning its stderr once it fails with exit status 1
+/// rather than a panic
+fn fails(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_find_dups"))
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert_eq!(out.status.code(), Some(1), "{:?}: {}", args, stderr);
+    assert!(!stderr.contains("panicked"), "{:?}: {}", args, stderr);
+    stderr
+}
+
+#[test]
+fn a_tiny_scan_exits_quietly() {
+    let dir = scratch("tiny");
+    let (archive, root) = (dir.join("archive"), dir.join("root"));
+    std::fs::create_dir(&archive).unwrap();
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("a"), b"same").unwrap();
+    std::fs::write(root.join("sub/b"), b"same").unwrap();
+    let (archive, root) = (archive.to_str().unwrap(), root.to_str().unwrap());
+    // the timer and the broker end together at any point of the timer's
+    // sleep, so run a few times
+    for _ in 0..5 {
+        for mode in ["-i", "-c"] {
+            let stderr = run(&["-a", archive, mode, root]);
+            assert!(!stderr.contains("spawn:"), "{}", stderr);
+            assert!(!stderr.contains("send failed"), "{}", stderr);
+        }
+    }
+}
+
+#[test]
+fn quiet_still_warns() {
+    let dir = scratch("quiet");
+    let (root, empty) = (dir.join("root"), dir.join("empty"));
+    let archive = root.join(".finddups");
+    std::fs::create_dir_all(&archive).unwrap();
+    std::fs::create_dir(&empty).unwrap();
+    std::fs::write(root.join("a"), b"a").unwrap();
+    let (archive, root) = (archive.to_str().unwrap(), root.to_str().unwrap());
+    let stderr = run(&["-q", "-a", archive, "-i", root]);
+    assert!(stderr.contains("is the archive directory"), "{}", stderr);
+    let stderr = run(&[
+        "-q",
+        "-a",
+        archive,
+        "-i",
+        empty.to_str().unwrap(),
+        "--prune",
+    ]);
+    assert!(stderr.contains("will not prune"), "{}", stderr);
+}
+
+#[test]
+fn archive_manifest_refuses_bad_input() {
+    let dir = scratch("manifest");
+    fails(&["archive-manifest", "-a", ""]);
+    let stderr = fails(&[
+        "archive-manifest",
+        "-a",
+        dir.to_str().unwrap(),
+        "--check-manifest",
+    ]);
+    assert!(stderr.contains("archive-manifest: "), "{}", stderr);
+    assert!(stderr.contains("no manifest"), "{}", stderr);
+}
+
+#[test]
+fn pack_and_unpack_refuse_an_empty_path() {
+    let dir = scratch("pack");
+    let fda = dir.join("a.fda");
+    fails(&["pack", "-a", "", "-o", fda.to_str().unwrap()]);
+    fails(&["unpack", fda.to_str().unwrap(), "-o", ""]);
+}
+
+#[test]
+fn upgrade_refuses_a_version_that_is_not_a_number() {
+    let dir = scratch("upgrade");
+    let archive = dir.to_str().unwrap();
+    let stderr = fails(&["upgrade", "-a", archive, "--to-version", "abc"]);
+    assert!(stderr.contains("upgrade: --to-version abc"), "{}", stderr);
+}
+
+#[test]
+fn second_archive_refuses_a_missing_or_empty_path() {
+    let dir = scratch("second");
+    let archive = dir.to_str().unwrap();
+    for args in [vec!["-a", archive], vec!["-a", archive, "-s", ""]] {
+        let out = Command::new(env!("CARGO_BIN_EXE_find_dups_second_archive"))
+            .args(&args)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(1), "{:?}: {}", args, stderr);
+        assert!(
+            stderr.starts_with("find_dups_second_archive: "),
+            "{}",
+            stderr
+        );
+    }
+}